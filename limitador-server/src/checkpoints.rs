@@ -0,0 +1,119 @@
+use limitador::storage::disk::DiskStorage;
+use limitador::storage::StorageErr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PREFIX: &str = "checkpoint-";
+
+/// Writes checkpoints of a disk storage to a directory, each to its own
+/// `checkpoint-<millis since epoch>` one, keeping only the latest few.
+pub struct Checkpoints {
+    storage: Weak<DiskStorage>,
+    dir: PathBuf,
+    keep: usize,
+    // only one checkpoint at a time, so that pruning sees them all
+    writing: Mutex<()>,
+}
+
+impl Checkpoints {
+    pub fn new(storage: &Arc<DiskStorage>, dir: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            storage: Arc::downgrade(storage),
+            dir: dir.into(),
+            keep,
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// Writes a checkpoint, returning where to, and drops the oldest ones.
+    /// Blocks on the file system.
+    pub fn create(&self) -> Result<PathBuf, String> {
+        let storage = self
+            .storage
+            .upgrade()
+            .ok_or_else(|| "the storage is gone".to_string())?;
+        let _writing = self.writing.lock().unwrap();
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("couldn't create {}: {e}", self.dir.display()))?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.dir.join(format!("{PREFIX}{millis}"));
+        storage
+            .checkpoint(&path)
+            .map_err(|e: StorageErr| e.to_string())?;
+        self.prune();
+        Ok(path)
+    }
+
+    fn prune(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut checkpoints: Vec<(u128, PathBuf)> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let millis = entry.file_name().to_str()?.strip_prefix(PREFIX)?.parse();
+                Some((millis.ok()?, entry.path()))
+            })
+            .collect();
+        checkpoints.sort_unstable_by_key(|(millis, _)| std::cmp::Reverse(*millis));
+        for (_, path) in checkpoints.into_iter().skip(self.keep) {
+            if let Err(err) = std::fs::remove_dir_all(&path) {
+                warn!("Couldn't remove the checkpoint {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Writes a checkpoint every `interval`, for as long as the storage is around.
+pub fn write_checkpoints(checkpoints: Arc<Checkpoints>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if checkpoints.storage.strong_count() == 0 {
+                break;
+            }
+            let checkpoints = Arc::clone(&checkpoints);
+            // RocksDB flushes its memtables to disk first
+            match tokio::task::spawn_blocking(move || checkpoints.create())
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()))
+            {
+                Ok(path) => info!("Wrote the checkpoint {}", path.display()),
+                Err(err) => warn!("Couldn't write a checkpoint: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoints;
+    use limitador::storage::disk::{DiskStorage, OptimizeFor};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn keeps_the_latest_checkpoints() {
+        let tmp = TempDir::new().unwrap();
+        let storage =
+            Arc::new(DiskStorage::open(tmp.path().join("db"), OptimizeFor::Throughput).unwrap());
+        let checkpoints = Checkpoints::new(&storage, tmp.path().join("checkpoints"), 2);
+
+        let written: Vec<_> = (0..3)
+            .map(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(2));
+                checkpoints.create().unwrap()
+            })
+            .collect();
+
+        assert!(!written[0].exists());
+        assert!(written[1].join("CURRENT").is_file());
+        assert!(written[2].join("CURRENT").is_file());
+    }
+}
@@ -2,7 +2,16 @@
 //
 // LIMIT_NAME_IN_PROMETHEUS_LABELS: bool
 //
+// COUNTER_METRICS_INTERVAL_SECS: u64
+// └ COUNTER_UTILIZATION_METRICS: bool
+//
+// HASH_COUNTER_VARIABLES_KEY_FILE: Path
+//
 // REDIS_URL: StorageType { String }
+// └ REDIS_CLUSTER: bool
+// └ REDIS_SHARDED: bool
+// └ REDIS_EXACT_LIMITS: bool
+// └ REDIS_KEY_PREFIX: String
 // └ REDIS_LOCAL_CACHE_ENABLED: bool
 //   └ REDIS_LOCAL_CACHE_FLUSHING_PERIOD_MS: i64 ?!
 //   └ REDIS_LOCAL_CACHE_BATCH_SIZE: u64
@@ -14,27 +23,75 @@
 // HTTP_API_PORT: port
 
 use crate::envoy_rls::server::RateLimitHeaders;
-use limitador::limit::Expression;
+use limitador::limit::{Expression, Namespace, OnEvaluationError};
 use limitador::storage;
+use std::collections::HashMap;
 use std::fmt;
+use std::time::Duration;
 use tracing::level_filters::LevelFilter;
 use url::Url;
 
+/// Masks the passwords in `url`, or in each of the URLs of a comma separated
+/// list of them, as taken by Redis Cluster and the sharded storage. The
+/// `sentinel_password` of a Redis Sentinel URL is masked too.
 pub fn redacted_url(url: String) -> String {
-    match Url::parse(url.as_str()) {
+    url.split(',')
+        .map(redacted_single_url)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn redacted_single_url(url: &str) -> String {
+    // The sharded storage trims the URLs it is given, keep what's around them
+    let trimmed = url.trim();
+    if trimmed.len() != url.len() {
+        let start = url.len() - url.trim_start().len();
+        let end = start + trimmed.len();
+        return format!(
+            "{}{}{}",
+            &url[..start],
+            redacted_single_url(trimmed),
+            &url[end..]
+        );
+    }
+    let url = redacted_query(url);
+    let url = url.as_str();
+    match Url::parse(url) {
         Ok(url_object) => {
             if url_object.password().is_some() {
                 let mut owned_url = url_object.clone();
                 if owned_url.set_password(Some("****")).is_ok() {
                     String::from(owned_url)
                 } else {
-                    url.clone()
+                    url.to_string()
                 }
             } else {
-                url.clone()
+                url.to_string()
             }
         }
-        Err(_) => url.clone(),
+        Err(_) => url.to_string(),
+    }
+}
+
+fn redacted_query(url: &str) -> String {
+    let Some((head, rest)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let (query, fragment) = match rest.split_once('#') {
+        Some((query, fragment)) => (query, Some(fragment)),
+        None => (rest, None),
+    };
+    let query = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some(("sentinel_password", _)) => "sentinel_password=****",
+            _ => param,
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    match fragment {
+        Some(fragment) => format!("{head}?{query}#{fragment}"),
+        None => format!("{head}?{query}"),
     }
 }
 
@@ -49,11 +106,18 @@ pub struct Configuration {
     pub limit_name_in_labels: bool,
     pub metric_labels_file: Option<String>,
     pub metric_labels_default: Option<Expression>,
+    pub counter_metrics_interval: Option<Duration>,
+    pub counter_utilization_metrics: bool,
+    pub counter_variables_key_file: Option<String>,
+    pub on_evaluation_error: EvaluationErrorConfiguration,
+    pub storage_resilience: StorageResilienceConfiguration,
+    pub storage_fallback: Option<StorageFallbackConfiguration>,
     pub tracing_endpoint: String,
     pub log_level: Option<LevelFilter>,
     pub structured_logs: bool,
     pub rate_limit_headers: RateLimitHeaders,
     pub grpc_reflection_service: bool,
+    pub migration: Option<CounterMigration>,
 }
 
 pub mod env {
@@ -69,9 +133,19 @@ pub mod env {
         pub static ref TRACING_ENDPOINT: Option<&'static str> = value_for("TRACING_ENDPOINT");
         pub static ref LIMIT_NAME_IN_PROMETHEUS_LABELS: bool =
             env_option_is_enabled("LIMIT_NAME_IN_PROMETHEUS_LABELS");
+        pub static ref COUNTER_METRICS_INTERVAL_SECS: Option<&'static str> =
+            value_for("COUNTER_METRICS_INTERVAL_SECS");
+        pub static ref COUNTER_UTILIZATION_METRICS: bool =
+            env_option_is_enabled("COUNTER_UTILIZATION_METRICS");
+        pub static ref HASH_COUNTER_VARIABLES_KEY_FILE: Option<&'static str> =
+            value_for("HASH_COUNTER_VARIABLES_KEY_FILE");
         pub static ref DISK_PATH: Option<&'static str> = value_for("DISK_PATH");
         pub static ref DISK_OPTIMIZE: Option<&'static str> = value_for("DISK_OPTIMIZE");
         pub static ref REDIS_URL: Option<&'static str> = value_for("REDIS_URL");
+        pub static ref REDIS_CLUSTER: bool = env_option_is_enabled("REDIS_CLUSTER");
+        pub static ref REDIS_SHARDED: bool = env_option_is_enabled("REDIS_SHARDED");
+        pub static ref REDIS_EXACT_LIMITS: bool = env_option_is_enabled("REDIS_EXACT_LIMITS");
+        pub static ref REDIS_KEY_PREFIX: Option<&'static str> = value_for("REDIS_KEY_PREFIX");
         pub static ref REDIS_LOCAL_CACHE_ENABLED: bool =
             env_option_is_enabled("REDIS_LOCAL_CACHE_ENABLED");
         pub static ref REDIS_LOCAL_CACHE_FLUSHING_PERIOD_MS: Option<&'static str> =
@@ -126,11 +200,18 @@ impl Configuration {
             limit_name_in_labels,
             metric_labels_file,
             metric_labels_default,
+            counter_metrics_interval: None,
+            counter_utilization_metrics: false,
+            counter_variables_key_file: None,
+            on_evaluation_error: EvaluationErrorConfiguration::default(),
+            storage_resilience: StorageResilienceConfiguration::default(),
+            storage_fallback: None,
             tracing_endpoint,
             log_level: None,
             structured_logs: false,
             rate_limit_headers,
             grpc_reflection_service,
+            migration: None,
         }
     }
 
@@ -150,6 +231,8 @@ impl Default for Configuration {
             limits_file: "".to_string(),
             storage: StorageConfiguration::InMemory(InMemoryStorageConfiguration {
                 cache_size: Some(10_000),
+                snapshot: None,
+                snapshot_interval: Duration::from_secs(60),
             }),
             rls_host: "".to_string(),
             rls_port: 0,
@@ -158,11 +241,18 @@ impl Default for Configuration {
             limit_name_in_labels: false,
             metric_labels_file: None,
             metric_labels_default: None,
+            counter_metrics_interval: None,
+            counter_utilization_metrics: false,
+            counter_variables_key_file: None,
+            on_evaluation_error: EvaluationErrorConfiguration::default(),
+            storage_resilience: StorageResilienceConfiguration::default(),
+            storage_fallback: None,
             tracing_endpoint: "".to_string(),
             log_level: None,
             structured_logs: false,
             rate_limit_headers: RateLimitHeaders::None,
             grpc_reflection_service: false,
+            migration: None,
         }
     }
 }
@@ -176,9 +266,61 @@ pub enum StorageConfiguration {
     Distributed(DistributedStorageConfiguration),
 }
 
+/// Moves the counters from one storage, or dump, to another, instead of
+/// serving requests, see the `migrate` subcommand.
+#[derive(PartialEq, Eq, Debug)]
+pub struct CounterMigration {
+    pub from: CounterEndpoint,
+    pub to: CounterEndpoint,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum CounterEndpoint {
+    Storage(StorageConfiguration),
+    // The counters dumped as JSON lines
+    File(String),
+}
+
+/// The policies on evaluation errors of the limits that don't have their own,
+/// see [`OnEvaluationError`].
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct EvaluationErrorConfiguration {
+    pub default: OnEvaluationError,
+    pub namespaces: HashMap<Namespace, OnEvaluationError>,
+}
+
+/// How the storages backed by Redis or a disk weather their failures, see
+/// [`storage::resilient::ResilientStorage`].
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct StorageResilienceConfiguration {
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    pub breaker: Option<(u32, Duration)>,
+}
+
+impl StorageResilienceConfiguration {
+    pub fn is_enabled(&self) -> bool {
+        self.timeout.is_some() || self.retries > 0 || self.breaker.is_some()
+    }
+}
+
+/// How the storages backed by Redis or a disk fall back to counting in memory
+/// while failing, see [`storage::fallback::FallbackStorage`].
+#[derive(PartialEq, Eq, Debug)]
+pub struct StorageFallbackConfiguration {
+    pub fallback_after: u32,
+    pub probe_interval: Duration,
+    pub recover_after: u32,
+    pub replay: bool,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct InMemoryStorageConfiguration {
     pub cache_size: Option<u64>,
+    // Where to save the counters to, and restore them from at startup
+    pub snapshot: Option<String>,
+    pub snapshot_interval: Duration,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -194,11 +336,27 @@ pub struct DistributedStorageConfiguration {
 pub struct DiskStorageConfiguration {
     pub path: String,
     pub optimization: storage::disk::OptimizeFor,
+    pub checkpoints: Option<DiskCheckpointConfiguration>,
+    // A checkpoint to create the DB from at startup
+    pub restore_from: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct DiskCheckpointConfiguration {
+    pub dir: String,
+    pub interval: Option<Duration>,
+    // Whether `POST /checkpoint` writes one
+    pub on_request: bool,
+    pub keep: usize,
 }
 
 #[derive(PartialEq, Eq)]
 pub struct RedisStorageConfiguration {
     pub url: String,
+    pub cluster: bool,
+    pub sharded: bool,
+    pub exact_limits: bool,
+    pub key_prefix: String,
     pub cache: Option<RedisStorageCacheConfiguration>,
 }
 
@@ -206,6 +364,10 @@ impl fmt::Debug for RedisStorageConfiguration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Foo")
             .field("cache", &self.cache)
+            .field("cluster", &self.cluster)
+            .field("exact_limits", &self.exact_limits)
+            .field("key_prefix", &self.key_prefix)
+            .field("sharded", &self.sharded)
             .field(
                 "url",
                 &format_args!("{}", redacted_url(self.url.clone()).as_str()),
@@ -220,4 +382,59 @@ pub struct RedisStorageCacheConfiguration {
     pub flushing_period: i64,
     pub max_counters: usize,
     pub response_timeout: u64,
+    pub partition_policy: storage::redis::PartitionPolicy,
+    pub adaptive_flush: bool,
+    pub publish_updates: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_the_password_of_a_url() {
+        assert_eq!(
+            redacted_url("redis://:secret@localhost:6379".to_string()),
+            "redis://:****@localhost:6379"
+        );
+        assert_eq!(
+            redacted_url("redis://localhost:6379".to_string()),
+            "redis://localhost:6379"
+        );
+    }
+
+    #[test]
+    fn redacts_the_passwords_of_a_list_of_urls() {
+        assert_eq!(
+            redacted_url("redis://:pw@a:7000,redis://user:pw@b:7001,redis://c:7002".to_string()),
+            "redis://:****@a:7000,redis://user:****@b:7001,redis://c:7002"
+        );
+    }
+
+    #[test]
+    fn redacts_the_passwords_of_the_shards() {
+        assert_eq!(
+            redacted_url("redis://:pw@a:6379/1, rediss://user:pw@b:6380/2 ,".to_string()),
+            "redis://:****@a:6379/1, rediss://user:****@b:6380/2 ,"
+        );
+    }
+
+    #[test]
+    fn redacts_the_passwords_of_a_sentinel_url() {
+        assert_eq!(
+            redacted_url(
+                "redis+sentinel://:pw@a:26379/mymaster?sentinel_password=secret".to_string()
+            ),
+            "redis+sentinel://:****@a:26379/mymaster?sentinel_password=****"
+        );
+        assert_eq!(
+            redacted_url(
+                "rediss+sentinel://user:pw@a:26379,b:26379/mymaster/1\
+                ?sentinel_username=admin&sentinel_password=secret#insecure"
+                    .to_string()
+            ),
+            "rediss+sentinel://user:****@a:26379,b:26379/mymaster/1\
+            ?sentinel_username=admin&sentinel_password=****#insecure"
+        );
+    }
 }
@@ -7,16 +7,16 @@ use super::server::custom::service::ratelimit::v1::rate_limit_service_server::Ra
 use super::server::envoy::service::ratelimit::v3::rate_limit_response::Code;
 use super::server::envoy::service::ratelimit::v3::{RateLimitRequest, RateLimitResponse};
 use crate::prometheus_metrics::PrometheusMetrics;
-use crate::Limiter;
 use limitador::limit::Context;
+use limitador::Limiter;
 
 pub struct KuadrantService {
-    limiter: Arc<Limiter>,
+    limiter: Arc<dyn Limiter>,
     metrics: Arc<PrometheusMetrics>,
 }
 
 impl KuadrantService {
-    pub fn new(limiter: Arc<Limiter>, metrics: Arc<PrometheusMetrics>) -> Self {
+    pub fn new(limiter: Arc<dyn Limiter>, metrics: Arc<PrometheusMetrics>) -> Self {
         Self { limiter, metrics }
     }
 }
@@ -59,10 +59,7 @@ impl RateLimitService for KuadrantService {
         let mut ctx = Context::default();
         ctx.list_binding("descriptors".to_string(), values);
 
-        let rate_limited_resp = match &*self.limiter {
-            Limiter::Blocking(limiter) => limiter.is_rate_limited(&namespace, &ctx, 1),
-            Limiter::Async(limiter) => limiter.is_rate_limited(&namespace, &ctx, 1).await,
-        };
+        let rate_limited_resp = self.limiter.is_rate_limited(&namespace, &ctx, 1).await;
 
         if let Err(e) = rate_limited_resp {
             // In this case we could return "Code::Unknown" but that's not
@@ -148,10 +145,10 @@ impl RateLimitService for KuadrantService {
         let mut ctx = Context::default();
         ctx.list_binding("descriptors".to_string(), values);
 
-        let rate_limited_resp = match &*self.limiter {
-            Limiter::Blocking(limiter) => limiter.update_counters(&namespace, &ctx, hits_addend),
-            Limiter::Async(limiter) => limiter.update_counters(&namespace, &ctx, hits_addend).await,
-        };
+        let rate_limited_resp = self
+            .limiter
+            .update_counters(&namespace, &ctx, hits_addend)
+            .await;
 
         if let Err(e) = rate_limited_resp {
             // In this case we could return "Code::Unknown" but that's not
@@ -225,7 +222,7 @@ mod tests {
             limiter.add_limit(limit);
 
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -286,7 +283,7 @@ mod tests {
             limiter.add_limit(limit);
 
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -327,7 +324,7 @@ mod tests {
             // No limits saved
             let limiter = RateLimiter::new(10_000);
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -360,7 +357,7 @@ mod tests {
         async fn test_returns_unknown_when_domain_is_empty() {
             let limiter = RateLimiter::new(10_000);
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -425,7 +422,7 @@ mod tests {
             });
 
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -503,7 +500,7 @@ mod tests {
             limiter.add_limit(limit);
 
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -555,7 +552,7 @@ mod tests {
             limiter.add_limit(limit);
 
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -593,7 +590,7 @@ mod tests {
             // No limits saved
             let limiter = RateLimiter::new(10_000);
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -622,7 +619,7 @@ mod tests {
         async fn test_returns_unknown_when_domain_is_empty() {
             let limiter = RateLimiter::new(10_000);
             let rate_limiter = KuadrantService::new(
-                Arc::new(Limiter::Blocking(limiter)),
+                Arc::new(limiter),
                 Arc::new(PrometheusMetrics::new_with_handle(
                     false,
                     TEST_PROMETHEUS_HANDLE.clone(),
@@ -13,11 +13,11 @@ use crate::envoy_rls::server::envoy::service::ratelimit::v3::{
     RateLimitRequest, RateLimitResponse,
 };
 use crate::prometheus_metrics::PrometheusMetrics;
-use crate::Limiter;
 use limitador::limit::Context;
-use limitador::CheckResult;
+use limitador::{CheckResult, Limiter};
 use tonic::body::Body;
 use tonic::codegen::http::HeaderMap;
+use tonic::metadata::MetadataValue;
 use tonic::{async_trait, transport, transport::Server, Request, Response, Status};
 use tonic_middleware::{Middleware, MiddlewareLayer, ServiceBound};
 use tracing::{Instrument, Level, Span};
@@ -25,6 +25,11 @@ use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 include!("envoy_types.rs");
 
+/// Request metadata that, set to `true`, opts into explaining the decision.
+pub const EXPLAIN_METADATA: &str = "x-limitador-explain";
+/// Response metadata holding the explanation, as JSON, when opted into.
+pub const EXPLANATION_METADATA: &str = "x-limitador-explanation-bin";
+
 pub mod custom {
     pub mod service {
         pub mod ratelimit {
@@ -57,14 +62,14 @@ impl RateLimitHeaders {
 }
 
 pub struct MyRateLimiter {
-    limiter: Arc<Limiter>,
+    limiter: Arc<dyn Limiter>,
     rate_limit_headers: RateLimitHeaders,
     metrics: Arc<PrometheusMetrics>,
 }
 
 impl MyRateLimiter {
     pub fn new(
-        limiter: Arc<Limiter>,
+        limiter: Arc<dyn Limiter>,
         rate_limit_headers: RateLimitHeaders,
         metrics: Arc<PrometheusMetrics>,
     ) -> Self {
@@ -89,6 +94,7 @@ impl RateLimitService for MyRateLimiter {
         let (metadata, _ext, req) = request.into_parts();
         let namespace = req.domain;
         let rl_headers = RateLimitRequestHeaders::new(metadata.into_headers());
+        let explain = rl_headers.get(EXPLAIN_METADATA) == Some("true");
         let parent_context =
             global::get_text_map_propagator(|propagator| propagator.extract(&rl_headers));
         let span = Span::current();
@@ -127,23 +133,19 @@ impl RateLimitService for MyRateLimiter {
         let mut ctx = Context::default();
         ctx.list_binding("descriptors".to_string(), values);
 
-        let rate_limited_resp = match &*self.limiter {
-            Limiter::Blocking(limiter) => limiter.check_rate_limited_and_update(
-                &namespace,
-                &ctx,
-                hits_addend,
-                self.rate_limit_headers != RateLimitHeaders::None,
-            ),
-            Limiter::Async(limiter) => {
-                limiter
-                    .check_rate_limited_and_update(
-                        &namespace,
-                        &ctx,
-                        hits_addend,
-                        self.rate_limit_headers != RateLimitHeaders::None,
-                    )
-                    .await
-            }
+        let rate_limited_resp = if explain {
+            self.limiter
+                .explain_rate_limited_and_update(&namespace, &ctx, hits_addend)
+                .await
+        } else {
+            self.limiter
+                .check_rate_limited_and_update(
+                    &namespace,
+                    &ctx,
+                    hits_addend,
+                    self.rate_limit_headers != RateLimitHeaders::None,
+                )
+                .await
         };
 
         if let Err(e) = rate_limited_resp {
@@ -185,7 +187,19 @@ impl RateLimitService for MyRateLimiter {
             quota: None,
         };
 
-        Ok(Response::new(reply))
+        let mut response = Response::new(reply);
+        if let Some(explanation) = rate_limited_resp.explanation {
+            match serde_json::to_vec(&explanation) {
+                Ok(json) => {
+                    response
+                        .metadata_mut()
+                        .insert_bin(EXPLANATION_METADATA, MetadataValue::from_bytes(&json));
+                }
+                Err(e) => error!("Error serializing the explanation: {:?}", e),
+            }
+        }
+
+        Ok(response)
     }
 }
 
@@ -218,7 +232,7 @@ mod rls_proto {
 
 pub async fn run_envoy_rls_server(
     address: String,
-    limiter: Arc<Limiter>,
+    limiter: Arc<dyn Limiter>,
     rate_limit_headers: RateLimitHeaders,
     metrics: Arc<PrometheusMetrics>,
     grpc_reflection_service: bool,
@@ -291,7 +305,7 @@ pub mod tests {
 
     use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
     use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
-    use crate::Configuration;
+    use crate::{create_limiter, Configuration};
 
     // Setting recorder once for all test cases
     lazy_static! {
@@ -334,7 +348,7 @@ pub mod tests {
         limiter.add_limit(limit);
 
         let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::Blocking(limiter)),
+            Arc::new(limiter),
             RateLimitHeaders::DraftVersion03,
             Arc::new(PrometheusMetrics::new_with_handle(
                 false,
@@ -392,11 +406,73 @@ pub mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_explains_when_asked_to() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["descriptors[0]['req.method'] == 'GET'"
+                .try_into()
+                .expect("failed parsing!")],
+            Vec::<limitador::limit::Expression>::default(),
+        ));
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(limiter),
+            RateLimitHeaders::None,
+            Arc::new(PrometheusMetrics::new_with_handle(
+                false,
+                TEST_PROMETHEUS_HANDLE.clone(),
+            )),
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "req.method".to_string(),
+                    value: "GET".to_string(),
+                }],
+                limit: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap();
+        assert!(response.metadata().get_bin(EXPLANATION_METADATA).is_none());
+
+        let mut request = req.into_request();
+        request
+            .metadata_mut()
+            .insert(EXPLAIN_METADATA, "true".parse().unwrap());
+        let response = rate_limiter.should_rate_limit(request).await.unwrap();
+        let explanation = response
+            .metadata()
+            .get_bin(EXPLANATION_METADATA)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let explanation: serde_json::Value = serde_json::from_slice(&explanation).unwrap();
+        assert_eq!(
+            response.into_inner().overall_code,
+            i32::from(Code::OverLimit)
+        );
+        assert_eq!(explanation["decision"]["reason"], "limited");
+        assert_eq!(explanation["limits"][0]["outcome"], "limited");
+        assert_eq!(explanation["limits"][0]["counter"]["remaining"], 0);
+    }
+
     #[tokio::test]
     async fn test_returns_ok_when_no_limits_apply() {
         // No limits saved
         let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::new(Configuration::default()).await.unwrap()),
+            create_limiter(Configuration::default()).await.unwrap(),
             RateLimitHeaders::DraftVersion03,
             Arc::new(PrometheusMetrics::new_with_handle(
                 false,
@@ -430,7 +506,7 @@ pub mod tests {
     #[tokio::test]
     async fn test_returns_unknown_when_domain_is_empty() {
         let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::new(Configuration::default()).await.unwrap()),
+            create_limiter(Configuration::default()).await.unwrap(),
             RateLimitHeaders::DraftVersion03,
             Arc::new(PrometheusMetrics::new_with_handle(
                 false,
@@ -497,7 +573,7 @@ pub mod tests {
         });
 
         let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::Blocking(limiter)),
+            Arc::new(limiter),
             RateLimitHeaders::DraftVersion03,
             Arc::new(PrometheusMetrics::new_with_handle(
                 false,
@@ -567,7 +643,7 @@ pub mod tests {
         limiter.add_limit(limit);
 
         let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::Blocking(limiter)),
+            Arc::new(limiter),
             RateLimitHeaders::DraftVersion03,
             Arc::new(PrometheusMetrics::new_with_handle(
                 false,
@@ -644,7 +720,7 @@ pub mod tests {
         limiter.add_limit(limit);
 
         let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::Blocking(limiter)),
+            Arc::new(limiter),
             RateLimitHeaders::DraftVersion03,
             Arc::new(PrometheusMetrics::new_with_handle(
                 false,
@@ -10,11 +10,16 @@ use std::collections::{BTreeMap, HashMap};
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
 pub struct CheckAndReportInfo {
     pub namespace: String,
-    pub values: HashMap<String, String>,
+    pub values: HashMap<String, serde_json::Value>,
     pub delta: u64,
     pub response_headers: Option<String>,
 }
 
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct Checkpoint {
+    pub path: String,
+}
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
 pub struct Limit {
     id: Option<String>,
@@ -84,6 +89,14 @@ pub struct Counter {
     expires_in_seconds: Option<u64>,
 }
 
+impl Counter {
+    pub fn redact_variables(&mut self) {
+        for value in self.set_variables.values_mut() {
+            *value = "****".to_string();
+        }
+    }
+}
+
 impl From<&LimitadorCounter> for Counter {
     fn from(lc: &LimitadorCounter) -> Self {
         Self {
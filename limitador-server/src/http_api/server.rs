@@ -1,10 +1,11 @@
-use crate::http_api::request_types::{CheckAndReportInfo, Counter, Limit};
+use crate::checkpoints::Checkpoints;
+use crate::http_api::request_types::{CheckAndReportInfo, Checkpoint, Counter, Limit};
 use crate::prometheus_metrics::PrometheusMetrics;
-use crate::{Limiter, Status};
+use crate::Status;
 use actix_web::{dev::Service, http::StatusCode, HttpResponse, HttpResponseBuilder, ResponseError};
 use actix_web::{App, HttpServer};
 use limitador::limit::Context;
-use limitador::CheckResult;
+use limitador::{CheckResult, Limiter};
 use paperclip::actix::{
     api_v2_errors,
     api_v2_operation,
@@ -18,14 +19,16 @@ use std::sync::{Arc, RwLock};
 use tracing::{Instrument, Level};
 
 struct RateLimitData {
-    limiter: Arc<Limiter>,
+    limiter: Arc<dyn Limiter>,
     metrics: Arc<PrometheusMetrics>,
     status: Arc<RwLock<Status>>,
+    redact_counter_variables: bool,
+    checkpoints: Option<Arc<Checkpoints>>,
 }
 
 impl RateLimitData {
     fn new(
-        limiter: Arc<Limiter>,
+        limiter: Arc<dyn Limiter>,
         metrics: Arc<PrometheusMetrics>,
         status: Arc<RwLock<Status>>,
     ) -> Self {
@@ -33,9 +36,25 @@ impl RateLimitData {
             limiter,
             metrics,
             status,
+            redact_counter_variables: false,
+            checkpoints: None,
         }
     }
-    fn limiter(&self) -> &Limiter {
+
+    /// Hides the values of the variables of the counters, which are only known
+    /// hashed when the limiter hashes them.
+    fn with_redacted_counter_variables(mut self, redact: bool) -> Self {
+        self.redact_counter_variables = redact;
+        self
+    }
+
+    /// Where the disk storage writes its checkpoints to, when asked to.
+    fn with_checkpoints(mut self, checkpoints: Option<Arc<Checkpoints>>) -> Self {
+        self.checkpoints = checkpoints;
+        self
+    }
+
+    fn limiter(&self) -> &dyn Limiter {
         self.limiter.as_ref()
     }
 
@@ -48,9 +67,10 @@ impl RateLimitData {
     }
 }
 
-#[api_v2_errors(429, 500)]
+#[api_v2_errors(404, 429, 500)]
 #[derive(Debug)]
 enum ErrorResponse {
+    NotFound,
     TooManyRequests,
     InternalServerError,
 }
@@ -58,6 +78,7 @@ enum ErrorResponse {
 impl fmt::Display for ErrorResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::NotFound => write!(f, "Not found"),
             Self::TooManyRequests => write!(f, "Too many requests"),
             Self::InternalServerError => write!(f, "Internal server error"),
         }
@@ -67,6 +88,7 @@ impl fmt::Display for ErrorResponse {
 impl ResponseError for ErrorResponse {
     fn status_code(&self) -> StatusCode {
         match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
             Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -92,10 +114,7 @@ async fn get_limits(
     namespace: web::Path<String>,
 ) -> Result<web::Json<Vec<Limit>>, ErrorResponse> {
     let namespace = &namespace.into_inner().into();
-    let limits = match data.get_ref().limiter() {
-        Limiter::Blocking(limiter) => limiter.get_limits(namespace),
-        Limiter::Async(limiter) => limiter.get_limits(namespace),
-    };
+    let limits = data.get_ref().limiter().get_limits(namespace);
     let resp_limits: Vec<Limit> = limits.iter().map(|l| l.into()).collect();
     Ok(Json(resp_limits))
 }
@@ -107,16 +126,17 @@ async fn get_counters(
     namespace: web::Path<String>,
 ) -> Result<web::Json<Vec<Counter>>, ErrorResponse> {
     let namespace = namespace.into_inner().into();
-    let get_counters_result = match data.get_ref().limiter() {
-        Limiter::Blocking(limiter) => limiter.get_counters(&namespace),
-        Limiter::Async(limiter) => limiter.get_counters(&namespace).await,
-    };
+    let get_counters_result = data.get_ref().limiter().get_counters(&namespace).await;
 
     match get_counters_result {
         Ok(counters) => {
             let mut resp_counters: Vec<Counter> = vec![];
             for c in &counters {
-                resp_counters.push(c.into());
+                let mut counter: Counter = c.into();
+                if data.redact_counter_variables {
+                    counter.redact_variables();
+                }
+                resp_counters.push(counter);
             }
             Ok(Json(resp_counters))
         }
@@ -139,10 +159,11 @@ async fn check(
     let namespace = namespace.into();
     let mut ctx = Context::default();
     ctx.list_binding("descriptors".to_string(), vec![values]);
-    let is_rate_limited_result = match state.get_ref().limiter() {
-        Limiter::Blocking(limiter) => limiter.is_rate_limited(&namespace, &ctx, delta),
-        Limiter::Async(limiter) => limiter.is_rate_limited(&namespace, &ctx, delta).await,
-    };
+    let is_rate_limited_result = state
+        .get_ref()
+        .limiter()
+        .is_rate_limited(&namespace, &ctx, delta)
+        .await;
 
     match is_rate_limited_result {
         Ok(rate_limited) => {
@@ -171,10 +192,11 @@ async fn report(
     let namespace = namespace.into();
     let mut ctx = Context::default();
     ctx.list_binding("descriptors".to_string(), vec![values]);
-    let update_counters_result = match data.get_ref().limiter() {
-        Limiter::Blocking(limiter) => limiter.update_counters(&namespace, &ctx, delta),
-        Limiter::Async(limiter) => limiter.update_counters(&namespace, &ctx, delta).await,
-    };
+    let update_counters_result = data
+        .get_ref()
+        .limiter()
+        .update_counters(&namespace, &ctx, delta)
+        .await;
 
     match update_counters_result {
         Ok(_) => Ok(Json(())),
@@ -198,19 +220,10 @@ async fn check_and_report(
     let mut ctx = Context::default();
     ctx.list_binding("descriptors".to_string(), vec![values]);
     let rate_limit_data = data.get_ref();
-    let rate_limited_and_update_result = match rate_limit_data.limiter() {
-        Limiter::Blocking(limiter) => limiter.check_rate_limited_and_update(
-            &namespace,
-            &ctx,
-            delta,
-            response_headers.is_some(),
-        ),
-        Limiter::Async(limiter) => {
-            limiter
-                .check_rate_limited_and_update(&namespace, &ctx, delta, response_headers.is_some())
-                .await
-        }
-    };
+    let rate_limited_and_update_result = rate_limit_data
+        .limiter()
+        .check_rate_limited_and_update(&namespace, &ctx, delta, response_headers.is_some())
+        .await;
 
     match rate_limited_and_update_result {
         Ok(mut is_rate_limited) => {
@@ -259,6 +272,76 @@ async fn check_and_report(
     }
 }
 
+#[tracing::instrument(skip(data))]
+#[api_v2_operation]
+async fn explain(
+    data: web::Data<RateLimitData>,
+    request: web::Json<CheckAndReportInfo>,
+) -> HttpResponse {
+    let CheckAndReportInfo {
+        namespace,
+        values,
+        delta,
+        response_headers: _,
+    } = request.into_inner();
+    let namespace = namespace.into();
+    let mut ctx = Context::default();
+    ctx.list_binding("descriptors".to_string(), vec![values]);
+    let rate_limit_data = data.get_ref();
+    let explain_result = rate_limit_data
+        .limiter()
+        .explain_rate_limited_and_update(&namespace, &ctx, delta)
+        .await;
+
+    match explain_result {
+        Ok(result) => {
+            if result.limited {
+                rate_limit_data.metrics().incr_limited_calls(
+                    &namespace,
+                    result.limit_name.as_deref(),
+                    &ctx,
+                );
+                HttpResponse::TooManyRequests().json(result.explanation)
+            } else {
+                rate_limit_data
+                    .metrics()
+                    .incr_authorized_calls(&namespace, &ctx);
+                rate_limit_data
+                    .metrics()
+                    .incr_authorized_hits(&namespace, &ctx, delta);
+                HttpResponse::Ok().json(result.explanation)
+            }
+        }
+        Err(_) => HttpResponse::InternalServerError().json(()),
+    }
+}
+
+// Only found when the disk storage is configured to write checkpoints on request
+#[tracing::instrument(skip(data))]
+#[api_v2_operation]
+async fn checkpoint(
+    data: web::Data<RateLimitData>,
+) -> Result<web::Json<Checkpoint>, ErrorResponse> {
+    let Some(checkpoints) = data.get_ref().checkpoints.clone() else {
+        return Err(ErrorResponse::NotFound);
+    };
+    match actix_web::web::block(move || checkpoints.create())
+        .await
+        .unwrap_or_else(|err| Err(err.to_string()))
+    {
+        Ok(path) => {
+            info!("Wrote the checkpoint {}", path.display());
+            Ok(Json(Checkpoint {
+                path: path.display().to_string(),
+            }))
+        }
+        Err(err) => {
+            error!("Couldn't write a checkpoint: {}", err);
+            Err(ErrorResponse::InternalServerError)
+        }
+    }
+}
+
 pub fn add_response_header(
     resp: &mut HttpResponseBuilder,
     rate_limit_headers: &str,
@@ -281,15 +364,17 @@ pub fn add_response_header(
 
 pub async fn run_http_server(
     address: &str,
-    rate_limiter: Arc<Limiter>,
+    rate_limiter: Arc<dyn Limiter>,
     prometheus_metrics: Arc<PrometheusMetrics>,
     status_reader: Arc<RwLock<Status>>,
+    redact_counter_variables: bool,
+    checkpoints: Option<Arc<Checkpoints>>,
 ) -> std::io::Result<()> {
-    let data = web::Data::new(RateLimitData::new(
-        rate_limiter,
-        prometheus_metrics,
-        status_reader,
-    ));
+    let data = web::Data::new(
+        RateLimitData::new(rate_limiter, prometheus_metrics, status_reader)
+            .with_redacted_counter_variables(redact_counter_variables)
+            .with_checkpoints(checkpoints),
+    );
 
     // This uses the paperclip crate to generate an OpenAPI spec.
     // Ref: https://paperclip.waffles.space/actix-plugin.html
@@ -322,6 +407,8 @@ pub async fn run_http_server(
             .route("/check_and_report", web::post().to(check_and_report))
             .route("/check", web::post().to(check))
             .route("/report", web::post().to(report))
+            .route("/explain", web::post().to(explain))
+            .route("/checkpoint", web::post().to(checkpoint))
             .build()
     })
     .bind(address)?
@@ -333,9 +420,10 @@ pub async fn run_http_server(
 mod tests {
     use super::*;
     use crate::envoy_rls::server::tests::TEST_PROMETHEUS_HANDLE;
-    use crate::Configuration;
+    use crate::{create_limiter, Configuration};
     use actix_web::{test, web};
     use limitador::limit::Limit as LimitadorLimit;
+    use limitador::storage::disk::{DiskStorage, OptimizeFor};
     use std::collections::HashMap;
 
     // All these tests use the in-memory storage implementation to simplify. We
@@ -347,8 +435,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_status() {
-        let limiter = Limiter::new(Configuration::default()).await.unwrap();
-        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
         let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
             PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
         );
@@ -372,8 +459,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_metrics() {
-        let rate_limiter: Arc<Limiter> =
-            Arc::new(Limiter::new(Configuration::default()).await.unwrap());
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
         let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
             PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
         );
@@ -400,11 +486,10 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_limits_read() {
-        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
         let namespace = "test_namespace";
 
-        let limit = create_test_limit(&limiter, namespace, 10).await;
-        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let limit = create_test_limit(rate_limiter.as_ref(), namespace, 10).await;
         let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
             PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
         );
@@ -430,14 +515,61 @@ mod tests {
         assert_eq!(*resp_limits.first().unwrap(), Limit::from(&limit));
     }
 
+    #[actix_rt::test]
+    async fn test_counters_read_redacted() {
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let _limit = create_test_limit(rate_limiter.as_ref(), namespace, 10).await;
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(
+            RateLimitData::new(rate_limiter, prometheus_metrics, Default::default())
+                .with_redacted_counter_variables(true),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/report", web::post().to(report))
+                .route("/counters/{namespace}", web::get().to(get_counters)),
+        )
+        .await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".into(), "GET".into());
+        values.insert("app.id".into(), "alice".into());
+        let info = CheckAndReportInfo {
+            namespace: namespace.into(),
+            values,
+            delta: 1,
+            response_headers: None,
+        };
+        let req = test::TestRequest::post()
+            .uri("/report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/counters/{namespace}"))
+            .data(data.clone())
+            .to_request();
+        let resp_counters: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            resp_counters[0]["set_variables"]["descriptors[0]['app.id']"],
+            "****"
+        );
+    }
+
     #[actix_rt::test]
     async fn test_check_and_report() {
-        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
 
         // Create a limit with max == 1
         let namespace = "test_namespace";
-        let _limit = create_test_limit(&limiter, namespace, 1).await;
-        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let _limit = create_test_limit(rate_limiter.as_ref(), namespace, 1).await;
         let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
             PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
         );
@@ -487,12 +619,11 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_check_and_report_with_draftversion03_response_headers() {
-        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
 
         // Create a limit with max == 1
         let namespace = "test_namespace";
-        let _limit = create_test_limit(&limiter, namespace, 2).await;
-        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let _limit = create_test_limit(rate_limiter.as_ref(), namespace, 2).await;
         let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
             PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
         );
@@ -564,13 +695,128 @@ mod tests {
         assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
     }
 
+    #[actix_rt::test]
+    async fn test_check_and_report_with_typed_values() {
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        rate_limiter.add_limit(LimitadorLimit::new(
+            namespace,
+            1,
+            60,
+            vec!["descriptors[0].size > 100 && 'bulk' in descriptors[0].tags"
+                .try_into()
+                .expect("failed parsing!")],
+            vec!["descriptors[0].user.id"
+                .try_into()
+                .expect("failed parsing!")],
+        ));
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(
+            rate_limiter,
+            prometheus_metrics,
+            Default::default(),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/check_and_report", web::post().to(check_and_report)),
+        )
+        .await;
+
+        let info: CheckAndReportInfo = serde_json::from_value(serde_json::json!({
+            "namespace": namespace,
+            "values": {
+                "size": 101,
+                "tags": ["bulk"],
+                "user": {"id": 42},
+            },
+            "delta": 1,
+            "response_headers": null,
+        }))
+        .unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_explain() {
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let _limit = create_test_limit(rate_limiter.as_ref(), namespace, 1).await;
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(
+            rate_limiter,
+            prometheus_metrics,
+            Default::default(),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/explain", web::post().to(explain)),
+        )
+        .await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".into(), "GET".into());
+        values.insert("app.id".into(), "1".into());
+        let info = CheckAndReportInfo {
+            namespace: namespace.into(),
+            values,
+            delta: 1,
+            response_headers: None,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/explain")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let explanation: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(explanation["decision"]["reason"], "within_limits");
+        let limit = &explanation["limits"][0];
+        assert_eq!(limit["outcome"], "within_limits");
+        assert_eq!(limit["conditions"][0]["matched"], true);
+        assert_eq!(limit["variables"]["descriptors[0]['app.id']"], "1");
+        assert_eq!(limit["counter"]["remaining"], 0);
+
+        let req = test::TestRequest::post()
+            .uri("/explain")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let explanation: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(explanation["decision"]["reason"], "limited");
+        assert_eq!(explanation["limits"][0]["outcome"], "limited");
+    }
+
     #[actix_rt::test]
     async fn test_check_and_report_endpoints_separately() {
         let namespace = "test_namespace";
-        let limiter = Limiter::new(Configuration::default()).await.unwrap();
-        let _limit = create_test_limit(&limiter, namespace, 1).await;
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
+        let _limit = create_test_limit(rate_limiter.as_ref(), namespace, 1).await;
 
-        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
         let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
             PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
         );
@@ -626,7 +872,7 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 
-    async fn create_test_limit(limiter: &Limiter, namespace: &str, max: u64) -> LimitadorLimit {
+    async fn create_test_limit(limiter: &dyn Limiter, namespace: &str, max: u64) -> LimitadorLimit {
         // Create a limit
         let limit = LimitadorLimit::new(
             namespace,
@@ -640,10 +886,46 @@ mod tests {
                 .expect("failed parsing!")],
         );
 
-        match &limiter {
-            Limiter::Blocking(limiter) => limiter.add_limit(limit.clone()),
-            Limiter::Async(limiter) => limiter.add_limit(limit.clone()),
-        };
+        limiter.add_limit(limit.clone());
         limit
     }
+
+    #[actix_rt::test]
+    async fn test_checkpoint() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let storage =
+            Arc::new(DiskStorage::open(tmp.path().join("db"), OptimizeFor::Throughput).unwrap());
+        let rate_limiter = create_limiter(Configuration::default()).await.unwrap();
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RateLimitData::new(
+                    rate_limiter.clone(),
+                    prometheus_metrics.clone(),
+                    Default::default(),
+                )))
+                .route("/checkpoint", web::post().to(checkpoint)),
+        )
+        .await;
+        let req = test::TestRequest::post().uri("/checkpoint").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let checkpoints = Checkpoints::new(&storage, tmp.path().join("checkpoints"), 1);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    RateLimitData::new(rate_limiter, prometheus_metrics, Default::default())
+                        .with_checkpoints(Some(Arc::new(checkpoints))),
+                ))
+                .route("/checkpoint", web::post().to(checkpoint)),
+        )
+        .await;
+        let req = test::TestRequest::post().uri("/checkpoint").to_request();
+        let resp: Checkpoint = test::call_and_read_body_json(&app, req).await;
+        assert!(std::path::Path::new(&resp.path).join("CURRENT").is_file());
+    }
 }
@@ -6,11 +6,14 @@ extern crate tracing;
 extern crate clap;
 extern crate tonic_middleware;
 
+use crate::checkpoints::{write_checkpoints, Checkpoints};
 #[cfg(feature = "distributed_storage")]
 use crate::config::DistributedStorageConfiguration;
 use crate::config::{
-    redacted_url, Configuration, DiskStorageConfiguration, InMemoryStorageConfiguration,
+    redacted_url, Configuration, CounterEndpoint, CounterMigration, DiskCheckpointConfiguration,
+    DiskStorageConfiguration, EvaluationErrorConfiguration, InMemoryStorageConfiguration,
     RedisStorageCacheConfiguration, RedisStorageConfiguration, StorageConfiguration,
+    StorageFallbackConfiguration, StorageResilienceConfiguration,
 };
 use crate::envoy_rls::server::{run_envoy_rls_server, RateLimitHeaders};
 use crate::http_api::server::run_http_server;
@@ -19,22 +22,28 @@ use chrono::{NaiveDateTime, Utc};
 use clap::builder::ValueParser;
 #[cfg(feature = "distributed_storage")]
 use clap::parser::ValuesRef;
-use clap::{value_parser, Arg, ArgAction, Command};
+use clap::{value_parser, Arg, ArgAction, ArgGroup, Command};
 use const_format::formatcp;
-use limitador::counter::Counter;
+use limitador::counter::{Counter, VariableHasher};
 use limitador::errors::LimitadorError;
-use limitador::limit::{Expression, Limit};
+use limitador::limit::lint::{lint, Severity};
+use limitador::limit::{Expression, Limit, Namespace, OnEvaluationError};
+use limitador::simulator::Simulator;
+use limitador::storage::blocking::BlockingStorageAdapter;
 use limitador::storage::disk::DiskStorage;
+use limitador::storage::dump::CounterDump;
+use limitador::storage::fallback::FallbackStorage;
+use limitador::storage::in_memory::InMemoryStorage;
 use limitador::storage::redis::{
-    AsyncRedisStorage, CachedRedisStorage, CachedRedisStorageBuilder, DEFAULT_BATCH_SIZE,
-    DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS, DEFAULT_RESPONSE_TIMEOUT_MS,
+    AsyncRedisStorage, CachedRedisStorage, CachedRedisStorageBuilder, FlushPolicy, PartitionPolicy,
+    ShardedRedisStorage, DEFAULT_BATCH_SIZE, DEFAULT_FLUSHING_PERIOD_SEC,
+    DEFAULT_MAX_CACHED_COUNTERS, DEFAULT_RESPONSE_TIMEOUT_MS,
 };
+use limitador::storage::resilient::ResilientStorage;
 #[cfg(feature = "distributed_storage")]
 use limitador::storage::DistributedInMemoryStorage;
 use limitador::storage::{AsyncCounterStorage, AsyncStorage, Storage};
-use limitador::{
-    storage, AsyncRateLimiter, AsyncRateLimiterBuilder, RateLimiter, RateLimiterBuilder,
-};
+use limitador::{storage, AsyncRateLimiterBuilder, Limiter, RateLimiterBuilder};
 use notify::event::{CreateKind, ModifyKind, RenameMode};
 use notify::{Error, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use opentelemetry::{global, KeyValue};
@@ -44,21 +53,24 @@ use opentelemetry_sdk::{trace, Resource};
 use paperclip::actix::Apiv2Schema;
 use prometheus_metrics::PrometheusMetrics;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Weak};
 use std::time::Duration;
 use std::{env, process};
 use sysinfo::{MemoryRefreshKind, RefreshKind, System};
 use thiserror::Error;
 use tokio::runtime::Handle;
+use tokio::time::MissedTickBehavior;
 use tracing::level_filters::LevelFilter;
 use tracing::Subscriber;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{layer::SubscriberExt, Layer};
 
+mod checkpoints;
 mod envoy_rls;
 mod http_api;
 
@@ -77,11 +89,8 @@ pub enum LimitadorServerError {
     ConfigFile(String),
     #[error("Internal error: {0}")]
     Internal(LimitadorError),
-}
-
-pub enum Limiter {
-    Blocking(RateLimiter),
-    Async(AsyncRateLimiter),
+    #[error("Couldn't migrate the counters: {0}")]
+    Migration(String),
 }
 
 impl From<LimitadorError> for LimitadorServerError {
@@ -90,127 +99,340 @@ impl From<LimitadorError> for LimitadorServerError {
     }
 }
 
-impl Limiter {
-    pub async fn new(config: Configuration) -> Result<Self, LimitadorServerError> {
-        let rate_limiter = match config.storage {
-            StorageConfiguration::Redis(cfg) => Self::redis_limiter(cfg).await,
-            StorageConfiguration::InMemory(cfg) => Self::in_memory_limiter(cfg),
-            #[cfg(feature = "distributed_storage")]
-            StorageConfiguration::Distributed(cfg) => Self::distributed_limiter(cfg),
-            StorageConfiguration::Disk(cfg) => Self::disk_limiter(cfg),
-        };
+pub async fn create_limiter(
+    config: Configuration,
+) -> Result<Arc<dyn Limiter>, LimitadorServerError> {
+    Ok(create_limiter_and_checkpoints(config).await?.0)
+}
 
-        Ok(rate_limiter)
-    }
+// The checkpoints are those of the disk storage, when configured to write them
+// on `POST /checkpoint`, and the snapshot the in-memory storage, when configured to save its counters
+type LimiterAndCheckpoints = (
+    Arc<dyn Limiter>,
+    Option<Arc<Checkpoints>>,
+    Option<Arc<InMemoryStorage>>,
+);
+
+// What the limiter is built with, whichever its storage
+#[derive(Default)]
+struct LimiterOptions {
+    hasher: Option<VariableHasher>,
+    on_error: EvaluationErrorConfiguration,
+}
 
-    async fn redis_limiter(cfg: RedisStorageConfiguration) -> Self {
-        let storage = Self::storage_using_redis(cfg).await;
-        let rate_limiter_builder = AsyncRateLimiterBuilder::new(storage);
+// Sets either kind of rate limiter builder up as per the `LimiterOptions`
+macro_rules! with_options {
+    ($builder:expr, $options:expr) => {{
+        let LimiterOptions { hasher, on_error } = $options;
+        let mut builder = $builder.on_evaluation_error(on_error.default);
+        for (namespace, policy) in on_error.namespaces {
+            builder = builder.namespace_on_evaluation_error(namespace, policy);
+        }
+        if let Some(hasher) = hasher {
+            builder = builder.hash_variables(hasher);
+        }
+        builder
+    }};
+}
 
-        Self::Async(rate_limiter_builder.build())
+async fn create_limiter_and_checkpoints(
+    config: Configuration,
+) -> Result<LimiterAndCheckpoints, LimitadorServerError> {
+    let options = LimiterOptions {
+        hasher: config
+            .counter_variables_key_file
+            .as_deref()
+            .map(variable_hasher_from_file),
+        on_error: config.on_evaluation_error,
+    };
+    let guards = (&config.storage_resilience, &config.storage_fallback);
+    Ok(storage_limiter(config.storage, guards, options).await)
+}
+
+async fn storage_limiter(
+    storage: StorageConfiguration,
+    guards: StorageGuards<'_>,
+    options: LimiterOptions,
+) -> LimiterAndCheckpoints {
+    match storage {
+        StorageConfiguration::Redis(cfg) => (redis_limiter(cfg, guards, options).await, None, None),
+        StorageConfiguration::InMemory(cfg) => {
+            let (limiter, snapshot) = in_memory_limiter(cfg, options);
+            (limiter, None, snapshot)
+        }
+        #[cfg(feature = "distributed_storage")]
+        StorageConfiguration::Distributed(cfg) => (distributed_limiter(cfg, options), None, None),
+        StorageConfiguration::Disk(cfg) => disk_limiter(cfg, guards, options),
     }
+}
 
-    async fn storage_using_redis(cfg: RedisStorageConfiguration) -> AsyncStorage {
-        let counters: Box<dyn AsyncCounterStorage> = if let Some(cache) = &cfg.cache {
-            Box::new(Self::storage_using_redis_and_local_cache(&cfg.url, cache).await)
-        } else {
-            // Let's use the async impl. This could be configurable if needed.
-            Box::new(Self::storage_using_async_redis(&cfg.url).await)
-        };
-        AsyncStorage::with_counter_storage(counters)
+fn variable_hasher_from_file(path: &str) -> VariableHasher {
+    match std::fs::read(path) {
+        // don't make the trailing newline of the file part of the key
+        Ok(key) => VariableHasher::new(key.trim_ascii_end()),
+        Err(err) => {
+            eprintln!("Failed to read the key to hash counter variables from {path}: {err}");
+            process::exit(1)
+        }
     }
+}
 
-    async fn storage_using_async_redis(redis_url: &str) -> AsyncRedisStorage {
-        AsyncRedisStorage::new(redis_url)
-            .await
-            .unwrap_or_else(|err| {
-                let redacted_redis_url = redacted_url(String::from(redis_url));
-                eprintln!("Failed to connect to Redis at {redacted_redis_url}: {err}");
-                process::exit(1)
-            })
+type StorageGuards<'a> = (
+    &'a StorageResilienceConfiguration,
+    &'a Option<StorageFallbackConfiguration>,
+);
+
+fn guarded_storage<S: AsyncCounterStorage + 'static>(
+    storage: S,
+    (resilience, fallback): StorageGuards,
+) -> AsyncStorage {
+    let counters: Box<dyn AsyncCounterStorage> = match (resilience.is_enabled(), fallback) {
+        (false, None) => Box::new(storage),
+        (true, None) => Box::new(resilient_storage(storage, resilience)),
+        (false, Some(fallback)) => Box::new(fallback_storage(storage, fallback)),
+        (true, Some(fallback)) => Box::new(fallback_storage(
+            resilient_storage(storage, resilience),
+            fallback,
+        )),
+    };
+    AsyncStorage::with_counter_storage(counters)
+}
+
+fn resilient_storage<S: AsyncCounterStorage>(
+    storage: S,
+    cfg: &StorageResilienceConfiguration,
+) -> ResilientStorage<S> {
+    let mut storage = ResilientStorage::new(storage).retries(cfg.retries, cfg.retry_backoff);
+    if let Some(timeout) = cfg.timeout {
+        storage = storage.timeout(timeout);
+    }
+    if let Some((failures, cool_down)) = cfg.breaker {
+        storage = storage.circuit_breaker(failures, cool_down);
     }
+    storage
+}
+
+fn fallback_storage<S: AsyncCounterStorage + 'static>(
+    storage: S,
+    cfg: &StorageFallbackConfiguration,
+) -> FallbackStorage<S, InMemoryStorage> {
+    let in_memory = InMemoryStorage::new(guess_cache_size().unwrap());
+    FallbackStorage::new(storage, in_memory)
+        .fallback_after(cfg.fallback_after)
+        .probe_interval(cfg.probe_interval)
+        .recover_after(cfg.recover_after)
+        .replay(cfg.replay)
+}
+
+async fn redis_limiter(
+    cfg: RedisStorageConfiguration,
+    guards: StorageGuards<'_>,
+    options: LimiterOptions,
+) -> Arc<dyn Limiter> {
+    let storage = storage_using_redis(cfg, guards).await;
+    let rate_limiter_builder = with_options!(AsyncRateLimiterBuilder::new(storage), options);
+
+    Arc::new(rate_limiter_builder.build())
+}
+
+async fn storage_using_redis(
+    cfg: RedisStorageConfiguration,
+    guards: StorageGuards<'_>,
+) -> AsyncStorage {
+    if let Some(cache) = &cfg.cache {
+        guarded_storage(
+            storage_using_redis_and_local_cache(&cfg, cache).await,
+            guards,
+        )
+    } else if cfg.sharded {
+        guarded_storage(storage_using_sharded_redis(&cfg).await, guards)
+    } else {
+        // Let's use the async impl. This could be configurable if needed.
+        guarded_storage(storage_using_async_redis(&cfg).await, guards)
+    }
+}
 
-    async fn storage_using_redis_and_local_cache(
-        redis_url: &str,
-        cache_cfg: &RedisStorageCacheConfiguration,
-    ) -> CachedRedisStorage {
-        // TODO: Not all the options are configurable via ENV. Add them as needed.
+/// Exits after reporting the prefix of the keys, from `--key-prefix` or
+/// `REDIS_KEY_PREFIX`, as invalid.
+fn invalid_key_prefix<T>(err: String) -> T {
+    eprintln!("Invalid configuration of the Redis storage: {err}");
+    process::exit(1)
+}
 
-        let cached_redis_storage = CachedRedisStorageBuilder::new(redis_url)
-            .batch_size(cache_cfg.batch_size)
-            .flushing_period(Duration::from_millis(cache_cfg.flushing_period as u64))
-            .max_cached_counters(cache_cfg.max_counters)
-            .response_timeout(Duration::from_millis(cache_cfg.response_timeout));
+async fn storage_using_sharded_redis(cfg: &RedisStorageConfiguration) -> ShardedRedisStorage {
+    ShardedRedisStorage::new(&cfg.url)
+        .await
+        .unwrap_or_else(|err| {
+            let redacted_redis_url = redacted_url(cfg.url.clone());
+            eprintln!("Failed to connect to Redis at {redacted_redis_url}: {err}");
+            process::exit(1)
+        })
+        .exact_limits(cfg.exact_limits)
+        .key_prefix(&cfg.key_prefix)
+        .unwrap_or_else(invalid_key_prefix)
+}
 
-        cached_redis_storage.build().await.unwrap_or_else(|err| {
-            let redacted_redis_url = redacted_url(String::from(redis_url));
+async fn storage_using_async_redis(cfg: &RedisStorageConfiguration) -> AsyncRedisStorage {
+    let storage = if cfg.cluster {
+        AsyncRedisStorage::new_cluster(&cfg.url).await
+    } else {
+        AsyncRedisStorage::new(&cfg.url).await
+    };
+    storage
+        .unwrap_or_else(|err| {
+            let redacted_redis_url = redacted_url(cfg.url.clone());
             eprintln!("Failed to connect to Redis at {redacted_redis_url}: {err}");
             process::exit(1)
         })
-    }
+        .exact_limits(cfg.exact_limits)
+        .key_prefix(&cfg.key_prefix)
+        .unwrap_or_else(invalid_key_prefix)
+}
 
-    fn disk_limiter(cfg: DiskStorageConfiguration) -> Self {
-        let storage = match DiskStorage::open(cfg.path.as_str(), cfg.optimization) {
+async fn storage_using_redis_and_local_cache(
+    cfg: &RedisStorageConfiguration,
+    cache_cfg: &RedisStorageCacheConfiguration,
+) -> CachedRedisStorage {
+    // TODO: Not all the options are configurable via ENV. Add them as needed.
+
+    let cached_redis_storage = CachedRedisStorageBuilder::new(&cfg.url)
+        .batch_size(cache_cfg.batch_size)
+        .flushing_period(Duration::from_millis(cache_cfg.flushing_period as u64))
+        .max_cached_counters(cache_cfg.max_counters)
+        .response_timeout(Duration::from_millis(cache_cfg.response_timeout))
+        .cluster(cfg.cluster)
+        .key_prefix(&cfg.key_prefix)
+        .unwrap_or_else(invalid_key_prefix)
+        .partition_policy(cache_cfg.partition_policy)
+        .flush_policy(if cache_cfg.adaptive_flush {
+            FlushPolicy::adaptive()
+        } else {
+            FlushPolicy::Periodic
+        })
+        .publish_updates(cache_cfg.publish_updates);
+
+    cached_redis_storage.build().await.unwrap_or_else(|err| {
+        let redacted_redis_url = redacted_url(cfg.url.clone());
+        eprintln!("Failed to connect to Redis at {redacted_redis_url}: {err}");
+        process::exit(1)
+    })
+}
+
+fn disk_limiter(
+    cfg: DiskStorageConfiguration,
+    guards: StorageGuards<'_>,
+    options: LimiterOptions,
+) -> LimiterAndCheckpoints {
+    let storage = match &cfg.restore_from {
+        Some(checkpoint) => {
+            match DiskStorage::restore(checkpoint, cfg.path.as_str(), cfg.optimization) {
+                Ok(storage) => storage,
+                Err(err) => {
+                    eprintln!(
+                        "Failed to restore checkpoint {checkpoint} to {}: {err}",
+                        cfg.path
+                    );
+                    process::exit(1)
+                }
+            }
+        }
+        None => match DiskStorage::open(cfg.path.as_str(), cfg.optimization) {
             Ok(storage) => storage,
             Err(err) => {
                 eprintln!("Failed to open DB at {}: {err}", cfg.path);
                 process::exit(1)
             }
-        };
-        let rate_limiter_builder =
-            RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)));
-
-        Self::Blocking(rate_limiter_builder.build())
-    }
-
-    fn in_memory_limiter(cfg: InMemoryStorageConfiguration) -> Self {
-        let rate_limiter_builder =
-            RateLimiterBuilder::new(cfg.cache_size.or_else(guess_cache_size).unwrap());
+        },
+    };
+    let storage = Arc::new(storage);
+    let checkpoints = cfg.checkpoints.and_then(|checkpoints| {
+        let (interval, on_request) = (checkpoints.interval, checkpoints.on_request);
+        let checkpoints = Arc::new(Checkpoints::new(
+            &storage,
+            checkpoints.dir,
+            checkpoints.keep,
+        ));
+        if let Some(interval) = interval {
+            write_checkpoints(Arc::clone(&checkpoints), interval);
+        }
+        on_request.then_some(checkpoints)
+    });
+    // RocksDB does blocking I/O, keep it off the async workers
+    let rate_limiter_builder = with_options!(
+        AsyncRateLimiterBuilder::new(guarded_storage(
+            BlockingStorageAdapter::new(storage),
+            guards,
+        )),
+        options
+    );
 
-        Self::Blocking(rate_limiter_builder.build())
-    }
+    (Arc::new(rate_limiter_builder.build()), checkpoints, None)
+}
 
-    #[cfg(feature = "distributed_storage")]
-    fn distributed_limiter(cfg: DistributedStorageConfiguration) -> Self {
-        let storage = DistributedInMemoryStorage::new(
-            cfg.name,
-            cfg.cache_size.or_else(guess_cache_size).unwrap(),
-            cfg.listen_address,
-            cfg.peer_urls,
-        );
-        let rate_limiter_builder =
-            RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)));
+// Along with the storage, when it's to save its counters to a snapshot
+fn in_memory_limiter(
+    cfg: InMemoryStorageConfiguration,
+    options: LimiterOptions,
+) -> (Arc<dyn Limiter>, Option<Arc<InMemoryStorage>>) {
+    let cache_size = cfg.cache_size.or_else(guess_cache_size).unwrap();
+    let mut snapshot = None;
+    let rate_limiter_builder = match cfg.snapshot {
+        Some(path) => {
+            let storage = Arc::new(InMemoryStorage::new(cache_size).with_snapshot(path));
+            save_snapshots(Arc::downgrade(&storage), cfg.snapshot_interval);
+            snapshot = Some(Arc::clone(&storage));
+            RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)))
+        }
+        None => RateLimiterBuilder::new(cache_size),
+    };
+    let rate_limiter_builder = with_options!(rate_limiter_builder, options);
 
-        Self::Blocking(rate_limiter_builder.build())
-    }
+    (Arc::new(rate_limiter_builder.build()), snapshot)
+}
 
-    pub async fn load_limits_from_file<P: AsRef<Path>>(
-        &self,
-        path: &P,
-    ) -> Result<(), LimitadorServerError> {
-        match std::fs::File::open(path) {
-            Ok(f) => {
-                let parsed_limits: Result<Vec<Limit>, _> = serde_yaml::from_reader(f);
-                match parsed_limits {
-                    Ok(limits) => {
-                        match &self {
-                            Self::Blocking(limiter) => limiter.configure_with(limits)?,
-                            Self::Async(limiter) => limiter.configure_with(limits).await?,
-                        }
-                        Ok(())
-                    }
-                    Err(e) => Err(LimitadorServerError::ConfigFile(format!(
-                        "Couldn't parse: {e}"
-                    ))),
-                }
+// Saves the counters every `interval`, on top of when the server shuts down
+fn save_snapshots(storage: Weak<InMemoryStorage>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(storage) = storage.upgrade() else {
+                break;
+            };
+            if let Err(err) = storage.save_snapshot() {
+                warn!("Couldn't save the counters: {}", err);
             }
-            Err(e) => Err(LimitadorServerError::ConfigFile(format!(
-                "Couldn't read file '{}': {}",
-                path.as_ref().display(),
-                e
-            ))),
         }
-    }
+    });
+}
+
+#[cfg(feature = "distributed_storage")]
+fn distributed_limiter(
+    cfg: DistributedStorageConfiguration,
+    options: LimiterOptions,
+) -> Arc<dyn Limiter> {
+    let storage = DistributedInMemoryStorage::new(
+        cfg.name,
+        cfg.cache_size.or_else(guess_cache_size).unwrap(),
+        cfg.listen_address,
+        cfg.peer_urls,
+    );
+    let rate_limiter_builder = with_options!(
+        RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage))),
+        options
+    );
+
+    Arc::new(rate_limiter_builder.build())
+}
+
+pub async fn load_limits_from_file<P: AsRef<Path>>(
+    limiter: &dyn Limiter,
+    path: &P,
+) -> Result<(), LimitadorServerError> {
+    let limits = read_limits_file(path)?;
+    limiter.configure_with(limits).await?;
+    Ok(())
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Apiv2Schema)]
@@ -248,11 +470,24 @@ impl Default for Status {
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = {
-        let (config, version) = create_config();
+        let (mut config, version) = create_config();
         eprintln!("{LIMITADOR_HEADER} {version}");
 
         configure_tracing_subscriber(&config);
 
+        if let Some(migration) = config.migration.take() {
+            match migrate_counters(&config.limits_file, migration).await {
+                Ok((exported, imported)) => {
+                    eprintln!("Migrated {imported} of the {exported} counters exported");
+                    return Ok(());
+                }
+                Err(error) => {
+                    eprintln!("{error}");
+                    process::exit(1);
+                }
+            }
+        }
+
         info!("Version: {}", version);
         info!("Using config: {:?}", config);
         config
@@ -269,9 +504,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let http_api_address = config.http_address();
     let rate_limit_headers = config.rate_limit_headers.clone();
     let grpc_reflection_service = config.grpc_reflection_service;
+    let counter_metrics_interval = config.counter_metrics_interval;
+    let counter_utilization_metrics = config.counter_utilization_metrics;
+    let redact_counter_variables = config.counter_variables_key_file.is_some();
 
-    let rate_limiter: Arc<Limiter> = match Limiter::new(config).await {
-        Ok(limiter) => Arc::new(limiter),
+    let (rate_limiter, checkpoints, snapshot) = match create_limiter_and_checkpoints(config).await {
+        Ok(limiter_and_checkpoints) => limiter_and_checkpoints,
         Err(e) => {
             eprintln!("Error: {e}");
             process::exit(1)
@@ -279,7 +517,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     info!("limits file path: {}", limit_file);
-    if let Err(e) = rate_limiter.load_limits_from_file(&limit_file).await {
+    if let Err(e) = load_limits_from_file(rate_limiter.as_ref(), &limit_file).await {
         eprintln!("Failed to load limit file: {e}");
         process::exit(1)
     }
@@ -343,7 +581,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     let limit_cfg = limit_cfg.clone();
 
                                     handle.spawn(async move {
-                                        match limiter.load_limits_from_file(&limit_cfg).await {
+                                        match load_limits_from_file(limiter.as_ref(), &limit_cfg)
+                                            .await
+                                        {
                                             Ok(_) => {
                                                 status_updater.write().unwrap().config_success();
                                                 info!("data modified; reloaded limit file")
@@ -406,6 +646,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    if let Some(interval) = counter_metrics_interval {
+        info!("Sampling the counters of each limit every {:?}", interval);
+        tokio::spawn(sample_counter_metrics(
+            rate_limiter.clone(),
+            prometheus_metrics.clone(),
+            interval,
+            counter_utilization_metrics,
+        ));
+    }
+
     info!("Envoy RLS server starting on {}", envoy_rls_address);
     tokio::spawn(run_envoy_rls_server(
         envoy_rls_address.to_string(),
@@ -421,9 +671,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         rate_limiter.clone(),
         prometheus_metrics,
         status,
+        redact_counter_variables,
+        checkpoints,
     )
     .await?;
 
+    // the storage outlives the servers, held by the tasks they leave behind,
+    // so it wouldn't be dropped, and save its counters, before exiting
+    if let Some(storage) = snapshot {
+        match storage.save_snapshot() {
+            Ok(()) => info!("Saved the counters to the snapshot"),
+            Err(err) => error!("Couldn't save the counters: {}", err),
+        }
+    }
+
     Ok(())
 }
 
@@ -449,6 +710,283 @@ async fn parse_custom_labels_file(path: &Path) -> Result<HashMap<String, Express
     }
 }
 
+/// Periodically samples how many counters each limit holds and, when
+/// `utilization` is set, how close each of them is to its max. The latter
+/// reads every counter of every namespace, which can be expensive on Redis.
+async fn sample_counter_metrics(
+    limiter: Arc<dyn Limiter>,
+    metrics: Arc<PrometheusMetrics>,
+    interval: Duration,
+    utilization: bool,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let mut cardinalities = Vec::new();
+        for namespace in limiter.get_namespaces() {
+            for limit in limiter.get_limits(&namespace) {
+                match limiter.cardinality(&limit).await {
+                    Ok(cardinality) => cardinalities.push((limit, cardinality)),
+                    Err(e) => warn!("Failed to sample the counters of a limit: {e}"),
+                }
+            }
+
+            if utilization {
+                match limiter.get_counters(&namespace).await {
+                    Ok(counters) => counters
+                        .iter()
+                        .for_each(PrometheusMetrics::record_counter_utilization),
+                    Err(e) => warn!(
+                        "Failed to sample the counters of {}: {e}",
+                        namespace.as_ref()
+                    ),
+                }
+            }
+        }
+        metrics.set_limit_counters(
+            cardinalities
+                .iter()
+                .map(|(limit, cardinality)| (limit, *cardinality)),
+        );
+    }
+}
+
+/// Prints the findings about the limits in `path`, returning whether none of
+/// them is an error.
+fn lint_limits_file(path: &str, output: &str) -> Result<bool, LimitadorServerError> {
+    let limits = read_limits_file(path)?;
+
+    let findings = lint(&limits);
+    print_as(output, "findings", &findings)?;
+
+    Ok(findings.iter().all(|f| f.severity < Severity::Error))
+}
+
+/// Replays the records of the JSON lines `log` against the limits in `path`
+/// and prints what the limits would have done.
+fn simulate_limits_file(path: &str, log: &str, output: &str) -> Result<(), LimitadorServerError> {
+    let limits = read_limits_file(path)?;
+    let log = std::fs::File::open(log).map_err(|e| {
+        LimitadorServerError::ConfigFile(format!("Couldn't read file '{log}': {e}"))
+    })?;
+
+    let mut simulator = Simulator::new(limits);
+    simulator
+        .replay_log(std::io::BufReader::new(log))
+        .map_err(|e| LimitadorServerError::ConfigFile(e.to_string()))?;
+    print_as(output, "report", &simulator.report())
+}
+
+/// Reads and parses the limits in the YAML file at `path`.
+fn read_limits_file<P: AsRef<Path>>(path: P) -> Result<Vec<Limit>, LimitadorServerError> {
+    let path = path.as_ref();
+    let f = std::fs::File::open(path).map_err(|e| {
+        LimitadorServerError::ConfigFile(format!("Couldn't read file '{}': {e}", path.display()))
+    })?;
+    serde_yaml::from_reader(f)
+        .map_err(|e| LimitadorServerError::ConfigFile(format!("Couldn't parse: {e}")))
+}
+
+/// Prints `value` to stdout, as YAML or pretty JSON depending on `output`.
+fn print_as<T: Serialize>(output: &str, what: &str, value: &T) -> Result<(), LimitadorServerError> {
+    let printed = match output {
+        "yaml" => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        _ => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| LimitadorServerError::ConfigFile(format!("Couldn't output {what}: {e}")))?;
+    println!("{printed}");
+    Ok(())
+}
+
+/// Exports the counters of the limits in `path` from one storage, or dump, and
+/// imports them into another, a batch at a time, returning how many got
+/// exported and imported.
+async fn migrate_counters(
+    path: &str,
+    migration: CounterMigration,
+) -> Result<(u64, u64), LimitadorServerError> {
+    let limits = read_limits_file(path)?;
+
+    let mut source = match migration.from {
+        CounterEndpoint::File(path) => DumpSource::file(path)?,
+        CounterEndpoint::Storage(storage) => DumpSource::Limiter {
+            limiter: migration_limiter(storage, limits.clone()).await?,
+            // once each, should the file repeat any
+            limits: HashSet::from_iter(limits.clone()).into_iter(),
+        },
+    };
+    let mut sink = match migration.to {
+        CounterEndpoint::File(path) => DumpSink::file(path)?,
+        CounterEndpoint::Storage(storage) => {
+            DumpSink::Limiter(migration_limiter(storage, limits).await?)
+        }
+    };
+
+    let (mut exported, mut imported) = (0, 0);
+    while let Some(dumps) = source.next_batch().await? {
+        exported += dumps.len() as u64;
+        imported += sink.write(dumps).await?;
+    }
+    sink.finish()?;
+    Ok((exported, imported))
+}
+
+// Neither retries nor falls back to memory, so that no counter goes astray
+async fn migration_limiter(
+    storage: StorageConfiguration,
+    limits: Vec<Limit>,
+) -> Result<Arc<dyn Limiter>, LimitadorServerError> {
+    let guards = (&StorageResilienceConfiguration::default(), &None);
+    let (limiter, _, _) = storage_limiter(storage, guards, LimiterOptions::default()).await;
+    limiter.configure_with(limits).await?;
+    Ok(limiter)
+}
+
+// How many lines of a dump get imported at once
+const MIGRATION_BATCH_SIZE: usize = 1000;
+
+/// Where the counters get migrated from: the lines of a dump, or the storage
+/// of a limiter, one limit at a time.
+enum DumpSource {
+    File {
+        path: String,
+        lines: std::iter::Enumerate<std::io::Lines<std::io::BufReader<std::fs::File>>>,
+    },
+    Limiter {
+        limiter: Arc<dyn Limiter>,
+        limits: std::collections::hash_set::IntoIter<Limit>,
+    },
+}
+
+impl DumpSource {
+    fn file(path: String) -> Result<Self, LimitadorServerError> {
+        let f = std::fs::File::open(&path).map_err(|e| {
+            LimitadorServerError::Migration(format!("Couldn't read file '{path}': {e}"))
+        })?;
+        let lines = std::io::BufReader::new(f).lines().enumerate();
+        Ok(Self::File { path, lines })
+    }
+
+    /// The next batch of counters, or `None` once they've all been read.
+    async fn next_batch(&mut self) -> Result<Option<Vec<CounterDump>>, LimitadorServerError> {
+        match self {
+            Self::File { path, lines } => {
+                let mut dumps = Vec::new();
+                for (n, line) in lines.by_ref() {
+                    let line = line.map_err(|e| {
+                        LimitadorServerError::Migration(format!("Couldn't read file '{path}': {e}"))
+                    })?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    dumps.push(serde_json::from_str(&line).map_err(|e| {
+                        LimitadorServerError::Migration(format!(
+                            "Couldn't parse line {}: {e}",
+                            n + 1
+                        ))
+                    })?);
+                    if dumps.len() == MIGRATION_BATCH_SIZE {
+                        break;
+                    }
+                }
+                Ok(Some(dumps).filter(|dumps| !dumps.is_empty()))
+            }
+            Self::Limiter { limiter, limits } => match limits.next() {
+                Some(limit) => Ok(Some(limiter.export_limit_counters(&limit).await?)),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+/// Where the counters get migrated to: a dump, as JSON lines, or the storage
+/// of a limiter.
+enum DumpSink {
+    File {
+        path: String,
+        writer: std::io::BufWriter<std::fs::File>,
+    },
+    Limiter(Arc<dyn Limiter>),
+}
+
+impl DumpSink {
+    fn file(path: String) -> Result<Self, LimitadorServerError> {
+        let f = std::fs::File::create(&path).map_err(|e| write_error(&path, &e))?;
+        let writer = std::io::BufWriter::new(f);
+        Ok(Self::File { path, writer })
+    }
+
+    /// Writes `dumps`, returning how many of them got imported.
+    async fn write(&mut self, dumps: Vec<CounterDump>) -> Result<u64, LimitadorServerError> {
+        match self {
+            Self::File { path, writer } => {
+                for dump in &dumps {
+                    serde_json::to_writer(&mut *writer, dump).map_err(|e| write_error(path, &e))?;
+                    writer.write_all(b"\n").map_err(|e| write_error(path, &e))?;
+                }
+                Ok(dumps.len() as u64)
+            }
+            Self::Limiter(limiter) => Ok(limiter.import_counters(dumps).await?),
+        }
+    }
+
+    fn finish(self) -> Result<(), LimitadorServerError> {
+        match self {
+            Self::File { path, mut writer } => writer.flush().map_err(|e| write_error(&path, &e)),
+            Self::Limiter(_) => Ok(()),
+        }
+    }
+}
+
+fn write_error(path: &str, e: &dyn Display) -> LimitadorServerError {
+    LimitadorServerError::Migration(format!("Couldn't write '{path}': {e}"))
+}
+
+/// Parses a `KIND:TARGET` argument of the `migrate` subcommand.
+fn counter_endpoint(spec: &str, key_prefix: &str) -> Result<CounterEndpoint, String> {
+    let Some((kind, target)) = spec
+        .split_once(':')
+        .filter(|(_, target)| !target.is_empty())
+    else {
+        return Err(format!("expected KIND:TARGET, got '{spec}'"));
+    };
+    let redis = |cluster, sharded| {
+        CounterEndpoint::Storage(StorageConfiguration::Redis(RedisStorageConfiguration {
+            url: target.to_owned(),
+            cluster,
+            sharded,
+            exact_limits: false,
+            key_prefix: key_prefix.to_owned(),
+            cache: None,
+        }))
+    };
+    Ok(match kind {
+        "file" => CounterEndpoint::File(target.to_owned()),
+        "memory" => CounterEndpoint::Storage(StorageConfiguration::InMemory(
+            InMemoryStorageConfiguration {
+                cache_size: None,
+                snapshot: Some(target.to_owned()),
+                snapshot_interval: Duration::from_secs(60),
+            },
+        )),
+        "disk" => CounterEndpoint::Storage(StorageConfiguration::Disk(DiskStorageConfiguration {
+            path: target.to_owned(),
+            optimization: storage::disk::OptimizeFor::Throughput,
+            checkpoints: None,
+            restore_from: None,
+        })),
+        "redis" => redis(false, false),
+        "redis_cluster" => redis(true, false),
+        "redis_sharded" => redis(false, true),
+        _ => {
+            return Err(format!(
+                "unknown kind '{kind}', expected one of file, memory, disk, redis, redis_cluster or redis_sharded"
+            ))
+        }
+    })
+}
+
 fn create_config() -> (Configuration, &'static str) {
     let full_version: &'static str = formatcp!(
         "v{} ({}) {} {}",
@@ -472,6 +1010,25 @@ fn create_config() -> (Configuration, &'static str) {
         None => redis_url_arg.required(true),
         Some(url) => redis_url_arg.default_value(url),
     };
+    let redis_key_prefix_arg = Arg::new("key_prefix")
+        .long("key-prefix")
+        .action(ArgAction::Set)
+        .value_name("PREFIX")
+        .default_value(config::env::REDIS_KEY_PREFIX.unwrap_or(""))
+        .value_parser(|prefix: &str| {
+            if prefix.contains(['{', '}']) {
+                Err("can't contain '{' or '}'".to_string())
+            } else {
+                Ok(prefix.to_string())
+            }
+        })
+        .display_order(22)
+        .help("Prefix of all the keys, only those get cleared");
+    let redis_cluster_arg = Arg::new("cluster")
+        .long("cluster")
+        .action(ArgAction::SetTrue)
+        .display_order(20)
+        .help("Connects to a Redis Cluster, URL lists some of its nodes comma separated");
 
     let disk_path_arg = Arg::new("PATH").help("Path to counter DB").index(1);
     let disk_path_arg = match *config::env::DISK_PATH {
@@ -557,6 +1114,126 @@ fn create_config() -> (Configuration, &'static str) {
                 .display_order(56)
                 .help("A CEL expression resolving to a Map with labels & their values to use"),
         )
+        .arg(
+            Arg::new("counter_metrics_interval")
+                .long("counter-metrics-interval")
+                .default_value(config::env::COUNTER_METRICS_INTERVAL_SECS.unwrap_or("0"))
+                .value_parser(value_parser!(u64))
+                .display_order(57)
+                .help("Seconds between samples of the counters of each limit, 0 to disable"),
+        )
+        .arg(
+            Arg::new("counter_utilization_metrics")
+                .long("counter-utilization-metrics")
+                .action(ArgAction::SetTrue)
+                .display_order(58)
+                .help("Also sample how close counters are to their max, reading them all"),
+        )
+        .arg(
+            Arg::new("hash_counter_variables")
+                .long("hash-counter-variables")
+                .value_name("KEY_FILE")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(String))
+                .display_order(59)
+                .help("Stores the values of counter variables hashed, keyed with the file's content"),
+        )
+        .arg(
+            Arg::new("on_evaluation_error")
+                .long("on-evaluation-error")
+                .value_name("[NAMESPACE=]POLICY")
+                .action(ArgAction::Append)
+                .value_parser(ValueParser::new(evaluation_error_policy))
+                .display_order(59)
+                .help("Policy of the limits, of NAMESPACE or all, without an on_error: skip, apply or fail"),
+        )
+        .arg(
+            Arg::new("storage_timeout")
+                .long("storage-timeout")
+                .value_name("MS")
+                .default_value("0")
+                .value_parser(value_parser!(u64))
+                .display_order(61)
+                .help("Milliseconds after which a call to the storage fails, 0 to disable"),
+        )
+        .arg(
+            Arg::new("storage_retries")
+                .long("storage-retries")
+                .value_name("N")
+                .default_value("0")
+                .value_parser(value_parser!(u32))
+                .display_order(62)
+                .help("Times a read from the storage is retried on transient errors"),
+        )
+        .arg(
+            Arg::new("storage_retry_backoff")
+                .long("storage-retry-backoff")
+                .value_name("MS")
+                .default_value("10")
+                .value_parser(value_parser!(u64))
+                .display_order(63)
+                .help("Milliseconds to wait before the first retry, doubling for each next one"),
+        )
+        .arg(
+            Arg::new("breaker_failures")
+                .long("breaker-failures")
+                .value_name("N")
+                .default_value("0")
+                .value_parser(value_parser!(u32))
+                .display_order(64)
+                .help("Transient storage failures in a row that open the circuit breaker, 0 to disable"),
+        )
+        .arg(
+            Arg::new("breaker_cool_down")
+                .long("breaker-cool-down")
+                .value_name("MS")
+                .default_value("5000")
+                .value_parser(value_parser!(u64))
+                .display_order(65)
+                .help("Milliseconds the circuit breaker stays open before probing the storage"),
+        )
+        .arg(
+            Arg::new("fallback_to_memory")
+                .long("fallback-to-memory")
+                .action(ArgAction::SetTrue)
+                .display_order(66)
+                .help("Counts in memory while the storage is failing"),
+        )
+        .arg(
+            Arg::new("fallback_after")
+                .long("fallback-after")
+                .value_name("N")
+                .default_value("1")
+                .value_parser(value_parser!(u32))
+                .display_order(67)
+                .help("Transient storage failures in a row after which to count in memory"),
+        )
+        .arg(
+            Arg::new("fallback_probe_interval")
+                .long("fallback-probe-interval")
+                .value_name("MS")
+                .default_value("1000")
+                .value_parser(value_parser!(u64))
+                .display_order(68)
+                .help("Milliseconds between the calls let through to the storage while counting in memory"),
+        )
+        .arg(
+            Arg::new("fallback_recover_after")
+                .long("fallback-recover-after")
+                .value_name("N")
+                .default_value("3")
+                .value_parser(value_parser!(u32))
+                .display_order(69)
+                .help("Calls in a row the storage must serve to stop counting in memory"),
+        )
+        .arg(
+            Arg::new("fallback_replay")
+                .long("fallback-replay")
+                .action(ArgAction::SetTrue)
+                .requires("fallback_to_memory")
+                .display_order(70)
+                .help("Adds the hits counted in memory to the storage once it recovers"),
+        )
         .arg(
             Arg::new("tracing_endpoint")
                 .long("tracing-endpoint")
@@ -617,6 +1294,25 @@ fn create_config() -> (Configuration, &'static str) {
                         .value_parser(value_parser!(u64))
                         .display_order(1)
                         .help("Sets the size of the cache for 'qualified counters'"),
+                )
+                .arg(
+                    Arg::new("SNAPSHOT")
+                        .long("snapshot")
+                        .action(ArgAction::Set)
+                        .value_name("PATH")
+                        .display_order(2)
+                        .help("Saves the counters to PATH, and restores them from it at startup"),
+                )
+                .arg(
+                    Arg::new("SNAPSHOT_INTERVAL")
+                        .long("snapshot-interval")
+                        .action(ArgAction::Set)
+                        .value_name("SECS")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("60")
+                        .requires("SNAPSHOT")
+                        .display_order(3)
+                        .help("How often, in seconds, to save the counters to the snapshot"),
                 ),
         )
         .subcommand(
@@ -635,19 +1331,89 @@ fn create_config() -> (Configuration, &'static str) {
                             "disk",
                         ]))
                         .help("Optimizes either to save disk space or higher throughput"),
+                )
+                .arg(
+                    Arg::new("CHECKPOINTS")
+                        .long("checkpoints")
+                        .action(ArgAction::Set)
+                        .value_name("DIR")
+                        .requires("checkpoint_triggers")
+                        .display_order(2)
+                        .help("Writes checkpoints of the DB to DIR"),
+                )
+                .arg(
+                    Arg::new("CHECKPOINT_INTERVAL")
+                        .long("checkpoint-interval")
+                        .action(ArgAction::Set)
+                        .value_name("SECS")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .requires("CHECKPOINTS")
+                        .display_order(3)
+                        .help("Writes a checkpoint every SECS seconds"),
+                )
+                .arg(
+                    Arg::new("CHECKPOINT_ON_REQUEST")
+                        .long("checkpoint-on-request")
+                        .action(ArgAction::SetTrue)
+                        .requires("CHECKPOINTS")
+                        .display_order(3)
+                        .help("Writes a checkpoint on `POST /checkpoint` to the HTTP API"),
+                )
+                .group(
+                    ArgGroup::new("checkpoint_triggers")
+                        .args(["CHECKPOINT_INTERVAL", "CHECKPOINT_ON_REQUEST"])
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new("KEEP_CHECKPOINTS")
+                        .long("keep-checkpoints")
+                        .action(ArgAction::Set)
+                        .value_name("COUNT")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("3")
+                        .requires("CHECKPOINTS")
+                        .display_order(4)
+                        .help("How many of the latest checkpoints to keep"),
+                )
+                .arg(
+                    Arg::new("RESTORE")
+                        .long("restore")
+                        .action(ArgAction::Set)
+                        .value_name("CHECKPOINT")
+                        .display_order(5)
+                        .help("Creates the DB at PATH from CHECKPOINT, PATH mustn't hold one yet"),
                 ),
         )
         .subcommand(
             Command::new("redis")
                 .display_order(30)
                 .about("Uses Redis to store counters")
-                .arg(redis_url_arg.clone()),
+                .arg(redis_url_arg.clone())
+                .arg(redis_cluster_arg.clone())
+                .arg(redis_key_prefix_arg.clone())
+                .arg(
+                    Arg::new("exact")
+                        .long("exact-limits")
+                        .action(ArgAction::SetTrue)
+                        .display_order(21)
+                        .help("Checks and updates all the counters of a request atomically"),
+                )
+                .arg(
+                    Arg::new("sharded")
+                        .long("sharded")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("cluster")
+                        .display_order(22)
+                        .help("Shards the counters over standalone Redis servers, URL lists them comma separated"),
+                ),
         )
         .subcommand(
             Command::new("redis_cached")
                 .about("Uses Redis to store counters, with an in-memory cache")
                 .display_order(40)
                 .arg(redis_url_arg)
+                .arg(redis_cluster_arg)
+                .arg(redis_key_prefix_arg.clone())
                 .arg(
                     Arg::new("batch")
                         .long("batch-size")
@@ -689,7 +1455,121 @@ fn create_config() -> (Configuration, &'static str) {
                         .default_value(leak(DEFAULT_RESPONSE_TIMEOUT_MS))
                         .display_order(60)
                         .help("Timeout for Redis commands in milliseconds"),
+                )
+                .arg(
+                    Arg::new("adaptive_flush")
+                        .long("adaptive-flush")
+                        .action(ArgAction::SetTrue)
+                        .display_order(61)
+                        .help("Flushes counters close to their limits sooner, and idle ones later"),
+                )
+                .arg(
+                    Arg::new("publish_updates")
+                        .long("publish-updates")
+                        .action(ArgAction::SetTrue)
+                        .display_order(62)
+                        .help("Shares the flushed counters with the other instances as they flush them"),
+                )
+                .arg(
+                    Arg::new("partition_policy")
+                        .long("partition-policy")
+                        .value_name("POLICY")
+                        .action(ArgAction::Set)
+                        .default_value("serve")
+                        .value_parser(clap::builder::PossibleValuesParser::new([
+                            "serve",
+                            "serve_for",
+                            "divide",
+                            "fail_closed",
+                        ]))
+                        .display_order(70)
+                        .help("What to do while Redis can't be reached"),
+                )
+                .arg(
+                    Arg::new("partition_max_duration")
+                        .long("partition-max-duration")
+                        .value_name("SECS")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64))
+                        .required_if_eq("partition_policy", "serve_for")
+                        .display_order(71)
+                        .help("How long to serve from the cache with the 'serve_for' policy"),
+                )
+                .arg(
+                    Arg::new("partition_replicas")
+                        .long("partition-replicas")
+                        .value_name("N")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .required_if_eq("partition_policy", "divide")
+                        .display_order(72)
+                        .help("Number of replicas to divide the limits among with the 'divide' policy"),
+                ),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about("Analyses the LIMITS_FILE for mistakes, reports them and exits")
+                .display_order(50)
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .action(ArgAction::Set)
+                        .display_order(1)
+                        .default_value("json")
+                        .value_parser(clap::builder::PossibleValuesParser::new(["json", "yaml"]))
+                        .help("Format of the findings"),
                 ),
+        )
+        .subcommand(
+            Command::new("simulate")
+                .about("Replays a log of requests against the LIMITS_FILE, reports the outcome and exits")
+                .display_order(51)
+                .arg(
+                    Arg::new("LOG_FILE")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .display_order(1)
+                        .help("The requests to replay, as JSON lines"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .action(ArgAction::Set)
+                        .display_order(2)
+                        .default_value("json")
+                        .value_parser(clap::builder::PossibleValuesParser::new(["json", "yaml"]))
+                        .help("Format of the report"),
+                ),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Copies the counters of the LIMITS_FILE from one storage to another and exits")
+                .display_order(52)
+                .arg(
+                    Arg::new("FROM")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .display_order(1)
+                        .value_parser(|spec: &str| {
+                            counter_endpoint(spec, "").map(|_| spec.to_owned())
+                        })
+                        .help("Storage to export the counters from, as KIND:TARGET, see TO"),
+                )
+                .arg(
+                    Arg::new("TO")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .display_order(2)
+                        .value_parser(|spec: &str| {
+                            counter_endpoint(spec, "").map(|_| spec.to_owned())
+                        })
+                        .help("Storage to import the counters into, as KIND:TARGET, one of: \
+                            disk:PATH, redis:URL, redis_cluster:URLS, redis_sharded:URLS, \
+                            memory:SNAPSHOT or file:PATH, the latter as JSON lines"),
+                )
+                .arg(redis_key_prefix_arg),
         );
 
     #[cfg(feature = "distributed_storage")]
@@ -762,9 +1642,43 @@ fn create_config() -> (Configuration, &'static str) {
         process::exit(1);
     }
 
+    if let Some(("lint", sub)) = matches.subcommand() {
+        let output = sub.get_one::<String>("output").unwrap();
+        match lint_limits_file(limits_file, output) {
+            Ok(clean) => process::exit(if clean { 0 } else { 1 }),
+            Err(error) => {
+                eprintln!("{error}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(("simulate", sub)) = matches.subcommand() {
+        let log = sub.get_one::<String>("LOG_FILE").unwrap();
+        let output = sub.get_one::<String>("output").unwrap();
+        if let Err(error) = simulate_limits_file(limits_file, log, output) {
+            eprintln!("{error}");
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    let migration = matches.subcommand_matches("migrate").map(|sub| {
+        let key_prefix = sub.get_one::<String>("key_prefix").unwrap();
+        let endpoint = |arg| counter_endpoint(sub.get_one::<String>(arg).unwrap(), key_prefix);
+        CounterMigration {
+            from: endpoint("FROM").unwrap(),
+            to: endpoint("TO").unwrap(),
+        }
+    });
+
     let storage = match matches.subcommand() {
         Some(("redis", sub)) => StorageConfiguration::Redis(RedisStorageConfiguration {
             url: sub.get_one::<String>("URL").unwrap().to_owned(),
+            cluster: sub.get_flag("cluster") || *config::env::REDIS_CLUSTER,
+            sharded: sub.get_flag("sharded") || *config::env::REDIS_SHARDED,
+            exact_limits: sub.get_flag("exact") || *config::env::REDIS_EXACT_LIMITS,
+            key_prefix: sub.get_one::<String>("key_prefix").unwrap().to_owned(),
             cache: None,
         }),
         Some(("disk", sub)) => StorageConfiguration::Disk(DiskStorageConfiguration {
@@ -777,18 +1691,53 @@ fn create_config() -> (Configuration, &'static str) {
                 Some("throughput") => storage::disk::OptimizeFor::Throughput,
                 _ => unreachable!("Some disk OptimizeFor wasn't configured!"),
             },
+            checkpoints: sub.get_one::<String>("CHECKPOINTS").map(|dir| {
+                DiskCheckpointConfiguration {
+                    dir: dir.to_owned(),
+                    interval: sub
+                        .get_one::<u64>("CHECKPOINT_INTERVAL")
+                        .map(|secs| Duration::from_secs(*secs)),
+                    on_request: sub.get_flag("CHECKPOINT_ON_REQUEST"),
+                    keep: *sub.get_one::<u64>("KEEP_CHECKPOINTS").unwrap() as usize,
+                }
+            }),
+            restore_from: sub.get_one::<String>("RESTORE").cloned(),
         }),
         Some(("redis_cached", sub)) => StorageConfiguration::Redis(RedisStorageConfiguration {
             url: sub.get_one::<String>("URL").unwrap().to_owned(),
+            cluster: sub.get_flag("cluster") || *config::env::REDIS_CLUSTER,
+            sharded: false,
+            exact_limits: false,
+            key_prefix: sub.get_one::<String>("key_prefix").unwrap().to_owned(),
             cache: Some(RedisStorageCacheConfiguration {
                 batch_size: *sub.get_one("batch").unwrap(),
                 flushing_period: *sub.get_one("flush").unwrap(),
                 max_counters: *sub.get_one("max").unwrap(),
                 response_timeout: *sub.get_one("timeout").unwrap(),
+                partition_policy: match sub
+                    .get_one::<String>("partition_policy")
+                    .map(String::as_str)
+                {
+                    Some("serve") => PartitionPolicy::ServeFromCache,
+                    Some("serve_for") => PartitionPolicy::ServeFromCacheFor(Duration::from_secs(
+                        *sub.get_one("partition_max_duration").unwrap(),
+                    )),
+                    Some("divide") => {
+                        PartitionPolicy::DivideAmong(*sub.get_one("partition_replicas").unwrap())
+                    }
+                    Some("fail_closed") => PartitionPolicy::FailClosed,
+                    _ => unreachable!("Some partition policy wasn't configured!"),
+                },
+                adaptive_flush: sub.get_flag("adaptive_flush"),
+                publish_updates: sub.get_flag("publish_updates"),
             }),
         }),
         Some(("memory", sub)) => StorageConfiguration::InMemory(InMemoryStorageConfiguration {
             cache_size: sub.get_one::<u64>("CACHE_SIZE").copied(),
+            snapshot: sub.get_one::<String>("SNAPSHOT").cloned(),
+            snapshot_interval: Duration::from_secs(
+                *sub.get_one::<u64>("SNAPSHOT_INTERVAL").unwrap(),
+            ),
         }),
         #[cfg(feature = "distributed_storage")]
         Some(("distributed", sub)) => {
@@ -803,7 +1752,7 @@ fn create_config() -> (Configuration, &'static str) {
                 cache_size: sub.get_one::<u64>("CACHE_SIZE").copied(),
             })
         }
-        None => storage_config_from_env(),
+        None | Some(("migrate", _)) => storage_config_from_env(),
         _ => unreachable!("Some storage wasn't configured!"),
     };
 
@@ -846,14 +1795,91 @@ fn create_config() -> (Configuration, &'static str) {
         _ => unreachable!("Verbosity should at most be 4!"),
     };
     config.structured_logs = matches.get_flag("S");
+    config.migration = migration;
+    config.counter_metrics_interval =
+        match *matches.get_one::<u64>("counter_metrics_interval").unwrap() {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    config.counter_utilization_metrics = matches.get_flag("counter_utilization_metrics")
+        || *config::env::COUNTER_UTILIZATION_METRICS;
+    config.counter_variables_key_file = matches
+        .get_one::<String>("hash_counter_variables")
+        .cloned()
+        .or_else(|| config::env::HASH_COUNTER_VARIABLES_KEY_FILE.map(str::to_owned));
+    for (namespace, policy) in matches
+        .get_many::<(Option<Namespace>, OnEvaluationError)>("on_evaluation_error")
+        .unwrap_or_default()
+        .cloned()
+    {
+        match namespace {
+            Some(namespace) => {
+                config
+                    .on_evaluation_error
+                    .namespaces
+                    .insert(namespace, policy);
+            }
+            None => config.on_evaluation_error.default = policy,
+        }
+    }
+    config.storage_resilience = StorageResilienceConfiguration {
+        timeout: match *matches.get_one::<u64>("storage_timeout").unwrap() {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        },
+        retries: *matches.get_one("storage_retries").unwrap(),
+        retry_backoff: Duration::from_millis(*matches.get_one("storage_retry_backoff").unwrap()),
+        breaker: match *matches.get_one::<u32>("breaker_failures").unwrap() {
+            0 => None,
+            failures => Some((
+                failures,
+                Duration::from_millis(*matches.get_one("breaker_cool_down").unwrap()),
+            )),
+        },
+    };
+    config.storage_fallback =
+        matches
+            .get_flag("fallback_to_memory")
+            .then(|| StorageFallbackConfiguration {
+                fallback_after: *matches.get_one("fallback_after").unwrap(),
+                probe_interval: Duration::from_millis(
+                    *matches.get_one("fallback_probe_interval").unwrap(),
+                ),
+                recover_after: *matches.get_one("fallback_recover_after").unwrap(),
+                replay: matches.get_flag("fallback_replay"),
+            });
 
     (config, full_version)
 }
 
+// Parses an `--on-evaluation-error`, of a namespace when it's prefixed with one
+fn evaluation_error_policy(arg: &str) -> Result<(Option<Namespace>, OnEvaluationError), String> {
+    let (namespace, policy) = match arg.rsplit_once('=') {
+        Some((namespace, policy)) => (Some(namespace.into()), policy),
+        None => (None, arg),
+    };
+    let policy = match policy {
+        "skip" => OnEvaluationError::Skip,
+        "apply" => OnEvaluationError::Apply,
+        "fail" => OnEvaluationError::Fail,
+        _ => {
+            return Err(format!(
+                "unknown policy {policy:?}, expected skip, apply or fail"
+            ))
+        }
+    };
+    Ok((namespace, policy))
+}
+
 fn storage_config_from_env() -> StorageConfiguration {
     if let Some(url) = config::env::REDIS_URL.map(str::to_owned) {
         StorageConfiguration::Redis(RedisStorageConfiguration {
             url,
+            cluster: *config::env::REDIS_CLUSTER,
+            sharded: *config::env::REDIS_SHARDED && !*config::env::REDIS_LOCAL_CACHE_ENABLED,
+            exact_limits: *config::env::REDIS_EXACT_LIMITS
+                && !*config::env::REDIS_LOCAL_CACHE_ENABLED,
+            key_prefix: config::env::REDIS_KEY_PREFIX.unwrap_or("").to_owned(),
             cache: if *config::env::REDIS_LOCAL_CACHE_ENABLED {
                 Some(RedisStorageCacheConfiguration {
                     batch_size: config::env::REDIS_LOCAL_CACHE_BATCH_SIZE
@@ -868,13 +1894,20 @@ fn storage_config_from_env() -> StorageConfiguration {
                         .expect("Expected an i64"),
                     max_counters: DEFAULT_MAX_CACHED_COUNTERS,
                     response_timeout: DEFAULT_RESPONSE_TIMEOUT_MS,
+                    partition_policy: PartitionPolicy::default(),
+                    adaptive_flush: false,
+                    publish_updates: false,
                 })
             } else {
                 None
             },
         })
     } else {
-        StorageConfiguration::InMemory(InMemoryStorageConfiguration { cache_size: None })
+        StorageConfiguration::InMemory(InMemoryStorageConfiguration {
+            cache_size: None,
+            snapshot: None,
+            snapshot_interval: Duration::from_secs(60),
+        })
     }
 }
 
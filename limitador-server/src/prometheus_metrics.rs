@@ -1,20 +1,26 @@
 use crate::metrics::Timings;
-use limitador::limit::{Context, Expression, Namespace};
+use limitador::counter::Counter;
+use limitador::limit::{Context, Expression, Limit, Namespace};
 use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use std::collections::HashMap;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::string::ToString;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 const NAMESPACE_LABEL: &str = "limitador_namespace";
 const LIMIT_NAME_LABEL: &str = "limit_name";
+const LIMIT_LABEL: &str = "limit";
+const UTILIZATION_BUCKETS: [f64; 10] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
 
 pub struct PrometheusMetrics {
     prometheus_handle: Arc<PrometheusHandle>,
     use_limit_name_label: bool,
     custom_labels: RwLock<HashMap<String, Expression>>,
     default_labels: Option<Expression>,
+    // The namespace and limit labels of the `limit_counters` last set
+    limit_counters: Mutex<HashSet<(String, String)>>,
 }
 
 impl Default for PrometheusMetrics {
@@ -66,6 +72,48 @@ impl PrometheusMetrics {
         );
         describe_counter!("authorized_calls", "Authorized calls");
         describe_counter!("limited_calls", "Limited calls");
+        describe_counter!(
+            "limit_evaluation_errors",
+            "Conditions or variables of limits that failed to evaluate"
+        );
+        describe_counter!(
+            "limit_counters_over_cap",
+            "Requests that would have created a counter past the max_counters of its limit"
+        );
+        describe_gauge!(
+            "limit_counters",
+            "Live qualified counters of the limits sharing an id, or name"
+        );
+        describe_histogram!(
+            "counter_utilization",
+            "Ratio of the max value of their limit the sampled counters are at"
+        );
+        describe_histogram!(
+            "storage_operation_duration_seconds",
+            "Latency of the operations on the counter storage, by backend"
+        );
+        describe_counter!(
+            "storage_errors",
+            "Operations on the counter storage that failed, by backend and whether transiently"
+        );
+        describe_histogram!(
+            "batcher_flush_size",
+            "Counters flushed to Redis at once by the cached storage"
+        );
+        describe_histogram!(
+            "counter_staleness",
+            "Seconds the oldest pending write of a counter waited before being flushed to Redis"
+        );
+        describe_counter!("disk_merges", "Merges of counter updates run by RocksDB");
+        describe_histogram!(
+            "disk_merge_operands",
+            "Counter updates merged at once by RocksDB"
+        );
+        describe_counter!("disk_compactions", "Compactions run by RocksDB");
+        describe_counter!(
+            "disk_compaction_removals",
+            "Expired counters removed by the compactions of RocksDB"
+        );
         describe_gauge!("limitador_up", "Limitador is running");
         gauge!("limitador_up").set(1);
         describe_gauge!(
@@ -73,18 +121,44 @@ impl PrometheusMetrics {
             "Limitador is partitioned from backing datastore"
         );
         gauge!("datastore_partitioned").set(0);
+        describe_counter!(
+            "datastore_partition_over_admitted",
+            "Hits admitted over the limits while partitioned from the backing datastore"
+        );
+        describe_counter!(
+            "storage_retries",
+            "Calls to the counter storage retried after a transient failure"
+        );
+        describe_gauge!(
+            "storage_circuit_breaker_state",
+            "State of the circuit breaker in front of the counter storage: 0 closed, 1 open, 2 half-open"
+        );
+        describe_counter!(
+            "storage_circuit_breaker_rejections",
+            "Calls failed fast while the circuit breaker in front of the counter storage was open"
+        );
+        describe_gauge!(
+            "storage_fallback_active",
+            "Limitador is counting in memory while its counter storage is failing"
+        );
         Self {
             use_limit_name_label,
             prometheus_handle,
             custom_labels: RwLock::default(),
             default_labels,
+            limit_counters: Mutex::default(),
         }
     }
 
     // Creates and installs the prometheus exporter as global recorder
     // Only one recorder can be registered for the lifetime of the application
     pub fn init_handle() -> PrometheusHandle {
-        let prom_builder = PrometheusBuilder::new();
+        let prom_builder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("counter_utilization".to_string()),
+                &UTILIZATION_BUCKETS,
+            )
+            .expect("invalid buckets for counter_utilization");
         prom_builder
             .install_recorder()
             .expect("failed to create prometheus metrics exporter")
@@ -132,6 +206,54 @@ impl PrometheusMetrics {
         histogram!("datastore_latency").record(Duration::from(timings).as_secs_f64())
     }
 
+    /// Sets the number of counters of the limits, of every namespace. Limits
+    /// are labeled as per [`limit_label`], and those sharing one are summed.
+    /// The ones set last time but left out this time, e.g. as their limit was
+    /// removed, are zeroed.
+    pub fn set_limit_counters<'a>(
+        &self,
+        cardinalities: impl IntoIterator<Item = (&'a Limit, u64)>,
+    ) {
+        let mut by_label: HashMap<(String, String), u64> = HashMap::new();
+        for (limit, cardinality) in cardinalities {
+            let labels = (
+                limit.namespace().as_ref().to_string(),
+                limit_label(limit).into_owned(),
+            );
+            *by_label.entry(labels).or_default() += cardinality;
+        }
+        let mut last_set = self.limit_counters.lock().unwrap();
+        for (namespace, limit) in last_set
+            .drain()
+            .filter(|labels| !by_label.contains_key(labels))
+        {
+            gauge!("limit_counters", NAMESPACE_LABEL => namespace, LIMIT_LABEL => limit).set(0);
+        }
+        for ((namespace, limit), cardinality) in by_label {
+            gauge!(
+                "limit_counters",
+                NAMESPACE_LABEL => namespace.clone(),
+                LIMIT_LABEL => limit.clone()
+            )
+            .set(cardinality as f64);
+            last_set.insert((namespace, limit));
+        }
+    }
+
+    pub fn record_counter_utilization(counter: &Counter) {
+        let max_value = counter.max_value();
+        if max_value == 0 {
+            return;
+        }
+        let value = max_value - counter.remaining().unwrap_or(max_value).min(max_value);
+        histogram!(
+            "counter_utilization",
+            NAMESPACE_LABEL => counter.namespace().as_ref().to_string(),
+            LIMIT_LABEL => limit_label(counter.limit()).into_owned()
+        )
+        .record(value as f64 / max_value as f64);
+    }
+
     pub fn set_custom_labels(&self, new_labels: HashMap<String, Expression>) -> Result<(), String> {
         match self.custom_labels.write() {
             Ok(mut custom_labels) => {
@@ -147,7 +269,7 @@ impl PrometheusMetrics {
             custom_labels
                 .iter()
                 .filter_map(|(label, exp)| {
-                    if let Ok(Some(val)) = exp.eval(ctx) {
+                    if let Ok(Some(val)) = exp.eval_string(ctx) {
                         return Some((label.to_string(), val));
                     }
                     None
@@ -167,6 +289,23 @@ impl PrometheusMetrics {
     }
 }
 
+/// The id of the limit, or its name, or else a hash of what identifies it,
+/// which stays the same from one run to the next. Uses 64-bit FNV-1a, as
+/// `hashBucket` does, over the limit as JSON, as the hashers of std may change
+/// from one release of Rust to the next.
+fn limit_label(limit: &Limit) -> Cow<'_, str> {
+    match limit.id().or(limit.name()) {
+        Some(label) => Cow::Borrowed(label),
+        None => {
+            let identity = serde_json::to_vec(limit).unwrap_or_default();
+            let hash = identity.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+            });
+            Cow::Owned(format!("{hash:016x}"))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -412,6 +551,105 @@ mod tests {
         });
     }
 
+    #[test]
+    fn shows_limit_counters_by_limit_id_or_name() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle: Arc<PrometheusHandle> = recorder.handle().into();
+
+        with_local_recorder(&recorder, || {
+            let prometheus_metrics = PrometheusMetrics::new_with_handle(false, handle.clone());
+            let namespace: Namespace = "limit_counters".into();
+            let mut by_id = Limit::with_id("per_user", namespace.clone(), 10, 60, vec![], vec![]);
+            by_id.set_name("ignored".to_string());
+            let mut named = Limit::new(namespace.clone(), 10, 60, vec![], vec![]);
+            named.set_name("per_ip".to_string());
+            let mut also_named = Limit::new(namespace.clone(), 20, 60, vec![], vec![]);
+            also_named.set_name("per_ip".to_string());
+
+            prometheus_metrics.set_limit_counters([(&by_id, 3), (&named, 4), (&also_named, 5)]);
+            let metrics_output = prometheus_metrics.gather_metrics();
+
+            assert!(metrics_output.contains(
+                "limit_counters{limitador_namespace=\"limit_counters\",limit=\"per_user\"} 3"
+            ));
+            assert!(metrics_output.contains(
+                "limit_counters{limitador_namespace=\"limit_counters\",limit=\"per_ip\"} 9"
+            ));
+        });
+    }
+
+    #[test]
+    fn zeroes_the_limit_counters_of_removed_limits() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle: Arc<PrometheusHandle> = recorder.handle().into();
+
+        with_local_recorder(&recorder, || {
+            let prometheus_metrics = PrometheusMetrics::new_with_handle(false, handle.clone());
+            let mut named = Limit::new("removed", 10, 60, vec![], vec![]);
+            named.set_name("per_ip".to_string());
+            let unnamed = Limit::new("kept", 10, 60, vec![], vec![]);
+            let label = limit_label(&unnamed).into_owned();
+            assert_eq!(label, limit_label(&unnamed.clone()));
+            assert_ne!(
+                label,
+                limit_label(&Limit::new("kept", 10, 30, vec![], vec![]))
+            );
+
+            prometheus_metrics.set_limit_counters([(&named, 4), (&unnamed, 2)]);
+            prometheus_metrics.set_limit_counters([(&unnamed, 3)]);
+            let metrics_output = prometheus_metrics.gather_metrics();
+
+            assert!(metrics_output
+                .contains("limit_counters{limitador_namespace=\"removed\",limit=\"per_ip\"} 0"));
+            assert!(metrics_output.contains(&format!(
+                "limit_counters{{limitador_namespace=\"kept\",limit=\"{label}\"}} 3"
+            )));
+        });
+    }
+
+    #[test]
+    fn labels_unnamed_limits_the_same_across_releases() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().unwrap()],
+            vec!["app_id".try_into().unwrap()],
+        );
+        assert_eq!(limit_label(&limit), "8690bc7b2bcb254d");
+    }
+
+    #[test]
+    fn records_counter_utilization() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("counter_utilization".to_string()),
+                &UTILIZATION_BUCKETS,
+            )
+            .unwrap()
+            .build_recorder();
+        let handle: Arc<PrometheusHandle> = recorder.handle().into();
+
+        with_local_recorder(&recorder, || {
+            let prometheus_metrics = PrometheusMetrics::new_with_handle(false, handle.clone());
+            let mut limit = Limit::new("utilization", 10, 60, vec![], vec![]);
+            limit.set_name("global".to_string());
+            let mut counter = Counter::new(limit, &Context::default()).unwrap().unwrap();
+            counter.set_remaining(3);
+
+            PrometheusMetrics::record_counter_utilization(&counter);
+            let metrics_output = prometheus_metrics.gather_metrics();
+
+            let labels = "limitador_namespace=\"utilization\",limit=\"global\"";
+            assert!(metrics_output.contains(&format!(
+                "counter_utilization_bucket{{{labels},le=\"0.6\"}} 0"
+            )));
+            assert!(metrics_output.contains(&format!(
+                "counter_utilization_bucket{{{labels},le=\"0.7\"}} 1"
+            )));
+        });
+    }
+
     fn formatted_counter_with_namespace(
         metric_name: &str,
         count: i32,
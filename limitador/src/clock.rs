@@ -0,0 +1,105 @@
+//! Where the storages get the current time from.
+//!
+//! All the storages default to the [`SystemClock`]. Giving them a
+//! [`ManualClock`] instead makes the expiry of the limits' windows
+//! deterministic, e.g. to test or simulate traffic without sleeping:
+//!
+//! ```
+//! use limitador::clock::ManualClock;
+//! use limitador::limit::{Context, Expression, Limit};
+//! use limitador::RateLimiterBuilder;
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! let clock = Arc::new(ManualClock::default());
+//! let rate_limiter = RateLimiterBuilder::with_clock(1000, clock.clone()).build();
+//! rate_limiter.add_limit(Limit::new("ns", 1, 60, vec![], Vec::<Expression>::new()));
+//!
+//! let namespace = "ns".into();
+//! let ctx = Context::default();
+//! assert!(!rate_limiter.check_rate_limited_and_update(&namespace, &ctx, 1, false).unwrap().limited);
+//! assert!(rate_limiter.check_rate_limited_and_update(&namespace, &ctx, 1, false).unwrap().limited);
+//!
+//! clock.advance(Duration::from_secs(60));
+//! assert!(!rate_limiter.check_rate_limited_and_update(&namespace, &ctx, 1, false).unwrap().limited);
+//! ```
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The operating system's clock, i.e. [`SystemTime::now`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    micros_since_epoch: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            micros_since_epoch: AtomicU64::new(micros_since_epoch(now)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.micros_since_epoch
+            .fetch_add(by.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        self.micros_since_epoch
+            .store(micros_since_epoch(now), Ordering::SeqCst);
+    }
+}
+
+/// Starts at the current time of the [`SystemClock`].
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.micros_since_epoch.load(Ordering::SeqCst))
+    }
+}
+
+fn micros_since_epoch(when: SystemTime) -> u64 {
+    when.duration_since(UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH!")
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ManualClock};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn manual_clock_only_moves_when_told_to() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(60));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(60));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(60));
+
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_millis(61_500));
+
+        clock.set(UNIX_EPOCH);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+    }
+}
@@ -1,11 +1,17 @@
 use crate::limit::{Context, Limit, Namespace};
 use crate::LimitadorResult;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::time::Duration;
 
+/// The value of the variables of an overflow counter, see [`Counter::overflow`].
+pub const OVERFLOW_VALUE: &str = "\u{0}overflow";
+
 #[derive(Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct Counter {
     limit: Arc<Limit>,
@@ -36,8 +42,9 @@ impl Counter {
         set_variables: HashMap<String, String>,
     ) -> LimitadorResult<Self> {
         let limit = limit.into();
+        let variables = limit.variables();
         let mut vars = set_variables;
-        vars.retain(|var, _| limit.has_variable(var));
+        vars.retain(|var, _| variables.contains(var));
 
         Ok(Self {
             limit,
@@ -47,7 +54,30 @@ impl Counter {
         })
     }
 
-    #[cfg(any(feature = "redis_storage", feature = "disk_storage"))]
+    /// The counter that the qualified counters of this counter's limit, past its
+    /// [`max_counters`](Limit::max_counters), are folded into: the same one with
+    /// every variable set to [`OVERFLOW_VALUE`].
+    pub fn overflow(&self) -> Self {
+        Self {
+            limit: Arc::clone(&self.limit),
+            set_variables: self
+                .set_variables
+                .keys()
+                .map(|var| (var.clone(), OVERFLOW_VALUE.to_string()))
+                .collect(),
+            remaining: None,
+            expires_in: None,
+        }
+    }
+
+    /// Replaces the values of the variables with their keyed hash, so that
+    /// they are never stored as they were resolved.
+    pub(crate) fn hash_variables(&mut self, hasher: &VariableHasher) {
+        for value in self.set_variables.values_mut() {
+            *value = hasher.hash(value);
+        }
+    }
+
     pub(crate) fn key(&self) -> Self {
         Self {
             limit: Arc::clone(&self.limit),
@@ -85,6 +115,9 @@ impl Counter {
         self.limit.namespace()
     }
 
+    /// The values of the variables that qualify this counter. These are the
+    /// hashes of the values the variables resolved to when the counter was
+    /// built by a rate limiter that [hashes them](VariableHasher).
     pub fn set_variables(&self) -> &BTreeMap<String, String> {
         &self.set_variables
     }
@@ -109,13 +142,12 @@ impl Counter {
         !self.set_variables.is_empty()
     }
 
-    #[cfg(feature = "disk_storage")]
     pub(crate) fn variables_for_key(&self) -> Vec<(&str, &str)> {
         let mut variables = Vec::with_capacity(self.set_variables.len());
         for (var, value) in &self.set_variables {
             variables.push((var.as_str(), value.as_str()));
         }
-        variables.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
+        variables.sort_by_key(|(key, _)| *key);
         variables
     }
 }
@@ -137,9 +169,46 @@ impl PartialEq for Counter {
     }
 }
 
+/// Hashes the values of the variables of counters with HMAC-SHA256, keyed with
+/// a secret, so that values such as API keys, emails or IPs never reach the
+/// storage. The hashes are truncated to 128 bits, hex encoded.
+///
+/// All the instances sharing a storage must use the same secret, or they won't
+/// share counters either.
+#[derive(Clone)]
+pub struct VariableHasher {
+    mac: Hmac<Sha256>,
+}
+
+impl VariableHasher {
+    const HASH_LEN: usize = 16;
+
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            mac: Hmac::new_from_slice(secret.as_ref()).expect("HMAC takes keys of any size"),
+        }
+    }
+
+    pub fn hash(&self, value: &str) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        digest[..Self::HASH_LEN]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl Debug for VariableHasher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VariableHasher { .. }")
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::counter::Counter;
+    use crate::counter::{Counter, VariableHasher};
     use crate::limit::Limit;
     use std::collections::HashMap;
 
@@ -161,4 +230,34 @@ mod tests {
             Some("13".to_string()).as_ref()
         );
     }
+
+    #[test]
+    fn hashes_variables_with_the_secret() {
+        let limit = Limit::new(
+            "",
+            10,
+            60,
+            Vec::default(),
+            ["user".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("user".to_string(), "alice@example.com".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .expect("failed creating counter")
+            .expect("must have a counter");
+
+        let mut hashed = counter.clone();
+        hashed.hash_variables(&VariableHasher::new("secret"));
+        let value = hashed.set_variables().get("user").unwrap();
+        assert_eq!(value.len(), 32);
+        assert_ne!(value, "alice@example.com");
+
+        let mut again = counter.clone();
+        again.hash_variables(&VariableHasher::new("secret"));
+        assert_eq!(hashed, again);
+
+        let mut other_secret = counter;
+        other_secret.hash_variables(&VariableHasher::new("another secret"));
+        assert_ne!(hashed, other_secret);
+    }
 }
@@ -1,14 +1,20 @@
-use crate::limit::EvaluationError;
 use crate::limit::ParseError;
+use crate::limit::{EvaluationError, Limit};
 use crate::storage::StorageErr;
 use std::convert::Infallible;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum LimitadorError {
     StorageError(StorageErr),
     InterpreterError(EvaluationError),
+    /// A condition or variable of `limit` failed to evaluate.
+    LimitEvaluationError {
+        limit: Arc<Limit>,
+        source: EvaluationError,
+    },
 }
 
 impl Display for LimitadorError {
@@ -20,6 +26,14 @@ impl Display for LimitadorError {
             LimitadorError::InterpreterError(err) => {
                 write!(f, "error parsing condition: {err:?}")
             }
+            LimitadorError::LimitEvaluationError { limit, source } => {
+                let name = limit.name().or(limit.id()).unwrap_or_default();
+                write!(
+                    f,
+                    "error evaluating limit `{name}` of namespace `{}`: {source}",
+                    limit.namespace().as_ref()
+                )
+            }
         }
     }
 }
@@ -29,6 +43,7 @@ impl Error for LimitadorError {
         match self {
             LimitadorError::StorageError(err) => Some(err),
             LimitadorError::InterpreterError(err) => Some(err),
+            LimitadorError::LimitEvaluationError { source, .. } => Some(source),
         }
     }
 }
@@ -0,0 +1,205 @@
+//! Explanations of rate limiting decisions.
+//!
+//! [`RateLimiter::explain_rate_limited_and_update`](crate::RateLimiter::explain_rate_limited_and_update)
+//! and its async counterpart behave as `check_rate_limited_and_update` does,
+//! but also report how every limit of the namespace was evaluated: which of
+//! its conditions matched, what its variables resolved to, and the state of
+//! its counter, if any.
+
+use crate::counter::{Counter, VariableHasher};
+use crate::limit::{Context, Limit, OnEvaluationError};
+use crate::storage::Authorization;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Explanation {
+    pub decision: Decision,
+    pub limits: Vec<LimitExplanation>,
+}
+
+/// Why the request was, or wasn't, rate limited.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "reason")]
+pub enum Decision {
+    /// None of the limits of the namespace applies to the request.
+    NoLimitApplies,
+    /// All the counters of the limits that apply had room for the request.
+    WithinLimits,
+    /// At least one of the counters was exhausted.
+    Limited {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit_name: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LimitExplanation {
+    #[serde(skip)]
+    pub limit: Arc<Limit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub max_value: u64,
+    pub seconds: u64,
+    pub conditions: Vec<ConditionExplanation>,
+    /// The value of each variable, `None` when it isn't set in the context.
+    /// Hashed, as in the counters, when the limiter hashes them.
+    pub variables: BTreeMap<String, Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter: Option<CounterExplanation>,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConditionExplanation {
+    pub condition: String,
+    /// `None` when the condition failed to evaluate.
+    pub matched: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CounterExplanation {
+    /// The limit and the values of its variables that identify the counter,
+    /// as JSON.
+    pub key: String,
+    /// The hits in the current window, this request's included, capped to the
+    /// limit's `max_value`.
+    pub value: Option<u64>,
+    pub remaining: Option<u64>,
+    pub expires_in_seconds: Option<u64>,
+}
+
+/// What became of a limit of the namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// One of its conditions doesn't hold.
+    ConditionsNotMet,
+    /// One of its variables isn't set.
+    VariablesNotSet,
+    /// A condition or variable failed to evaluate and the limit's policy is to
+    /// skip it.
+    Skipped,
+    /// A condition or variable failed to evaluate and the limit's policy fails
+    /// the request, as `apply` does for variables.
+    Failed,
+    /// Its counter had room for the request.
+    WithinLimits,
+    /// Its counter was exhausted.
+    Limited,
+}
+
+impl Explanation {
+    /// Explains the decision taken for `ctx`, given the `counters` that applied,
+    /// as loaded by the storage when checking them.
+    pub(crate) fn new(
+        limits: &HashSet<Arc<Limit>>,
+        ctx: &Context,
+        on_error: OnEvaluationError,
+        hasher: Option<&VariableHasher>,
+        counters: &[Counter],
+        authorization: &Authorization,
+    ) -> Self {
+        let decision = match authorization {
+            Authorization::Limited(name) => Decision::Limited {
+                limit_name: name.clone(),
+            },
+            Authorization::Ok if counters.is_empty() => Decision::NoLimitApplies,
+            Authorization::Ok => Decision::WithinLimits,
+        };
+
+        let limited = matches!(authorization, Authorization::Limited(_));
+        let mut limits: Vec<&Arc<Limit>> = limits.iter().collect();
+        limits.sort();
+        let limits = limits
+            .into_iter()
+            .map(|limit| {
+                let counter = counters.iter().find(|c| c.limit() == limit.as_ref());
+                LimitExplanation::new(limit, ctx, on_error, hasher, counter, limited)
+            })
+            .collect();
+
+        Self { decision, limits }
+    }
+}
+
+impl LimitExplanation {
+    fn new(
+        limit: &Arc<Limit>,
+        ctx: &Context,
+        on_error: OnEvaluationError,
+        hasher: Option<&VariableHasher>,
+        counter: Option<&Counter>,
+        limited: bool,
+    ) -> Self {
+        let conditions = limit.test_conditions(ctx);
+        let variables = limit.eval_variables(ctx);
+        let condition_failed = conditions.iter().any(|(_, r)| r.is_err());
+        let variable_failed = variables.iter().any(|(_, r)| r.is_err());
+
+        let outcome = match counter {
+            // A denied request leaves every counter as it was, loaded with the
+            // room it had left: the ones without any are the ones that limited.
+            Some(counter) if limited && counter.remaining() == Some(0) => Outcome::Limited,
+            Some(_) => Outcome::WithinLimits,
+            None if conditions.iter().any(|(_, r)| matches!(r, Ok(false))) => {
+                Outcome::ConditionsNotMet
+            }
+            None if condition_failed || variable_failed => {
+                match limit.on_error().unwrap_or(on_error) {
+                    OnEvaluationError::Skip => Outcome::Skipped,
+                    OnEvaluationError::Apply if !variable_failed => Outcome::VariablesNotSet,
+                    _ => Outcome::Failed,
+                }
+            }
+            None => Outcome::VariablesNotSet,
+        };
+
+        Self {
+            limit: Arc::clone(limit),
+            id: limit.id().map(String::from),
+            name: limit.name().map(String::from),
+            max_value: limit.max_value(),
+            seconds: limit.seconds(),
+            conditions: conditions
+                .into_iter()
+                .map(|(condition, result)| ConditionExplanation {
+                    condition,
+                    matched: result.as_ref().ok().copied(),
+                    error: result.err().map(|err| err.to_string()),
+                })
+                .collect(),
+            variables: variables
+                .into_iter()
+                .map(|(variable, result)| {
+                    let value = result.ok().flatten();
+                    let value = match hasher {
+                        Some(hasher) => value.map(|value| hasher.hash(&value)),
+                        None => value,
+                    };
+                    (variable, value)
+                })
+                .collect(),
+            counter: counter.map(CounterExplanation::new),
+            outcome,
+        }
+    }
+}
+
+impl CounterExplanation {
+    fn new(counter: &Counter) -> Self {
+        Self {
+            key: serde_json::to_string(&counter.key()).unwrap_or_default(),
+            value: counter
+                .remaining()
+                .map(|remaining| counter.max_value().saturating_sub(remaining)),
+            remaining: counter.remaining(),
+            expires_in_seconds: counter.expires_in().map(|duration| duration.as_secs()),
+        }
+    }
+}
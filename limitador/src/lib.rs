@@ -181,6 +181,25 @@
 //! # }
 //! ```
 //!
+//! Code that needs to work with either of them can rely on the [`Limiter`]
+//! trait instead, which both implement with an async interface. Blocking
+//! storages can also be driven by an "AsyncRateLimiter" by wrapping them in a
+//! "BlockingStorageAdapter", which runs them on tokio's blocking thread pool:
+//!
+//! ```no_run
+//! #[cfg(feature = "disk_storage")]
+//! # {
+//! use limitador::{AsyncRateLimiter, Limiter};
+//! use limitador::storage::blocking::BlockingStorageAdapter;
+//! use limitador::storage::disk::{DiskStorage, OptimizeFor};
+//!
+//! let storage = DiskStorage::open("/tmp/limitador", OptimizeFor::Throughput);
+//! let rate_limiter: Box<dyn Limiter> = Box::new(AsyncRateLimiter::new_with_storage(
+//!     Box::new(BlockingStorageAdapter::new(storage.unwrap())),
+//! ));
+//! # }
+//! ```
+//!
 //! # Limits accuracy
 //!
 //! When storing the counters in memory, Limitador guarantees that we'll never go
@@ -188,39 +207,85 @@
 //! Redis driver sacrifices a bit of accuracy when applying the limits to be
 //! more performant.
 //!
+//! # Metrics
+//!
+//! The storages report through the [metrics](https://docs.rs/metrics) facade,
+//! to whichever recorder the application installs:
+//!
+//! - `storage_operation_duration_seconds`: histogram of the time each
+//!   operation on the counters took, labeled by `backend`, e.g. `memory` or
+//!   `redis_cached`, and `operation`.
+//! - `storage_errors`: the operations that failed, labeled by `backend`,
+//!   `operation` and `kind`, either `transient` or `permanent`.
+//! - `batcher_flush_size`: histogram of the number of counters the cached
+//!   Redis storage flushes at once.
+//! - `disk_merges`, `disk_merge_operands`, `disk_compactions` and
+//!   `disk_compaction_removals`: the merges of counter updates and the
+//!   compactions RocksDB runs for the disk storage, along with the expired
+//!   counters they removed.
+//!
 
 #![deny(clippy::all, clippy::cargo)]
 // TODO this needs review to reduce the bloat pulled in by dependencies
 #![allow(clippy::multiple_crate_versions)]
 
-use crate::counter::Counter;
+use crate::clock::Clock;
+use crate::counter::{Counter, VariableHasher};
 use crate::errors::LimitadorError;
-use crate::limit::{Context, Limit, Namespace};
+use crate::explain::Explanation;
+use crate::limit::{Context, Limit, Namespace, OnEvaluationError};
+use crate::storage::dump::CounterDump;
 use crate::storage::in_memory::InMemoryStorage;
 use crate::storage::{
     AsyncCounterStorage, AsyncStorage, Authorization, CounterStorage, Storage, StorageErr,
 };
+use async_trait::async_trait;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 #[macro_use]
 extern crate core;
 
+pub mod clock;
 pub mod counter;
 pub mod errors;
+pub mod explain;
 pub mod limit;
+pub mod simulator;
 pub mod storage;
 
 pub struct RateLimiter {
     storage: Storage,
+    on_error: EvaluationErrorPolicies,
+    hasher: Option<VariableHasher>,
 }
 
 pub struct AsyncRateLimiter {
     storage: AsyncStorage,
+    on_error: EvaluationErrorPolicies,
+    hasher: Option<VariableHasher>,
 }
 
 pub struct RateLimiterBuilder {
     storage: Storage,
+    on_error: EvaluationErrorPolicies,
+    hasher: Option<VariableHasher>,
+}
+
+/// The [`OnEvaluationError`] policies for limits that don't have their own.
+#[derive(Clone, Debug, Default)]
+struct EvaluationErrorPolicies {
+    default: OnEvaluationError,
+    namespaces: HashMap<Namespace, OnEvaluationError>,
+}
+
+impl EvaluationErrorPolicies {
+    fn for_namespace(&self, namespace: &Namespace) -> OnEvaluationError {
+        self.namespaces
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.default)
+    }
 }
 
 type LimitadorResult<T> = Result<T, LimitadorError>;
@@ -229,6 +294,8 @@ pub struct CheckResult {
     pub limited: bool,
     pub counters: Vec<Counter>,
     pub limit_name: Option<String>,
+    /// Only set by the `explain_rate_limited_and_update` methods.
+    pub explanation: Option<Explanation>,
 }
 
 impl CheckResult {
@@ -279,13 +346,21 @@ impl From<CheckResult> for bool {
 
 impl RateLimiterBuilder {
     pub fn with_storage(storage: Storage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            on_error: EvaluationErrorPolicies::default(),
+            hasher: None,
+        }
     }
 
     pub fn new(cache_size: u64) -> Self {
-        Self {
-            storage: Storage::new(cache_size),
-        }
+        Self::with_storage(Storage::new(cache_size))
+    }
+
+    /// Same as [`RateLimiterBuilder::new`], but the counters tell the time
+    /// using `clock`.
+    pub fn with_clock(cache_size: u64, clock: Arc<dyn Clock>) -> Self {
+        Self::with_storage(Storage::with_clock(cache_size, clock))
     }
 
     pub fn storage(mut self, storage: Storage) -> Self {
@@ -293,40 +368,96 @@ impl RateLimiterBuilder {
         self
     }
 
+    /// Sets the policy on evaluation errors of the limits that don't have
+    /// their own, nor one for their namespace.
+    pub fn on_evaluation_error(mut self, policy: OnEvaluationError) -> Self {
+        self.on_error.default = policy;
+        self
+    }
+
+    /// Sets the policy on evaluation errors of the limits of `namespace` that
+    /// don't have their own.
+    pub fn namespace_on_evaluation_error(
+        mut self,
+        namespace: Namespace,
+        policy: OnEvaluationError,
+    ) -> Self {
+        self.on_error.namespaces.insert(namespace, policy);
+        self
+    }
+
+    /// Hashes the values of the variables of the counters with `hasher`
+    /// before they reach the storage.
+    pub fn hash_variables(mut self, hasher: VariableHasher) -> Self {
+        self.hasher = Some(hasher);
+        self
+    }
+
     pub fn build(self) -> RateLimiter {
         RateLimiter {
             storage: self.storage,
+            on_error: self.on_error,
+            hasher: self.hasher,
         }
     }
 }
 
 pub struct AsyncRateLimiterBuilder {
     storage: AsyncStorage,
+    on_error: EvaluationErrorPolicies,
+    hasher: Option<VariableHasher>,
 }
 
 impl AsyncRateLimiterBuilder {
     pub fn new(storage: AsyncStorage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            on_error: EvaluationErrorPolicies::default(),
+            hasher: None,
+        }
+    }
+
+    /// Sets the policy on evaluation errors of the limits that don't have
+    /// their own, nor one for their namespace.
+    pub fn on_evaluation_error(mut self, policy: OnEvaluationError) -> Self {
+        self.on_error.default = policy;
+        self
+    }
+
+    /// Sets the policy on evaluation errors of the limits of `namespace` that
+    /// don't have their own.
+    pub fn namespace_on_evaluation_error(
+        mut self,
+        namespace: Namespace,
+        policy: OnEvaluationError,
+    ) -> Self {
+        self.on_error.namespaces.insert(namespace, policy);
+        self
+    }
+
+    /// Hashes the values of the variables of the counters with `hasher`
+    /// before they reach the storage.
+    pub fn hash_variables(mut self, hasher: VariableHasher) -> Self {
+        self.hasher = Some(hasher);
+        self
     }
 
     pub fn build(self) -> AsyncRateLimiter {
         AsyncRateLimiter {
             storage: self.storage,
+            on_error: self.on_error,
+            hasher: self.hasher,
         }
     }
 }
 
 impl RateLimiter {
     pub fn new(cache_size: u64) -> Self {
-        Self {
-            storage: Storage::new(cache_size),
-        }
+        RateLimiterBuilder::new(cache_size).build()
     }
 
     pub fn new_with_storage(counters: Box<dyn CounterStorage>) -> Self {
-        Self {
-            storage: Storage::with_counter_storage(counters),
-        }
+        RateLimiterBuilder::with_storage(Storage::with_counter_storage(counters)).build()
     }
 
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
@@ -370,11 +501,13 @@ impl RateLimiter {
                     limited: false,
                     counters: Vec::default(),
                     limit_name: None,
+                    explanation: None,
                 }),
                 Authorization::Limited(name) => Ok(CheckResult {
                     limited: true,
                     counters: Vec::default(),
                     limit_name: name,
+                    explanation: None,
                 }),
             },
         }
@@ -432,6 +565,7 @@ impl RateLimiter {
                 limited: false,
                 counters,
                 limit_name: None,
+                explanation: None,
             });
         }
 
@@ -450,21 +584,77 @@ impl RateLimiter {
                 limited: false,
                 counters,
                 limit_name: None,
+                explanation: None,
             }),
             Authorization::Limited(name) => Ok(CheckResult {
                 limited: true,
                 counters,
                 limit_name: name,
+                explanation: None,
             }),
         }
     }
 
+    /// Does what [`RateLimiter::check_rate_limited_and_update`] does, loading
+    /// the counters, and explains how each limit of the namespace took part in
+    /// the decision. Meant for troubleshooting, as it evaluates the limits once
+    /// more to explain them.
+    pub fn explain_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        let limits = self.storage.get_limits(namespace);
+        let on_error = self.on_error.for_namespace(namespace);
+        let ctx = &ctx.at(self.storage.clock().now());
+        let mut counters = counters_that_apply(&limits, ctx, on_error, self.hasher.as_ref())?;
+
+        let authorization = if counters.is_empty() {
+            Authorization::Ok
+        } else {
+            self.storage.check_and_update(&mut counters, delta, true)?
+        };
+
+        let explanation = Explanation::new(
+            &limits,
+            ctx,
+            on_error,
+            self.hasher.as_ref(),
+            &counters,
+            &authorization,
+        );
+        Ok(explained(authorization, counters, explanation))
+    }
+
     pub fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>> {
         self.storage
             .get_counters(namespace)
             .map_err(|err| err.into())
     }
 
+    /// The number of qualified counters `limit` currently has.
+    pub fn cardinality(&self, limit: &Limit) -> LimitadorResult<u64> {
+        self.storage.cardinality(limit).map_err(|err| err.into())
+    }
+
+    /// Dumps the live counters of the limits of `namespace`, see
+    /// [`Storage::export`].
+    pub fn export_counters(&self, namespace: &Namespace) -> LimitadorResult<Vec<CounterDump>> {
+        self.storage.export(namespace).map_err(|err| err.into())
+    }
+
+    /// Dumps the live counters of `limit`, see [`Storage::export_limit`].
+    pub fn export_limit_counters(&self, limit: &Limit) -> LimitadorResult<Vec<CounterDump>> {
+        self.storage.export_limit(limit).map_err(|err| err.into())
+    }
+
+    /// Sets the counters of the limits configured to the ones dumped, see
+    /// [`Storage::import`]. Returns how many got imported.
+    pub fn import_counters(&self, dumps: Vec<CounterDump>) -> LimitadorResult<u64> {
+        self.storage.import(dumps).map_err(|err| err.into())
+    }
+
     // Deletes all the limits stored except the ones received in the params. For
     // every limit received, if it does not exist, it is created. If it already
     // exists, its associated counters are not reset.
@@ -506,15 +696,12 @@ impl RateLimiter {
         ctx: &Context,
     ) -> LimitadorResult<Vec<Counter>> {
         let limits = self.storage.get_limits(namespace);
-        limits
-            .iter()
-            .filter(|lim| lim.applies(ctx))
-            .filter_map(|lim| match Counter::new(Arc::clone(lim), ctx) {
-                Ok(None) => None,
-                Ok(Some(c)) => Some(Ok(c)),
-                Err(e) => Some(Err(e)),
-            })
-            .collect()
+        counters_that_apply(
+            &limits,
+            &ctx.at(self.storage.clock().now()),
+            self.on_error.for_namespace(namespace),
+            self.hasher.as_ref(),
+        )
     }
 }
 
@@ -525,9 +712,7 @@ impl RateLimiter {
 
 impl AsyncRateLimiter {
     pub fn new_with_storage(storage: Box<dyn AsyncCounterStorage>) -> Self {
-        Self {
-            storage: AsyncStorage::with_counter_storage(storage),
-        }
+        AsyncRateLimiterBuilder::new(AsyncStorage::with_counter_storage(storage)).build()
     }
 
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
@@ -571,11 +756,13 @@ impl AsyncRateLimiter {
                     limited: false,
                     counters: Vec::default(),
                     limit_name: None,
+                    explanation: None,
                 }),
                 Authorization::Limited(name) => Ok(CheckResult {
                     limited: true,
                     counters: Vec::default(),
                     limit_name: name,
+                    explanation: None,
                 }),
             },
         }
@@ -635,6 +822,7 @@ impl AsyncRateLimiter {
                 limited: false,
                 counters,
                 limit_name: None,
+                explanation: None,
             });
         }
 
@@ -654,15 +842,51 @@ impl AsyncRateLimiter {
                 limited: false,
                 counters,
                 limit_name: None,
+                explanation: None,
             }),
             Authorization::Limited(name) => Ok(CheckResult {
                 limited: true,
                 counters,
                 limit_name: name,
+                explanation: None,
             }),
         }
     }
 
+    /// Does what [`AsyncRateLimiter::check_rate_limited_and_update`] does,
+    /// loading the counters, and explains how each limit of the namespace took
+    /// part in the decision. See
+    /// [`RateLimiter::explain_rate_limited_and_update`].
+    pub async fn explain_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        let limits = self.storage.get_limits(namespace);
+        let on_error = self.on_error.for_namespace(namespace);
+        let ctx = &ctx.at(self.storage.clock().now());
+        let mut counters = counters_that_apply(&limits, ctx, on_error, self.hasher.as_ref())?;
+
+        let authorization = if counters.is_empty() {
+            Authorization::Ok
+        } else {
+            self.storage
+                .check_and_update(&mut counters, delta, true)
+                .await?
+        };
+
+        let explanation = Explanation::new(
+            &limits,
+            ctx,
+            on_error,
+            self.hasher.as_ref(),
+            &counters,
+            &authorization,
+        );
+        Ok(explained(authorization, counters, explanation))
+    }
+
     pub async fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>> {
         self.storage
             .get_counters(namespace)
@@ -670,6 +894,40 @@ impl AsyncRateLimiter {
             .map_err(|err| err.into())
     }
 
+    /// The number of qualified counters `limit` currently has.
+    pub async fn cardinality(&self, limit: &Limit) -> LimitadorResult<u64> {
+        self.storage
+            .cardinality(limit)
+            .await
+            .map_err(|err| err.into())
+    }
+
+    /// Dumps the live counters of the limits of `namespace`, see
+    /// [`AsyncStorage::export`].
+    pub async fn export_counters(
+        &self,
+        namespace: &Namespace,
+    ) -> LimitadorResult<Vec<CounterDump>> {
+        self.storage
+            .export(namespace)
+            .await
+            .map_err(|err| err.into())
+    }
+
+    /// Dumps the live counters of `limit`, see [`AsyncStorage::export_limit`].
+    pub async fn export_limit_counters(&self, limit: &Limit) -> LimitadorResult<Vec<CounterDump>> {
+        self.storage
+            .export_limit(limit)
+            .await
+            .map_err(|err| err.into())
+    }
+
+    /// Sets the counters of the limits configured to the ones dumped, see
+    /// [`AsyncStorage::import`]. Returns how many got imported.
+    pub async fn import_counters(&self, dumps: Vec<CounterDump>) -> LimitadorResult<u64> {
+        self.storage.import(dumps).await.map_err(|err| err.into())
+    }
+
     // Deletes all the limits stored except the ones received in the params. For
     // every limit received, if it does not exist, it is created. If it already
     // exists, its associated counters are not reset.
@@ -714,15 +972,292 @@ impl AsyncRateLimiter {
         ctx: &Context<'_>,
     ) -> LimitadorResult<Vec<Counter>> {
         let limits = self.storage.get_limits(namespace);
-        limits
-            .iter()
-            .filter(|lim| lim.applies(ctx))
-            .filter_map(|lim| match Counter::new(Arc::clone(lim), ctx) {
-                Ok(None) => None,
-                Ok(Some(c)) => Some(Ok(c)),
-                Err(e) => Some(Err(e)),
-            })
-            .collect()
+        counters_that_apply(
+            &limits,
+            &ctx.at(self.storage.clock().now()),
+            self.on_error.for_namespace(namespace),
+            self.hasher.as_ref(),
+        )
+    }
+}
+
+/// The operations shared by [`RateLimiter`] and [`AsyncRateLimiter`].
+///
+/// [`RateLimiter`] calls into its storage inline, which is fine for storages
+/// that don't perform I/O. Those that do should be wrapped in a
+/// [`storage::blocking::BlockingStorageAdapter`] and used through an
+/// [`AsyncRateLimiter`] instead.
+#[async_trait]
+pub trait Limiter: Send + Sync {
+    fn get_namespaces(&self) -> HashSet<Namespace>;
+
+    fn add_limit(&self, limit: Limit) -> bool;
+
+    async fn delete_limit(&self, limit: &Limit) -> LimitadorResult<()>;
+
+    fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit>;
+
+    async fn delete_limits(&self, namespace: &Namespace) -> LimitadorResult<()>;
+
+    async fn is_rate_limited(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult>;
+
+    async fn update_counters(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<()>;
+
+    async fn check_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+        load_counters: bool,
+    ) -> LimitadorResult<CheckResult>;
+
+    async fn explain_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult>;
+
+    async fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>>;
+
+    async fn cardinality(&self, limit: &Limit) -> LimitadorResult<u64>;
+
+    async fn export_counters(&self, namespace: &Namespace) -> LimitadorResult<Vec<CounterDump>>;
+
+    async fn export_limit_counters(&self, limit: &Limit) -> LimitadorResult<Vec<CounterDump>>;
+
+    async fn import_counters(&self, dumps: Vec<CounterDump>) -> LimitadorResult<u64>;
+
+    async fn configure_with(&self, limits: Vec<Limit>) -> LimitadorResult<()>;
+}
+
+#[async_trait]
+impl Limiter for RateLimiter {
+    fn get_namespaces(&self) -> HashSet<Namespace> {
+        RateLimiter::get_namespaces(self)
+    }
+
+    fn add_limit(&self, limit: Limit) -> bool {
+        RateLimiter::add_limit(self, limit)
+    }
+
+    async fn delete_limit(&self, limit: &Limit) -> LimitadorResult<()> {
+        RateLimiter::delete_limit(self, limit)
+    }
+
+    fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit> {
+        RateLimiter::get_limits(self, namespace)
+    }
+
+    async fn delete_limits(&self, namespace: &Namespace) -> LimitadorResult<()> {
+        RateLimiter::delete_limits(self, namespace)
+    }
+
+    async fn is_rate_limited(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        RateLimiter::is_rate_limited(self, namespace, ctx, delta)
+    }
+
+    async fn update_counters(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<()> {
+        RateLimiter::update_counters(self, namespace, ctx, delta)
+    }
+
+    async fn check_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+        load_counters: bool,
+    ) -> LimitadorResult<CheckResult> {
+        RateLimiter::check_rate_limited_and_update(self, namespace, ctx, delta, load_counters)
+    }
+
+    async fn explain_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        RateLimiter::explain_rate_limited_and_update(self, namespace, ctx, delta)
+    }
+
+    async fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>> {
+        RateLimiter::get_counters(self, namespace)
+    }
+
+    async fn cardinality(&self, limit: &Limit) -> LimitadorResult<u64> {
+        RateLimiter::cardinality(self, limit)
+    }
+
+    async fn export_counters(&self, namespace: &Namespace) -> LimitadorResult<Vec<CounterDump>> {
+        RateLimiter::export_counters(self, namespace)
+    }
+
+    async fn export_limit_counters(&self, limit: &Limit) -> LimitadorResult<Vec<CounterDump>> {
+        RateLimiter::export_limit_counters(self, limit)
+    }
+
+    async fn import_counters(&self, dumps: Vec<CounterDump>) -> LimitadorResult<u64> {
+        RateLimiter::import_counters(self, dumps)
+    }
+
+    async fn configure_with(&self, limits: Vec<Limit>) -> LimitadorResult<()> {
+        RateLimiter::configure_with(self, limits)
+    }
+}
+
+#[async_trait]
+impl Limiter for AsyncRateLimiter {
+    fn get_namespaces(&self) -> HashSet<Namespace> {
+        AsyncRateLimiter::get_namespaces(self)
+    }
+
+    fn add_limit(&self, limit: Limit) -> bool {
+        AsyncRateLimiter::add_limit(self, limit)
+    }
+
+    async fn delete_limit(&self, limit: &Limit) -> LimitadorResult<()> {
+        AsyncRateLimiter::delete_limit(self, limit).await
+    }
+
+    fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit> {
+        AsyncRateLimiter::get_limits(self, namespace)
+    }
+
+    async fn delete_limits(&self, namespace: &Namespace) -> LimitadorResult<()> {
+        AsyncRateLimiter::delete_limits(self, namespace).await
+    }
+
+    async fn is_rate_limited(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        AsyncRateLimiter::is_rate_limited(self, namespace, ctx, delta).await
+    }
+
+    async fn update_counters(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<()> {
+        AsyncRateLimiter::update_counters(self, namespace, ctx, delta).await
+    }
+
+    async fn check_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+        load_counters: bool,
+    ) -> LimitadorResult<CheckResult> {
+        AsyncRateLimiter::check_rate_limited_and_update(self, namespace, ctx, delta, load_counters)
+            .await
+    }
+
+    async fn explain_rate_limited_and_update(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        AsyncRateLimiter::explain_rate_limited_and_update(self, namespace, ctx, delta).await
+    }
+
+    async fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>> {
+        AsyncRateLimiter::get_counters(self, namespace).await
+    }
+
+    async fn cardinality(&self, limit: &Limit) -> LimitadorResult<u64> {
+        AsyncRateLimiter::cardinality(self, limit).await
+    }
+
+    async fn export_counters(&self, namespace: &Namespace) -> LimitadorResult<Vec<CounterDump>> {
+        AsyncRateLimiter::export_counters(self, namespace).await
+    }
+
+    async fn export_limit_counters(&self, limit: &Limit) -> LimitadorResult<Vec<CounterDump>> {
+        AsyncRateLimiter::export_limit_counters(self, limit).await
+    }
+
+    async fn import_counters(&self, dumps: Vec<CounterDump>) -> LimitadorResult<u64> {
+        AsyncRateLimiter::import_counters(self, dumps).await
+    }
+
+    async fn configure_with(&self, limits: Vec<Limit>) -> LimitadorResult<()> {
+        AsyncRateLimiter::configure_with(self, limits).await
+    }
+}
+
+fn counters_that_apply(
+    limits: &HashSet<Arc<Limit>>,
+    ctx: &Context,
+    on_error: OnEvaluationError,
+    hasher: Option<&VariableHasher>,
+) -> LimitadorResult<Vec<Counter>> {
+    let mut counters = Vec::new();
+    for limit in limits {
+        let failed = |source| LimitadorError::LimitEvaluationError {
+            limit: Arc::clone(limit),
+            source,
+        };
+        if !limit.applies(ctx, on_error).map_err(failed)? {
+            continue;
+        }
+        match Counter::new(Arc::clone(limit), ctx) {
+            Ok(Some(mut counter)) => {
+                if let Some(hasher) = hasher {
+                    counter.hash_variables(hasher);
+                }
+                counters.push(counter)
+            }
+            Ok(None) => {}
+            Err(LimitadorError::InterpreterError(err)) => {
+                if limit.on_error().unwrap_or(on_error) != OnEvaluationError::Skip {
+                    return Err(failed(err));
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(counters)
+}
+
+fn explained(
+    authorization: Authorization,
+    counters: Vec<Counter>,
+    explanation: Explanation,
+) -> CheckResult {
+    let (limited, limit_name) = match authorization {
+        Authorization::Ok => (false, None),
+        Authorization::Limited(name) => (true, name),
+    };
+    CheckResult {
+        limited,
+        counters,
+        limit_name,
+        explanation: Some(explanation),
     }
 }
 
@@ -749,9 +1284,16 @@ fn classify_limits_by_namespace(
 
 #[cfg(test)]
 mod test {
-    use crate::limit::{Context, Expression, Limit};
-    use crate::RateLimiter;
-    use std::collections::HashMap;
+    use crate::clock::ManualClock;
+    use crate::counter::VariableHasher;
+    use crate::errors::LimitadorError;
+    use crate::explain::{Decision, Explanation, Outcome};
+    use crate::limit::{Context, Expression, Limit, OnEvaluationError};
+    use crate::storage::Authorization;
+    use crate::{RateLimiter, RateLimiterBuilder};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn properly_updates_existing_limits() {
@@ -811,4 +1353,236 @@ mod test {
             .unwrap();
         assert_eq!(r.counters.first().unwrap().remaining(), Some(41));
     }
+
+    #[test]
+    fn tells_the_time_of_day_with_the_clock_of_the_storage() {
+        let clock = Arc::new(ManualClock::new(
+            UNIX_EPOCH + Duration::from_secs(11 * 60 * 60),
+        ));
+        let rl = RateLimiterBuilder::with_clock(100, clock.clone()).build();
+        let namespace = "foo";
+        rl.add_limit(Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["timeOfDay() < duration('12h')"
+                .try_into()
+                .expect("failed parsing!")],
+            Vec::<Expression>::default(),
+        ));
+
+        let check = || {
+            rl.check_rate_limited_and_update(&namespace.into(), &Context::default(), 1, false)
+                .unwrap()
+                .limited
+        };
+        assert!(!check());
+        assert!(check());
+
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+        assert!(!check());
+        assert!(!check());
+    }
+
+    #[test]
+    fn explains_decisions() {
+        let rl = RateLimiter::new(100);
+        let namespace = "foo";
+
+        let mut per_user = Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["method == 'GET'".try_into().unwrap()],
+            vec![Expression::parse("user").unwrap()],
+        );
+        per_user.set_name("per_user".to_string());
+        rl.add_limit(per_user);
+        rl.add_limit(Limit::new(
+            namespace,
+            10,
+            60,
+            vec!["method == 'POST'".try_into().unwrap()],
+            Vec::<Expression>::default(),
+        ));
+        rl.add_limit(Limit::new(
+            namespace,
+            10,
+            60,
+            vec![],
+            vec![Expression::parse("tenant").unwrap()],
+        ));
+
+        let ctx = Context::from(HashMap::from([
+            ("method".to_string(), "GET".to_string()),
+            ("user".to_string(), "alice".to_string()),
+        ]));
+
+        let r = rl
+            .explain_rate_limited_and_update(&namespace.into(), &ctx, 1)
+            .unwrap();
+        assert!(!r.limited);
+        let explanation = r.explanation.unwrap();
+        assert_eq!(explanation.decision, Decision::WithinLimits);
+        let outcome_of = |max_value: u64, seconds: u64, condition: Option<&str>| {
+            explanation
+                .limits
+                .iter()
+                .find(|l| {
+                    l.max_value == max_value
+                        && l.seconds == seconds
+                        && l.conditions.first().map(|c| c.condition.as_str()) == condition
+                })
+                .unwrap()
+                .clone()
+        };
+        let get = outcome_of(1, 60, Some("method == 'GET'"));
+        assert_eq!(get.outcome, Outcome::WithinLimits);
+        assert_eq!(get.conditions[0].matched, Some(true));
+        assert_eq!(get.variables["user"], Some("alice".to_string()));
+        assert_eq!(get.counter.as_ref().unwrap().value, Some(1));
+        assert_eq!(get.counter.as_ref().unwrap().remaining, Some(0));
+        let post = outcome_of(10, 60, Some("method == 'POST'"));
+        assert_eq!(post.outcome, Outcome::ConditionsNotMet);
+        assert_eq!(post.conditions[0].matched, Some(false));
+        assert!(post.counter.is_none());
+        let per_tenant = outcome_of(10, 60, None);
+        assert_eq!(per_tenant.outcome, Outcome::VariablesNotSet);
+        assert_eq!(per_tenant.variables["tenant"], None);
+
+        let r = rl
+            .explain_rate_limited_and_update(&namespace.into(), &ctx, 1)
+            .unwrap();
+        assert!(r.limited);
+        let explanation = r.explanation.unwrap();
+        assert_eq!(
+            explanation.decision,
+            Decision::Limited {
+                limit_name: Some("per_user".to_string())
+            }
+        );
+        assert!(explanation
+            .limits
+            .iter()
+            .any(|l| l.name.as_deref() == Some("per_user") && l.outcome == Outcome::Limited));
+
+        let r = rl
+            .explain_rate_limited_and_update(&"bar".into(), &ctx, 1)
+            .unwrap();
+        assert_eq!(r.explanation.unwrap().decision, Decision::NoLimitApplies);
+    }
+
+    #[test]
+    fn explains_decisions_with_the_variables_hashed() {
+        let hasher = VariableHasher::new("secret");
+        let rl = RateLimiterBuilder::new(100)
+            .hash_variables(hasher.clone())
+            .build();
+        rl.add_limit(Limit::new(
+            "foo",
+            10,
+            60,
+            vec![],
+            vec![Expression::parse("user").unwrap()],
+        ));
+        let ctx = Context::from(HashMap::from([(
+            "user".to_string(),
+            "alice@example.com".to_string(),
+        )]));
+
+        let explanation = rl
+            .explain_rate_limited_and_update(&"foo".into(), &ctx, 1)
+            .unwrap()
+            .explanation
+            .unwrap();
+        let limit = &explanation.limits[0];
+        assert_eq!(
+            limit.variables["user"],
+            Some(hasher.hash("alice@example.com"))
+        );
+        assert!(!limit.counter.as_ref().unwrap().key.contains("alice"));
+        assert!(!serde_json::to_string(&explanation)
+            .unwrap()
+            .contains("alice"));
+    }
+
+    #[test]
+    fn explains_variable_errors_as_per_the_policy() {
+        let ctx: Context = HashMap::from([("x".to_string(), "one".to_string())]).into();
+        for (policy, outcome) in [
+            (OnEvaluationError::Skip, Outcome::Skipped),
+            (OnEvaluationError::Apply, Outcome::Failed),
+            (OnEvaluationError::Fail, Outcome::Failed),
+        ] {
+            let rl = RateLimiterBuilder::new(100)
+                .on_evaluation_error(policy)
+                .build();
+            let limit = Limit::new(
+                "foo",
+                10,
+                60,
+                vec![],
+                vec![Expression::parse("int(x)").unwrap()],
+            );
+            rl.add_limit(limit.clone());
+
+            let explained = rl.explain_rate_limited_and_update(&"foo".into(), &ctx, 1);
+            if outcome == Outcome::Skipped {
+                let explanation = explained.unwrap().explanation.unwrap();
+                assert_eq!(explanation.limits[0].outcome, Outcome::Skipped);
+            } else {
+                assert!(matches!(
+                    explained,
+                    Err(LimitadorError::LimitEvaluationError { .. })
+                ));
+            }
+
+            let limits = HashSet::from([Arc::new(limit)]);
+            let explanation =
+                Explanation::new(&limits, &ctx, policy, None, &[], &Authorization::Ok);
+            assert_eq!(explanation.limits[0].outcome, outcome);
+        }
+    }
+
+    #[test]
+    fn limits_without_a_policy_follow_the_one_of_their_namespace() {
+        let rl = RateLimiterBuilder::new(100)
+            .on_evaluation_error(OnEvaluationError::Skip)
+            .namespace_on_evaluation_error("applied".into(), OnEvaluationError::Apply)
+            .build();
+        for namespace in ["applied", "skipped"] {
+            let limit = Limit::new(
+                namespace,
+                0,
+                60,
+                vec!["int(x) + 1 == 2".try_into().expect("failed parsing!")],
+                Vec::<Expression>::default(),
+            );
+            rl.add_limit(limit);
+        }
+        let ctx: Context = HashMap::from([("x".to_string(), "one".to_string())]).into();
+
+        let applied = rl
+            .check_rate_limited_and_update(&"applied".into(), &ctx, 1, false)
+            .unwrap();
+        assert!(applied.limited);
+        let skipped = rl
+            .check_rate_limited_and_update(&"skipped".into(), &ctx, 1, false)
+            .unwrap();
+        assert!(!skipped.limited);
+
+        let mut failing = Limit::new(
+            "applied",
+            10,
+            60,
+            vec!["int(x) == 1".try_into().expect("failed parsing!")],
+            Vec::<Expression>::default(),
+        );
+        failing.set_on_error(OnEvaluationError::Fail);
+        rl.add_limit(failing);
+        assert!(matches!(
+            rl.check_rate_limited_and_update(&"applied".into(), &ctx, 1, false),
+            Err(LimitadorError::LimitEvaluationError { .. })
+        ));
+    }
 }
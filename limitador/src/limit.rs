@@ -1,3 +1,4 @@
+use metrics::counter;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet, HashSet};
@@ -5,8 +6,9 @@ use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 
 mod cel;
+pub mod lint;
 
-pub use cel::{Context, Expression, Predicate};
+pub use cel::{Context, ContextValue, Expression, Predicate};
 pub use cel::{EvaluationError, ParseError};
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, PartialOrd, Ord, Serialize, Deserialize)]
@@ -30,6 +32,37 @@ impl From<String> for Namespace {
     }
 }
 
+/// What to do with a limit when one of its conditions or variables fails to
+/// evaluate, e.g. on a type mismatch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnEvaluationError {
+    /// The limit doesn't apply to the request.
+    Skip,
+    /// The failing condition is considered to hold. A failing variable can't
+    /// qualify a counter, so for variables this behaves as `Fail`.
+    Apply,
+    /// The request fails with a
+    /// [`LimitadorError::LimitEvaluationError`](crate::errors::LimitadorError::LimitEvaluationError).
+    #[default]
+    Fail,
+}
+
+/// What to do with a request that would create a qualified counter for a limit
+/// that already has `max_counters` of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnTooManyCounters {
+    /// The request is rate limited.
+    #[default]
+    Deny,
+    /// The request isn't counted against the limit.
+    AllowUntracked,
+    /// The request is counted against a single counter shared by all the ones
+    /// over the cap, which takes one of the `max_counters` slots.
+    Overflow,
+}
+
 #[derive(Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct Limit {
     #[serde(skip_serializing, default)]
@@ -40,6 +73,12 @@ pub struct Limit {
     seconds: u64,
     #[serde(skip_serializing, default)]
     name: Option<String>,
+    #[serde(skip_serializing, default)]
+    on_error: Option<OnEvaluationError>,
+    #[serde(skip_serializing, default)]
+    max_counters: Option<u64>,
+    #[serde(skip_serializing, default)]
+    on_too_many_counters: OnTooManyCounters,
 
     // Need to sort to generate the same object when using the JSON as a key or
     // value in Redis.
@@ -64,6 +103,9 @@ impl Limit {
             max_value,
             seconds,
             name: None,
+            on_error: None,
+            max_counters: None,
+            on_too_many_counters: OnTooManyCounters::default(),
             conditions: conditions.into_iter().collect(),
             variables: variables.into_iter().collect(),
         }
@@ -83,6 +125,9 @@ impl Limit {
             max_value,
             seconds,
             name: None,
+            on_error: None,
+            max_counters: None,
+            on_too_many_counters: OnTooManyCounters::default(),
             conditions: conditions.into_iter().collect(),
             variables: variables.into_iter().collect(),
         }
@@ -116,6 +161,34 @@ impl Limit {
         self.max_value = value;
     }
 
+    /// The policy of this limit on evaluation errors, if it has its own.
+    pub fn on_error(&self) -> Option<OnEvaluationError> {
+        self.on_error
+    }
+
+    pub fn set_on_error(&mut self, policy: OnEvaluationError) {
+        self.on_error = Some(policy)
+    }
+
+    /// The maximum number of qualified counters this limit keeps track of at
+    /// any time, if capped. The storages check the cap and create the counters
+    /// atomically, see [`CounterStorage::admit_counter`](crate::storage::CounterStorage::admit_counter).
+    pub fn max_counters(&self) -> Option<u64> {
+        self.max_counters
+    }
+
+    pub fn set_max_counters(&mut self, max: u64) {
+        self.max_counters = Some(max)
+    }
+
+    pub fn on_too_many_counters(&self) -> OnTooManyCounters {
+        self.on_too_many_counters
+    }
+
+    pub fn set_on_too_many_counters(&mut self, policy: OnTooManyCounters) {
+        self.on_too_many_counters = policy
+    }
+
     pub fn conditions(&self) -> HashSet<String> {
         self.conditions
             .iter()
@@ -137,7 +210,10 @@ impl Limit {
         let mut map = BTreeMap::new();
         for variable in &self.variables {
             let name = variable.source().into();
-            match variable.eval(ctx)? {
+            match variable
+                .eval_string(ctx)
+                .inspect_err(|_| self.record_error())?
+            {
                 None => return Ok(None),
                 Some(value) => {
                     map.insert(name, value);
@@ -154,12 +230,29 @@ impl Limit {
             .any(|v| v.as_str() == var)
     }
 
-    pub fn applies(&self, ctx: &Context) -> bool {
+    /// Whether the limit applies to the request described by `ctx`. Conditions
+    /// failing to evaluate are handled as per the limit's own policy, if it has
+    /// one, or `on_error` otherwise.
+    pub fn applies(
+        &self,
+        ctx: &Context,
+        on_error: OnEvaluationError,
+    ) -> Result<bool, EvaluationError> {
         let ctx = ctx.for_limit(self);
-        let all_conditions_apply = self
-            .conditions
-            .iter()
-            .all(|predicate| predicate.test(&ctx.for_limit(self)).unwrap());
+        for predicate in &self.conditions {
+            match predicate.test(&ctx) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(err) => {
+                    self.record_error();
+                    match self.on_error.unwrap_or(on_error) {
+                        OnEvaluationError::Skip => return Ok(false),
+                        OnEvaluationError::Apply => {}
+                        OnEvaluationError::Fail => return Err(err),
+                    }
+                }
+            }
+        }
 
         let all_vars_are_set = self.variables.iter().all(|var| {
             ctx.has_variables(
@@ -170,7 +263,50 @@ impl Limit {
             )
         });
 
-        all_conditions_apply && all_vars_are_set
+        Ok(all_vars_are_set)
+    }
+
+    /// Tests every condition against `ctx`, where [`Limit::applies`] stops at
+    /// the first one not holding.
+    pub(crate) fn test_conditions(
+        &self,
+        ctx: &Context,
+    ) -> Vec<(String, Result<bool, EvaluationError>)> {
+        let ctx = ctx.for_limit(self);
+        self.conditions
+            .iter()
+            .map(|predicate| (predicate.clone().into(), predicate.test(&ctx)))
+            .collect()
+    }
+
+    /// Evaluates every variable against `ctx`, where
+    /// [`Limit::resolve_variables`] stops at the first one not set.
+    pub(crate) fn eval_variables(
+        &self,
+        ctx: &Context,
+    ) -> Vec<(String, Result<Option<String>, EvaluationError>)> {
+        let limit_ctx = ctx.for_limit(self);
+        self.variables
+            .iter()
+            .map(|variable| {
+                let names = variable.variables();
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                let value = if limit_ctx.has_variables(&names) {
+                    variable.eval_string(ctx)
+                } else {
+                    Ok(None)
+                };
+                (variable.source().into(), value)
+            })
+            .collect()
+    }
+
+    fn record_error(&self) {
+        counter!(
+            "limit_evaluation_errors",
+            "limitador_namespace" => self.namespace.0.clone()
+        )
+        .increment(1);
     }
 }
 
@@ -250,7 +386,9 @@ mod tests {
         values.insert("x".into(), "5".into());
         values.insert("y".into(), "1".into());
 
-        assert!(limit.applies(&values.into()))
+        assert!(limit
+            .applies(&values.into(), OnEvaluationError::Fail)
+            .unwrap())
     }
 
     #[test]
@@ -267,7 +405,9 @@ mod tests {
         values.insert("x".into(), "1".into());
         values.insert("y".into(), "1".into());
 
-        assert!(!limit.applies(&values.into()))
+        assert!(!limit
+            .applies(&values.into(), OnEvaluationError::Fail)
+            .unwrap())
     }
 
     #[test]
@@ -285,7 +425,9 @@ mod tests {
         values.insert("a".into(), "1".into());
         values.insert("y".into(), "1".into());
 
-        assert!(!limit.applies(&values.into()))
+        assert!(!limit
+            .applies(&values.into(), OnEvaluationError::Fail)
+            .unwrap())
     }
 
     #[test]
@@ -302,7 +444,9 @@ mod tests {
         let mut values: HashMap<String, String> = HashMap::new();
         values.insert("x".into(), "5".into());
 
-        assert!(!limit.applies(&values.into()))
+        assert!(!limit
+            .applies(&values.into(), OnEvaluationError::Fail)
+            .unwrap())
     }
 
     #[test]
@@ -323,7 +467,9 @@ mod tests {
         values.insert("y".into(), "2".into());
         values.insert("z".into(), "1".into());
 
-        assert!(limit.applies(&values.into()))
+        assert!(limit
+            .applies(&values.into(), OnEvaluationError::Fail)
+            .unwrap())
     }
 
     #[test]
@@ -344,7 +490,9 @@ mod tests {
         values.insert("y".into(), "2".into());
         values.insert("z".into(), "1".into());
 
-        assert!(!limit.applies(&values.into()))
+        assert!(!limit
+            .applies(&values.into(), OnEvaluationError::Fail)
+            .unwrap())
     }
 
     #[test]
@@ -408,10 +556,14 @@ mod tests {
                 .expect("failed parsing!")],
             Vec::default(),
         );
-        assert!(!limit.applies(&Context::default()));
+        assert!(!limit
+            .applies(&Context::default(), OnEvaluationError::Fail)
+            .unwrap());
 
         limit.set_name("named_limit".to_string());
-        assert!(limit.applies(&Context::default()));
+        assert!(limit
+            .applies(&Context::default(), OnEvaluationError::Fail)
+            .unwrap());
 
         let limit = Limit::with_id(
             "my_id",
@@ -424,7 +576,9 @@ mod tests {
             ],
             Vec::default(),
         );
-        assert!(limit.applies(&Context::default()));
+        assert!(limit
+            .applies(&Context::default(), OnEvaluationError::Fail)
+            .unwrap());
 
         let limit = Limit::with_id(
             "my_id",
@@ -436,7 +590,35 @@ mod tests {
                 .expect("failed parsing!")],
             Vec::default(),
         );
-        assert!(!limit.applies(&Context::default()));
+        assert!(!limit
+            .applies(&Context::default(), OnEvaluationError::Fail)
+            .unwrap());
+    }
+
+    #[test]
+    fn evaluation_errors_follow_the_policy() {
+        let mut limit = Limit::new(
+            "ns",
+            42,
+            10,
+            vec![
+                "x + 1 == 2".try_into().expect("failed parsing!"),
+                "y == '1'".try_into().expect("failed parsing!"),
+            ],
+            Vec::default(),
+        );
+        let ctx: Context = HashMap::from([
+            ("x".to_string(), "1".to_string()),
+            ("y".to_string(), "1".to_string()),
+        ])
+        .into();
+
+        assert_eq!(limit.applies(&ctx, OnEvaluationError::Skip), Ok(false));
+        assert_eq!(limit.applies(&ctx, OnEvaluationError::Apply), Ok(true));
+        assert!(limit.applies(&ctx, OnEvaluationError::Fail).is_err());
+
+        limit.set_on_error(OnEvaluationError::Skip);
+        assert_eq!(limit.applies(&ctx, OnEvaluationError::Fail), Ok(false));
     }
 
     #[test]
@@ -453,7 +635,7 @@ mod tests {
             ("bar".to_string(), "foo,baz".to_string()),
         ]);
         let ctx = map.into();
-        assert!(limit.applies(&ctx));
+        assert!(limit.applies(&ctx, OnEvaluationError::Fail).unwrap());
         assert_eq!(
             Counter::new(limit, &ctx)
                 .expect("failed")
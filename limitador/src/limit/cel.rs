@@ -1,12 +1,17 @@
 use crate::limit::Limit;
 use cel::objects::Key;
 use cel::{ExecutionError, Value};
+use chrono::{DateTime, FixedOffset, Utc};
 pub use errors::{EvaluationError, ParseError};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+
+mod functions;
 
 pub(super) mod errors {
     use cel::ExecutionError;
@@ -52,6 +57,13 @@ pub(super) mod errors {
                 source: Box::new(source),
             }
         }
+
+        pub fn invalid(reason: String, input: String) -> Self {
+            Self {
+                input,
+                source: reason.into(),
+            }
+        }
     }
 
     impl Display for ParseError {
@@ -73,40 +85,261 @@ pub(super) mod errors {
     }
 }
 
+/// A typed value that can be bound in a [`Context`], and that [`Expression`]s
+/// evaluate to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContextValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Double(f64),
+    String(String),
+    Timestamp(DateTime<FixedOffset>),
+    List(Vec<ContextValue>),
+    Map(BTreeMap<String, ContextValue>),
+}
+
+impl Display for ContextValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextValue::Null => write!(f, "null"),
+            ContextValue::Bool(b) => write!(f, "{b}"),
+            ContextValue::Int(i) => write!(f, "{i}"),
+            ContextValue::UInt(u) => write!(f, "{u}"),
+            ContextValue::Double(d) => write!(f, "{d}"),
+            ContextValue::String(s) => write!(f, "{s}"),
+            ContextValue::Timestamp(ts) => write!(f, "{}", ts.to_rfc3339()),
+            ContextValue::List(list) => {
+                write!(f, "[")?;
+                for (i, value) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            }
+            ContextValue::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key}: {value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<ContextValue> for Value {
+    fn from(value: ContextValue) -> Self {
+        match value {
+            ContextValue::Null => Value::Null,
+            ContextValue::Bool(b) => Value::Bool(b),
+            ContextValue::Int(i) => Value::Int(i),
+            ContextValue::UInt(u) => Value::UInt(u),
+            ContextValue::Double(d) => Value::Float(d),
+            ContextValue::String(s) => Value::String(Arc::new(s)),
+            ContextValue::Timestamp(ts) => Value::Timestamp(ts),
+            ContextValue::List(list) => {
+                Value::List(list.into_iter().map(Value::from).collect::<Vec<_>>().into())
+            }
+            ContextValue::Map(map) => Value::Map(cel::objects::Map::from(
+                map.into_iter()
+                    .map(|(k, v)| (k, Value::from(v)))
+                    .collect::<HashMap<_, _>>(),
+            )),
+        }
+    }
+}
+
+impl TryFrom<Value> for ContextValue {
+    type Error = EvaluationError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Null => Ok(ContextValue::Null),
+            Value::Bool(b) => Ok(ContextValue::Bool(b)),
+            Value::Int(i) => Ok(ContextValue::Int(i)),
+            Value::UInt(u) => Ok(ContextValue::UInt(u)),
+            Value::Float(f) => Ok(ContextValue::Double(f)),
+            Value::String(s) => Ok(ContextValue::String(s.to_string())),
+            Value::Timestamp(ts) => Ok(ContextValue::Timestamp(ts)),
+            Value::List(list) => list
+                .iter()
+                .cloned()
+                .map(ContextValue::try_from)
+                .collect::<Result<_, _>>()
+                .map(ContextValue::List),
+            Value::Map(map) => map
+                .map
+                .iter()
+                .map(|(k, v)| {
+                    let key = match k {
+                        Key::String(s) => s.to_string(),
+                        Key::Int(i) => i.to_string(),
+                        Key::Uint(u) => u.to_string(),
+                        Key::Bool(b) => b.to_string(),
+                    };
+                    ContextValue::try_from(v.clone()).map(|v| (key, v))
+                })
+                .collect::<Result<_, _>>()
+                .map(ContextValue::Map),
+            val => Err(err_on_value(val)),
+        }
+    }
+}
+
+impl From<bool> for ContextValue {
+    fn from(value: bool) -> Self {
+        ContextValue::Bool(value)
+    }
+}
+
+impl From<i64> for ContextValue {
+    fn from(value: i64) -> Self {
+        ContextValue::Int(value)
+    }
+}
+
+impl From<u64> for ContextValue {
+    fn from(value: u64) -> Self {
+        ContextValue::UInt(value)
+    }
+}
+
+impl From<f64> for ContextValue {
+    fn from(value: f64) -> Self {
+        ContextValue::Double(value)
+    }
+}
+
+impl From<String> for ContextValue {
+    fn from(value: String) -> Self {
+        ContextValue::String(value)
+    }
+}
+
+impl From<&str> for ContextValue {
+    fn from(value: &str) -> Self {
+        ContextValue::String(value.to_string())
+    }
+}
+
+impl From<DateTime<FixedOffset>> for ContextValue {
+    fn from(value: DateTime<FixedOffset>) -> Self {
+        ContextValue::Timestamp(value)
+    }
+}
+
+impl From<SystemTime> for ContextValue {
+    fn from(value: SystemTime) -> Self {
+        ContextValue::Timestamp(DateTime::<Utc>::from(value).fixed_offset())
+    }
+}
+
+impl<T: Into<ContextValue>> From<Vec<T>> for ContextValue {
+    fn from(value: Vec<T>) -> Self {
+        ContextValue::List(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<ContextValue>> From<HashMap<String, T>> for ContextValue {
+    fn from(value: HashMap<String, T>) -> Self {
+        ContextValue::Map(value.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+impl<T: Into<ContextValue>> From<BTreeMap<String, T>> for ContextValue {
+    fn from(value: BTreeMap<String, T>) -> Self {
+        ContextValue::Map(value.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+impl From<serde_json::Value> for ContextValue {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => ContextValue::Null,
+            serde_json::Value::Bool(b) => ContextValue::Bool(b),
+            serde_json::Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    ContextValue::Int(i)
+                } else if let Some(u) = n.as_u64() {
+                    ContextValue::UInt(u)
+                } else {
+                    ContextValue::Double(n.as_f64().unwrap_or(f64::NAN))
+                }
+            }
+            serde_json::Value::String(s) => ContextValue::String(s),
+            serde_json::Value::Array(list) => list.into(),
+            serde_json::Value::Object(map) => ContextValue::Map(
+                map.into_iter()
+                    .map(|(k, v)| (k, ContextValue::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 pub struct Context<'a> {
     variables: HashSet<String>,
     ctx: cel::Context<'a>,
 }
 
+// The standard definitions and our functions, shared by every context
+static BASE: LazyLock<cel::Context<'static>> = LazyLock::new(|| {
+    let mut ctx = cel::Context::default();
+    functions::register(&mut ctx);
+    ctx
+});
+
 impl<'a> Context<'a> {
-    pub(crate) fn new(root: String, values: HashMap<String, String>) -> Self {
-        let mut ctx = cel::Context::default();
+    pub(crate) fn new<V: Into<ContextValue>>(root: String, values: HashMap<String, V>) -> Self {
+        let mut ctx = BASE.new_inner_scope();
         let mut variables = HashSet::new();
 
         if root.is_empty() {
             for (binding, value) in values {
-                ctx.add_variable_from_value(binding.clone(), value.clone());
+                ctx.add_variable_from_value(binding.clone(), Value::from(value.into()));
                 variables.insert(binding);
             }
         } else {
-            let map = cel::objects::Map::from(values.clone());
-            ctx.add_variable_from_value(root, Value::Map(map));
+            ctx.add_variable_from_value(root, Value::from(ContextValue::from(values)));
         }
 
         Self { variables, ctx }
     }
 
-    pub fn list_binding(&mut self, name: String, value: Vec<HashMap<String, String>>) {
-        let v = value
-            .iter()
-            .map(|values| {
-                let map = cel::objects::Map::from(values.clone());
-                Value::Map(map)
-            })
-            .collect::<Vec<_>>();
+    /// Binds `value` to the variable `name`.
+    pub fn value_binding(&mut self, name: String, value: impl Into<ContextValue>) {
         self.variables.insert(name.clone());
         self.ctx
-            .add_variable_from_value(name, Value::List(v.into()));
+            .add_variable_from_value(name, Value::from(value.into()));
+    }
+
+    pub fn list_binding<V: Into<ContextValue>>(
+        &mut self,
+        name: String,
+        value: Vec<HashMap<String, V>>,
+    ) {
+        self.value_binding(name, value)
+    }
+
+    /// The same context, with `timeOfDay()` telling the time as of `now`
+    /// rather than the system's.
+    pub(crate) fn at<'b>(&'b self, now: SystemTime) -> Self
+    where
+        'b: 'a,
+    {
+        let mut inner = self.ctx.new_inner_scope();
+        inner.add_variable_from_value(functions::NOW, Value::from(ContextValue::from(now)));
+        Self {
+            variables: self.variables.clone(),
+            ctx: inner,
+        }
     }
 
     pub(crate) fn for_limit<'b>(&'b self, limit: &Limit) -> Self
@@ -146,12 +379,15 @@ impl<'a> Context<'a> {
 
 impl Default for Context<'_> {
     fn default() -> Self {
-        Self::new(String::default(), HashMap::default())
+        Self::new(
+            String::default(),
+            HashMap::<String, ContextValue>::default(),
+        )
     }
 }
 
-impl From<HashMap<String, String>> for Context<'_> {
-    fn from(value: HashMap<String, String>) -> Self {
+impl<V: Into<ContextValue>> From<HashMap<String, V>> for Context<'_> {
+    fn from(value: HashMap<String, V>) -> Self {
         Self::new(String::default(), value)
     }
 }
@@ -168,12 +404,27 @@ impl Expression {
         let source = source.to_string();
         let parser = cel::parser::Parser::new();
         match parser.parse(&source) {
-            Ok(expression) => Ok(Self { source, expression }),
+            Ok(expression) => match functions::validate(&expression) {
+                Ok(()) => Ok(Self { source, expression }),
+                Err(reason) => Err(ParseError::invalid(reason, source)),
+            },
             Err(err) => Err(ParseError::from(err, source)),
         }
     }
 
-    pub fn eval(&self, ctx: &Context) -> Result<Option<String>, EvaluationError> {
+    /// Evaluates the expression, returning `None` when it refers to a key
+    /// missing from `ctx`.
+    pub fn eval(&self, ctx: &Context) -> Result<Option<ContextValue>, EvaluationError> {
+        match self.resolve(ctx) {
+            Ok(value) => ContextValue::try_from(value).map(Some),
+            Err(ExecutionError::NoSuchKey(_)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Evaluates the expression to the textual form of a scalar value, as used
+    /// for counter keys and metric labels.
+    pub fn eval_string(&self, ctx: &Context) -> Result<Option<String>, EvaluationError> {
         let result = self.resolve(ctx);
         match result {
             Ok(value) => match value {
@@ -216,6 +467,10 @@ impl Expression {
         self.source.as_str()
     }
 
+    pub(super) fn ast(&self) -> &cel::IdedExpr {
+        &self.expression
+    }
+
     pub fn variables(&self) -> Vec<String> {
         self.expression
             .references()
@@ -318,6 +573,10 @@ impl Predicate {
         })
     }
 
+    pub(super) fn expression(&self) -> &Expression {
+        &self.expression
+    }
+
     pub fn test(&self, ctx: &Context) -> Result<bool, EvaluationError> {
         if !self
             .variables
@@ -389,13 +648,32 @@ impl From<Predicate> for String {
 
 #[cfg(test)]
 mod tests {
-    use super::{Context, Expression, Predicate};
-    use std::collections::{HashMap, HashSet};
+    use super::{Context, ContextValue, Expression, Predicate};
+    use std::collections::HashMap;
 
     #[test]
     fn expression() {
         let exp = Expression::parse("100").expect("failed to parse");
-        assert_eq!(exp.eval(&ctx()), Ok(Some(String::from("100"))));
+        assert_eq!(exp.eval(&ctx()), Ok(Some(ContextValue::Int(100))));
+        assert_eq!(exp.eval_string(&ctx()), Ok(Some(String::from("100"))));
+    }
+
+    #[test]
+    fn expression_typed_values() {
+        let exp = Expression::parse("[size * 2, ratio > 0.5, tags]").expect("failed to parse");
+        let ctx = Context::from(HashMap::from([
+            ("size".to_string(), ContextValue::Int(21)),
+            ("ratio".to_string(), ContextValue::Double(0.75)),
+            ("tags".to_string(), vec!["a", "b"].into()),
+        ]));
+        assert_eq!(
+            exp.eval(&ctx),
+            Ok(Some(ContextValue::List(vec![
+                ContextValue::Int(42),
+                ContextValue::Bool(true),
+                ContextValue::List(vec!["a".into(), "b".into()]),
+            ])))
+        );
     }
 
     #[test]
@@ -411,7 +689,7 @@ mod tests {
     fn unexpected_value_type_expression() {
         let exp = Expression::parse("['100']").expect("failed to parse");
         assert_eq!(
-            exp.eval(&ctx()).map_err(|e| format!("{e}")),
+            exp.eval_string(&ctx()).map_err(|e| format!("{e}")),
             Err("unexpected value of type list: `[String(\"100\")]`".to_string())
         );
     }
@@ -461,10 +739,35 @@ mod tests {
         assert_eq!(pred.test(&ctx).map_err(|e| format!("{e}")), Ok(true));
     }
 
+    #[test]
+    fn supports_typed_bindings() {
+        let pred = Predicate::parse(
+            "req.size > 100 && req.secure && 'admin' in req.roles && req.at < timestamp('2024-01-01T00:00:00Z')",
+        )
+        .expect("failed to parse");
+        let mut ctx = Context::default();
+        ctx.value_binding(
+            "req".to_string(),
+            ContextValue::from(serde_json::json!({
+                "size": 101,
+                "secure": true,
+                "roles": ["user", "admin"],
+            })),
+        );
+        assert_eq!(pred.test(&ctx), Ok(false));
+
+        let mut values: HashMap<String, ContextValue> = HashMap::from([
+            ("size".to_string(), 101_i64.into()),
+            ("secure".to_string(), true.into()),
+            ("roles".to_string(), vec!["admin"].into()),
+        ]);
+        values.insert("at".to_string(), ContextValue::from(std::time::UNIX_EPOCH));
+        let mut ctx = Context::default();
+        ctx.value_binding("req".to_string(), values);
+        assert_eq!(pred.test(&ctx), Ok(true));
+    }
+
     fn ctx<'a>() -> Context<'a> {
-        Context {
-            variables: HashSet::default(),
-            ctx: cel::Context::default(),
-        }
+        Context::default()
     }
 }
@@ -0,0 +1,477 @@
+//! Functions available to every [`Predicate`](super::Predicate) and
+//! [`Expression`](super::Expression), on top of CEL's standard definitions.
+//!
+//! | Function                                  | Returns    |
+//! |-------------------------------------------|------------|
+//! | `inCidr(ip, cidr)`                        | `bool`     |
+//! | `regexCapture(value, pattern[, group])`   | `string`   |
+//! | `equalsIgnoreCase(a, b)`                  | `bool`     |
+//! | `hashBucket(value, buckets)`              | `int`      |
+//! | `jwtClaim(header, claim)`                 | any        |
+//! | `timeOfDay([offset])`                     | `duration` |
+//!
+//! These are only callable as global functions, e.g. `inCidr(source.ip, '10.0.0.0/8')`
+//! and not `source.ip.inCidr('10.0.0.0/8')`. Calls are checked when an expression
+//! is parsed: the number of arguments, the type of the literal ones and, for
+//! CIDR blocks, patterns and offsets given as literals, their syntax.
+
+use super::ContextValue;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use cel::common::ast::{EntryExpr, Expr};
+use cel::common::value::CelVal;
+use cel::extractors::Arguments;
+use cel::{ExecutionError, FunctionContext, IdedExpr, ResolveResult, Value};
+use chrono::{FixedOffset, NaiveTime, Utc};
+use moka::sync::Cache;
+use regex::Regex;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+
+/// The variable holding the time `timeOfDay()` tells, when not the system's.
+/// Not a valid identifier, so that no expression can refer to it.
+pub(super) const NOW: &str = "@now";
+
+// The patterns of `regexCapture`, compiled once
+static PATTERNS: LazyLock<Cache<Arc<String>, Regex>> = LazyLock::new(|| Cache::new(1_000));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    String,
+    Int,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::String => "string",
+            Kind::Int => "int",
+        }
+    }
+}
+
+struct Signature {
+    name: &'static str,
+    min_args: usize,
+    args: &'static [Kind],
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        name: "inCidr",
+        min_args: 2,
+        args: &[Kind::String, Kind::String],
+    },
+    Signature {
+        name: "regexCapture",
+        min_args: 2,
+        args: &[Kind::String, Kind::String, Kind::Int],
+    },
+    Signature {
+        name: "equalsIgnoreCase",
+        min_args: 2,
+        args: &[Kind::String, Kind::String],
+    },
+    Signature {
+        name: "hashBucket",
+        min_args: 2,
+        args: &[Kind::String, Kind::Int],
+    },
+    Signature {
+        name: "jwtClaim",
+        min_args: 2,
+        args: &[Kind::String, Kind::String],
+    },
+    Signature {
+        name: "timeOfDay",
+        min_args: 0,
+        args: &[Kind::String],
+    },
+];
+
+pub(super) fn register(ctx: &mut cel::Context) {
+    ctx.add_function("inCidr", in_cidr);
+    ctx.add_function("regexCapture", regex_capture);
+    ctx.add_function("equalsIgnoreCase", equals_ignore_case);
+    ctx.add_function("hashBucket", hash_bucket);
+    ctx.add_function("jwtClaim", jwt_claim);
+    ctx.add_function("timeOfDay", time_of_day);
+}
+
+/// Checks every call to one of this library's functions within `expr`.
+pub(super) fn validate(expr: &IdedExpr) -> Result<(), String> {
+    match &expr.expr {
+        Expr::Call(call) => {
+            if let Some(signature) = SIGNATURES.iter().find(|s| s.name == call.func_name) {
+                validate_call(signature, call.target.is_some(), &call.args)?;
+            }
+            if let Some(target) = &call.target {
+                validate(target)?;
+            }
+            call.args.iter().try_for_each(validate)
+        }
+        Expr::Comprehension(comp) => [
+            &comp.iter_range,
+            &comp.accu_init,
+            &comp.loop_cond,
+            &comp.loop_step,
+            &comp.result,
+        ]
+        .into_iter()
+        .try_for_each(validate),
+        Expr::List(list) => list.elements.iter().try_for_each(validate),
+        Expr::Map(map) => map.entries.iter().try_for_each(|entry| match &entry.expr {
+            EntryExpr::StructField(field) => validate(&field.value),
+            EntryExpr::MapEntry(entry) => {
+                validate(&entry.key)?;
+                validate(&entry.value)
+            }
+        }),
+        Expr::Select(select) => validate(&select.operand),
+        Expr::Struct(s) => s.entries.iter().try_for_each(|entry| match &entry.expr {
+            EntryExpr::StructField(field) => validate(&field.value),
+            EntryExpr::MapEntry(entry) => {
+                validate(&entry.key)?;
+                validate(&entry.value)
+            }
+        }),
+        Expr::Unspecified | Expr::Ident(_) | Expr::Literal(_) => Ok(()),
+    }
+}
+
+fn validate_call(signature: &Signature, has_target: bool, args: &[IdedExpr]) -> Result<(), String> {
+    let name = signature.name;
+    if has_target {
+        return Err(format!("`{name}` can't be called as a method"));
+    }
+    if args.len() < signature.min_args || args.len() > signature.args.len() {
+        let expected = if signature.min_args == signature.args.len() {
+            signature.min_args.to_string()
+        } else {
+            format!("{} to {}", signature.min_args, signature.args.len())
+        };
+        return Err(format!(
+            "`{name}` expects {expected} argument(s), got {}",
+            args.len()
+        ));
+    }
+
+    for (pos, (arg, kind)) in args.iter().zip(signature.args).enumerate() {
+        let found = match &arg.expr {
+            Expr::Literal(CelVal::String(_)) => "string",
+            Expr::Literal(CelVal::Int(_)) => "int",
+            Expr::Literal(CelVal::UInt(_)) => "uint",
+            Expr::Literal(CelVal::Double(_)) => "double",
+            Expr::Literal(CelVal::Boolean(_)) => "bool",
+            Expr::Literal(CelVal::Bytes(_)) => "bytes",
+            Expr::Literal(CelVal::Null) => "null",
+            Expr::List(_) => "list",
+            Expr::Map(_) => "map",
+            // only known once evaluated
+            _ => continue,
+        };
+        if found != kind.name() {
+            return Err(format!(
+                "argument {} of `{name}` must be of type {}, got {found}",
+                pos + 1,
+                kind.name()
+            ));
+        }
+    }
+
+    let literal = |pos: usize| match args.get(pos).map(|arg| &arg.expr) {
+        Some(Expr::Literal(CelVal::String(s))) => Some(s.as_str()),
+        _ => None,
+    };
+    let int_literal = |pos: usize| match args.get(pos).map(|arg| &arg.expr) {
+        Some(Expr::Literal(CelVal::Int(i))) => Some(*i),
+        _ => None,
+    };
+    match name {
+        "inCidr" => {
+            if let Some(cidr) = literal(1) {
+                parse_cidr(cidr)?;
+            }
+        }
+        "regexCapture" => {
+            if let Some(pattern) = literal(1) {
+                let regex = Regex::new(pattern).map_err(|e| format!("invalid pattern: {e}"))?;
+                let group = int_literal(2).unwrap_or(1);
+                if group < 0 || group as usize >= regex.captures_len() {
+                    return Err(format!("`{pattern}` has no capture group {group}"));
+                }
+            }
+        }
+        "hashBucket" => {
+            if let Some(buckets) = int_literal(1) {
+                if buckets <= 0 {
+                    return Err("`hashBucket` needs a positive number of buckets".to_string());
+                }
+            }
+        }
+        "timeOfDay" => {
+            if let Some(offset) = literal(0) {
+                parse_offset(offset)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `inCidr(ip, cidr)`: whether `ip` belongs to the `cidr` block, e.g.
+/// `10.0.0.0/8` or `2001:db8::/32`. `ip` may carry a port, as in `10.1.2.3:8080`.
+fn in_cidr(
+    ftx: &FunctionContext,
+    ip: Arc<String>,
+    cidr: Arc<String>,
+) -> Result<bool, ExecutionError> {
+    let (network, prefix) = parse_cidr(&cidr).map_err(|e| ftx.error(e))?;
+    let ip = ip
+        .parse::<IpAddr>()
+        .or_else(|_| ip.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .map_err(|_| ftx.error(format!("`{ip}` is not an IP address")))?;
+
+    Ok(match (ip.to_canonical(), network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    })
+}
+
+fn parse_cidr(cidr: &str) -> Result<(IpAddr, u32), String> {
+    let invalid = || format!("`{cidr}` is not a CIDR block");
+    let (network, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+    let network: IpAddr = network.parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+    let max = if network.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return Err(invalid());
+    }
+    Ok((network, prefix))
+}
+
+/// `regexCapture(value, pattern[, group])`: the text matched by capture
+/// `group`, defaulting to `1`, on the first match of `pattern` in `value`.
+/// Resolves to nothing, like a missing key would, when there is no match.
+fn regex_capture(ftx: &FunctionContext, Arguments(args): Arguments) -> ResolveResult {
+    let (value, pattern, group) = match args.as_slice() {
+        [Value::String(v), Value::String(p)] => (v, p, 1),
+        [Value::String(v), Value::String(p), Value::Int(g)] => (v, p, *g),
+        _ => return Err(ftx.error("expects (string, string[, int]) arguments")),
+    };
+    let regex = PATTERNS
+        .try_get_with_by_ref(pattern, || Regex::new(pattern))
+        .map_err(|e| ftx.error(format!("invalid pattern: {e}")))?;
+    let group = usize::try_from(group).map_err(|_| ftx.error("negative capture group"))?;
+    regex
+        .captures(value)
+        .and_then(|captures| captures.get(group))
+        .map(|m| Value::String(Arc::new(m.as_str().to_string())))
+        .ok_or_else(|| ExecutionError::no_such_key(pattern))
+}
+
+/// `equalsIgnoreCase(a, b)`: whether both strings are equal, regardless of case.
+fn equals_ignore_case(a: Arc<String>, b: Arc<String>) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// `hashBucket(value, buckets)`: spreads values over `[0, buckets)`. Uses 64-bit
+/// FNV-1a, so that the bucket of a value is the same on every instance and
+/// across releases.
+fn hash_bucket(ftx: &FunctionContext, value: Arc<String>, buckets: i64) -> ResolveResult {
+    let buckets = u64::try_from(buckets)
+        .ok()
+        .filter(|b| *b > 0)
+        .ok_or_else(|| ftx.error("needs a positive number of buckets"))?;
+    let hash = value.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    Ok(Value::Int((hash % buckets) as i64))
+}
+
+/// `jwtClaim(header, claim)`: the value of `claim` in the payload of the JWT
+/// in `header`, with or without its `Bearer ` prefix. The token's signature is
+/// _not_ verified, it must have been authenticated upstream already. Resolves
+/// to nothing, like a missing key would, when the claim is absent.
+fn jwt_claim(ftx: &FunctionContext, header: Arc<String>, claim: Arc<String>) -> ResolveResult {
+    let token = header.trim();
+    let token = match token.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        _ => token,
+    };
+    let payload = match token.split('.').collect::<Vec<_>>().as_slice() {
+        [_, payload, _] => *payload,
+        _ => return Err(ftx.error("not a JWT")),
+    };
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|e| ftx.error(format!("invalid JWT payload: {e}")))?;
+    match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(serde_json::Value::Object(mut claims)) => claims
+            .remove(claim.as_str())
+            .map(|value| ContextValue::from(value).into())
+            .ok_or_else(|| ExecutionError::no_such_key(&claim)),
+        _ => Err(ftx.error("invalid JWT payload: not a JSON object")),
+    }
+}
+
+/// `timeOfDay([offset])`: the time elapsed since midnight, in UTC or at the
+/// given UTC offset, e.g. `'+02:00'`. Compare it with durations:
+/// `timeOfDay() >= duration('9h')`. The rate limiter tells the time with the
+/// clock of its storage.
+fn time_of_day(ftx: &FunctionContext, Arguments(args): Arguments) -> ResolveResult {
+    let offset = match args.as_slice() {
+        [] => FixedOffset::east_opt(0).expect("valid offset"),
+        [Value::String(offset)] => parse_offset(offset).map_err(|e| ftx.error(e))?,
+        _ => return Err(ftx.error("expects an optional string argument")),
+    };
+    let now = match ftx.ptx.get_variable(NOW) {
+        Ok(Value::Timestamp(now)) => now.with_timezone(&offset),
+        _ => Utc::now().with_timezone(&offset),
+    };
+    Ok(Value::Duration(now.time() - NaiveTime::MIN))
+}
+
+fn parse_offset(offset: &str) -> Result<FixedOffset, String> {
+    if offset.eq_ignore_ascii_case("z") {
+        return Ok(FixedOffset::east_opt(0).expect("valid offset"));
+    }
+    offset
+        .parse()
+        .map_err(|_| format!("`{offset}` is not a UTC offset"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Context, ContextValue, Expression, Predicate};
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn eval(source: &str, values: &[(&str, &str)]) -> Option<ContextValue> {
+        let ctx = Context::from(
+            values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+        );
+        Expression::parse(source)
+            .expect("failed to parse")
+            .eval(&ctx)
+            .expect("failed to evaluate")
+    }
+
+    #[test]
+    fn in_cidr() {
+        assert_eq!(
+            eval("inCidr(ip, '10.0.0.0/8')", &[("ip", "10.1.2.3")]),
+            Some(true.into())
+        );
+        assert_eq!(
+            eval("inCidr(ip, '10.0.0.0/8')", &[("ip", "11.1.2.3:8080")]),
+            Some(false.into())
+        );
+        assert_eq!(
+            eval("inCidr(ip, '2001:db8::/32')", &[("ip", "2001:db8::1")]),
+            Some(true.into())
+        );
+        assert_eq!(
+            eval("inCidr(ip, '0.0.0.0/0')", &[("ip", "::ffff:192.168.0.1")]),
+            Some(true.into())
+        );
+        assert!(Expression::parse("inCidr(ip, '10.0.0.0/33')").is_err());
+        assert!(Expression::parse("inCidr(ip, '10.0.0.0')").is_err());
+    }
+
+    #[test]
+    fn regex_capture() {
+        let values = [("path", "/v1/users/42/orders")];
+        assert_eq!(
+            eval("regexCapture(path, '^/v1/users/([0-9]+)')", &values),
+            Some("42".into())
+        );
+        assert_eq!(
+            eval("regexCapture(path, '^/(v[0-9]+)/([a-z]+)', 2)", &values),
+            Some("users".into())
+        );
+        assert_eq!(eval("regexCapture(path, '^/v2/(.*)')", &values), None);
+        assert!(Predicate::parse("regexCapture(path, '(')").is_err());
+        assert!(Predicate::parse("regexCapture(path, '(a)', 2)").is_err());
+    }
+
+    #[test]
+    fn equals_ignore_case() {
+        assert_eq!(
+            eval("equalsIgnoreCase(method, 'get')", &[("method", "GET")]),
+            Some(true.into())
+        );
+        assert_eq!(
+            eval("equalsIgnoreCase(method, 'get')", &[("method", "POST")]),
+            Some(false.into())
+        );
+    }
+
+    #[test]
+    fn hash_bucket() {
+        let bucket = eval("hashBucket(user, 10)", &[("user", "alice")]);
+        assert_eq!(bucket, Some(ContextValue::Int(3)));
+        assert_eq!(
+            eval("hashBucket(user, 1)", &[("user", "alice")]),
+            Some(0_i64.into())
+        );
+        assert!(Expression::parse("hashBucket(user, 0)").is_err());
+        assert!(Expression::parse("hashBucket(user, '10')").is_err());
+    }
+
+    #[test]
+    fn jwt_claim() {
+        // {"alg":"none"}.{"sub":"alice","tier":"gold","quota":5}.
+        let token = "Bearer eyJhbGciOiJub25lIn0.\
+            eyJzdWIiOiJhbGljZSIsInRpZXIiOiJnb2xkIiwicXVvdGEiOjV9.";
+        let values = [("auth", token)];
+        assert_eq!(eval("jwtClaim(auth, 'sub')", &values), Some("alice".into()));
+        assert_eq!(eval("jwtClaim(auth, 'quota')", &values), Some(5_i64.into()));
+        assert_eq!(eval("jwtClaim(auth, 'missing')", &values), None);
+        assert!(Expression::parse("jwtClaim(auth, 'sub')")
+            .unwrap()
+            .eval(&HashMap::from([("auth".to_string(), "nope".to_string())]).into())
+            .is_err());
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!(
+            eval(
+                "timeOfDay() >= duration('0s') && timeOfDay('+02:00') < duration('24h')",
+                &[]
+            ),
+            Some(true.into())
+        );
+        assert!(Expression::parse("timeOfDay('noon')").is_err());
+
+        let ctx = Context::default();
+        let ctx = ctx.at(UNIX_EPOCH + Duration::from_secs(9 * 60 * 60 + 30 * 60));
+        let at = |source: &str| Expression::parse(source).unwrap().eval(&ctx).unwrap();
+        assert_eq!(at("timeOfDay() == duration('9h30m')"), Some(true.into()));
+        assert_eq!(
+            at("timeOfDay('+02:00') == duration('11h30m')"),
+            Some(true.into())
+        );
+    }
+
+    #[test]
+    fn validates_calls() {
+        assert!(Expression::parse("inCidr(ip)").is_err());
+        assert!(Expression::parse("ip.inCidr('10.0.0.0/8')").is_err());
+        assert!(Expression::parse("equalsIgnoreCase(a, 'b', 'c')").is_err());
+        assert!(Expression::parse("timeOfDay(2)").is_err());
+        assert!(Predicate::parse("[1].exists(x, hashBucket(k, -1) == x)").is_err());
+        assert!(Predicate::parse("hashBucket(k, n) == 0").is_ok());
+    }
+}
@@ -0,0 +1,786 @@
+//! Static analysis of a set of [`Limit`]s.
+//!
+//! Parsing a limit only guarantees its conditions and variables are valid CEL.
+//! [`lint`] goes further and reports limits that are redundant, can never
+//! apply, can never be the one limiting a request, or use values of the wrong
+//! type.
+
+use crate::limit::{Context, Limit, Predicate};
+use cel::common::ast::{operators, CallExpr, EntryExpr, Expr};
+use cel::common::value::CelVal;
+use cel::IdedExpr;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// Identical to another limit but for its name or id.
+    Duplicate,
+    /// Same namespace, window, conditions and variables as another limit, with
+    /// a different `max_value`: only one of the two is enforced.
+    Conflict,
+    /// Has conditions that can never be met, so the limit never applies.
+    Unsatisfiable,
+    /// Has a condition that always holds.
+    AlwaysTrue,
+    /// A stricter limit applies whenever this one does, so it never is the one
+    /// limiting a request.
+    Shadowed,
+    /// A value tested by a condition doesn't qualify the limit's counters.
+    UnqualifiedVariable,
+    /// A condition or variable operates on values of the wrong type.
+    TypeError,
+}
+
+impl Rule {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Rule::Conflict | Rule::Unsatisfiable | Rule::TypeError => Severity::Error,
+            Rule::Duplicate | Rule::AlwaysTrue | Rule::Shadowed => Severity::Warning,
+            Rule::UnqualifiedVariable => Severity::Info,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub rule: Rule,
+    /// Position of the offending limit in the linted slice.
+    pub limit: usize,
+    /// Position of the other limit involved, for rules relating two limits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other: Option<usize>,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(rule: Rule, limit: usize, message: String) -> Self {
+        Self {
+            severity: rule.severity(),
+            rule,
+            limit,
+            other: None,
+            message,
+        }
+    }
+
+    fn relating(rule: Rule, limit: usize, other: usize, message: String) -> Self {
+        Self {
+            other: Some(other),
+            ..Self::new(rule, limit, message)
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} [{:?}] limit #{}: {}",
+            self.severity, self.rule, self.limit, self.message
+        )
+    }
+}
+
+/// Analyses `limits`, returning the findings ordered by the position of the
+/// limit they concern.
+pub fn lint(limits: &[Limit]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for (pos, limit) in limits.iter().enumerate() {
+        lint_conditions(pos, limit, &mut findings);
+        lint_variables(pos, limit, &mut findings);
+
+        for (other_pos, other) in limits.iter().enumerate().take(pos) {
+            if limit.namespace != other.namespace {
+                continue;
+            }
+            if limit == other {
+                findings.push(if limit.max_value == other.max_value {
+                    Finding::relating(
+                        Rule::Duplicate,
+                        pos,
+                        other_pos,
+                        format!("duplicates {}", label(other_pos, other)),
+                    )
+                } else {
+                    Finding::relating(
+                        Rule::Conflict,
+                        pos,
+                        other_pos,
+                        format!(
+                            "has a max_value of {} where {} has {}, only one of them is enforced",
+                            limit.max_value,
+                            label(other_pos, other),
+                            other.max_value
+                        ),
+                    )
+                });
+            } else if shadows(other, limit) {
+                findings.push(Finding::relating(
+                    Rule::Shadowed,
+                    pos,
+                    other_pos,
+                    format!("is shadowed by the stricter {}", label(other_pos, other)),
+                ));
+            } else if shadows(limit, other) {
+                findings.push(Finding::relating(
+                    Rule::Shadowed,
+                    other_pos,
+                    pos,
+                    format!("is shadowed by the stricter {}", label(pos, limit)),
+                ));
+            }
+        }
+    }
+
+    findings.sort_by_key(|finding| finding.limit);
+    findings
+}
+
+fn label(pos: usize, limit: &Limit) -> String {
+    match limit.name() {
+        Some(name) => format!("limit #{pos} ({name})"),
+        None => format!("limit #{pos}"),
+    }
+}
+
+/// Whether `stricter` applies to, at least, every request `limit` applies to,
+/// counts them at least as coarsely, and allows fewer of them.
+fn shadows(stricter: &Limit, limit: &Limit) -> bool {
+    stricter.conditions.is_subset(&limit.conditions)
+        && stricter.variables.is_subset(&limit.variables)
+        && stricter.seconds >= limit.seconds
+        && stricter.max_value <= limit.max_value
+}
+
+fn lint_conditions(pos: usize, limit: &Limit, findings: &mut Vec<Finding>) {
+    let mut comparisons: BTreeMap<String, Vec<(bool, String, &str)>> = BTreeMap::new();
+    let mut tested = BTreeSet::new();
+
+    for predicate in &limit.conditions {
+        let ast = predicate.expression().ast();
+        match infer(ast) {
+            Ok(Type::Bool | Type::Dyn) => lint_constant(pos, limit, predicate, findings),
+            Ok(t) => findings.push(Finding::new(
+                Rule::TypeError,
+                pos,
+                format!(
+                    "`{}` evaluates to {t}, not bool",
+                    predicate.expression().source()
+                ),
+            )),
+            Err(err) => findings.push(Finding::new(
+                Rule::TypeError,
+                pos,
+                format!("`{}`: {err}", predicate.expression().source()),
+            )),
+        }
+        for (path, equal, literal) in comparisons_of(ast) {
+            comparisons.entry(path).or_default().push((
+                equal,
+                literal,
+                predicate.expression().source(),
+            ));
+        }
+        paths(ast, &mut Vec::new(), &mut tested);
+    }
+
+    'paths: for (path, tests) in &comparisons {
+        for (i, (equal, literal, source)) in tests.iter().enumerate() {
+            for (other_equal, other_literal, other_source) in &tests[..i] {
+                let contradicts = match (equal, other_equal) {
+                    (true, true) => literal != other_literal,
+                    (true, false) | (false, true) => literal == other_literal,
+                    (false, false) => false,
+                };
+                if contradicts {
+                    findings.push(Finding::new(
+                        Rule::Unsatisfiable,
+                        pos,
+                        format!("`{path}` can't satisfy both `{other_source}` and `{source}`"),
+                    ));
+                    continue 'paths;
+                }
+            }
+        }
+    }
+
+    // Paths tested for equality hold the same value on every request the
+    // limit applies to, there's no point in qualifying counters with those.
+    let pinned = |path: &str| {
+        comparisons
+            .get(path)
+            .is_some_and(|tests| tests.iter().any(|(equal, _, _)| *equal))
+    };
+    if !limit.variables.is_empty() {
+        let mut qualifying = BTreeSet::new();
+        for variable in &limit.variables {
+            paths(variable.ast(), &mut Vec::new(), &mut qualifying);
+        }
+        for path in tested
+            .iter()
+            .filter(|path| !path.starts_with("limit") && !pinned(path))
+        {
+            if !qualifying.iter().any(|q| overlaps(path, q)) {
+                findings.push(Finding::new(
+                    Rule::UnqualifiedVariable,
+                    pos,
+                    format!(
+                        "`{path}` is tested by a condition, but qualifies none of the counters"
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// Evaluates `predicate` if it only depends on the limit itself.
+fn lint_constant(pos: usize, limit: &Limit, predicate: &Predicate, findings: &mut Vec<Finding>) {
+    let references = predicate.expression().ast().references();
+    if !references.variables().iter().all(|v| *v == "limit")
+        || references.functions().contains(&"timeOfDay")
+    {
+        return;
+    }
+
+    let root = Context::default();
+    let finding = match predicate.test(&root.for_limit(limit)) {
+        Ok(true) => Finding::new(
+            Rule::AlwaysTrue,
+            pos,
+            format!("`{}` always holds", predicate.expression().source()),
+        ),
+        Ok(false) => Finding::new(
+            Rule::Unsatisfiable,
+            pos,
+            format!("`{}` never holds", predicate.expression().source()),
+        ),
+        Err(err) => Finding::new(
+            Rule::TypeError,
+            pos,
+            format!("`{}`: {err}", predicate.expression().source()),
+        ),
+    };
+    findings.push(finding);
+}
+
+fn lint_variables(pos: usize, limit: &Limit, findings: &mut Vec<Finding>) {
+    for variable in &limit.variables {
+        match infer(variable.ast()) {
+            Ok(t @ (Type::List | Type::Map | Type::Bytes | Type::Duration | Type::Timestamp)) => {
+                findings.push(Finding::new(
+                    Rule::TypeError,
+                    pos,
+                    format!(
+                        "`{}` evaluates to {t}, which can't qualify a counter",
+                        variable.source()
+                    ),
+                ))
+            }
+            Ok(_) => {}
+            Err(err) => findings.push(Finding::new(
+                Rule::TypeError,
+                pos,
+                format!("`{}`: {err}", variable.source()),
+            )),
+        }
+    }
+}
+
+/// The `path == literal` and `path != literal` tests that must all hold for
+/// `expr` to hold.
+fn comparisons_of(expr: &IdedExpr) -> Vec<(String, bool, String)> {
+    match &expr.expr {
+        Expr::Call(call) if call.func_name == operators::LOGICAL_AND => {
+            call.args.iter().flat_map(comparisons_of).collect()
+        }
+        Expr::Call(call)
+            if call.func_name == operators::EQUALS || call.func_name == operators::NOT_EQUALS =>
+        {
+            let equal = call.func_name == operators::EQUALS;
+            match call.args.as_slice() {
+                [lhs, IdedExpr {
+                    expr: Expr::Literal(literal),
+                    ..
+                }]
+                | [IdedExpr {
+                    expr: Expr::Literal(literal),
+                    ..
+                }, lhs] => path(lhs)
+                    .map(|path| vec![(path, equal, format!("{literal:?}"))])
+                    .unwrap_or_default(),
+                _ => Vec::default(),
+            }
+        }
+        _ => Vec::default(),
+    }
+}
+
+/// Renders the attribute path `expr` refers to, e.g. `descriptors[0].user`.
+fn path(expr: &IdedExpr) -> Option<String> {
+    match &expr.expr {
+        Expr::Ident(name) if !name.starts_with('@') => Some(name.clone()),
+        Expr::Select(select) => path(&select.operand).map(|p| format!("{p}.{}", select.field)),
+        Expr::Call(CallExpr {
+            func_name, args, ..
+        }) if func_name == operators::INDEX => match args.as_slice() {
+            [operand, IdedExpr {
+                expr: Expr::Literal(key),
+                ..
+            }] => {
+                let operand = path(operand)?;
+                match key {
+                    CelVal::Int(i) => Some(format!("{operand}[{i}]")),
+                    CelVal::UInt(u) => Some(format!("{operand}[{u}]")),
+                    CelVal::String(key)
+                        if !key.is_empty()
+                            && key.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+                    {
+                        Some(format!("{operand}.{key}"))
+                    }
+                    CelVal::String(key) => Some(format!("{operand}['{key}']")),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Collects the attribute paths `expr` refers to, leaving out the ones rooted
+/// at a comprehension's variables.
+fn paths(expr: &IdedExpr, bound: &mut Vec<String>, found: &mut BTreeSet<String>) {
+    if let Some(path) = path(expr) {
+        let root = path.split(['.', '[']).next().unwrap_or_default();
+        if !bound.iter().any(|var| var == root) {
+            found.insert(path);
+        }
+        return;
+    }
+    match &expr.expr {
+        Expr::Call(call) => {
+            if let Some(target) = &call.target {
+                paths(target, bound, found);
+            }
+            call.args.iter().for_each(|arg| paths(arg, bound, found));
+        }
+        Expr::Comprehension(comp) => {
+            paths(&comp.iter_range, bound, found);
+            bound.push(comp.iter_var.clone());
+            bound.extend(comp.iter_var2.clone());
+            for e in [
+                &comp.accu_init,
+                &comp.loop_cond,
+                &comp.loop_step,
+                &comp.result,
+            ] {
+                paths(e, bound, found);
+            }
+            bound.truncate(bound.len() - 1 - usize::from(comp.iter_var2.is_some()));
+        }
+        Expr::List(list) => list.elements.iter().for_each(|e| paths(e, bound, found)),
+        Expr::Map(map) => map.entries.iter().for_each(|entry| match &entry.expr {
+            EntryExpr::StructField(field) => paths(&field.value, bound, found),
+            EntryExpr::MapEntry(entry) => {
+                paths(&entry.key, bound, found);
+                paths(&entry.value, bound, found);
+            }
+        }),
+        Expr::Select(select) => paths(&select.operand, bound, found),
+        _ => {}
+    }
+}
+
+/// Whether one path is the other, or is nested within it.
+fn overlaps(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    long.strip_prefix(short)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Bool,
+    Int,
+    UInt,
+    Double,
+    String,
+    Bytes,
+    Null,
+    List,
+    Map,
+    Duration,
+    Timestamp,
+    /// Only known once evaluated
+    Dyn,
+}
+
+impl Type {
+    fn is_numeric(&self) -> bool {
+        matches!(self, Type::Int | Type::UInt | Type::Double)
+    }
+
+    fn is_known(&self) -> bool {
+        *self != Type::Dyn
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Type::Bool => "bool",
+            Type::Int => "int",
+            Type::UInt => "uint",
+            Type::Double => "double",
+            Type::String => "string",
+            Type::Bytes => "bytes",
+            Type::Null => "null",
+            Type::List => "list",
+            Type::Map => "map",
+            Type::Duration => "duration",
+            Type::Timestamp => "timestamp",
+            Type::Dyn => "dyn",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Infers the type `expr` evaluates to, as far as it can be known without a
+/// context, failing on operations that can't succeed whatever the context.
+fn infer(expr: &IdedExpr) -> Result<Type, String> {
+    Ok(match &expr.expr {
+        Expr::Literal(literal) => match literal {
+            CelVal::Boolean(_) => Type::Bool,
+            CelVal::Int(_) => Type::Int,
+            CelVal::UInt(_) => Type::UInt,
+            CelVal::Double(_) => Type::Double,
+            CelVal::String(_) => Type::String,
+            CelVal::Bytes(_) => Type::Bytes,
+            CelVal::Null => Type::Null,
+            CelVal::Duration(_) => Type::Duration,
+            CelVal::Timestamp(_) => Type::Timestamp,
+            _ => Type::Dyn,
+        },
+        Expr::List(list) => {
+            for e in &list.elements {
+                infer(e)?;
+            }
+            Type::List
+        }
+        Expr::Map(map) => {
+            for entry in &map.entries {
+                match &entry.expr {
+                    EntryExpr::StructField(field) => infer(&field.value)?,
+                    EntryExpr::MapEntry(entry) => {
+                        infer(&entry.key)?;
+                        infer(&entry.value)?
+                    }
+                };
+            }
+            Type::Map
+        }
+        Expr::Select(select) => match infer(&select.operand)? {
+            _ if select.test => Type::Bool,
+            Type::Map | Type::Dyn => Type::Dyn,
+            t => return Err(format!("{t} has no field `{}`", select.field)),
+        },
+        Expr::Comprehension(comp) => {
+            for e in [
+                &comp.iter_range,
+                &comp.accu_init,
+                &comp.loop_cond,
+                &comp.loop_step,
+                &comp.result,
+            ] {
+                infer(e)?;
+            }
+            Type::Dyn
+        }
+        Expr::Call(call) => infer_call(call)?,
+        Expr::Ident(_) | Expr::Struct(_) | Expr::Unspecified => Type::Dyn,
+    })
+}
+
+fn infer_call(call: &CallExpr) -> Result<Type, String> {
+    let target = call.target.as_deref().map(infer).transpose()?;
+    let args = call.args.iter().map(infer).collect::<Result<Vec<_>, _>>()?;
+    let name = call.func_name.as_str();
+
+    let expect_bool = |t: Type| match t {
+        Type::Bool | Type::Dyn => Ok(()),
+        t => Err(format!("expected bool, got {t}")),
+    };
+
+    Ok(match (name, args.as_slice()) {
+        (operators::LOGICAL_AND | operators::LOGICAL_OR, [l, r]) => {
+            expect_bool(*l)?;
+            expect_bool(*r)?;
+            Type::Bool
+        }
+        (operators::LOGICAL_NOT, [t]) => {
+            expect_bool(*t)?;
+            Type::Bool
+        }
+        (operators::EQUALS | operators::NOT_EQUALS, [l, r]) => {
+            if l.is_known() && r.is_known() && l != r && !(l.is_numeric() && r.is_numeric()) {
+                return Err(format!(
+                    "comparing {l} to {r} is always {}",
+                    name == operators::NOT_EQUALS
+                ));
+            }
+            Type::Bool
+        }
+        (
+            operators::LESS
+            | operators::LESS_EQUALS
+            | operators::GREATER
+            | operators::GREATER_EQUALS,
+            [l, r],
+        ) => {
+            let orderable = |t: &Type| !matches!(t, Type::Null | Type::List | Type::Map);
+            if l.is_known()
+                && r.is_known()
+                && !(l == r && orderable(l) || l.is_numeric() && r.is_numeric())
+            {
+                return Err(format!("can't order {l} and {r}"));
+            }
+            Type::Bool
+        }
+        (operators::ADD, [l, r]) => match (*l, *r) {
+            (Type::Timestamp, Type::Duration) | (Type::Duration, Type::Timestamp) => {
+                Type::Timestamp
+            }
+            (
+                t @ (Type::Int
+                | Type::UInt
+                | Type::Double
+                | Type::String
+                | Type::Bytes
+                | Type::List
+                | Type::Duration),
+                u,
+            ) if t == u => t,
+            (Type::Dyn, _) | (_, Type::Dyn) => Type::Dyn,
+            (l, r) => return Err(format!("can't add {l} and {r}")),
+        },
+        (operators::SUBSTRACT, [l, r]) => match (*l, *r) {
+            (Type::Timestamp, Type::Timestamp) => Type::Duration,
+            (Type::Timestamp, Type::Duration) => Type::Timestamp,
+            (t @ (Type::Int | Type::UInt | Type::Double | Type::Duration), u) if t == u => t,
+            (Type::Dyn, _) | (_, Type::Dyn) => Type::Dyn,
+            (l, r) => return Err(format!("can't subtract {r} from {l}")),
+        },
+        (operators::MULTIPLY | operators::DIVIDE | operators::MODULO, [l, r]) => match (*l, *r) {
+            (t, u) if t == u && t.is_numeric() => t,
+            (Type::Dyn, t) | (t, Type::Dyn) if t.is_numeric() || !t.is_known() => Type::Dyn,
+            (l, r) => return Err(format!("can't apply `{name}` to {l} and {r}")),
+        },
+        (operators::NEGATE, [t]) => match t {
+            Type::Int | Type::Double | Type::Duration | Type::Dyn => *t,
+            t => return Err(format!("can't negate {t}")),
+        },
+        (operators::CONDITIONAL, [c, a, b]) => {
+            expect_bool(*c)?;
+            if a == b {
+                *a
+            } else {
+                Type::Dyn
+            }
+        }
+        (operators::IN, [_, container]) => match container {
+            Type::List | Type::Map | Type::Dyn => Type::Bool,
+            t => return Err(format!("`in` needs a list or map, got {t}")),
+        },
+        (operators::INDEX, [container, _]) => match container {
+            Type::List | Type::Map | Type::Dyn => Type::Dyn,
+            t => return Err(format!("can't index {t}")),
+        },
+        ("startsWith" | "endsWith" | "matches", _) => match target {
+            Some(Type::String | Type::Dyn) | None => Type::Bool,
+            Some(t) => return Err(format!("`{name}` needs a string, got {t}")),
+        },
+        ("contains" | "has" | "inCidr" | "equalsIgnoreCase", _) => Type::Bool,
+        ("size" | "int" | "hashBucket", _) => Type::Int,
+        ("uint", _) => Type::UInt,
+        ("double", _) => Type::Double,
+        ("string" | "regexCapture", _) => Type::String,
+        ("bytes", _) => Type::Bytes,
+        ("duration" | "timeOfDay", _) => Type::Duration,
+        ("timestamp", _) => Type::Timestamp,
+        _ => Type::Dyn,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Finding, Rule};
+    use crate::limit::Limit;
+
+    fn limit(max_value: u64, seconds: u64, conditions: &[&str], variables: &[&str]) -> Limit {
+        Limit::new(
+            "ns",
+            max_value,
+            seconds,
+            conditions
+                .iter()
+                .map(|c| (*c).try_into().expect("failed parsing!")),
+            variables
+                .iter()
+                .map(|v| (*v).try_into().expect("failed parsing!")),
+        )
+    }
+
+    fn rules(findings: &[Finding]) -> Vec<(Rule, usize, Option<usize>)> {
+        findings
+            .iter()
+            .map(|f| (f.rule, f.limit, f.other))
+            .collect()
+    }
+
+    #[test]
+    fn clean_limits_have_no_findings() {
+        let limits = [
+            limit(10, 60, &["descriptors[0].method == 'GET'"], &[]),
+            limit(5, 60, &["descriptors[0].method == 'POST'"], &[]),
+            limit(100, 60, &[], &["descriptors[0].user"]),
+        ];
+        assert_eq!(lint(&limits), vec![]);
+    }
+
+    #[test]
+    fn finds_duplicates_and_conflicts() {
+        let mut named = limit(10, 60, &["x == '1'"], &["y"]);
+        named.set_name("named".to_string());
+        let limits = [
+            limit(10, 60, &["x == '1'"], &["y"]),
+            named,
+            limit(20, 60, &["x == '1'"], &["y"]),
+        ];
+        assert_eq!(
+            rules(&lint(&limits)),
+            vec![
+                (Rule::Duplicate, 1, Some(0)),
+                (Rule::Conflict, 2, Some(0)),
+                (Rule::Conflict, 2, Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_shadowed_limits() {
+        let limits = [
+            limit(100, 60, &["x == '1'", "z == '2'"], &[]),
+            limit(10, 60, &["x == '1'"], &[]),
+            limit(100, 60, &["x == '2'"], &[]),
+        ];
+        assert_eq!(rules(&lint(&limits)), vec![(Rule::Shadowed, 0, Some(1))]);
+    }
+
+    #[test]
+    fn finds_constant_conditions() {
+        let limits = [
+            limit(1, 60, &["1 < 2"], &[]),
+            limit(1, 60, &["'a' == 'b'"], &[]),
+            limit(1, 60, &["x == 'a'", "x == 'b'"], &[]),
+            limit(1, 60, &["x == 'a'", "x != 'a'"], &[]),
+            limit(1, 60, &["x == 'a' && x != 'b'"], &[]),
+            limit(1, 60, &["limit.name == null"], &[]),
+        ];
+        assert_eq!(
+            rules(&lint(&limits)),
+            vec![
+                (Rule::AlwaysTrue, 0, None),
+                (Rule::Unsatisfiable, 1, None),
+                (Rule::Unsatisfiable, 2, None),
+                (Rule::Unsatisfiable, 3, None),
+                (Rule::AlwaysTrue, 5, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_unqualified_variables() {
+        let limits = [
+            limit(
+                1,
+                60,
+                &[
+                    "descriptors[0]['user'].id == 'a'",
+                    "descriptors[0].tags.exists(t, t == 'b')",
+                ],
+                &["descriptors[0].user"],
+            ),
+            limit(
+                1,
+                60,
+                &[
+                    "descriptors[0].method != 'GET'",
+                    "descriptors[0].app == 'a'",
+                ],
+                &["descriptors[0].user"],
+            ),
+        ];
+        let findings = lint(&limits);
+        assert_eq!(
+            rules(&findings),
+            vec![
+                (Rule::UnqualifiedVariable, 0, None),
+                (Rule::UnqualifiedVariable, 1, None),
+            ]
+        );
+        assert!(findings[0].message.contains("`descriptors[0].tags`"));
+        assert!(findings[1].message.contains("`descriptors[0].method`"));
+    }
+
+    #[test]
+    fn finds_type_errors() {
+        let limits = [
+            limit(1, 60, &["'a' + 1 == x"], &[]),
+            limit(1, 60, &["size(x) == 'a'"], &[]),
+            limit(1, 60, &["x.startsWith('a') || 1"], &[]),
+            limit(1, 60, &["x > 'a' + 1"], &[]),
+            limit(1, 60, &["x == 'a'"], &["[x]", "timestamp(x)"]),
+            limit(1, 60, &["1 in 2"], &[]),
+            limit(1, 60, &["int(x) > 1.5 && x[0] == 'a' && -x < 0"], &["x"]),
+        ];
+        assert_eq!(
+            rules(&lint(&limits)),
+            vec![
+                (Rule::TypeError, 0, None),
+                (Rule::TypeError, 1, None),
+                (Rule::TypeError, 2, None),
+                (Rule::TypeError, 3, None),
+                (Rule::TypeError, 4, None),
+                (Rule::TypeError, 4, None),
+                (Rule::TypeError, 5, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn findings_serialize() {
+        let limits = [limit(1, 60, &["1 in 2"], &[])];
+        assert_eq!(
+            serde_json::to_value(lint(&limits)).unwrap(),
+            serde_json::json!([{
+                "severity": "error",
+                "rule": "type_error",
+                "limit": 0,
+                "message": "`1 in 2`: `in` needs a list or map, got int",
+            }])
+        );
+    }
+}
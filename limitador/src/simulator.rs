@@ -0,0 +1,362 @@
+//! Replays recorded traffic against a set of limits.
+//!
+//! The [`Simulator`] drives an in-memory [`RateLimiter`] whose
+//! [`ManualClock`] follows the timestamps of the [`Record`]s it is given, so
+//! that a log spanning days replays in no time. It then [reports](Report),
+//! for every limit, how many requests it denied, the highest value any of its
+//! counters reached and how many distinct counters it qualified.
+//!
+//! Logs are read as [JSON lines](https://jsonlines.org/), one record per line:
+//!
+//! ```json
+//! {"timestamp": "2024-05-01T10:00:00Z", "namespace": "api", "descriptors": [{"user": "alice"}], "hits": 1}
+//! {"timestamp": 1714557601.5, "namespace": "api", "descriptors": [{"user": "bob"}]}
+//! ```
+//!
+//! `timestamp` is either RFC 3339 or the seconds since the UNIX epoch, and
+//! `hits` defaults to `1`. The `descriptors` are bound as they are by the
+//! server's RLS endpoint, i.e. a condition would read
+//! `descriptors[0].user == 'alice'`.
+
+use crate::clock::{Clock, ManualClock};
+use crate::errors::LimitadorError;
+use crate::explain::Outcome;
+use crate::limit::{Context, Limit};
+use crate::{RateLimiter, RateLimiterBuilder};
+use chrono::DateTime;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The number of qualified counters the simulator keeps track of.
+const CACHE_SIZE: u64 = 1_000_000;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Record {
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub timestamp: SystemTime,
+    pub namespace: String,
+    #[serde(default)]
+    pub descriptors: Vec<HashMap<String, serde_json::Value>>,
+    #[serde(default = "one")]
+    pub hits: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub records: u64,
+    pub hits: u64,
+    /// The records that were rate limited.
+    pub denied: u64,
+    pub denied_hits: u64,
+    /// The records that failed to evaluate against the limits.
+    pub errors: u64,
+    pub limits: Vec<LimitReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LimitReport {
+    pub namespace: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub max_value: u64,
+    pub seconds: u64,
+    /// The records the limit applied to.
+    pub matched: u64,
+    /// The records denied because this limit's counter was exhausted.
+    pub denied: u64,
+    /// The highest value any of its counters reached.
+    pub peak_value: u64,
+    /// The number of distinct counters, i.e. combinations of values of its
+    /// variables, the records qualified.
+    pub cardinality: u64,
+}
+
+#[derive(Debug)]
+pub enum SimulationError {
+    Io(std::io::Error),
+    /// The record on `line`, starting at `1`, couldn't be parsed.
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+impl Display for SimulationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimulationError::Io(err) => write!(f, "error reading the records: {err}"),
+            SimulationError::Parse { line, source } => {
+                write!(f, "error parsing the record on line {line}: {source}")
+            }
+        }
+    }
+}
+
+impl Error for SimulationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SimulationError::Io(err) => Some(err),
+            SimulationError::Parse { source, .. } => Some(source),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LimitStats {
+    matched: u64,
+    denied: u64,
+    peak_value: u64,
+    counters: HashSet<String>,
+}
+
+pub struct Simulator {
+    clock: Arc<ManualClock>,
+    rate_limiter: RateLimiter,
+    report: Report,
+    stats: BTreeMap<Arc<Limit>, LimitStats>,
+}
+
+impl Simulator {
+    pub fn new(limits: impl IntoIterator<Item = Limit>) -> Self {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let rate_limiter = RateLimiterBuilder::with_clock(CACHE_SIZE, clock.clone()).build();
+        let stats = limits
+            .into_iter()
+            .map(|limit| {
+                rate_limiter.add_limit(limit.clone());
+                (Arc::new(limit), LimitStats::default())
+            })
+            .collect();
+
+        Self {
+            clock,
+            rate_limiter,
+            report: Report::default(),
+            stats,
+        }
+    }
+
+    /// Replays `record`, returning whether it was rate limited.
+    ///
+    /// Time only moves forward: a record older than the ones replayed before
+    /// it is replayed as if it happened at the same time as the latest of
+    /// them.
+    pub fn replay(&mut self, record: &Record) -> Result<bool, LimitadorError> {
+        if record.timestamp > self.clock.now() {
+            self.clock.set(record.timestamp);
+        }
+        self.report.records += 1;
+        self.report.hits += record.hits;
+
+        let mut ctx = Context::default();
+        ctx.list_binding("descriptors".to_string(), record.descriptors.clone());
+        let result = match self.rate_limiter.explain_rate_limited_and_update(
+            &record.namespace.as_str().into(),
+            &ctx,
+            record.hits,
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                self.report.errors += 1;
+                return Err(err);
+            }
+        };
+
+        if result.limited {
+            self.report.denied += 1;
+            self.report.denied_hits += record.hits;
+        }
+        for explanation in result.explanation.iter().flat_map(|e| &e.limits) {
+            let Some(stats) = self.stats.get_mut(&explanation.limit) else {
+                continue;
+            };
+            let Some(counter) = &explanation.counter else {
+                continue;
+            };
+            stats.matched += 1;
+            if explanation.outcome == Outcome::Limited {
+                stats.denied += 1;
+            }
+            stats.peak_value = stats.peak_value.max(counter.value.unwrap_or_default());
+            stats.counters.insert(counter.key.clone());
+        }
+
+        Ok(result.limited)
+    }
+
+    /// Replays every record of the JSON lines `log`. Records that fail to
+    /// evaluate are counted as [errors](Report::errors), but don't stop the
+    /// replay.
+    pub fn replay_log<R: BufRead>(&mut self, log: R) -> Result<(), SimulationError> {
+        for (i, line) in log.lines().enumerate() {
+            let line = line.map_err(SimulationError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record =
+                serde_json::from_str(&line).map_err(|source| SimulationError::Parse {
+                    line: i + 1,
+                    source,
+                })?;
+            // errors are accounted for in the report
+            let _ = self.replay(&record);
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> Report {
+        let limits = self
+            .stats
+            .iter()
+            .map(|(limit, stats)| LimitReport {
+                namespace: limit.namespace().as_ref().to_string(),
+                id: limit.id().map(String::from),
+                name: limit.name().map(String::from),
+                max_value: limit.max_value(),
+                seconds: limit.seconds(),
+                matched: stats.matched,
+                denied: stats.denied,
+                peak_value: stats.peak_value,
+                cardinality: stats.counters.len() as u64,
+            })
+            .collect();
+
+        Report {
+            limits,
+            ..self.report.clone()
+        }
+    }
+}
+
+fn one() -> u64 {
+    1
+}
+
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SystemTime, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Seconds(f64),
+        Rfc3339(String),
+    }
+
+    match Timestamp::deserialize(deserializer)? {
+        Timestamp::Seconds(secs) => Duration::try_from_secs_f64(secs)
+            .map(|since_epoch| UNIX_EPOCH + since_epoch)
+            .map_err(serde::de::Error::custom),
+        Timestamp::Rfc3339(s) => DateTime::parse_from_rfc3339(&s)
+            .map(SystemTime::from)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimulationError, Simulator};
+    use crate::limit::{Expression, Limit};
+
+    fn limits() -> Vec<Limit> {
+        let mut per_user = Limit::new(
+            "api",
+            2,
+            60,
+            vec!["descriptors[0].method == 'GET'".try_into().unwrap()],
+            vec!["descriptors[0].user".try_into().unwrap()],
+        );
+        per_user.set_name("per_user".to_string());
+        let mut global = Limit::new("api", 5, 60, vec![], Vec::<Expression>::new());
+        global.set_name("global".to_string());
+        vec![per_user, global]
+    }
+
+    #[test]
+    fn reports_what_each_limit_did() {
+        let log = r#"
+{"timestamp": "2024-05-01T10:00:00Z", "namespace": "api", "descriptors": [{"method": "GET", "user": "alice"}]}
+{"timestamp": "2024-05-01T10:00:01Z", "namespace": "api", "descriptors": [{"method": "GET", "user": "alice"}]}
+{"timestamp": "2024-05-01T10:00:02Z", "namespace": "api", "descriptors": [{"method": "GET", "user": "alice"}]}
+{"timestamp": 1714557603, "namespace": "api", "descriptors": [{"method": "GET", "user": "bob"}]}
+{"timestamp": 1714557604, "namespace": "api", "descriptors": [{"method": "POST", "user": "bob"}], "hits": 2}
+{"timestamp": "2024-05-01T10:01:01Z", "namespace": "api", "descriptors": [{"method": "GET", "user": "alice"}]}
+{"timestamp": "2024-05-01T10:01:02Z", "namespace": "other", "descriptors": []}
+"#;
+        let mut simulator = Simulator::new(limits());
+        simulator.replay_log(log.as_bytes()).unwrap();
+        let report = simulator.report();
+
+        assert_eq!(report.records, 7);
+        assert_eq!(report.hits, 8);
+        assert_eq!(report.denied, 1);
+        assert_eq!(report.denied_hits, 1);
+        assert_eq!(report.errors, 0);
+
+        let per_user = report
+            .limits
+            .iter()
+            .find(|l| l.name.as_deref() == Some("per_user"))
+            .unwrap();
+        assert_eq!(per_user.matched, 5);
+        assert_eq!(per_user.denied, 1);
+        assert_eq!(per_user.peak_value, 2);
+        assert_eq!(per_user.cardinality, 2);
+
+        let global = report
+            .limits
+            .iter()
+            .find(|l| l.name.as_deref() == Some("global"))
+            .unwrap();
+        assert_eq!(global.matched, 6);
+        assert_eq!(global.denied, 0);
+        // the window expired before the last request to the namespace
+        assert_eq!(global.peak_value, 5);
+        assert_eq!(global.cardinality, 1);
+    }
+
+    #[test]
+    fn tells_the_time_of_day_of_the_records() {
+        let mut office_hours = Limit::new(
+            "api",
+            1,
+            60,
+            vec![
+                "timeOfDay() >= duration('9h') && timeOfDay() < duration('17h')"
+                    .try_into()
+                    .unwrap(),
+            ],
+            Vec::<Expression>::new(),
+        );
+        office_hours.set_name("office_hours".to_string());
+        let log = r#"
+{"timestamp": "2024-05-01T08:59:59Z", "namespace": "api", "descriptors": []}
+{"timestamp": "2024-05-01T09:00:00Z", "namespace": "api", "descriptors": []}
+{"timestamp": "2024-05-01T09:00:01Z", "namespace": "api", "descriptors": []}
+{"timestamp": "2024-05-01T17:00:00Z", "namespace": "api", "descriptors": []}
+"#;
+        let mut simulator = Simulator::new(vec![office_hours]);
+        simulator.replay_log(log.as_bytes()).unwrap();
+        let report = simulator.report();
+
+        assert_eq!(report.limits[0].matched, 2);
+        assert_eq!(report.denied, 1);
+    }
+
+    #[test]
+    fn reports_the_line_of_unparseable_records() {
+        let log = "{\"timestamp\": 0, \"namespace\": \"api\"}\n{\"namespace\": \"api\"}\n";
+        let mut simulator = Simulator::new(limits());
+        match simulator.replay_log(log.as_bytes()) {
+            Err(SimulationError::Parse { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(simulator.report().records, 1);
+    }
+}
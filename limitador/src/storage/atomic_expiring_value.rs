@@ -23,16 +23,16 @@ impl AtomicExpiringValue {
         self.value.load(Ordering::SeqCst)
     }
 
-    pub fn value(&self) -> u64 {
-        self.value_at(SystemTime::now())
-    }
-
-    #[cfg(feature = "redis_storage")]
     pub fn add_and_set_expiry(&self, delta: u64, expiry: SystemTime) -> u64 {
         self.expiry.update(expiry);
         self.value.fetch_add(delta, Ordering::SeqCst) + delta
     }
 
+    pub fn set(&self, value: u64, expiry: SystemTime) {
+        self.expiry.update(expiry);
+        self.value.store(value, Ordering::SeqCst);
+    }
+
     pub fn update(&self, delta: u64, ttl: Duration, when: SystemTime) -> u64 {
         if self.expiry.update_if_expired(ttl, when) {
             self.value.store(delta, Ordering::SeqCst);
@@ -41,8 +41,8 @@ impl AtomicExpiringValue {
         self.value.fetch_add(delta, Ordering::SeqCst) + delta
     }
 
-    pub fn ttl(&self) -> Duration {
-        self.expiry.ttl()
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expiry.ttl_at(when)
     }
 }
 
@@ -65,12 +65,10 @@ impl AtomicExpiryTime {
             .as_micros() as u64
     }
 
-    pub fn ttl(&self) -> Duration {
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
         let expiry =
             SystemTime::UNIX_EPOCH + Duration::from_micros(self.expiry.load(Ordering::SeqCst));
-        expiry
-            .duration_since(SystemTime::now())
-            .unwrap_or(Duration::ZERO)
+        expiry.duration_since(when).unwrap_or(Duration::ZERO)
     }
 
     pub fn expired_at(&self, when: SystemTime) -> bool {
@@ -78,7 +76,6 @@ impl AtomicExpiryTime {
         self.expiry.load(Ordering::SeqCst) <= when
     }
 
-    #[cfg(feature = "redis_storage")]
     pub fn update(&self, expiry: SystemTime) {
         self.expiry
             .store(Self::since_epoch(expiry), Ordering::SeqCst);
@@ -211,7 +208,7 @@ mod tests {
     fn updates_when_expired() {
         let now = SystemTime::now();
         let val = AtomicExpiringValue::new(42, now);
-        assert_eq!(val.ttl(), Duration::ZERO);
+        assert_eq!(val.ttl_at(now), Duration::ZERO);
         val.update(3, Duration::from_secs(10), now);
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 3);
     }
@@ -0,0 +1,299 @@
+use crate::clock::Clock;
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::task::{self, JoinError};
+
+/// Exposes any [`CounterStorage`] as an [`AsyncCounterStorage`].
+///
+/// Every call to the wrapped storage is moved to tokio's blocking thread pool,
+/// so that storages doing disk or network I/O synchronously, like
+/// `RocksDbStorage`, don't stall the async runtime they are used from.
+pub struct BlockingStorageAdapter {
+    storage: Arc<dyn CounterStorage>,
+}
+
+impl BlockingStorageAdapter {
+    pub fn new(storage: impl CounterStorage + 'static) -> Self {
+        Self {
+            storage: Arc::new(storage),
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, StorageErr>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn CounterStorage) -> Result<T, StorageErr> + Send + 'static,
+    {
+        let storage = Arc::clone(&self.storage);
+        task::spawn_blocking(move || f(storage.as_ref()))
+            .await
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+#[async_trait]
+impl AsyncCounterStorage for BlockingStorageAdapter {
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.storage.add_counter(limit)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let counter = counter.clone();
+        self.run(move |storage| storage.is_within_limits(&counter, delta))
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let counter = counter.clone();
+        self.run(move |storage| storage.update_counter(&counter, delta))
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        // a copy, so that the counters are still there if this is dropped, e.g.
+        // on a timeout, before the call returns
+        let mut owned = counters.clone();
+        let (owned, result) = self
+            .run(move |storage| {
+                let result = storage.check_and_update(&mut owned, delta, load_counters);
+                Ok((owned, result))
+            })
+            .await?;
+        *counters = owned;
+        result
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        let limits = limits.clone();
+        self.run(move |storage| storage.get_counters(&limits)).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let limits = limits.clone();
+        self.run(move |storage| storage.delete_counters(&limits))
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.run(|storage| storage.clear()).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        let counter = counter.clone();
+        self.run(move |storage| storage.admit_counter(&counter, max_counters))
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn can_admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        let counter = counter.clone();
+        self.run(move |storage| storage.can_admit_counter(&counter, max_counters))
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let limit = limit.clone();
+        self.run(move |storage| storage.cardinality(&limit)).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        let counter = counter.clone();
+        self.run(move |storage| storage.import_counter(&counter, value, expires_at))
+            .await
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.storage.clock()
+    }
+
+    fn backend(&self) -> &'static str {
+        self.storage.backend()
+    }
+}
+
+impl From<JoinError> for StorageErr {
+    fn from(err: JoinError) -> Self {
+        if err.is_panic() {
+            std::panic::resume_unwind(err.into_panic())
+        }
+        Self {
+            msg: err.to_string(),
+            source: Some(Box::new(err)),
+            transient: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockingStorageAdapter;
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::resilient::ResilientStorage;
+    use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+    use crate::AsyncRateLimiter;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Takes 100ms to answer its first `slow` calls.
+    struct Slow {
+        slow: AtomicU32,
+        inner: InMemoryStorage,
+    }
+
+    impl Slow {
+        fn stall(&self) {
+            let slow = self.slow.load(Ordering::Acquire);
+            if slow > 0 {
+                self.slow.store(slow - 1, Ordering::Release);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    impl CounterStorage for Slow {
+        fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+            self.stall();
+            self.inner.is_within_limits(counter, delta)
+        }
+
+        fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+            self.inner.add_counter(limit)
+        }
+
+        fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+            self.stall();
+            self.inner.update_counter(counter, delta)
+        }
+
+        fn check_and_update(
+            &self,
+            counters: &mut Vec<Counter>,
+            delta: u64,
+            load_counters: bool,
+        ) -> Result<Authorization, StorageErr> {
+            self.stall();
+            self.inner.check_and_update(counters, delta, load_counters)
+        }
+
+        fn get_counters(
+            &self,
+            limits: &HashSet<Arc<Limit>>,
+        ) -> Result<HashSet<Counter>, StorageErr> {
+            self.inner.get_counters(limits)
+        }
+
+        fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+            self.inner.delete_counters(limits)
+        }
+
+        fn clear(&self) -> Result<(), StorageErr> {
+            self.inner.clear()
+        }
+
+        fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+            self.inner.admit_counter(counter, max_counters)
+        }
+
+        fn can_admit_counter(
+            &self,
+            counter: &Counter,
+            max_counters: u64,
+        ) -> Result<bool, StorageErr> {
+            self.inner.can_admit_counter(counter, max_counters)
+        }
+
+        fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+            self.inner.cardinality(limit)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_the_counters_of_timed_out_calls() {
+        let slow = Slow {
+            slow: AtomicU32::new(0),
+            inner: InMemoryStorage::default(),
+        };
+        let limit = Limit::new("test_namespace", 1, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Default::default()).unwrap().unwrap();
+        CounterStorage::add_counter(&slow, counter.limit()).unwrap();
+        slow.update_counter(&counter, 1).unwrap();
+        slow.slow.store(2, Ordering::Release);
+        let storage = ResilientStorage::new(BlockingStorageAdapter::new(slow))
+            .timeout(Duration::from_millis(20))
+            .retries(2, Duration::from_millis(1));
+
+        let mut counters = vec![counter.clone()];
+        let result = storage.check_and_update(&mut counters, 1, true).await;
+        assert!(result.is_err_and(|err| err.msg().contains("timed out")));
+        assert_eq!(counters, vec![counter.clone()]);
+
+        // reads are retried
+        assert!(!storage.is_within_limits(&counter, 1).await.unwrap());
+
+        let result = storage.check_and_update(&mut counters, 1, true).await;
+        assert!(matches!(result, Ok(Authorization::Limited(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_blocking_storage_from_async_limiter() {
+        let limiter = AsyncRateLimiter::new_with_storage(Box::new(BlockingStorageAdapter::new(
+            InMemoryStorage::default(),
+        )));
+        let namespace = "test_namespace";
+        limiter.add_limit(Limit::new(namespace, 2, 60, vec![], vec![]));
+
+        let ctx = Context::default();
+        for _ in 0..2 {
+            let result = limiter
+                .check_rate_limited_and_update(&namespace.into(), &ctx, 1, true)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+        }
+        let result = limiter
+            .check_rate_limited_and_update(&namespace.into(), &ctx, 1, true)
+            .await
+            .unwrap();
+        assert!(result.limited);
+        assert_eq!(result.counters[0].remaining(), Some(0));
+    }
+}
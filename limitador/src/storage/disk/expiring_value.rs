@@ -20,10 +20,6 @@ impl ExpiringValue {
         self.value
     }
 
-    pub fn value(&self) -> u64 {
-        self.value_at(SystemTime::now())
-    }
-
     #[must_use]
     pub fn update(self, delta: u64, ttl: Duration, now: SystemTime) -> Self {
         let expiry = if self.expiry <= now {
@@ -48,10 +44,8 @@ impl ExpiringValue {
         }
     }
 
-    pub fn ttl(&self) -> Duration {
-        self.expiry
-            .duration_since(SystemTime::now())
-            .unwrap_or(Duration::ZERO)
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expiry.duration_since(when).unwrap_or(Duration::ZERO)
     }
 }
 
@@ -145,7 +139,7 @@ mod tests {
     fn updates_when_expired() {
         let now = SystemTime::now();
         let val = ExpiringValue::new(42, now);
-        assert_eq!(val.ttl(), Duration::ZERO);
+        assert_eq!(val.ttl_at(now), Duration::ZERO);
         let val = val.update(3, Duration::from_secs(10), now);
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 3);
     }
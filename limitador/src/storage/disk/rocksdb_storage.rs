@@ -1,3 +1,4 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
 use crate::limit::Limit;
 use crate::storage::disk::expiring_value::ExpiringValue;
@@ -6,18 +7,38 @@ use crate::storage::keys::bin::{
     key_for_counter, partial_counter_from_counter_key, prefix_for_namespace,
 };
 use crate::storage::{Authorization, CounterStorage, StorageErr};
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::RefMut;
+use dashmap::DashMap;
+use metrics::{counter, histogram};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::compaction_filter::CompactionFilter;
+use rocksdb::compaction_filter_factory::{CompactionFilterContext, CompactionFilterFactory};
 use rocksdb::{
     CompactionDecision, DBCompressionType, DBWithThreadMode, IteratorMode, MultiThreaded, Options,
     DB,
 };
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::CStr;
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tracing::debug_span;
 
 pub struct RocksDbStorage {
     db: DBWithThreadMode<MultiThreaded>,
+    clock: Arc<dyn Clock>,
+    capped_counters: DashMap<Limit, LiveCounters>,
+}
+
+// The keys of the live counters of a limit with `max_counters`, along with when
+// they were last seen to expire, so that admitting a counter only goes through
+// them rather than all the counters of the namespace. Loaded from the DB on
+// first use.
+#[derive(Default)]
+struct LiveCounters {
+    expiries: HashMap<Vec<u8>, SystemTime>,
 }
 
 impl CounterStorage for RocksDbStorage {
@@ -25,7 +46,7 @@ impl CounterStorage for RocksDbStorage {
     fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
         let key = key_for_counter(counter);
         let value = self.insert_or_update(&key, counter, 0)?;
-        Ok(counter.max_value() >= value.value() + delta)
+        Ok(counter.max_value() >= value.value_at(self.clock.now()) + delta)
     }
 
     #[tracing::instrument(skip_all)]
@@ -48,6 +69,7 @@ impl CounterStorage for RocksDbStorage {
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
         let mut keys: Vec<Vec<u8>> = Vec::with_capacity(counters.len());
+        let now = self.clock.now();
 
         for counter in &mut *counters {
             let key = key_for_counter(counter);
@@ -62,18 +84,13 @@ impl CounterStorage for RocksDbStorage {
                 Some(raw) => {
                     let slice: &[u8] = raw.as_ref();
                     let value: ExpiringValue = slice.try_into()?;
-                    (value.value(), value.ttl())
+                    (value.value_at(now), value.ttl_at(now))
                 }
             };
 
             if load_counters {
                 counter.set_expires_in(ttl);
-                counter.set_remaining(
-                    counter
-                        .max_value()
-                        .checked_sub(val + delta)
-                        .unwrap_or_default(),
-                );
+                counter.set_remaining(counter.max_value().saturating_sub(val + delta));
             }
 
             if counter.max_value() < val + delta {
@@ -95,6 +112,7 @@ impl CounterStorage for RocksDbStorage {
     #[tracing::instrument(skip_all)]
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
         let mut counters = HashSet::default();
+        let now = self.clock.now();
         let namepaces: BTreeSet<&str> = limits.iter().map(|l| l.namespace().as_ref()).collect();
         for ns in namepaces {
             let mut iterator = self.db.prefix_iterator(prefix_for_namespace(ns));
@@ -113,16 +131,15 @@ impl CounterStorage for RocksDbStorage {
                         if counter.namespace().as_ref() != ns {
                             break;
                         }
+                        // the other limits of the namespace
+                        let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit())
+                        else {
+                            continue;
+                        };
                         let value: ExpiringValue = value.as_ref().try_into()?;
-                        for limit in limits {
-                            if limit.deref() == counter.limit() {
-                                counter.update_to_limit(Arc::clone(limit));
-                                let ttl = value.ttl();
-                                counter.set_expires_in(ttl);
-                                counter.set_remaining(limit.max_value() - value.value());
-                                break;
-                            }
-                        }
+                        counter.update_to_limit(Arc::clone(limit));
+                        counter.set_expires_in(value.ttl_at(now));
+                        counter.set_remaining(limit.max_value() - value.value_at(now));
                         if counter.expires_in().expect("Duration needs to be set") > Duration::ZERO
                         {
                             counters.insert(counter);
@@ -136,6 +153,9 @@ impl CounterStorage for RocksDbStorage {
 
     #[tracing::instrument(skip_all)]
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        for limit in limits {
+            self.capped_counters.remove(limit.deref());
+        }
         let counters = self.get_counters(limits)?;
         for counter in &counters {
             let span = debug_span!("datastore");
@@ -147,6 +167,7 @@ impl CounterStorage for RocksDbStorage {
 
     #[tracing::instrument(skip_all)]
     fn clear(&self) -> Result<(), StorageErr> {
+        self.capped_counters.clear();
         let span = debug_span!("datastore");
         let _entered = span.enter();
         for entry in self.db.iterator(IteratorMode::Start) {
@@ -156,29 +177,197 @@ impl CounterStorage for RocksDbStorage {
         }
         Ok(())
     }
+
+    // The live counters of the limit are locked from counting them to creating
+    // `counter`, so that concurrent requests can't both take the last room left.
+    #[tracing::instrument(skip_all)]
+    fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
+        let mut live = self.live_counters_of(counter.limit(), now)?;
+        let key = key_for_counter(counter);
+        if live.expiries.contains_key(&key) {
+            return Ok(true);
+        }
+        if live.expiries.len() as u64 >= max_counters {
+            return Ok(false);
+        }
+        self.insert_or_update(&key, counter, 0)?;
+        live.expiries.insert(key, now + counter.window());
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn can_admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let live = self.live_counters_of(counter.limit(), self.clock.now())?;
+        Ok(live.expiries.contains_key(&key_for_counter(counter))
+            || (live.expiries.len() as u64) < max_counters)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let now = self.clock.now();
+        if limit.max_counters().is_some() {
+            let live = self.live_counters_of(limit, now)?;
+            return Ok(live.expiries.len() as u64);
+        }
+        Ok(self.scan_live_counters(limit, now)?.len() as u64)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        // replaces whatever the counter was at, as the merges of the hits
+        // that follow build on it
+        let key = key_for_counter(counter);
+        let expiring_value = ExpiringValue::new(value, expires_at);
+        {
+            let span = debug_span!("datastore");
+            let _entered = span.enter();
+            self.db
+                .put(&key, <ExpiringValue as Into<Vec<u8>>>::into(expiring_value))?;
+        }
+        if counter.is_qualified() && expires_at > self.clock.now() {
+            if let Some(mut live) = self.capped_counters.get_mut(counter.limit()) {
+                live.expiries.insert(key, expires_at);
+            }
+        }
+        Ok(())
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    fn backend(&self) -> &'static str {
+        "disk"
+    }
+}
+
+/// Hands a filter to each compaction, counting them.
+struct ExpiredValueFilterFactory {
+    // drops the expired values when set
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl CompactionFilterFactory for ExpiredValueFilterFactory {
+    type Filter = ExpiredValueFilter;
+
+    fn create(&mut self, _context: CompactionFilterContext) -> Self::Filter {
+        counter!("disk_compactions").increment(1);
+        ExpiredValueFilter {
+            clock: self.clock.clone(),
+        }
+    }
+
+    fn name(&self) -> &CStr {
+        c"ExpiredValueFilterFactory"
+    }
+}
+
+struct ExpiredValueFilter {
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl CompactionFilter for ExpiredValueFilter {
+    fn filter(&mut self, _level: u32, _key: &[u8], value: &[u8]) -> CompactionDecision {
+        let Some(clock) = &self.clock else {
+            return CompactionDecision::Keep;
+        };
+        if let Ok(value) = ExpiringValue::try_from(value) {
+            if value.value_at(clock.now()) != 0 {
+                return CompactionDecision::Keep;
+            }
+        }
+        counter!("disk_compaction_removals").increment(1);
+        CompactionDecision::Remove
+    }
+
+    fn name(&self) -> &CStr {
+        c"ExpiredValueFilter"
+    }
 }
 
 impl RocksDbStorage {
-    pub fn open<P: AsRef<std::path::Path>>(path: P, mode: OptimizeFor) -> Result<Self, StorageErr> {
+    pub fn open<P: AsRef<Path>>(path: P, mode: OptimizeFor) -> Result<Self, StorageErr> {
+        Self::open_with_clock(path, mode, Arc::new(SystemClock))
+    }
+
+    /// Opens a new DB at `path` holding the counters of the `checkpoint`, see
+    /// [`Self::checkpoint`]. The checkpoint itself is left untouched, and
+    /// `path` must not hold a DB already.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(
+        checkpoint: P,
+        path: Q,
+        mode: OptimizeFor,
+    ) -> Result<Self, StorageErr> {
+        let (checkpoint, path) = (checkpoint.as_ref(), path.as_ref());
+        if !checkpoint.join("CURRENT").is_file() {
+            return Err(checkpoint_err(format!(
+                "{} isn't a checkpoint",
+                checkpoint.display()
+            )));
+        }
+        if path.join("CURRENT").exists() {
+            return Err(checkpoint_err(format!(
+                "won't restore over the DB at {}",
+                path.display()
+            )));
+        }
+        let copy = |err: std::io::Error| {
+            checkpoint_err(format!(
+                "couldn't copy {} to {}: {err}",
+                checkpoint.display(),
+                path.display()
+            ))
+        };
+        std::fs::create_dir_all(path).map_err(copy)?;
+        // checkpoints are flat, with hard links to the SST files of the DB
+        for entry in std::fs::read_dir(checkpoint).map_err(copy)? {
+            let entry = entry.map_err(copy)?;
+            if entry.file_type().map_err(copy)?.is_file() {
+                std::fs::copy(entry.path(), path.join(entry.file_name())).map_err(copy)?;
+            }
+        }
+        Self::open(path, mode)
+    }
+
+    /// Writes a consistent, point-in-time copy of the DB to `path`, that must
+    /// not exist yet, while it keeps serving requests. On the same file system
+    /// as the DB, the immutable files are hard linked rather than copied.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), StorageErr> {
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    pub fn open_with_clock<P: AsRef<Path>>(
+        path: P,
+        mode: OptimizeFor,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, StorageErr> {
         let mut opts = Options::default();
         match mode {
             OptimizeFor::Space => {
                 opts.set_compression_type(DBCompressionType::Bz2);
-                opts.set_compaction_filter("ExpiredValueFilter", |_level, _key, value| {
-                    if let Ok(value) = ExpiringValue::try_from(value) {
-                        if value.value_at(SystemTime::now()) != 0 {
-                            return CompactionDecision::Keep;
-                        }
-                    }
-                    CompactionDecision::Remove
+                opts.set_compaction_filter_factory(ExpiredValueFilterFactory {
+                    clock: Some(Arc::clone(&clock)),
                 });
             }
             OptimizeFor::Throughput => {
                 opts.set_compression_type(DBCompressionType::None);
+                opts.set_compaction_filter_factory(ExpiredValueFilterFactory { clock: None });
             }
         }
-        opts.set_merge_operator_associative("ExpiringValueMerge", |_key, start, operands| {
-            let now = SystemTime::now();
+        let merge_clock = Arc::clone(&clock);
+        opts.set_merge_operator_associative("ExpiringValueMerge", move |_key, start, operands| {
+            counter!("disk_merges").increment(1);
+            histogram!("disk_merge_operands").record(operands.len() as f64);
+            let now = merge_clock.now();
             let mut value: ExpiringValue = start
                 .map(|raw: &[u8]| raw.try_into().unwrap_or_default())
                 .unwrap_or_default();
@@ -192,7 +381,93 @@ impl RocksDbStorage {
         });
         opts.create_if_missing(true);
         let db = DB::open(&opts, path).unwrap();
-        Ok(Self { db })
+        Ok(Self {
+            db,
+            clock,
+            capped_counters: DashMap::new(),
+        })
+    }
+
+    // The live counters of `limit`, locked until dropped, loaded from the DB if
+    // not yet tracked, and otherwise without the ones expired by `now`
+    fn live_counters_of(
+        &self,
+        limit: &Limit,
+        now: SystemTime,
+    ) -> Result<RefMut<'_, Limit, LiveCounters>, StorageErr> {
+        let mut live = match self.capped_counters.entry(limit.clone()) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                let expiries = self.scan_live_counters(limit, now)?;
+                return Ok(entry.insert(LiveCounters { expiries }));
+            }
+        };
+        let lapsed: Vec<Vec<u8>> = live
+            .expiries
+            .iter()
+            .filter(|(_, expiry)| **expiry <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in lapsed {
+            // unless hit again after its window
+            match self.expiry_of(&key, now)? {
+                Some(expiry) => live.expiries.insert(key, expiry),
+                None => live.expiries.remove(&key),
+            };
+        }
+        Ok(live)
+    }
+
+    // The keys of the live qualified counters of `limit`, along with when they
+    // expire, going through all the counters of its namespace
+    fn scan_live_counters(
+        &self,
+        limit: &Limit,
+        now: SystemTime,
+    ) -> Result<HashMap<Vec<u8>, SystemTime>, StorageErr> {
+        let ns = limit.namespace().as_ref();
+        let mut live = HashMap::new();
+        let mut iterator = self.db.prefix_iterator(prefix_for_namespace(ns));
+        loop {
+            let next = {
+                let span = debug_span!("datastore");
+                let _entered = span.enter();
+                iterator.next()
+            };
+            let Some(entry) = next else {
+                break;
+            };
+            let (key, value) = entry?;
+            let counter = partial_counter_from_counter_key(key.as_ref());
+            if counter.namespace().as_ref() != ns {
+                break;
+            }
+            if counter.is_qualified() && counter.limit() == limit {
+                let value: ExpiringValue = value.as_ref().try_into()?;
+                let ttl = value.ttl_at(now);
+                if ttl > Duration::ZERO {
+                    live.insert(key.to_vec(), now + ttl);
+                }
+            }
+        }
+        Ok(live)
+    }
+
+    // When the counter under `key` expires, if it's still live
+    fn expiry_of(&self, key: &[u8], now: SystemTime) -> Result<Option<SystemTime>, StorageErr> {
+        let entry = {
+            let span = debug_span!("datastore");
+            let _entered = span.enter();
+            self.db.get(key)?
+        };
+        match entry {
+            None => Ok(None),
+            Some(raw) => {
+                let value: ExpiringValue = raw.as_slice().try_into()?;
+                let ttl = value.ttl_at(now);
+                Ok((ttl > Duration::ZERO).then(|| now + ttl))
+            }
+        }
     }
 
     fn insert_or_update(
@@ -201,7 +476,7 @@ impl RocksDbStorage {
         counter: &Counter,
         delta: u64,
     ) -> Result<ExpiringValue, StorageErr> {
-        let now = SystemTime::now();
+        let now = self.clock.now();
         let entry = {
             let span = debug_span!("datastore");
             let _entered = span.enter();
@@ -227,18 +502,57 @@ impl RocksDbStorage {
     }
 }
 
+fn checkpoint_err(msg: String) -> StorageErr {
+    StorageErr {
+        msg,
+        source: None,
+        transient: false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::RocksDbStorage;
+    use super::{ExpiredValueFilter, RocksDbStorage};
+    use crate::clock::SystemClock;
     use crate::counter::Counter;
     use crate::limit::Limit;
+    use crate::storage::disk::expiring_value::ExpiringValue;
     use crate::storage::disk::OptimizeFor;
     use crate::storage::CounterStorage;
-    use std::collections::HashMap;
+    use rocksdb::compaction_filter::CompactionFilter;
+    use rocksdb::CompactionDecision;
+    use std::collections::{HashMap, HashSet};
     use std::fs;
-    use std::time::Duration;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
     use tempfile::TempDir;
 
+    #[test]
+    fn compactions_only_drop_expired_values_when_optimizing_for_space() {
+        let live: Vec<u8> =
+            ExpiringValue::new(1, SystemTime::now() + Duration::from_secs(60)).into();
+        let expired: Vec<u8> =
+            ExpiringValue::new(1, SystemTime::now() - Duration::from_secs(1)).into();
+
+        let mut space = ExpiredValueFilter {
+            clock: Some(Arc::new(SystemClock)),
+        };
+        assert!(matches!(
+            space.filter(0, b"key", &live),
+            CompactionDecision::Keep
+        ));
+        assert!(matches!(
+            space.filter(0, b"key", &expired),
+            CompactionDecision::Remove
+        ));
+
+        let mut throughput = ExpiredValueFilter { clock: None };
+        assert!(matches!(
+            throughput.filter(0, b"key", &expired),
+            CompactionDecision::Keep
+        ));
+    }
+
     #[test]
     fn opens_db_on_disk() {
         let namespace = "test_namespace";
@@ -290,4 +604,83 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn admits_counters_up_to_the_cap_after_reopening() {
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        limit.set_max_counters(2);
+        let counter = |app_id: &str| {
+            let map = HashMap::from([("app_id".to_string(), app_id.to_string())]);
+            Counter::new(limit.clone(), &map.into())
+                .unwrap()
+                .expect("must have a counter")
+        };
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        {
+            let storage = RocksDbStorage::open(tmp.path(), OptimizeFor::Throughput)
+                .expect("We should have a storage");
+            assert!(storage.admit_counter(&counter("a"), 2).unwrap());
+            assert!(storage.admit_counter(&counter("b"), 2).unwrap());
+            assert!(!storage.can_admit_counter(&counter("c"), 2).unwrap());
+            assert!(!storage.admit_counter(&counter("c"), 2).unwrap());
+            assert_eq!(storage.cardinality(&limit).unwrap(), 2);
+        }
+
+        {
+            let storage = RocksDbStorage::open(tmp.path(), OptimizeFor::Throughput)
+                .expect("We should still have a storage");
+            assert!(storage.admit_counter(&counter("a"), 2).unwrap());
+            assert!(!storage.admit_counter(&counter("c"), 2).unwrap());
+            storage
+                .delete_counters(&HashSet::from([Arc::new(limit.clone())]))
+                .unwrap();
+            assert!(storage.admit_counter(&counter("c"), 2).unwrap());
+            assert_eq!(storage.cardinality(&limit).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn restores_the_counters_of_a_checkpoint() {
+        let limit = Limit::new(
+            "test_namespace",
+            2,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let checkpoint = tmp.path().join("checkpoint");
+        let storage = RocksDbStorage::open(tmp.path().join("db"), OptimizeFor::Throughput)
+            .expect("We should have a storage");
+        storage.update_counter(&counter, 2).unwrap();
+        storage.checkpoint(&checkpoint).unwrap();
+        storage.clear().unwrap();
+
+        assert!(
+            RocksDbStorage::restore(&checkpoint, tmp.path().join("db"), OptimizeFor::Throughput)
+                .is_err(),
+            "Shouldn't overwrite a DB"
+        );
+        let restored = RocksDbStorage::restore(
+            &checkpoint,
+            tmp.path().join("restored"),
+            OptimizeFor::Throughput,
+        )
+        .expect("We should have restored the checkpoint");
+        assert!(!restored.is_within_limits(&counter, 1).unwrap());
+        assert!(storage.is_within_limits(&counter, 1).unwrap());
+    }
 }
@@ -18,12 +18,16 @@ pub struct CrCounterValue<A: Ord> {
 #[allow(dead_code)]
 impl<A: Ord> CrCounterValue<A> {
     pub fn new(actor: A, max_value: u64, time_window: Duration) -> Self {
+        Self::new_at(actor, max_value, time_window, SystemTime::now())
+    }
+
+    pub fn new_at(actor: A, max_value: u64, time_window: Duration, when: SystemTime) -> Self {
         Self {
             ourselves: actor,
             max_value,
             value: Default::default(),
             others: RwLock::default(),
-            expiry: AtomicExpiryTime::new(SystemTime::now() + time_window),
+            expiry: AtomicExpiryTime::new(when + time_window),
         }
     }
 
@@ -113,7 +117,11 @@ impl<A: Ord> CrCounterValue<A> {
     }
 
     pub fn ttl(&self) -> Duration {
-        self.expiry.ttl()
+        self.ttl_at(SystemTime::now())
+    }
+
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expiry.ttl_at(when)
     }
 
     pub fn expiry(&self) -> SystemTime {
@@ -298,6 +306,6 @@ mod tests {
         a.inc(3, later);
         b.inc(2, later);
         a.merge(b);
-        assert!(a.expiry.ttl() < sooner);
+        assert!(a.ttl() < sooner);
     }
 }
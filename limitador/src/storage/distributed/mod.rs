@@ -8,6 +8,7 @@ use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
 use tracing::debug;
 
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
 use crate::limit::{Context, Limit};
 use crate::storage::distributed::cr_counter_value::CrCounterValue;
@@ -26,6 +27,7 @@ pub struct CrInMemoryStorage {
     identifier: String,
     limits: Arc<RwLock<LimitsMap>>,
     broker: Broker,
+    clock: Arc<dyn Clock>,
 }
 
 impl CounterStorage for CrInMemoryStorage {
@@ -36,7 +38,7 @@ impl CounterStorage for CrInMemoryStorage {
         let mut value = 0;
         let key = encode_counter_to_key(counter);
         if let Some(counter_value) = limits.get(&key) {
-            value = counter_value.value.read()
+            value = counter_value.value.read_at(self.clock.now())
         }
         Ok(counter.max_value() >= value + delta)
     }
@@ -51,10 +53,11 @@ impl CounterStorage for CrInMemoryStorage {
                 counter: Counter::new(limit.clone(), &Context::default())
                     .expect("counter creation can't fail! no vars to resolve!")
                     .expect("must have a counter"),
-                value: CrCounterValue::new(
+                value: CrCounterValue::new_at(
                     self.identifier.clone(),
                     limit.max_value(),
                     Duration::from_secs(limit.seconds()),
+                    self.clock.now(),
                 ),
             }));
         }
@@ -64,7 +67,7 @@ impl CounterStorage for CrInMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
         let mut limits = self.limits.write().unwrap();
-        let now = SystemTime::now();
+        let now = self.clock.now();
 
         let key = encode_counter_to_key(counter);
         match limits.entry(key.clone()) {
@@ -73,10 +76,11 @@ impl CounterStorage for CrInMemoryStorage {
                 let value = Arc::new(CounterEntry {
                     key: key.clone(),
                     counter: counter.clone(),
-                    value: CrCounterValue::new(
+                    value: CrCounterValue::new_at(
                         self.identifier.clone(),
                         counter.max_value(),
                         duration,
+                        now,
                     ),
                 });
                 self.increment_counter(value.clone(), delta, now);
@@ -98,7 +102,7 @@ impl CounterStorage for CrInMemoryStorage {
     ) -> Result<Authorization, StorageErr> {
         let mut first_limited = None;
         let mut counter_values_to_update: Vec<Vec<u8>> = Vec::new();
-        let now = SystemTime::now();
+        let now = self.clock.now();
 
         let mut process_counter =
             |counter: &mut Counter, value: u64, delta: u64| -> Option<Authorization> {
@@ -132,7 +136,7 @@ impl CounterStorage for CrInMemoryStorage {
                     None => false,
                     Some(store_value) => {
                         if let Some(limited) =
-                            process_counter(counter, store_value.value.read(), delta)
+                            process_counter(counter, store_value.value.read_at(now), delta)
                         {
                             if !load_counters {
                                 return Ok(limited);
@@ -151,14 +155,17 @@ impl CounterStorage for CrInMemoryStorage {
                 let store_value = limits.entry(key.clone()).or_insert(Arc::new(CounterEntry {
                     key: key.clone(),
                     counter: counter.clone(),
-                    value: CrCounterValue::new(
+                    value: CrCounterValue::new_at(
                         self.identifier.clone(),
                         counter.max_value(),
                         counter.window(),
+                        now,
                     ),
                 }));
 
-                if let Some(limited) = process_counter(counter, store_value.value.read(), delta) {
+                if let Some(limited) =
+                    process_counter(counter, store_value.value.read_at(now), delta)
+                {
                     if !load_counters {
                         return Ok(limited);
                     }
@@ -184,12 +191,13 @@ impl CounterStorage for CrInMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
+        let now = self.clock.now();
         let limits_map = self.limits.read().unwrap();
         for (_, counter_entry) in limits_map.iter() {
             if limits.contains(counter_entry.counter.limit()) {
                 let mut counter: Counter = counter_entry.counter.clone();
-                counter.set_remaining(counter.max_value() - counter_entry.value.read());
-                counter.set_expires_in(counter_entry.value.ttl());
+                counter.set_remaining(counter.max_value() - counter_entry.value.read_at(now));
+                counter.set_expires_in(counter_entry.value.ttl_at(now));
                 if counter.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter);
                 }
@@ -211,20 +219,90 @@ impl CounterStorage for CrInMemoryStorage {
         self.limits.write().unwrap().clear();
         Ok(())
     }
+
+    // The counters are locked from counting them to creating `counter`, so that
+    // concurrent requests to this node can't get the limit past its cap. The
+    // ones to the other nodes of the cluster still can, until they're shared.
+    #[tracing::instrument(skip_all)]
+    fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
+        let mut limits = self.limits.write().unwrap();
+        let key = encode_counter_to_key(counter);
+        if limits
+            .get(&key)
+            .is_some_and(|entry| entry.value.ttl_at(now) > Duration::ZERO)
+        {
+            return Ok(true);
+        }
+        if live_counters_of(&limits, counter.limit(), now) >= max_counters {
+            return Ok(false);
+        }
+        limits.insert(
+            key.clone(),
+            Arc::new(CounterEntry {
+                key,
+                counter: counter.clone(),
+                value: CrCounterValue::new_at(
+                    self.identifier.clone(),
+                    counter.max_value(),
+                    counter.window(),
+                    now,
+                ),
+            }),
+        );
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn can_admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
+        let limits = self.limits.read().unwrap();
+        Ok(limits
+            .get(&encode_counter_to_key(counter))
+            .is_some_and(|entry| entry.value.ttl_at(now) > Duration::ZERO)
+            || live_counters_of(&limits, counter.limit(), now) < max_counters)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let limits = self.limits.read().unwrap();
+        Ok(live_counters_of(&limits, limit, self.clock.now()))
+    }
+
+    fn backend(&self) -> &'static str {
+        "distributed"
+    }
 }
 
 impl CrInMemoryStorage {
     pub fn new(
+        identifier: String,
+        cache_size: u64,
+        listen_address: String,
+        peer_urls: Vec<String>,
+    ) -> Self {
+        Self::with_clock(
+            identifier,
+            cache_size,
+            listen_address,
+            peer_urls,
+            Arc::new(SystemClock),
+        )
+    }
+
+    pub fn with_clock(
         identifier: String,
         _cache_size: u64,
         listen_address: String,
         peer_urls: Vec<String>,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         let listen_address = listen_address.to_socket_addrs().unwrap().next().unwrap();
         let peer_urls = peer_urls.clone();
         let limits = Arc::new(RwLock::new(LimitsMap::new()));
 
         let limits_clone = limits.clone();
+        let merge_clock = Arc::clone(&clock);
 
         let (re_sync_queue_tx, mut re_sync_queue_rx) = mpsc::channel(100);
         let broker = grpc::Broker::new(
@@ -240,9 +318,10 @@ impl CrInMemoryStorage {
                 );
                 let limits = limits_clone.read().unwrap();
                 let value = limits.get(&update.key).unwrap();
-                value
-                    .value
-                    .merge((UNIX_EPOCH + Duration::from_secs(update.expires_at), values).into());
+                value.value.merge_at(
+                    (UNIX_EPOCH + Duration::from_secs(update.expires_at), values).into(),
+                    merge_clock.now(),
+                );
             }),
             re_sync_queue_tx,
         );
@@ -257,9 +336,10 @@ impl CrInMemoryStorage {
         // process the re-sync requests...
         {
             let limits = limits.clone();
+            let clock = Arc::clone(&clock);
             tokio::spawn(async move {
                 while let Some(sender) = re_sync_queue_rx.recv().await {
-                    process_re_sync(&limits, sender).await;
+                    process_re_sync(&limits, clock.as_ref(), sender).await;
                 }
             });
         }
@@ -268,6 +348,7 @@ impl CrInMemoryStorage {
             identifier,
             limits,
             broker,
+            clock,
         }
     }
 
@@ -291,7 +372,11 @@ impl CrInMemoryStorage {
     }
 }
 
-async fn process_re_sync(limits: &Arc<RwLock<LimitsMap>>, sender: Sender<Option<CounterUpdate>>) {
+async fn process_re_sync(
+    limits: &Arc<RwLock<LimitsMap>>,
+    clock: &dyn Clock,
+    sender: Sender<Option<CounterUpdate>>,
+) {
     // sending all the counters to the peer might take a while, so we don't want to lock
     // the limits map for too long, lets figure first get the list of keys that needs to be sent.
     let keys: Vec<_> = {
@@ -304,7 +389,7 @@ async fn process_re_sync(limits: &Arc<RwLock<LimitsMap>>, sender: Sender<Option<
             let limits = limits.read().unwrap();
             limits.get(&key).and_then(|store_value| {
                 let (expiry, ourself, value) = store_value.value.local_values();
-                if value == 0 || expiry <= SystemTime::now() {
+                if value == 0 || expiry <= clock.now() {
                     None // no point in sending a counter that is empty
                 } else {
                     let values = HashMap::from([(ourself.clone(), value)]);
@@ -331,6 +416,18 @@ async fn process_re_sync(limits: &Arc<RwLock<LimitsMap>>, sender: Sender<Option<
     _ = sender.send(None).await;
 }
 
+// The number of qualified counters of `limit` live at `now`
+fn live_counters_of(limits: &LimitsMap, limit: &Limit, now: SystemTime) -> u64 {
+    limits
+        .values()
+        .filter(|entry| {
+            entry.counter.is_qualified()
+                && entry.counter.limit() == limit
+                && entry.value.ttl_at(now) > Duration::ZERO
+        })
+        .count() as u64
+}
+
 fn encode_counter_to_key(counter: &Counter) -> Vec<u8> {
     key_for_counter_v2(counter)
 }
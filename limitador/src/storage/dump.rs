@@ -0,0 +1,120 @@
+//! A portable format for the counters, to move them from one storage to
+//! another, whatever their kind, e.g. from disk to Redis.
+//!
+//! [`Storage::export`](crate::storage::Storage::export) dumps the live
+//! counters of the limits of a namespace known to a storage, that
+//! [`Storage::import`](crate::storage::Storage::import), or its async
+//! counterpart, sets in another one. The dumps serialize to JSON, one per line
+//! when streamed.
+
+use crate::counter::Counter;
+use crate::limit::Limit;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A counter, along with what identifies its limit across storages.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterDump {
+    pub namespace: String,
+    /// The window of the limit, in seconds.
+    pub seconds: u64,
+    pub conditions: BTreeSet<String>,
+    /// The value of each variable of the limit.
+    pub variables: BTreeMap<String, String>,
+    pub value: u64,
+    /// When the counter expires, in milliseconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+impl CounterDump {
+    /// Dumps a counter loaded from a storage, along with its remaining hits
+    /// and TTL, or `None` when it has neither, or has expired.
+    pub fn of(counter: &Counter, now: SystemTime) -> Option<Self> {
+        let remaining = counter.remaining()?;
+        let ttl = counter.expires_in().filter(|ttl| !ttl.is_zero())?;
+        Some(Self {
+            namespace: counter.namespace().as_ref().to_owned(),
+            seconds: counter.limit().seconds(),
+            conditions: counter.limit().conditions().into_iter().collect(),
+            variables: counter.set_variables().clone(),
+            value: counter.max_value().saturating_sub(remaining),
+            expires_at: (now + ttl)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        })
+    }
+
+    pub fn expiry(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.expires_at)
+    }
+
+    /// Whether this is a counter of `limit`, going by what identifies a limit:
+    /// its namespace, window, conditions and variables.
+    pub fn is_of(&self, limit: &Limit) -> bool {
+        self.namespace == limit.namespace().as_ref()
+            && self.seconds == limit.seconds()
+            && self.conditions == limit.conditions().into_iter().collect()
+            && self.variables.keys().cloned().collect::<BTreeSet<_>>()
+                == limit.variables().into_iter().collect()
+    }
+
+    /// The counter of `limit` this is a dump of, see [`Self::is_of`].
+    pub fn counter_of(&self, limit: Arc<Limit>) -> Counter {
+        Counter::resolved_vars(limit, self.variables.clone().into_iter().collect())
+            .expect("resolving set variables can't fail")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CounterDump;
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn identifies_the_limit_of_a_counter() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([
+            ("app_id".to_string(), "foo".to_string()),
+            ("req_method".to_string(), "GET".to_string()),
+        ])
+        .into();
+        let mut counter = Counter::new(limit.clone(), &ctx).unwrap().unwrap();
+        counter.set_remaining(7);
+        counter.set_expires_in(Duration::from_secs(30));
+
+        let dump = CounterDump::of(&counter, SystemTime::now()).unwrap();
+        assert_eq!(dump.value, 3);
+        assert!(dump.is_of(&limit));
+        assert_eq!(dump.counter_of(Arc::new(limit)), counter);
+
+        let mut other = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'POST'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        assert!(!dump.is_of(&other));
+        other = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["user_id".try_into().expect("failed parsing!")],
+        );
+        assert!(!dump.is_of(&other));
+    }
+}
@@ -0,0 +1,681 @@
+use crate::clock::Clock;
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+use async_trait::async_trait;
+use metrics::gauge;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// Delegates to a primary [`AsyncCounterStorage`], falling back to a
+/// secondary, local, [`CounterStorage`] while the primary is unhealthy.
+///
+/// The primary turns unhealthy after a number of
+/// [transient](StorageErr::is_transient) failures in a row, see
+/// [`Self::fallback_after`]; the calls failing that way are served by the
+/// secondary all along. While falling back, a call is let through to the
+/// primary every [probe interval](Self::probe_interval): once enough of
+/// them succeed in a row, see [`Self::recover_after`], the primary is healthy
+/// again. The secondary is then cleared, and the hits it took
+/// [replayed](Self::replay) into the primary in the background, if asked to.
+///
+/// Deleting or clearing counters while falling back only reaches the
+/// secondary at first: the primary gets it on recovery, before any replay.
+/// Should the primary fail that way before falling back, the call fails.
+///
+/// Whether falling back is published as the `storage_fallback_active` gauge,
+/// labeled by the backend of the primary.
+pub struct FallbackStorage<P, S> {
+    primary: Arc<P>,
+    secondary: S,
+    fallback_after: u32,
+    probe_interval: Duration,
+    recover_after: u32,
+    replay: bool,
+    falling_back: AtomicBool,
+    // transient failures in a row when healthy, successful probes in a row
+    // when falling back
+    streak: AtomicU32,
+    last_probe: Mutex<Instant>,
+    // the hits the secondary took, with when they expire
+    deltas: Mutex<HashMap<Counter, (u64, Instant)>>,
+    // to add back to the secondary once cleared
+    limits: Mutex<HashSet<Limit>>,
+    // the deletions the primary missed, in order
+    deferred: Mutex<Vec<Deferred>>,
+}
+
+enum Deferred {
+    Delete(HashSet<Arc<Limit>>),
+    Clear,
+}
+
+impl<P: AsyncCounterStorage + 'static, S: CounterStorage> FallbackStorage<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        gauge!("storage_fallback_active", "backend" => primary.backend()).set(0);
+        Self {
+            primary: Arc::new(primary),
+            secondary,
+            fallback_after: 1,
+            probe_interval: Duration::from_secs(1),
+            recover_after: 3,
+            replay: false,
+            falling_back: AtomicBool::new(false),
+            streak: AtomicU32::new(0),
+            last_probe: Mutex::new(Instant::now()),
+            deltas: Mutex::default(),
+            limits: Mutex::default(),
+            deferred: Mutex::default(),
+        }
+    }
+
+    /// Falls back after `failures` transient failures in a row of the primary.
+    pub fn fallback_after(mut self, failures: u32) -> Self {
+        self.fallback_after = failures.max(1);
+        self
+    }
+
+    /// How often a call is let through to the primary while falling back.
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    /// Recovers after `probes` calls in a row to the primary succeed.
+    pub fn recover_after(mut self, probes: u32) -> Self {
+        self.recover_after = probes.max(1);
+        self
+    }
+
+    /// Replays into the primary, when recovering, the hits the secondary took
+    /// on counters that haven't expired yet.
+    pub fn replay(mut self, replay: bool) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    pub fn is_falling_back(&self) -> bool {
+        self.falling_back.load(Ordering::Acquire)
+    }
+
+    fn use_primary(&self) -> bool {
+        if !self.is_falling_back() {
+            return true;
+        }
+        let mut last_probe = self.last_probe.lock().unwrap();
+        if last_probe.elapsed() >= self.probe_interval {
+            *last_probe = Instant::now();
+            return true;
+        }
+        false
+    }
+
+    /// Records the outcome of a call to the primary, returning it unless it is
+    /// for the secondary to serve the call.
+    fn settle<T>(&self, result: Result<T, StorageErr>) -> Option<Result<T, StorageErr>> {
+        match result {
+            Err(err) if err.is_transient() => {
+                if self.is_falling_back() {
+                    self.streak.store(0, Ordering::Release);
+                } else if self.streak.fetch_add(1, Ordering::AcqRel) + 1 >= self.fallback_after {
+                    *self.last_probe.lock().unwrap() = Instant::now();
+                    self.streak.store(0, Ordering::Release);
+                    if !self.falling_back.swap(true, Ordering::AcqRel) {
+                        gauge!("storage_fallback_active", "backend" => self.primary.backend())
+                            .set(1);
+                        warn!("Falling back to the secondary storage: {}", err);
+                    }
+                }
+                None
+            }
+            // the primary answered, even if with an error
+            result => {
+                if !self.is_falling_back() {
+                    self.streak.store(0, Ordering::Release);
+                } else if self.streak.fetch_add(1, Ordering::AcqRel) + 1 >= self.recover_after {
+                    self.recover();
+                }
+                Some(result)
+            }
+        }
+    }
+
+    fn recover(&self) {
+        if !self.falling_back.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        self.streak.store(0, Ordering::Release);
+        let deltas = std::mem::take(&mut *self.deltas.lock().unwrap());
+        let deferred = std::mem::take(&mut *self.deferred.lock().unwrap());
+        if let Err(err) = self.reset_secondary() {
+            warn!("Couldn't clear the secondary storage: {}", err);
+        }
+        gauge!("storage_fallback_active", "backend" => self.primary.backend()).set(0);
+        info!("Recovered the primary storage");
+        if deltas.is_empty() && deferred.is_empty() {
+            return;
+        }
+        // off the request that happened to recover it
+        let primary = Arc::clone(&self.primary);
+        tokio::spawn(async move {
+            for op in deferred {
+                let result = match &op {
+                    Deferred::Delete(limits) => primary.delete_counters(limits).await,
+                    Deferred::Clear => primary.clear().await,
+                };
+                if let Err(err) = result {
+                    warn!("Couldn't delete the counters the primary missed: {}", err);
+                }
+            }
+            if deltas.is_empty() {
+                return;
+            }
+            let now = Instant::now();
+            let mut replayed = 0;
+            for (counter, (delta, expires_at)) in deltas {
+                if expires_at <= now {
+                    continue;
+                }
+                match primary.update_counter(&counter, delta).await {
+                    Ok(()) => replayed += 1,
+                    Err(err) => warn!("Couldn't replay the hits on a counter: {}", err),
+                }
+            }
+            info!("Replayed the hits on {} counters", replayed);
+        });
+    }
+
+    fn reset_secondary(&self) -> Result<(), StorageErr> {
+        self.secondary.clear()?;
+        for limit in self.limits.lock().unwrap().iter() {
+            self.secondary.add_counter(limit)?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, counters: &[Counter], delta: u64) {
+        if !self.replay || delta == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut deltas = self.deltas.lock().unwrap();
+        for counter in counters {
+            deltas
+                .entry(counter.clone())
+                .or_insert((0, now + counter.window()))
+                .0 += delta;
+        }
+    }
+
+    /// Queues a deletion for the primary to get on recovery, failing unless
+    /// falling back.
+    fn defer(&self, op: Deferred) -> Result<(), StorageErr> {
+        // under the lock, for a concurrent recovery to either take it or fail it
+        let mut deferred = self.deferred.lock().unwrap();
+        if !self.is_falling_back() {
+            return Err(StorageErr {
+                msg: "the primary storage is unavailable".to_owned(),
+                source: None,
+                transient: true,
+            });
+        }
+        if let Deferred::Clear = op {
+            deferred.clear();
+        }
+        deferred.push(op);
+        Ok(())
+    }
+
+    fn forget(&self, limits: &HashSet<Arc<Limit>>) {
+        self.deltas
+            .lock()
+            .unwrap()
+            .retain(|counter, _| !limits.contains(counter.limit()));
+        self.limits
+            .lock()
+            .unwrap()
+            .retain(|limit| !limits.contains(limit));
+    }
+}
+
+#[async_trait]
+impl<P: AsyncCounterStorage + 'static, S: CounterStorage> AsyncCounterStorage
+    for FallbackStorage<P, S>
+{
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.primary.add_counter(limit)?;
+        self.secondary.add_counter(limit)?;
+        self.limits.lock().unwrap().insert(limit.clone());
+        Ok(())
+    }
+
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        if self.use_primary() {
+            let result = self.primary.is_within_limits(counter, delta).await;
+            if let Some(result) = self.settle(result) {
+                return result;
+            }
+        }
+        self.secondary.is_within_limits(counter, delta)
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        if self.use_primary() {
+            let result = self.primary.update_counter(counter, delta).await;
+            if let Some(result) = self.settle(result) {
+                return result;
+            }
+        }
+        self.secondary.update_counter(counter, delta)?;
+        self.record(std::slice::from_ref(counter), delta);
+        Ok(())
+    }
+
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        if self.use_primary() {
+            // the secondary needs the counters as they were, should this fail
+            let mut primary_counters = counters.clone();
+            let result = self
+                .primary
+                .check_and_update(&mut primary_counters, delta, load_counters)
+                .await;
+            if let Some(result) = self.settle(result) {
+                *counters = primary_counters;
+                return result;
+            }
+        }
+        let authorization = self
+            .secondary
+            .check_and_update(counters, delta, load_counters)?;
+        if let Authorization::Ok = authorization {
+            self.record(counters, delta);
+        }
+        Ok(authorization)
+    }
+
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        if self.use_primary() {
+            let result = self.primary.get_counters(limits).await;
+            if let Some(result) = self.settle(result) {
+                return result;
+            }
+        }
+        self.secondary.get_counters(limits)
+    }
+
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let result = if self.use_primary() {
+            self.settle(self.primary.delete_counters(limits).await)
+        } else {
+            None
+        };
+        result.unwrap_or_else(|| self.defer(Deferred::Delete(limits.clone())))?;
+        self.secondary.delete_counters(limits)?;
+        self.forget(limits);
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        let result = if self.use_primary() {
+            self.settle(self.primary.clear().await)
+        } else {
+            None
+        };
+        result.unwrap_or_else(|| self.defer(Deferred::Clear))?;
+        self.secondary.clear()?;
+        self.deltas.lock().unwrap().clear();
+        self.limits.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        if self.use_primary() {
+            let result = self.primary.admit_counter(counter, max_counters).await;
+            if let Some(result) = self.settle(result) {
+                return result;
+            }
+        }
+        self.secondary.admit_counter(counter, max_counters)
+    }
+
+    async fn can_admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        if self.use_primary() {
+            let result = self.primary.can_admit_counter(counter, max_counters).await;
+            if let Some(result) = self.settle(result) {
+                return result;
+            }
+        }
+        self.secondary.can_admit_counter(counter, max_counters)
+    }
+
+    async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        if self.use_primary() {
+            let result = self.primary.cardinality(limit).await;
+            if let Some(result) = self.settle(result) {
+                return result;
+            }
+        }
+        self.secondary.cardinality(limit)
+    }
+
+    // Only ever into the primary, which the counters would otherwise never reach
+    async fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        self.primary
+            .import_counter(counter, value, expires_at)
+            .await
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.primary.clock()
+    }
+
+    fn backend(&self) -> &'static str {
+        if self.is_falling_back() {
+            self.secondary.backend()
+        } else {
+            self.primary.backend()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FallbackStorage;
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+    use async_trait::async_trait;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// An in memory storage that can be taken down.
+    #[derive(Default)]
+    struct Switchable {
+        down: AtomicBool,
+        inner: InMemoryStorage,
+    }
+
+    impl Switchable {
+        fn check(&self) -> Result<(), StorageErr> {
+            if self.down.load(Ordering::Acquire) {
+                return Err(StorageErr {
+                    msg: "down".to_owned(),
+                    source: None,
+                    transient: true,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AsyncCounterStorage for Switchable {
+        fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+            CounterStorage::add_counter(&self.inner, limit)
+        }
+
+        async fn is_within_limits(
+            &self,
+            counter: &Counter,
+            delta: u64,
+        ) -> Result<bool, StorageErr> {
+            self.check()?;
+            CounterStorage::is_within_limits(&self.inner, counter, delta)
+        }
+
+        async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+            self.check()?;
+            CounterStorage::update_counter(&self.inner, counter, delta)
+        }
+
+        async fn check_and_update<'a>(
+            &self,
+            counters: &mut Vec<Counter>,
+            delta: u64,
+            load_counters: bool,
+        ) -> Result<Authorization, StorageErr> {
+            if let Err(err) = self.check() {
+                // as a call dropped on a timeout would
+                counters.clear();
+                return Err(err);
+            }
+            CounterStorage::check_and_update(&self.inner, counters, delta, load_counters)
+        }
+
+        async fn get_counters(
+            &self,
+            limits: &HashSet<Arc<Limit>>,
+        ) -> Result<HashSet<Counter>, StorageErr> {
+            self.check()?;
+            CounterStorage::get_counters(&self.inner, limits)
+        }
+
+        async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+            self.check()?;
+            CounterStorage::delete_counters(&self.inner, limits)
+        }
+
+        async fn clear(&self) -> Result<(), StorageErr> {
+            self.check()?;
+            CounterStorage::clear(&self.inner)
+        }
+
+        async fn admit_counter(
+            &self,
+            counter: &Counter,
+            max_counters: u64,
+        ) -> Result<bool, StorageErr> {
+            self.check()?;
+            CounterStorage::admit_counter(&self.inner, counter, max_counters)
+        }
+
+        async fn can_admit_counter(
+            &self,
+            counter: &Counter,
+            max_counters: u64,
+        ) -> Result<bool, StorageErr> {
+            self.check()?;
+            CounterStorage::can_admit_counter(&self.inner, counter, max_counters)
+        }
+
+        async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+            self.check()?;
+            CounterStorage::cardinality(&self.inner, limit)
+        }
+    }
+
+    fn test_counter() -> Counter {
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        Counter::new(limit, &Default::default()).unwrap().unwrap()
+    }
+
+    async fn hit(storage: &FallbackStorage<Switchable, InMemoryStorage>, counter: &Counter) {
+        let mut counters = vec![counter.clone()];
+        let result = storage.check_and_update(&mut counters, 1, false).await;
+        assert!(matches!(result, Ok(Authorization::Ok)));
+    }
+
+    fn hits_on(storage: &InMemoryStorage, counter: &Counter) -> u64 {
+        CounterStorage::get_counters(storage, &HashSet::from([counter.limit().clone().into()]))
+            .unwrap()
+            .iter()
+            .map(|c| counter.max_value() - c.remaining().unwrap())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn falls_back_while_the_primary_is_down() {
+        let storage = FallbackStorage::new(Switchable::default(), InMemoryStorage::default())
+            .fallback_after(2)
+            .probe_interval(Duration::from_secs(60));
+        let counter = test_counter();
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+
+        hit(&storage, &counter).await;
+        assert_eq!(hits_on(&storage.primary.inner, &counter), 1);
+
+        storage.primary.down.store(true, Ordering::Release);
+        hit(&storage, &counter).await;
+        assert!(!storage.is_falling_back());
+        hit(&storage, &counter).await;
+        assert!(storage.is_falling_back());
+        hit(&storage, &counter).await;
+        assert_eq!(hits_on(&storage.secondary, &counter), 3);
+
+        // no probe before the interval elapses
+        storage.primary.down.store(false, Ordering::Release);
+        hit(&storage, &counter).await;
+        assert!(storage.is_falling_back());
+        assert_eq!(hits_on(&storage.primary.inner, &counter), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_with_the_counters_the_primary_lost() {
+        let storage = FallbackStorage::new(Switchable::default(), InMemoryStorage::default());
+        let limit = Limit::new("test_namespace", 1, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Default::default()).unwrap().unwrap();
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+
+        storage.primary.down.store(true, Ordering::Release);
+        hit(&storage, &counter).await;
+        let mut counters = vec![counter.clone()];
+        let result = storage.check_and_update(&mut counters, 1, false).await;
+        assert!(matches!(result, Ok(Authorization::Limited(_))));
+    }
+
+    #[tokio::test]
+    async fn recovers_and_replays_the_local_hits() {
+        let storage = FallbackStorage::new(Switchable::default(), InMemoryStorage::default())
+            .probe_interval(Duration::ZERO)
+            .recover_after(2)
+            .replay(true);
+        let counter = test_counter();
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+
+        storage.primary.down.store(true, Ordering::Release);
+        for _ in 0..3 {
+            hit(&storage, &counter).await;
+        }
+        assert!(storage.is_falling_back());
+        assert_eq!(hits_on(&storage.secondary, &counter), 3);
+
+        storage.primary.down.store(false, Ordering::Release);
+        hit(&storage, &counter).await;
+        assert!(storage.is_falling_back());
+        hit(&storage, &counter).await;
+        assert!(!storage.is_falling_back());
+
+        assert_eq!(hits_on(&storage.secondary, &counter), 0);
+
+        // the two probes, and the three hits replayed in the background
+        for _ in 0..100 {
+            if hits_on(&storage.primary.inner, &counter) == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(hits_on(&storage.primary.inner, &counter), 5);
+    }
+
+    #[tokio::test]
+    async fn recovers_without_the_local_hits_on_qualified_counters() {
+        let storage = FallbackStorage::new(Switchable::default(), InMemoryStorage::default())
+            .probe_interval(Duration::ZERO)
+            .recover_after(1)
+            .replay(true);
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit, &ctx).unwrap().unwrap();
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+
+        storage.primary.down.store(true, Ordering::Release);
+        for _ in 0..3 {
+            hit(&storage, &counter).await;
+        }
+        assert_eq!(hits_on(&storage.secondary, &counter), 3);
+
+        storage.primary.down.store(false, Ordering::Release);
+        hit(&storage, &counter).await;
+        assert!(!storage.is_falling_back());
+        assert_eq!(hits_on(&storage.secondary, &counter), 0);
+
+        // the next outage starts over
+        storage.primary.down.store(true, Ordering::Release);
+        hit(&storage, &counter).await;
+        assert!(storage.is_falling_back());
+        assert_eq!(hits_on(&storage.secondary, &counter), 1);
+    }
+
+    #[tokio::test]
+    async fn deletes_the_counters_of_the_primary_on_recovery() {
+        let storage = FallbackStorage::new(Switchable::default(), InMemoryStorage::default())
+            .fallback_after(2)
+            .probe_interval(Duration::from_secs(60))
+            .recover_after(1);
+        let counter = test_counter();
+        let limits = HashSet::from([Arc::new(counter.limit().clone())]);
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+        hit(&storage, &counter).await;
+
+        // not falling back yet
+        storage.primary.down.store(true, Ordering::Release);
+        let result = storage.delete_counters(&limits).await;
+        assert!(matches!(result, Err(err) if err.is_transient()));
+
+        hit(&storage, &counter).await;
+        assert!(storage.is_falling_back());
+        storage.delete_counters(&limits).await.unwrap();
+        assert_eq!(hits_on(&storage.primary.inner, &counter), 1);
+        assert_eq!(hits_on(&storage.secondary, &counter), 0);
+
+        storage.primary.down.store(false, Ordering::Release);
+        *storage.last_probe.lock().unwrap() -= Duration::from_secs(60);
+        let mut counters = vec![counter.clone()];
+        storage
+            .check_and_update(&mut counters, 0, false)
+            .await
+            .unwrap();
+        assert!(!storage.is_falling_back());
+
+        for _ in 0..100 {
+            if hits_on(&storage.primary.inner, &counter) == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(hits_on(&storage.primary.inner, &counter), 0);
+    }
+}
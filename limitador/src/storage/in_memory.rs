@@ -1,33 +1,137 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
 use crate::limit::{Context, Limit, Namespace};
 use crate::storage::atomic_expiring_value::AtomicExpiringValue;
+use crate::storage::keys::bin::{key_for_counter, try_partial_counter_from_counter_key};
 use crate::storage::{Authorization, CounterStorage, StorageErr};
+use dashmap::DashMap;
 use moka::sync::{Cache, CacheBuilder};
 use moka::PredicateError;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::collections::btree_map::Entry;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+type CountersOfLimits = DashMap<Limit, CappedCounters>;
+
+// The live counters of a limit with `max_counters`, along with when they expire
+// next, so that counting them only goes through the ones expired since.
+#[derive(Default)]
+struct CappedCounters {
+    counters: HashMap<Counter, Arc<AtomicExpiringValue>>,
+    expiries: BinaryHeap<Reverse<Expiry>>,
+}
+
+struct Expiry {
+    at: SystemTime,
+    counter: Counter,
+    value: Arc<AtomicExpiringValue>,
+}
+
+impl PartialEq for Expiry {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Expiry {}
+
+impl PartialOrd for Expiry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Expiry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.at.cmp(&other.at)
+    }
+}
+
+impl CappedCounters {
+    fn insert(&mut self, counter: Counter, value: Arc<AtomicExpiringValue>, now: SystemTime) {
+        self.expiries.push(Reverse(Expiry {
+            at: now + value.ttl_at(now),
+            counter: counter.clone(),
+            value: Arc::clone(&value),
+        }));
+        self.counters.insert(counter, value);
+    }
+
+    // Drops the counters expired by `now`, returning them
+    fn expire(&mut self, now: SystemTime) -> Vec<Counter> {
+        let mut expired = Vec::new();
+        while self
+            .expiries
+            .peek()
+            .is_some_and(|Reverse(expiry)| expiry.at <= now)
+        {
+            let Reverse(mut expiry) = self.expiries.pop().unwrap();
+            // evicted, or replaced since, and then tracked by another entry
+            if !self
+                .counters
+                .get(&expiry.counter)
+                .is_some_and(|value| Arc::ptr_eq(value, &expiry.value))
+            {
+                continue;
+            }
+            let ttl = expiry.value.ttl_at(now);
+            if ttl > Duration::ZERO {
+                // hit again after its window
+                expiry.at = now + ttl;
+                self.expiries.push(Reverse(expiry));
+            } else {
+                self.counters.remove(&expiry.counter);
+                expired.push(expiry.counter);
+            }
+        }
+        expired
+    }
+}
+
+// The counters restored from a snapshot, waiting for their limit to be added
+type RestoredCounters = HashMap<Limit, Vec<(Counter, u64, SystemTime)>>;
+
+const SNAPSHOT_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    // see `keys::bin::key_for_counter`
+    key: Vec<u8>,
+    value: u64,
+    expires_at_micros: u64,
+}
 
 pub struct InMemoryStorage {
     simple_limits: RwLock<BTreeMap<Limit, AtomicExpiringValue>>,
     qualified_counters: Cache<Counter, Arc<AtomicExpiringValue>>,
+    // The qualified counters of the limits with `max_counters`, so as to count
+    // them without going through the ones of every other limit.
+    capped_counters: Arc<CountersOfLimits>,
+    clock: Arc<dyn Clock>,
+    snapshot: Option<PathBuf>,
+    restored: Mutex<RestoredCounters>,
 }
 
 impl CounterStorage for InMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
         let value = if counter.is_qualified() {
             self.qualified_counters
                 .get(counter)
-                .map(|c| c.value())
+                .map(|c| c.value_at(now))
                 .unwrap_or_default()
         } else {
             let limits_by_namespace = self.simple_limits.read().unwrap();
             limits_by_namespace
                 .get(counter.limit())
-                .map(|c| c.value())
+                .map(|c| c.value_at(now))
                 .unwrap_or_default()
         };
 
@@ -40,20 +144,19 @@ impl CounterStorage for InMemoryStorage {
             let mut limits_by_namespace = self.simple_limits.write().unwrap();
             limits_by_namespace.entry(limit.clone()).or_default();
         }
+        let restored = self.restored.lock().unwrap().remove(limit);
+        if let Some(restored) = restored {
+            self.apply_restored(limit, restored);
+        }
         Ok(())
     }
 
     #[tracing::instrument(skip_all)]
     fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
         let mut counters = self.simple_limits.write().unwrap();
-        let now = SystemTime::now();
+        let now = self.clock.now();
         if counter.is_qualified() {
-            let value = match self.qualified_counters.get(counter) {
-                None => self.qualified_counters.get_with(counter.clone(), || {
-                    Arc::new(AtomicExpiringValue::new(0, now + counter.window()))
-                }),
-                Some(counter) => counter,
-            };
+            let value = self.qualified_counter(counter, now);
             value.update(delta, counter.window(), now);
         } else {
             match counters.entry(counter.limit().clone()) {
@@ -80,7 +183,7 @@ impl CounterStorage for InMemoryStorage {
         let mut counter_values_to_update: Vec<(&AtomicExpiringValue, Duration)> = Vec::new();
         let mut qualified_counter_values_to_updated: Vec<(Arc<AtomicExpiringValue>, Duration)> =
             Vec::new();
-        let now = SystemTime::now();
+        let now = self.clock.now();
 
         let mut process_counter =
             |counter: &mut Counter, value: u64, delta: u64| -> Option<Authorization> {
@@ -106,7 +209,9 @@ impl CounterStorage for InMemoryStorage {
             let atomic_expiring_value: &AtomicExpiringValue =
                 limits_by_namespace.get(counter.limit()).unwrap();
 
-            if let Some(limited) = process_counter(counter, atomic_expiring_value.value(), delta) {
+            if let Some(limited) =
+                process_counter(counter, atomic_expiring_value.value_at(now), delta)
+            {
                 if !load_counters {
                     return Ok(limited);
                 }
@@ -116,14 +221,9 @@ impl CounterStorage for InMemoryStorage {
 
         // Process qualified counters
         for counter in counters.iter_mut().filter(|c| c.is_qualified()) {
-            let value = match self.qualified_counters.get(counter) {
-                None => self.qualified_counters.get_with_by_ref(counter, || {
-                    Arc::new(AtomicExpiringValue::new(0, now + counter.window()))
-                }),
-                Some(counter) => counter,
-            };
+            let value = self.qualified_counter(counter, now);
 
-            if let Some(limited) = process_counter(counter, value.value(), delta) {
+            if let Some(limited) = process_counter(counter, value.value_at(now), delta) {
                 if !load_counters {
                     return Ok(limited);
                 }
@@ -152,13 +252,17 @@ impl CounterStorage for InMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
+        let now = self.clock.now();
 
         for limit in limits {
             for (counter, expiring_value) in self.counters_in_namespace(limit.namespace()) {
+                if !limits.contains(counter.limit()) {
+                    continue;
+                }
                 let mut counter_with_val = counter.clone();
                 counter_with_val
-                    .set_remaining(counter_with_val.max_value() - expiring_value.value());
-                counter_with_val.set_expires_in(expiring_value.ttl());
+                    .set_remaining(counter_with_val.max_value() - expiring_value.value_at(now));
+                counter_with_val.set_expires_in(expiring_value.ttl_at(now));
                 if counter_with_val.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter_with_val);
                 }
@@ -169,8 +273,8 @@ impl CounterStorage for InMemoryStorage {
             if limits.contains(counter.limit()) {
                 let mut counter_with_val = counter.deref().clone();
                 counter_with_val
-                    .set_remaining(counter_with_val.max_value() - expiring_value.value());
-                counter_with_val.set_expires_in(expiring_value.ttl());
+                    .set_remaining(counter_with_val.max_value() - expiring_value.value_at(now));
+                counter_with_val.set_expires_in(expiring_value.ttl_at(now));
                 if counter_with_val.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter_with_val);
                 }
@@ -191,20 +295,335 @@ impl CounterStorage for InMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn clear(&self) -> Result<(), StorageErr> {
         self.simple_limits.write().unwrap().clear();
+        self.qualified_counters.invalidate_all();
+        // along with the expiries of their counters
+        self.capped_counters.clear();
+        self.restored.lock().unwrap().clear();
+        Ok(())
+    }
+
+    // The counters of the limit are locked from counting them to taking the
+    // room for `counter`, and the room is taken before the request gets
+    // checked, so that it's never given twice.
+    #[tracing::instrument(skip_all)]
+    fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
+        let mut reserved = None;
+        let (admitted, expired) = {
+            let mut capped = self
+                .capped_counters
+                .entry(counter.limit().clone())
+                .or_default();
+            let expired = capped.expire(now);
+            let admitted = if capped.counters.contains_key(counter) {
+                true
+            } else if (capped.counters.len() as u64) < max_counters {
+                let value = Arc::new(AtomicExpiringValue::new(0, now + counter.window()));
+                capped.insert(counter.key(), Arc::clone(&value), now);
+                reserved = Some(value);
+                true
+            } else {
+                false
+            };
+            (admitted, expired)
+        };
+        for counter in expired {
+            self.qualified_counters.invalidate(&counter);
+        }
+        if let Some(reserved) = reserved {
+            self.cache_reserved(counter, reserved, now);
+        }
+        Ok(admitted)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn can_admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
+        let (admissible, expired) = match self.capped_counters.get_mut(counter.limit()) {
+            None => (max_counters > 0, Vec::new()),
+            Some(mut capped) => {
+                let expired = capped.expire(now);
+                let admissible = capped.counters.contains_key(counter)
+                    || (capped.counters.len() as u64) < max_counters;
+                (admissible, expired)
+            }
+        };
+        for counter in expired {
+            self.qualified_counters.invalidate(&counter);
+        }
+        Ok(admissible)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let now = self.clock.now();
+        if limit.max_counters().is_none() {
+            return Ok(self
+                .qualified_counters
+                .iter()
+                .filter(|(counter, value)| {
+                    counter.limit() == limit && value.ttl_at(now) > Duration::ZERO
+                })
+                .count() as u64);
+        }
+
+        let (live, expired) = match self.capped_counters.get_mut(limit) {
+            None => (0, Vec::new()),
+            Some(mut counters) => {
+                let expired = counters.expire(now);
+                (counters.counters.len(), expired)
+            }
+        };
+        // An expired counter would otherwise be revived, untracked, on its next
+        // hit.
+        for counter in expired {
+            self.qualified_counters.invalidate(&counter);
+        }
+        Ok(live as u64)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        let mut counters = self.simple_limits.write().unwrap();
+        let now = self.clock.now();
+        if expires_at <= now {
+            return Ok(());
+        }
+        if counter.is_qualified() {
+            self.qualified_counter_expiring(counter, now, expires_at)
+                .set(value, expires_at);
+        } else {
+            counters
+                .entry(counter.limit().clone())
+                .or_default()
+                .set(value, expires_at);
+        }
         Ok(())
     }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
 }
 
 impl InMemoryStorage {
     pub fn new(cache_size: u64) -> Self {
+        Self::with_clock(cache_size, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(cache_size: u64, clock: Arc<dyn Clock>) -> Self {
+        let capped_counters = Arc::new(CountersOfLimits::new());
+        let evicted_from = Arc::clone(&capped_counters);
         Self {
             simple_limits: RwLock::new(BTreeMap::new()),
             qualified_counters: CacheBuilder::new(cache_size)
                 .support_invalidation_closures()
+                .eviction_listener(move |counter: Arc<Counter>, value, _| {
+                    if let Some(mut counters) = evicted_from.get_mut(counter.limit()) {
+                        // unless it's been replaced since
+                        if counters
+                            .counters
+                            .get(&counter)
+                            .is_some_and(|indexed| Arc::ptr_eq(indexed, &value))
+                        {
+                            counters.counters.remove(&counter);
+                        }
+                    }
+                })
                 .build(),
+            capped_counters,
+            clock,
+            snapshot: None,
+            restored: Mutex::default(),
         }
     }
 
+    /// Restores the counters from the snapshot at `path`, if there's one, and
+    /// writes them back to it when dropped, or [saved](Self::save_snapshot).
+    ///
+    /// The counters restored only come back once their limit is added, so
+    /// that the ones of the limits that no longer exist are left out. The
+    /// expired ones are skipped.
+    pub fn with_snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match std::fs::read(&path) {
+            Ok(snapshot) => match self.restore(&snapshot) {
+                Ok(restored) => info!("Restored {} counters from {}", restored, path.display()),
+                Err(err) => warn!("Ignoring the snapshot {}: {}", path.display(), err),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Couldn't read the snapshot {}: {}", path.display(), err),
+        }
+        self.snapshot = Some(path);
+        self
+    }
+
+    /// Writes the live counters to the snapshot file, if there's one, through a
+    /// temporary file next to it, so that it's never left half written.
+    pub fn save_snapshot(&self) -> Result<(), StorageErr> {
+        let Some(path) = &self.snapshot else {
+            return Ok(());
+        };
+        let snapshot = self.snapshot()?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, snapshot)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|err| snapshot_err(path, err))
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>, StorageErr> {
+        let now = self.clock.now();
+        let entry = |counter: &Counter, value: &AtomicExpiringValue| {
+            let ttl = value.ttl_at(now);
+            (ttl > Duration::ZERO).then(|| SnapshotEntry {
+                key: key_for_counter(counter),
+                value: value.value_at(now),
+                expires_at_micros: (now + ttl)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_micros() as u64,
+            })
+        };
+        let mut entries: Vec<SnapshotEntry> = self
+            .simple_limits
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(limit, value)| {
+                let counter = Counter::new(limit.clone(), &Context::default()).ok()??;
+                entry(&counter, value)
+            })
+            .collect();
+        entries.extend(
+            self.qualified_counters
+                .iter()
+                .filter_map(|(counter, value)| entry(&counter, &value)),
+        );
+        let encoded = postcard::to_extend(&SNAPSHOT_VERSION, Vec::new())?;
+        Ok(postcard::to_extend(&entries, encoded)?)
+    }
+
+    fn restore(&self, snapshot: &[u8]) -> Result<usize, StorageErr> {
+        let (version, entries) = postcard::take_from_bytes::<u8>(snapshot)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(StorageErr {
+                msg: format!("unknown snapshot version {version}"),
+                source: None,
+                transient: false,
+            });
+        }
+        let entries: Vec<SnapshotEntry> = postcard::from_bytes(entries)?;
+        let now = self.clock.now();
+        let mut restored = self.restored.lock().unwrap();
+        let mut count = 0;
+        for entry in entries {
+            let expires_at = UNIX_EPOCH + Duration::from_micros(entry.expires_at_micros);
+            if expires_at <= now {
+                continue;
+            }
+            let counter = match try_partial_counter_from_counter_key(&entry.key) {
+                Ok(counter) => counter,
+                Err(err) => {
+                    warn!("Skipping a counter of the snapshot: {}", err);
+                    continue;
+                }
+            };
+            restored.entry(counter.limit().clone()).or_default().push((
+                counter,
+                entry.value,
+                expires_at,
+            ));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn apply_restored(&self, limit: &Limit, restored: Vec<(Counter, u64, SystemTime)>) {
+        let now = self.clock.now();
+        let limit = Arc::new(limit.clone());
+        for (mut counter, value, expires_at) in restored {
+            if expires_at <= now {
+                continue;
+            }
+            counter.update_to_limit(Arc::clone(&limit));
+            if counter.is_qualified() {
+                self.qualified_counter(&counter, now)
+                    .add_and_set_expiry(value, expires_at);
+            } else {
+                self.simple_limits.write().unwrap().insert(
+                    counter.limit().clone(),
+                    AtomicExpiringValue::new(value, expires_at),
+                );
+            }
+        }
+    }
+
+    // Caches the value `reserved` for `counter` among the ones of its limit,
+    // unless a request got the counter a value of its own meanwhile, that then
+    // takes its place.
+    fn cache_reserved(
+        &self,
+        counter: &Counter,
+        reserved: Arc<AtomicExpiringValue>,
+        now: SystemTime,
+    ) {
+        let value = self
+            .qualified_counters
+            .entry_by_ref(counter)
+            .or_insert_with(|| Arc::clone(&reserved))
+            .into_value();
+        if Arc::ptr_eq(&value, &reserved) {
+            return;
+        }
+        if let Some(mut capped) = self.capped_counters.get_mut(counter.limit()) {
+            if capped
+                .counters
+                .get(counter)
+                .is_some_and(|indexed| Arc::ptr_eq(indexed, &reserved))
+            {
+                capped.insert(counter.key(), value, now);
+            }
+        }
+    }
+
+    fn qualified_counter(&self, counter: &Counter, now: SystemTime) -> Arc<AtomicExpiringValue> {
+        self.qualified_counter_expiring(counter, now, now + counter.window())
+    }
+
+    // The value of `counter`, that expires at `expiry` when it's a new one
+    fn qualified_counter_expiring(
+        &self,
+        counter: &Counter,
+        now: SystemTime,
+        expiry: SystemTime,
+    ) -> Arc<AtomicExpiringValue> {
+        if let Some(value) = self.qualified_counters.get(counter) {
+            return value;
+        }
+        let entry = self
+            .qualified_counters
+            .entry_by_ref(counter)
+            .or_insert_with(|| Arc::new(AtomicExpiringValue::new(0, expiry)));
+        let fresh = entry.is_fresh();
+        let value = entry.into_value();
+        if fresh && counter.limit().max_counters().is_some() {
+            self.capped_counters
+                .entry(counter.limit().clone())
+                .or_default()
+                .insert(counter.key(), Arc::clone(&value), now);
+        }
+        value
+    }
+
     fn counters_in_namespace(
         &self,
         namespace: &Namespace,
@@ -236,6 +655,7 @@ impl InMemoryStorage {
         if limit.variables().is_empty() {
             self.simple_limits.write().unwrap().remove(limit);
         } else {
+            self.capped_counters.remove(limit);
             let l = limit.clone();
             if let Err(PredicateError::InvalidationClosuresDisabled) = self
                 .qualified_counters
@@ -258,6 +678,32 @@ impl InMemoryStorage {
     }
 }
 
+impl Drop for InMemoryStorage {
+    fn drop(&mut self) {
+        if let Err(err) = self.save_snapshot() {
+            warn!("Couldn't save the counters: {}", err);
+        }
+    }
+}
+
+fn snapshot_err(path: &Path, err: std::io::Error) -> StorageErr {
+    StorageErr {
+        msg: format!("couldn't write the snapshot {}: {err}", path.display()),
+        source: Some(Box::new(err)),
+        transient: false,
+    }
+}
+
+impl From<postcard::Error> for StorageErr {
+    fn from(err: postcard::Error) -> Self {
+        Self {
+            msg: format!("couldn't encode or decode the snapshot: {err}"),
+            source: Some(Box::new(err)),
+            transient: false,
+        }
+    }
+}
+
 impl Default for InMemoryStorage {
     fn default() -> Self {
         Self::new(10_000)
@@ -267,6 +713,8 @@ impl Default for InMemoryStorage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::limit::Expression;
 
     #[test]
     fn counters_for_multiple_limit_per_ns() {
@@ -302,4 +750,225 @@ mod tests {
             2
         );
     }
+
+    #[test]
+    fn cardinality_only_counts_the_live_counters() {
+        let clock = Arc::new(ManualClock::default());
+        let storage = InMemoryStorage::with_clock(10_000, clock.clone());
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        limit.set_max_counters(100);
+
+        for app_id in ["foo", "bar"] {
+            let ctx = HashMap::from([("app_id".to_string(), app_id.to_string())]).into();
+            let counter = Counter::new(limit.clone(), &ctx)
+                .expect("counter creation failed!")
+                .expect("Should have a counter");
+            assert!(storage.admit_counter(&counter, 100).unwrap());
+            storage.update_counter(&counter, 1).unwrap();
+            clock.advance(Duration::from_secs(30));
+        }
+        assert_eq!(storage.cardinality(&limit).unwrap(), 1);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(storage.cardinality(&limit).unwrap(), 0);
+    }
+
+    #[test]
+    fn cardinality_counts_the_counters_hit_again_after_their_window() {
+        let clock = Arc::new(ManualClock::default());
+        let storage = InMemoryStorage::with_clock(10_000, clock.clone());
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        limit.set_max_counters(100);
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("counter creation failed!")
+            .expect("Should have a counter");
+
+        storage.update_counter(&counter, 1).unwrap();
+        clock.advance(Duration::from_secs(61));
+        storage.update_counter(&counter, 1).unwrap();
+        assert_eq!(storage.cardinality(&limit).unwrap(), 1);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(storage.cardinality(&limit).unwrap(), 1);
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(storage.cardinality(&limit).unwrap(), 0);
+    }
+
+    #[test]
+    fn clear_drops_the_qualified_counters() {
+        let storage = InMemoryStorage::default();
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        limit.set_max_counters(1);
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit.clone(), &ctx).unwrap().unwrap();
+        assert!(storage.admit_counter(&counter, 1).unwrap());
+        storage.update_counter(&counter, 1).unwrap();
+
+        storage.clear().unwrap();
+        assert!(storage
+            .get_counters(&HashSet::from([Arc::new(limit.clone())]))
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.cardinality(&limit).unwrap(), 0);
+    }
+
+    #[test]
+    fn restores_the_live_counters_of_the_limits_still_there() {
+        let dir = tempfile::tempdir().expect("couldn't create a temp dir");
+        let path = dir.path().join("counters.snapshot");
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let simple = Limit::new("test_namespace", 10, 60, vec![], Vec::<Expression>::new());
+        let qualified = Limit::new(
+            "test_namespace",
+            10,
+            10,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let removed = Limit::new("other_namespace", 10, 60, vec![], Vec::<Expression>::new());
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+
+        {
+            let storage = InMemoryStorage::with_clock(10_000, clock.clone()).with_snapshot(&path);
+            for (limit, delta) in [(&simple, 3), (&qualified, 2), (&removed, 1)] {
+                storage.add_counter(limit).unwrap();
+                let counter = Counter::new(limit.clone(), &ctx).unwrap().unwrap();
+                storage.update_counter(&counter, delta).unwrap();
+            }
+        }
+        clock.advance(Duration::from_secs(20));
+
+        let storage = InMemoryStorage::with_clock(10_000, clock.clone()).with_snapshot(&path);
+        storage.add_counter(&simple).unwrap();
+        storage.add_counter(&qualified).unwrap();
+
+        let limits = HashSet::from([
+            Arc::new(simple.clone()),
+            Arc::new(qualified),
+            Arc::new(removed),
+        ]);
+        let counters = storage.get_counters(&limits).unwrap();
+        assert_eq!(counters.len(), 1);
+        let counter = counters.into_iter().next().unwrap();
+        assert_eq!(counter.limit(), &simple);
+        assert_eq!(counter.remaining(), Some(7));
+        assert_eq!(counter.expires_in(), Some(Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn rejects_a_snapshot_of_garbage() {
+        let storage = InMemoryStorage::default();
+        assert!(storage.restore(&[]).is_err());
+        assert!(storage.restore(&[0xff; 16]).is_err());
+        assert!(storage
+            .restore(&[SNAPSHOT_VERSION, 0xff, 0xff, 0xff])
+            .is_err());
+    }
+
+    #[test]
+    fn skips_the_counters_of_a_snapshot_with_a_corrupt_key() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let limit = Limit::new("test_namespace", 10, 60, vec![], Vec::<Expression>::new());
+        let counter = Counter::new(limit.clone(), &Context::default())
+            .unwrap()
+            .unwrap();
+        let expires_at_micros = (clock.now() + Duration::from_secs(30))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let mut corrupt_condition = key_for_counter(
+            &Counter::new(
+                Limit::new(
+                    "test_namespace",
+                    10,
+                    60,
+                    vec!["x == '1'".try_into().expect("failed parsing!")],
+                    Vec::<Expression>::new(),
+                ),
+                &HashMap::from([("x".to_string(), "1".to_string())]).into(),
+            )
+            .unwrap()
+            .unwrap(),
+        );
+        let at = corrupt_condition
+            .windows(2)
+            .position(|bytes| bytes == b"==")
+            .unwrap();
+        corrupt_condition[at..at + 2].copy_from_slice(b"=(");
+        let entries = vec![
+            SnapshotEntry {
+                key: vec![0xff, 0xff, 0xff],
+                value: 1,
+                expires_at_micros,
+            },
+            SnapshotEntry {
+                key: corrupt_condition,
+                value: 1,
+                expires_at_micros,
+            },
+            SnapshotEntry {
+                key: key_for_counter(&counter),
+                value: 4,
+                expires_at_micros,
+            },
+        ];
+        let snapshot = postcard::to_extend(&SNAPSHOT_VERSION, Vec::new()).unwrap();
+        let snapshot = postcard::to_extend(&entries, snapshot).unwrap();
+
+        let storage = InMemoryStorage::with_clock(10_000, clock);
+        assert_eq!(storage.restore(&snapshot).unwrap(), 1);
+        storage.add_counter(&limit).unwrap();
+        let counters = storage
+            .get_counters(&HashSet::from([Arc::new(limit)]))
+            .unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.into_iter().next().unwrap().remaining(), Some(6));
+    }
+
+    #[test]
+    fn imported_counters_replace_the_live_ones() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let storage = InMemoryStorage::with_clock(10_000, clock.clone());
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit.clone(), &ctx).unwrap().unwrap();
+
+        let expires_at = clock.now() + Duration::from_secs(20);
+        storage.import_counter(&counter, 3, expires_at).unwrap();
+        storage
+            .import_counter(&counter, 2, expires_at + Duration::from_secs(5))
+            .unwrap();
+
+        let counters = storage
+            .get_counters(&HashSet::from([Arc::new(limit)]))
+            .unwrap();
+        let counter = counters.into_iter().next().unwrap();
+        assert_eq!(counter.remaining(), Some(8));
+        assert_eq!(counter.expires_in(), Some(Duration::from_secs(25)));
+    }
 }
@@ -11,12 +11,20 @@
 // Note: keep in mind that what's described above is the default in Redis, when
 // reusing this module for other storage implementations make sure that using
 // "{}" for sharding applies.
+// The binary keys of the limits with an id don't embed their namespace, so
+// they get prefixed with the same hash tag the text keys have. The ones from
+// before that are moved under their hash tag, see `key_for_legacy_counters_of_limit`.
 
+#[cfg(feature = "redis_storage")]
 use crate::counter::Counter;
+#[cfg(feature = "redis_storage")]
 use crate::limit::Limit;
+#[cfg(feature = "redis_storage")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "redis_storage")]
 use std::sync::Arc;
 
+#[cfg(feature = "redis_storage")]
 pub fn key_for_counter(counter: &Counter) -> Vec<u8> {
     if counter.id().is_none() {
         // continue to use the legacy text encoding...
@@ -35,34 +43,60 @@ pub fn key_for_counter(counter: &Counter) -> Vec<u8> {
         key.into_bytes()
     } else {
         // if the id is set, use the new binary encoding...
-        bin::key_for_counter_v2(counter)
+        let mut key = hash_tag(counter.namespace().as_ref());
+        key.extend(bin::key_for_counter_v2(counter));
+        key
     }
 }
 
+#[cfg(feature = "redis_storage")]
 pub fn key_for_counters_of_limit(limit: &Limit) -> Vec<u8> {
-    if let Some(id) = limit.id() {
-        #[derive(PartialEq, Debug, Serialize, Deserialize)]
-        struct IdLimitKey<'a> {
-            id: &'a str,
-        }
+    key_for_limit(limit, 2, "counters_of_limit")
+}
 
-        let key = IdLimitKey { id };
+#[cfg(feature = "redis_storage")]
+/// The key of the live counters of `limit`, scored by when they expire.
+pub fn key_for_live_counters_of_limit(limit: &Limit) -> Vec<u8> {
+    key_for_limit(limit, 3, "live_counters_of_limit")
+}
 
-        let mut encoded_key = Vec::new();
-        encoded_key = postcard::to_extend(&2u8, encoded_key).unwrap();
-        encoded_key = postcard::to_extend(&key, encoded_key).unwrap();
-        encoded_key
+#[cfg(feature = "redis_storage")]
+/// The key the counters of `limit` were kept under before the keys of the limits
+/// with an id got a hash tag, if it has one. The keys of the counters in it lack
+/// the [hash tag](hash_tag) the current ones start with.
+pub fn key_for_legacy_counters_of_limit(limit: &Limit) -> Option<Vec<u8>> {
+    limit.id().map(|id| key_for_limit_id(id, 2, Vec::new()))
+}
+
+#[cfg(feature = "redis_storage")]
+fn key_for_limit(limit: &Limit, version: u8, kind: &str) -> Vec<u8> {
+    if let Some(id) = limit.id() {
+        key_for_limit_id(id, version, hash_tag(limit.namespace().as_ref()))
     } else {
         let namespace = limit.namespace().as_ref();
         format!(
-            "namespace:{{{namespace}}},counters_of_limit:{}",
+            "namespace:{{{namespace}}},{kind}:{}",
             serde_json::to_string(limit).unwrap()
         )
         .into_bytes()
     }
 }
 
-pub fn counter_from_counter_key(key: &Vec<u8>, limit: Arc<Limit>) -> Counter {
+#[cfg(feature = "redis_storage")]
+fn key_for_limit_id(id: &str, version: u8, encoded_key: Vec<u8>) -> Vec<u8> {
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    struct IdLimitKey<'a> {
+        id: &'a str,
+    }
+
+    let key = IdLimitKey { id };
+
+    let encoded_key = postcard::to_extend(&version, encoded_key).unwrap();
+    postcard::to_extend(&key, encoded_key).unwrap()
+}
+
+#[cfg(feature = "redis_storage")]
+pub fn counter_from_counter_key(key: &[u8], limit: Arc<Limit>) -> Counter {
     let mut counter = partial_counter_from_counter_key(key);
     if !counter.update_to_limit(Arc::clone(&limit)) {
         // this means some kind of data corruption _or_ most probably
@@ -76,9 +110,10 @@ pub fn counter_from_counter_key(key: &Vec<u8>, limit: Arc<Limit>) -> Counter {
     counter
 }
 
-pub fn partial_counter_from_counter_key(key: &Vec<u8>) -> Counter {
+#[cfg(feature = "redis_storage")]
+pub fn partial_counter_from_counter_key(key: &[u8]) -> Counter {
     if key.starts_with(b"namespace:") {
-        let key = String::from_utf8_lossy(key.as_ref());
+        let key = String::from_utf8_lossy(key);
 
         // It's using to the legacy text encoding...
         let namespace_prefix = "namespace:";
@@ -99,15 +134,34 @@ pub fn partial_counter_from_counter_key(key: &Vec<u8>) -> Counter {
         let counter: Counter =
             serde_json::from_str(counter_str).expect("Failed to deserialize counter JSON");
         counter
+    } else if key.starts_with(b"{") {
+        // It's using to the new binary encoding, behind its hash tag...
+        let tag_end = key
+            .iter()
+            .position(|b| *b == b'}')
+            .expect("Hash tag not closed in the key");
+        bin::partial_counter_from_counter_key_v2(&key[tag_end + 1..])
     } else {
-        // It's using to the new binary encoding...
+        // It's using to the new binary encoding, from before it had a hash tag...
         bin::partial_counter_from_counter_key_v2(key)
     }
 }
 
-#[cfg(test)]
+#[cfg(feature = "redis_storage")]
+/// The hash tag of the keys of `namespace`, `{namespace}`. Redis only hashes
+/// up to the first "}" of a tag, so that's where it's cut, for the keys to map
+/// to the same slot as the text ones.
+pub fn hash_tag(namespace: &str) -> Vec<u8> {
+    let tag = namespace.split('}').next().unwrap_or_default();
+    format!("{{{tag}}}").into_bytes()
+}
+
+#[cfg(all(test, feature = "redis_storage"))]
 mod tests {
-    use super::{key_for_counter, key_for_counters_of_limit, partial_counter_from_counter_key};
+    use super::{
+        key_for_counter, key_for_counters_of_limit, key_for_legacy_counters_of_limit,
+        partial_counter_from_counter_key,
+    };
     use crate::counter::Counter;
     use crate::Limit;
     use std::collections::HashMap;
@@ -138,11 +192,58 @@ mod tests {
             vec!["app_id".try_into().expect("failed parsing!")],
         );
         assert_eq!(
-            "\u{2}\u{7}test_id".as_bytes(),
+            "{example.com}\u{2}\u{7}test_id".as_bytes(),
             key_for_counters_of_limit(&limit)
         )
     }
 
+    #[test]
+    fn counter_key_with_id_and_counter_are_symmetric() {
+        let limit = Limit::with_id(
+            "test_id",
+            "example.com",
+            1,
+            1,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .expect("counter creation failed!")
+            .expect("must have a counter");
+        let raw = key_for_counter(&counter);
+        assert!(raw.starts_with(b"{example.com}"));
+        let partial = partial_counter_from_counter_key(&raw);
+        assert_eq!(partial.id(), Some("test_id"));
+        assert_eq!(partial.set_variables(), counter.set_variables());
+    }
+
+    #[test]
+    fn legacy_key_for_counters_of_limit_with_id() {
+        let limit = Limit::with_id("test_id", "example.com", 1, 1, vec![], vec![]);
+        assert_eq!(
+            key_for_legacy_counters_of_limit(&limit),
+            Some("\u{2}\u{7}test_id".as_bytes().to_vec())
+        );
+        let limit = Limit::new("example.com", 1, 1, vec![], vec![]);
+        assert_eq!(key_for_legacy_counters_of_limit(&limit), None);
+    }
+
+    #[test]
+    fn keys_of_a_namespace_share_their_hash_tag() {
+        let namespace = "a}b";
+        let with_id = Limit::with_id("test_id", namespace, 1, 1, vec![], vec![]);
+        let without_id = Limit::new(namespace, 1, 1, vec![], vec![]);
+        let tag = |key: &[u8]| {
+            let start = key.iter().position(|b| *b == b'{').unwrap() + 1;
+            let end = start + key[start..].iter().position(|b| *b == b'}').unwrap();
+            key[start..end].to_vec()
+        };
+        assert_eq!(tag(&key_for_counters_of_limit(&with_id)), b"a");
+        assert_eq!(tag(&key_for_counters_of_limit(&without_id)), b"a");
+    }
+
     #[test]
     fn counter_key_and_counter_are_symmetric() {
         let namespace = "ns_counter:";
@@ -184,20 +285,22 @@ mod tests {
     }
 }
 
-#[cfg(feature = "disk_storage")]
 pub mod bin {
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
 
     use crate::counter::Counter;
-    use crate::limit::{Limit, Predicate};
+    use crate::limit::{Expression, Limit, Predicate};
+    use crate::storage::StorageErr;
 
+    #[cfg(any(feature = "redis_storage", feature = "distributed_storage"))]
     #[derive(PartialEq, Debug, Serialize, Deserialize)]
     struct IdCounterKey<'a> {
         id: &'a str,
         variables: Vec<(&'a str, &'a str)>,
     }
 
+    #[cfg(any(feature = "redis_storage", feature = "distributed_storage"))]
     impl<'a> From<&'a Counter> for IdCounterKey<'a> {
         fn from(counter: &'a Counter) -> Self {
             IdCounterKey {
@@ -233,6 +336,7 @@ pub mod bin {
         }
     }
 
+    #[cfg(any(feature = "redis_storage", feature = "distributed_storage"))]
     pub fn key_for_counter_v2(counter: &Counter) -> Vec<u8> {
         let mut encoded_key = Vec::new();
         if counter.id().is_none() {
@@ -247,6 +351,7 @@ pub mod bin {
         encoded_key
     }
 
+    #[cfg(feature = "redis_storage")]
     pub fn partial_counter_from_counter_key_v2(key: &[u8]) -> Counter {
         let (version, key) = postcard::take_from_bytes::<u8>(key).unwrap();
         match version {
@@ -302,12 +407,21 @@ pub mod bin {
         postcard::to_stdvec(&key).unwrap()
     }
 
+    #[cfg(feature = "disk_storage")]
     pub fn prefix_for_namespace(namespace: &str) -> Vec<u8> {
         postcard::to_stdvec(namespace).unwrap()
     }
 
+    #[cfg(any(test, feature = "disk_storage"))]
     pub fn partial_counter_from_counter_key(key: &[u8]) -> Counter {
-        let key: CounterKey = postcard::from_bytes(key).unwrap();
+        try_partial_counter_from_counter_key(key).expect("counter key corrupted!")
+    }
+
+    /// Like [`partial_counter_from_counter_key`], but errs on a key that
+    /// doesn't decode to a counter rather than panicking.
+    pub fn try_partial_counter_from_counter_key(key: &[u8]) -> Result<Counter, StorageErr> {
+        let key: CounterKey =
+            postcard::from_bytes(key).map_err(|err| corrupted_key("undecodable", err))?;
         let CounterKey {
             ns,
             seconds,
@@ -319,26 +433,34 @@ pub mod bin {
             .into_iter()
             .map(|(var, value)| (var.to_string(), value.to_string()))
             .collect();
-        let limit = Limit::new(
-            ns,
-            u64::default(),
-            seconds,
-            conditions
-                .into_iter()
-                .map(|p| p.try_into().expect("condition corrupted!"))
-                .collect::<Vec<Predicate>>(),
-            map.keys()
-                .map(|p| p.as_str().try_into().expect("variable corrupted!")),
-        );
-        Counter::resolved_vars(limit, map).unwrap()
+        let conditions = conditions
+            .into_iter()
+            .map(Predicate::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| corrupted_key("condition corrupted", err))?;
+        let variables = map
+            .keys()
+            .map(|var| Expression::try_from(var.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| corrupted_key("variable corrupted", err))?;
+        let limit = Limit::new(ns, u64::default(), seconds, conditions, variables);
+        Counter::resolved_vars(limit, map).map_err(|err| corrupted_key("invalid counter", err))
+    }
+
+    fn corrupted_key(
+        what: &str,
+        err: impl std::error::Error + Send + Sync + 'static,
+    ) -> StorageErr {
+        StorageErr {
+            msg: format!("counter key {what}: {err}"),
+            source: Some(Box::new(err)),
+            transient: false,
+        }
     }
 
     #[cfg(test)]
     mod tests {
-        use super::{
-            key_for_counter, key_for_counter_v2, partial_counter_from_counter_key,
-            prefix_for_namespace, CounterKey,
-        };
+        use super::{key_for_counter, partial_counter_from_counter_key, CounterKey};
         use crate::counter::Counter;
         use crate::Limit;
         use std::collections::HashMap;
@@ -393,6 +515,7 @@ pub mod bin {
             assert_eq!(counter, partial_counter_from_counter_key(&raw));
         }
 
+        #[cfg(feature = "disk_storage")]
         #[test]
         fn counter_key_starts_with_namespace_prefix() {
             let namespace = "ns_counter:";
@@ -410,10 +533,11 @@ pub mod bin {
                 .expect("must have a counter");
             let serialized_counter = key_for_counter(&counter);
 
-            let prefix = prefix_for_namespace(namespace);
+            let prefix = super::prefix_for_namespace(namespace);
             assert_eq!(&serialized_counter[..prefix.len()], &prefix);
         }
 
+        #[cfg(any(feature = "redis_storage", feature = "distributed_storage"))]
         #[test]
         fn counters_with_id() {
             let namespace = "ns_counter:";
@@ -451,11 +575,11 @@ pub mod bin {
 
             // serialized_counter_v2 will only encode the id.... so it will be smaller for
             // counters with an id.
-            let serialized_counter_with_id_v2 = key_for_counter_v2(&counter_with_id);
+            let serialized_counter_with_id_v2 = super::key_for_counter_v2(&counter_with_id);
             assert_eq!(serialized_counter_with_id_v2.clone().len(), 19);
 
             // but continues to be large for counters without an id.
-            let serialized_counter_without_id_v2 = key_for_counter_v2(&counter_without_id);
+            let serialized_counter_without_id_v2 = super::key_for_counter_v2(&counter_without_id);
             assert_eq!(serialized_counter_without_id_v2.clone().len(), 47);
         }
     }
@@ -1,17 +1,28 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
-use crate::limit::{Limit, Namespace};
+use crate::limit::{Limit, Namespace, OnTooManyCounters};
+use crate::storage::dump::CounterDump;
 use crate::InMemoryStorage;
 use async_trait::async_trait;
+use metrics::{counter, histogram};
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime};
 
+#[cfg(feature = "tokio")]
+pub mod blocking;
 #[cfg(feature = "disk_storage")]
 pub mod disk;
 #[cfg(feature = "distributed_storage")]
 pub mod distributed;
+pub mod dump;
+#[cfg(feature = "tokio")]
+pub mod fallback;
 pub mod in_memory;
+#[cfg(feature = "tokio")]
+pub mod resilient;
 
 #[cfg(feature = "distributed_storage")]
 pub use crate::storage::distributed::CrInMemoryStorage as DistributedInMemoryStorage;
@@ -20,7 +31,6 @@ pub use crate::storage::distributed::CrInMemoryStorage as DistributedInMemorySto
 pub mod redis;
 
 mod atomic_expiring_value;
-#[cfg(any(feature = "disk_storage", feature = "redis_storage"))]
 mod keys;
 
 pub enum Authorization {
@@ -28,6 +38,16 @@ pub enum Authorization {
     Limited(Option<String>), // First counter found over the limits
 }
 
+// Times a call to the `counters`, see `observe`
+macro_rules! observed {
+    ($counters:expr, $operation:literal, $call:expr) => {{
+        let started = Instant::now();
+        let result = $call;
+        observe($counters.backend(), $operation, started, &result);
+        result
+    }};
+}
+
 pub struct Storage {
     limits: RwLock<HashMap<Namespace, HashSet<Arc<Limit>>>>,
     counters: Box<dyn CounterStorage>,
@@ -46,6 +66,13 @@ impl Storage {
         }
     }
 
+    pub fn with_clock(cache_size: u64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            limits: RwLock::new(HashMap::new()),
+            counters: Box::new(InMemoryStorage::with_clock(cache_size, clock)),
+        }
+    }
+
     pub fn with_counter_storage(counters: Box<dyn CounterStorage>) -> Self {
         Self {
             limits: RwLock::new(HashMap::new()),
@@ -53,6 +80,11 @@ impl Storage {
         }
     }
 
+    /// The clock the counters tell the time with.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.counters.clock()
+    }
+
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
         self.limits.read().unwrap().keys().cloned().collect()
     }
@@ -69,7 +101,11 @@ impl Storage {
         let limits = namespaces.get_mut(update.namespace());
         if let Some(limits) = limits {
             let req_update = if let Some(limit) = limits.get(update) {
-                limit.max_value() != update.max_value() || limit.name() != update.name()
+                limit.max_value() != update.max_value()
+                    || limit.name() != update.name()
+                    || limit.on_error() != update.on_error()
+                    || limit.max_counters() != update.max_counters()
+                    || limit.on_too_many_counters() != update.on_too_many_counters()
             } else {
                 false
             };
@@ -101,7 +137,11 @@ impl Storage {
         };
         let mut limits = HashSet::new();
         limits.insert(arc);
-        self.counters.delete_counters(&limits)?;
+        observed!(
+            self.counters,
+            "delete_counters",
+            self.counters.delete_counters(&limits)
+        )?;
 
         let mut limits = self.limits.write().unwrap();
 
@@ -117,17 +157,46 @@ impl Storage {
 
     pub fn delete_limits(&self, namespace: &Namespace) -> Result<(), StorageErr> {
         if let Some(data) = self.limits.write().unwrap().remove(namespace) {
-            self.counters.delete_counters(&data)?;
+            observed!(
+                self.counters,
+                "delete_counters",
+                self.counters.delete_counters(&data)
+            )?;
         }
         Ok(())
     }
 
     pub fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
-        self.counters.is_within_limits(counter, delta)
+        match self.check_admission(counter)? {
+            Admission::Tracked => observed!(
+                self.counters,
+                "is_within_limits",
+                self.counters.is_within_limits(counter, delta)
+            ),
+            Admission::Denied => Ok(false),
+            Admission::Untracked => Ok(true),
+            Admission::Overflow(overflow) => observed!(
+                self.counters,
+                "is_within_limits",
+                self.counters.is_within_limits(&overflow, delta)
+            ),
+        }
     }
 
     pub fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
-        self.counters.update_counter(counter, delta)
+        match self.admit(counter)? {
+            Admission::Tracked => observed!(
+                self.counters,
+                "update_counter",
+                self.counters.update_counter(counter, delta)
+            ),
+            Admission::Denied | Admission::Untracked => Ok(()),
+            Admission::Overflow(overflow) => observed!(
+                self.counters,
+                "update_counter",
+                self.counters.update_counter(&overflow, delta)
+            ),
+        }
     }
 
     pub fn check_and_update(
@@ -136,20 +205,131 @@ impl Storage {
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        self.counters
-            .check_and_update(counters, delta, load_counters)
+        if counters.iter().any(is_capped) {
+            // The room for new counters only gets taken once all of them are
+            // admitted, so that a denied request doesn't hold any
+            let mut admissions = Vec::with_capacity(counters.len());
+            for counter in counters.iter() {
+                admissions.push(self.check_admission(counter)?);
+            }
+            if let Some(denied) = Admission::denial(counters, &admissions) {
+                return Ok(denied);
+            }
+            for (counter, admission) in counters.iter().zip(admissions.iter_mut()) {
+                if matches!(admission, Admission::Tracked) {
+                    *admission = self.admit(counter)?;
+                }
+            }
+            if let Some(denied) = Admission::denial(counters, &admissions) {
+                return Ok(denied);
+            }
+            for (counter, admission) in std::mem::take(counters).into_iter().zip(admissions) {
+                admission.apply(counter, counters);
+            }
+        }
+        observed!(
+            self.counters,
+            "check_and_update",
+            self.counters
+                .check_and_update(counters, delta, load_counters)
+        )
+    }
+
+    /// The number of qualified counters `limit` has in the storage.
+    pub fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        observed!(
+            self.counters,
+            "cardinality",
+            self.counters.cardinality(limit)
+        )
+    }
+
+    fn admit(&self, counter: &Counter) -> Result<Admission, StorageErr> {
+        if let Some(max) = counter.limit().max_counters() {
+            if is_capped(counter)
+                && !observed!(
+                    self.counters,
+                    "admit_counter",
+                    self.counters.admit_counter(counter, max)
+                )?
+            {
+                return Ok(Admission::over_cap(counter));
+            }
+        }
+        Ok(Admission::Tracked)
+    }
+
+    /// Like [`Storage::admit`], without taking any room for `counter`.
+    fn check_admission(&self, counter: &Counter) -> Result<Admission, StorageErr> {
+        if let Some(max) = counter.limit().max_counters() {
+            if is_capped(counter)
+                && !observed!(
+                    self.counters,
+                    "can_admit_counter",
+                    self.counters.can_admit_counter(counter, max)
+                )?
+            {
+                return Ok(Admission::over_cap(counter));
+            }
+        }
+        Ok(Admission::Tracked)
     }
 
     pub fn get_counters(&self, namespace: &Namespace) -> Result<HashSet<Counter>, StorageErr> {
         match self.limits.read().unwrap().get(namespace) {
-            Some(limits) => self.counters.get_counters(limits),
+            Some(limits) => observed!(
+                self.counters,
+                "get_counters",
+                self.counters.get_counters(limits)
+            ),
             None => Ok(HashSet::new()),
         }
     }
 
     pub fn clear(&self) -> Result<(), StorageErr> {
         self.limits.write().unwrap().clear();
-        self.counters.clear()
+        observed!(self.counters, "clear", self.counters.clear())
+    }
+
+    /// Dumps the live counters of the limits of `namespace`, see [`dump`].
+    pub fn export(&self, namespace: &Namespace) -> Result<Vec<CounterDump>, StorageErr> {
+        let now = self.clock().now();
+        let counters = self.get_counters(namespace)?;
+        Ok(dumps_of(&counters, now))
+    }
+
+    /// Dumps the live counters of `limit`, see [`dump`].
+    pub fn export_limit(&self, limit: &Limit) -> Result<Vec<CounterDump>, StorageErr> {
+        let now = self.clock().now();
+        let limits = HashSet::from([Arc::new(limit.clone())]);
+        let counters = observed!(
+            self.counters,
+            "get_counters",
+            self.counters.get_counters(&limits)
+        )?;
+        Ok(dumps_of(&counters, now))
+    }
+
+    /// Sets the counters of the limits the `dumps` are of to them, see
+    /// [`CounterStorage::import_counter`], skipping the expired ones and those
+    /// of limits unknown to this storage. Returns how many got imported.
+    pub fn import(&self, dumps: impl IntoIterator<Item = CounterDump>) -> Result<u64, StorageErr> {
+        let now = self.clock().now();
+        let mut imported = 0;
+        for dump in dumps {
+            let Some(limit) = limit_of(&self.limits.read().unwrap(), &dump, now) else {
+                continue;
+            };
+            let counter = dump.counter_of(limit);
+            observed!(
+                self.counters,
+                "import_counter",
+                self.counters
+                    .import_counter(&counter, dump.value, dump.expiry())
+            )?;
+            imported += 1;
+        }
+        Ok(imported)
     }
 }
 
@@ -161,6 +341,11 @@ impl AsyncStorage {
         }
     }
 
+    /// The clock the counters tell the time with.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.counters.clock()
+    }
+
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
         self.limits.read().unwrap().keys().cloned().collect()
     }
@@ -169,6 +354,7 @@ impl AsyncStorage {
         let namespace = limit.namespace().clone();
 
         let mut limits_for_namespace = self.limits.write().unwrap();
+        self.counters.add_counter(&limit).unwrap();
 
         match limits_for_namespace.get_mut(&namespace) {
             Some(limits) => limits.insert(Arc::new(limit)),
@@ -186,7 +372,11 @@ impl AsyncStorage {
         let limits = namespaces.get_mut(update.namespace());
         if let Some(limits) = limits {
             let req_update = if let Some(limit) = limits.get(update) {
-                limit.max_value() != update.max_value() || limit.name() != update.name()
+                limit.max_value() != update.max_value()
+                    || limit.name() != update.name()
+                    || limit.on_error() != update.on_error()
+                    || limit.max_counters() != update.max_counters()
+                    || limit.on_too_many_counters() != update.on_too_many_counters()
             } else {
                 false
             };
@@ -217,7 +407,11 @@ impl AsyncStorage {
         };
         let mut limits = HashSet::new();
         limits.insert(arc);
-        self.counters.delete_counters(&limits).await?;
+        observed!(
+            self.counters,
+            "delete_counters",
+            self.counters.delete_counters(&limits).await
+        )?;
 
         let mut limits_for_namespace = self.limits.write().unwrap();
 
@@ -234,7 +428,11 @@ impl AsyncStorage {
     pub async fn delete_limits(&self, namespace: &Namespace) -> Result<(), StorageErr> {
         let option = { self.limits.write().unwrap().remove(namespace) };
         if let Some(data) = option {
-            self.counters.delete_counters(&data).await?;
+            observed!(
+                self.counters,
+                "delete_counters",
+                self.counters.delete_counters(&data).await
+            )?;
         }
         Ok(())
     }
@@ -244,11 +442,36 @@ impl AsyncStorage {
         counter: &Counter,
         delta: u64,
     ) -> Result<bool, StorageErr> {
-        self.counters.is_within_limits(counter, delta).await
+        match self.check_admission(counter).await? {
+            Admission::Tracked => observed!(
+                self.counters,
+                "is_within_limits",
+                self.counters.is_within_limits(counter, delta).await
+            ),
+            Admission::Denied => Ok(false),
+            Admission::Untracked => Ok(true),
+            Admission::Overflow(overflow) => observed!(
+                self.counters,
+                "is_within_limits",
+                self.counters.is_within_limits(&overflow, delta).await
+            ),
+        }
     }
 
     pub async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
-        self.counters.update_counter(counter, delta).await
+        match self.admit(counter).await? {
+            Admission::Tracked => observed!(
+                self.counters,
+                "update_counter",
+                self.counters.update_counter(counter, delta).await
+            ),
+            Admission::Denied | Admission::Untracked => Ok(()),
+            Admission::Overflow(overflow) => observed!(
+                self.counters,
+                "update_counter",
+                self.counters.update_counter(&overflow, delta).await
+            ),
+        }
     }
 
     pub async fn check_and_update(
@@ -257,9 +480,74 @@ impl AsyncStorage {
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        self.counters
-            .check_and_update(counters, delta, load_counters)
-            .await
+        if counters.iter().any(is_capped) {
+            // See [`Storage::check_and_update`]
+            let mut admissions = Vec::with_capacity(counters.len());
+            for counter in counters.iter() {
+                admissions.push(self.check_admission(counter).await?);
+            }
+            if let Some(denied) = Admission::denial(counters, &admissions) {
+                return Ok(denied);
+            }
+            for (counter, admission) in counters.iter().zip(admissions.iter_mut()) {
+                if matches!(admission, Admission::Tracked) {
+                    *admission = self.admit(counter).await?;
+                }
+            }
+            if let Some(denied) = Admission::denial(counters, &admissions) {
+                return Ok(denied);
+            }
+            for (counter, admission) in std::mem::take(counters).into_iter().zip(admissions) {
+                admission.apply(counter, counters);
+            }
+        }
+        observed!(
+            self.counters,
+            "check_and_update",
+            self.counters
+                .check_and_update(counters, delta, load_counters)
+                .await
+        )
+    }
+
+    /// The number of qualified counters `limit` has in the storage.
+    pub async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        observed!(
+            self.counters,
+            "cardinality",
+            self.counters.cardinality(limit).await
+        )
+    }
+
+    async fn admit(&self, counter: &Counter) -> Result<Admission, StorageErr> {
+        if let Some(max) = counter.limit().max_counters() {
+            if is_capped(counter)
+                && !observed!(
+                    self.counters,
+                    "admit_counter",
+                    self.counters.admit_counter(counter, max).await
+                )?
+            {
+                return Ok(Admission::over_cap(counter));
+            }
+        }
+        Ok(Admission::Tracked)
+    }
+
+    /// Like [`AsyncStorage::admit`], without taking any room for `counter`.
+    async fn check_admission(&self, counter: &Counter) -> Result<Admission, StorageErr> {
+        if let Some(max) = counter.limit().max_counters() {
+            if is_capped(counter)
+                && !observed!(
+                    self.counters,
+                    "can_admit_counter",
+                    self.counters.can_admit_counter(counter, max).await
+                )?
+            {
+                return Ok(Admission::over_cap(counter));
+            }
+        }
+        Ok(Admission::Tracked)
     }
 
     pub async fn get_counters(
@@ -267,13 +555,174 @@ impl AsyncStorage {
         namespace: &Namespace,
     ) -> Result<HashSet<Counter>, StorageErr> {
         let limits = self.get_limits(namespace);
-        self.counters.get_counters(&limits).await
+        observed!(
+            self.counters,
+            "get_counters",
+            self.counters.get_counters(&limits).await
+        )
     }
 
     pub async fn clear(&self) -> Result<(), StorageErr> {
         self.limits.write().unwrap().clear();
-        self.counters.clear().await
+        observed!(self.counters, "clear", self.counters.clear().await)
+    }
+
+    /// Dumps the live counters of the limits of `namespace`, see [`dump`].
+    pub async fn export(&self, namespace: &Namespace) -> Result<Vec<CounterDump>, StorageErr> {
+        let now = self.clock().now();
+        let counters = self.get_counters(namespace).await?;
+        Ok(dumps_of(&counters, now))
+    }
+
+    /// Dumps the live counters of `limit`, see [`dump`].
+    pub async fn export_limit(&self, limit: &Limit) -> Result<Vec<CounterDump>, StorageErr> {
+        let now = self.clock().now();
+        let limits = HashSet::from([Arc::new(limit.clone())]);
+        let counters = observed!(
+            self.counters,
+            "get_counters",
+            self.counters.get_counters(&limits).await
+        )?;
+        Ok(dumps_of(&counters, now))
+    }
+
+    /// Sets the counters of the limits the `dumps` are of to them, see
+    /// [`AsyncCounterStorage::import_counter`], skipping the expired ones and
+    /// those of limits unknown to this storage. Returns how many got imported.
+    pub async fn import(
+        &self,
+        dumps: impl IntoIterator<Item = CounterDump>,
+    ) -> Result<u64, StorageErr> {
+        let now = self.clock().now();
+        let mut imported = 0;
+        for dump in dumps {
+            let limit = limit_of(&self.limits.read().unwrap(), &dump, now);
+            let Some(limit) = limit else {
+                continue;
+            };
+            let counter = dump.counter_of(limit);
+            observed!(
+                self.counters,
+                "import_counter",
+                self.counters
+                    .import_counter(&counter, dump.value, dump.expiry())
+                    .await
+            )?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+}
+
+// The dumps of the `counters` still live by `now`
+fn dumps_of(counters: &HashSet<Counter>, now: SystemTime) -> Vec<CounterDump> {
+    counters
+        .iter()
+        .filter_map(|c| CounterDump::of(c, now))
+        .collect()
+}
+
+// The limit of `dump` among `limits`, unless it has expired by `now`
+fn limit_of(
+    limits: &HashMap<Namespace, HashSet<Arc<Limit>>>,
+    dump: &CounterDump,
+    now: SystemTime,
+) -> Option<Arc<Limit>> {
+    if dump.expiry() <= now {
+        return None;
+    }
+    limits
+        .get(&Namespace::from(dump.namespace.as_str()))?
+        .iter()
+        .find(|limit| dump.is_of(limit))
+        .cloned()
+}
+
+/// Records how long an `operation` on the counters of a `backend` took, in the
+/// `storage_operation_duration_seconds` histogram, and counts its failures in
+/// `storage_errors`, by whether they're transient or not.
+fn observe<T>(
+    backend: &'static str,
+    operation: &'static str,
+    started: Instant,
+    result: &Result<T, StorageErr>,
+) {
+    histogram!(
+        "storage_operation_duration_seconds",
+        "backend" => backend,
+        "operation" => operation
+    )
+    .record(started.elapsed().as_secs_f64());
+    if let Err(err) = result {
+        let kind = if err.is_transient() {
+            "transient"
+        } else {
+            "permanent"
+        };
+        counter!(
+            "storage_errors",
+            "backend" => backend,
+            "operation" => operation,
+            "kind" => kind
+        )
+        .increment(1);
+    }
+}
+
+/// What becomes of a counter, given the cap on the number of qualified counters
+/// of its limit.
+enum Admission {
+    Tracked,
+    Denied,
+    Untracked,
+    Overflow(Counter),
+}
+
+impl Admission {
+    fn over_cap(counter: &Counter) -> Self {
+        counter!(
+            "limit_counters_over_cap",
+            "limitador_namespace" => counter.namespace().as_ref().to_string()
+        )
+        .increment(1);
+        match counter.limit().on_too_many_counters() {
+            OnTooManyCounters::Deny => Admission::Denied,
+            OnTooManyCounters::AllowUntracked => Admission::Untracked,
+            OnTooManyCounters::Overflow => Admission::Overflow(counter.overflow()),
+        }
+    }
+
+    /// Adds what has to be checked and updated in place of `counter` to
+    /// `counters`.
+    fn apply(self, counter: Counter, counters: &mut Vec<Counter>) {
+        match self {
+            Admission::Tracked | Admission::Denied => counters.push(counter),
+            Admission::Untracked => {}
+            Admission::Overflow(overflow) => counters.push(overflow),
+        }
     }
+
+    /// The request for `counters` is limited when any of their `admissions`
+    /// is denied, by the first of them. The denied counters are left with
+    /// nothing remaining, and all the `counters` are kept.
+    fn denial(counters: &mut [Counter], admissions: &[Admission]) -> Option<Authorization> {
+        let mut denial = None;
+        for (counter, admission) in counters.iter_mut().zip(admissions) {
+            if matches!(admission, Admission::Denied) {
+                denial.get_or_insert_with(|| {
+                    Authorization::Limited(counter.limit().name().map(|n| n.to_owned()))
+                });
+                counter.set_remaining(0);
+            }
+        }
+        denial
+    }
+}
+
+/// Whether `counter` is subject to the cap on the number of qualified counters
+/// of its limit.
+fn is_capped(counter: &Counter) -> bool {
+    counter.is_qualified() && counter.limit().max_counters().is_some()
 }
 
 pub trait CounterStorage: Sync + Send {
@@ -289,10 +738,120 @@ pub trait CounterStorage: Sync + Send {
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr>; // todo revise typing here?
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr>; // todo revise typing here?
     fn clear(&self) -> Result<(), StorageErr>;
+    /// Whether `counter`, qualified, is one of the at most `max_counters` live
+    /// counters of its limit, making it one if there's room left for it. The
+    /// check and the taking of the room are atomic, so that concurrent requests
+    /// creating counters can't get the limit past its cap. The storages that
+    /// can't cap the counters of a limit admit them all.
+    fn admit_counter(&self, _counter: &Counter, _max_counters: u64) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+    /// Whether [`CounterStorage::admit_counter`] would admit `counter`, without
+    /// taking any room for it.
+    fn can_admit_counter(
+        &self,
+        _counter: &Counter,
+        _max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+    /// The number of qualified counters of `limit` hit during their current
+    /// window. The storages that can't count them err.
+    fn cardinality(&self, _limit: &Limit) -> Result<u64, StorageErr> {
+        Err(StorageErr::unsupported("cardinality"))
+    }
+    /// Sets `counter` to `value`, expiring at `expires_at`, whatever it was at.
+    /// The storages that can't set a counter add `value` to it instead, as a
+    /// hit would.
+    fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        _expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        self.update_counter(counter, value)
+    }
+    /// The clock the storage tells the time with.
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+    /// The kind of storage the counters are in, that labels the metrics of
+    /// the calls to it, e.g. `memory`.
+    fn backend(&self) -> &'static str {
+        "custom"
+    }
+}
+
+// So that a storage can be shared with the [`Storage`] it's handed to
+impl<S: CounterStorage + ?Sized> CounterStorage for Arc<S> {
+    fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        (**self).is_within_limits(counter, delta)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        (**self).add_counter(limit)
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        (**self).update_counter(counter, delta)
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        (**self).check_and_update(counters, delta, load_counters)
+    }
+
+    fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
+        (**self).get_counters(limits)
+    }
+
+    fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        (**self).delete_counters(limits)
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        (**self).clear()
+    }
+
+    fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        (**self).admit_counter(counter, max_counters)
+    }
+
+    fn can_admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        (**self).can_admit_counter(counter, max_counters)
+    }
+
+    fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        (**self).cardinality(limit)
+    }
+
+    fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        (**self).import_counter(counter, value, expires_at)
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        (**self).clock()
+    }
+
+    fn backend(&self) -> &'static str {
+        (**self).backend()
+    }
 }
 
 #[async_trait]
 pub trait AsyncCounterStorage: Sync + Send {
+    fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
+        Ok(())
+    }
     async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr>;
     async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr>;
     async fn check_and_update<'a>(
@@ -307,12 +866,49 @@ pub trait AsyncCounterStorage: Sync + Send {
     ) -> Result<HashSet<Counter>, StorageErr>;
     async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr>;
     async fn clear(&self) -> Result<(), StorageErr>;
+    /// See [`CounterStorage::admit_counter`].
+    async fn admit_counter(
+        &self,
+        _counter: &Counter,
+        _max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+    /// See [`CounterStorage::can_admit_counter`].
+    async fn can_admit_counter(
+        &self,
+        _counter: &Counter,
+        _max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+    /// See [`CounterStorage::cardinality`].
+    async fn cardinality(&self, _limit: &Limit) -> Result<u64, StorageErr> {
+        Err(StorageErr::unsupported("cardinality"))
+    }
+    /// See [`CounterStorage::import_counter`].
+    async fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        _expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        self.update_counter(counter, value).await
+    }
+    /// See [`CounterStorage::clock`].
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+    /// See [`CounterStorage::backend`].
+    fn backend(&self) -> &'static str {
+        "custom"
+    }
 }
 
 #[derive(Debug)]
 pub struct StorageErr {
     msg: String,
-    source: Option<Box<dyn Error + 'static>>,
+    source: Option<Box<dyn Error + Send + Sync + 'static>>,
     transient: bool,
 }
 
@@ -324,7 +920,9 @@ impl Display for StorageErr {
 
 impl Error for StorageErr {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.source.as_ref().map(|source| source.as_ref())
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn Error + 'static))
     }
 }
 
@@ -336,4 +934,12 @@ impl StorageErr {
     pub fn is_transient(&self) -> bool {
         self.transient
     }
+
+    fn unsupported(operation: &str) -> Self {
+        Self {
+            msg: format!("{operation} isn't supported by this storage"),
+            source: None,
+            transient: false,
+        }
+    }
 }
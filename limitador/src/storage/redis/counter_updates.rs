@@ -0,0 +1,174 @@
+// The values the instances of the cached Redis storage flush, published on a
+// channel of the Redis they share, so that the others apply them right away
+// instead of on their next flush.
+
+use crate::counter::Counter;
+use crate::storage::redis::connection::pubsub_connection;
+use crate::storage::redis::counters_cache::CountersCache;
+use crate::storage::redis::redis_cached::PendingUpdate;
+use crate::storage::redis::KeyPrefix;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+const CHANNEL: &str = "limitador:counter_updates";
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize)]
+struct Message<'a> {
+    origin: u64,
+    updates: Vec<Update<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Update<'a> {
+    // the limit of a deserialized counter lacks its id and max value, which
+    // don't take part in its equality, see `impl PartialEq for Limit`
+    counter: Cow<'a, Counter>,
+    max_value: u64,
+    value: u64,
+    expires_at_ms: u64,
+}
+
+pub(super) struct CounterUpdates {
+    channel: Vec<u8>,
+    // tells the messages of this instance apart
+    origin: u64,
+}
+
+impl CounterUpdates {
+    pub(super) fn new(key_prefix: &KeyPrefix) -> Self {
+        Self {
+            channel: key_prefix.prefixed(CHANNEL.as_bytes().to_vec()),
+            origin: RandomState::new().build_hasher().finish(),
+        }
+    }
+
+    /// The `PUBLISH` of the `updates` just flushed: each counter with its new
+    /// value and expiration.
+    pub(super) fn publish_cmd(&self, updates: &[PendingUpdate]) -> redis::Cmd {
+        let message = Message {
+            origin: self.origin,
+            updates: updates
+                .iter()
+                .map(|(counter, value, _, expires_at)| Update {
+                    counter: Cow::Borrowed(counter),
+                    max_value: counter.max_value(),
+                    value: *value,
+                    expires_at_ms: expires_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                })
+                .collect(),
+        };
+        let mut cmd = redis::cmd("PUBLISH");
+        cmd.arg(&self.channel)
+            .arg(serde_json::to_vec(&message).expect("Counters serialize to JSON"));
+        cmd
+    }
+
+    fn apply(&self, payload: &[u8], cache: &CountersCache) {
+        let message: Message = match serde_json::from_slice(payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Ignoring a malformed counter update: {}", err);
+                return;
+            }
+        };
+        if message.origin == self.origin {
+            return;
+        }
+        for update in message.updates {
+            let mut counter = update.counter.into_owned();
+            let mut limit = counter.limit().clone();
+            limit.set_max_value(update.max_value);
+            counter.update_to_limit(Arc::new(limit));
+            cache.apply_remote_value(
+                counter,
+                update.value,
+                UNIX_EPOCH + Duration::from_millis(update.expires_at_ms),
+            );
+        }
+    }
+
+    /// Applies the updates the other instances publish to `cache`, subscribing
+    /// again whenever the connection drops.
+    pub(super) async fn subscribe(
+        self: Arc<Self>,
+        redis_url: String,
+        cluster: bool,
+        cache: Arc<CountersCache>,
+    ) {
+        loop {
+            match pubsub_connection(&redis_url, cluster).await {
+                Ok(mut pubsub) => match pubsub.subscribe(&self.channel).await {
+                    Ok(()) => {
+                        info!("Subscribed to the counter updates");
+                        let mut messages = pubsub.into_on_message();
+                        while let Some(msg) = messages.next().await {
+                            self.apply(msg.get_payload_bytes(), &cache);
+                        }
+                        warn!("Lost the subscription to the counter updates");
+                    }
+                    Err(err) => warn!("Couldn't subscribe to the counter updates: {}", err),
+                },
+                Err(err) => warn!("Couldn't subscribe to the counter updates: {}", err),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CounterUpdates;
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use crate::storage::redis::counters_cache::CountersCacheBuilder;
+    use crate::storage::redis::KeyPrefix;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn applies_the_updates_of_other_instances() {
+        let limit = Limit::with_id(
+            "limit_id",
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'POST'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit, &ctx).unwrap().unwrap();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+
+        let cache = CountersCacheBuilder::new().build(Duration::default());
+        cache.apply_remote_delta(counter.clone(), 2, 0, expires_at);
+
+        let publisher = CounterUpdates::new(&KeyPrefix::new("limitador:").unwrap());
+        let cmd = publisher.publish_cmd(&[(counter.clone(), 7, 0, expires_at)]);
+        let args: Vec<_> = cmd.args_iter().collect();
+        let redis::Arg::Simple(channel) = args[1] else {
+            panic!("channel expected")
+        };
+        assert_eq!(channel, b"limitador:limitador:counter_updates");
+        let redis::Arg::Simple(payload) = args[2] else {
+            panic!("payload expected")
+        };
+
+        // its own updates are ignored
+        publisher.apply(payload, &cache);
+        assert_eq!(cache.get(&counter).unwrap().hits(&counter), 2);
+
+        let subscriber = CounterUpdates::new(&KeyPrefix::new("limitador:").unwrap());
+        subscriber.apply(payload, &cache);
+        assert_eq!(cache.get(&counter).unwrap().hits(&counter), 7);
+    }
+}
@@ -1,4 +1,6 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
+use crate::limit::Limit;
 use crate::storage::atomic_expiring_value::AtomicExpiringValue;
 use crate::storage::redis::DEFAULT_MAX_CACHED_COUNTERS;
 use dashmap::mapref::entry::Entry;
@@ -11,7 +13,7 @@ use std::future::Future;
 use std::ops::Not;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::select;
 use tokio::sync::{Notify, Semaphore};
 use tracing::info;
@@ -21,24 +23,93 @@ pub struct CachedCounterValue {
     value: AtomicExpiringValue,
     initial_value: AtomicU64,
     from_authority: AtomicBool,
+    // micros since the epoch of the oldest write not yet flushed, 0 if none
+    pending_since: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+/// When the pending writes of the [`CachedRedisStorage`] are flushed to Redis.
+///
+/// [`CachedRedisStorage`]: crate::storage::redis::CachedRedisStorage
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushPolicy {
+    /// Flushes every flushing period, or sooner when a batch fills up or a
+    /// counter is about to expire.
+    #[default]
+    Periodic,
+    /// Also flushes a counter right away when its pending writes reach
+    /// `hot_ratio` of its remaining headroom, and holds back a counter whose
+    /// pending writes are below `cold_ratio` of it for up to `cold_periods`
+    /// flushing periods.
+    Adaptive {
+        hot_ratio: f64,
+        cold_ratio: f64,
+        cold_periods: u32,
+    },
+}
+
+impl FlushPolicy {
+    /// An [`FlushPolicy::Adaptive`] policy, flushing counters with pending
+    /// writes of half their headroom right away, and holding back those under
+    /// 5% of it for up to 10 periods.
+    pub fn adaptive() -> Self {
+        Self::Adaptive {
+            hot_ratio: 0.5,
+            cold_ratio: 0.05,
+            cold_periods: 10,
+        }
+    }
+
+    fn is_hot(&self, counter: &Counter, value: &CachedCounterValue) -> bool {
+        match self {
+            Self::Periodic => false,
+            Self::Adaptive { hot_ratio, .. } => {
+                let (pending, headroom) = value.pending_writes_and_headroom(counter);
+                pending > 0 && pending as f64 >= hot_ratio * headroom as f64
+            }
+        }
+    }
+
+    fn holds_back(&self, counter: &Counter, value: &CachedCounterValue, period: Duration) -> bool {
+        match self {
+            Self::Periodic => false,
+            Self::Adaptive {
+                cold_ratio,
+                cold_periods,
+                ..
+            } => {
+                let (pending, headroom) = value.pending_writes_and_headroom(counter);
+                (pending as f64) < cold_ratio * headroom as f64
+                    && value.staleness() < period * *cold_periods
+            }
+        }
+    }
 }
 
 impl CachedCounterValue {
-    pub fn from_authority(counter: &Counter, value: u64) -> Self {
-        let now = SystemTime::now();
+    pub fn from_authority(counter: &Counter, value: u64, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
         Self {
             value: AtomicExpiringValue::new(value, now + counter.window()),
             initial_value: AtomicU64::new(value),
             from_authority: AtomicBool::new(true),
+            pending_since: AtomicU64::new(0),
+            clock,
         }
     }
 
-    pub fn load_from_authority_asap(counter: &Counter, temp_value: u64) -> Self {
-        let now = SystemTime::now();
+    pub fn load_from_authority_asap(
+        counter: &Counter,
+        temp_value: u64,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
         Self {
             value: AtomicExpiringValue::new(temp_value, now + counter.window()),
             initial_value: AtomicU64::new(0),
             from_authority: AtomicBool::new(false),
+            pending_since: AtomicU64::new(0),
+            clock,
         }
     }
 
@@ -53,24 +124,54 @@ impl CachedCounterValue {
     }
 
     pub fn delta(&self, counter: &Counter, delta: u64) -> u64 {
-        let value = self
-            .value
-            .update(delta, counter.window(), SystemTime::now());
+        let now = self.clock.now();
+        let value = self.value.update(delta, counter.window(), now);
         if value == delta {
             // new window, invalidate initial value
             // which happens _after_ the self.value was reset, see `pending_writes`
             self.initial_value.store(0, Ordering::SeqCst);
         }
+        if delta > 0 {
+            self.mark_pending_since(now);
+        }
         value
     }
 
+    fn mark_pending_since(&self, when: SystemTime) {
+        let micros = when
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let _ = self.pending_since.compare_exchange(
+            0,
+            micros.max(1),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// How long the oldest of the pending writes has been waiting to be
+    /// flushed.
+    pub fn staleness(&self) -> Duration {
+        match self.pending_since.load(Ordering::Acquire) {
+            0 => Duration::ZERO,
+            micros => self
+                .clock
+                .now()
+                .duration_since(UNIX_EPOCH + Duration::from_micros(micros))
+                .unwrap_or_default(),
+        }
+    }
+
     pub fn pending_writes(&self) -> Result<u64, ()> {
         self.pending_writes_and_value().map(|(writes, _)| writes)
     }
 
     pub fn pending_writes_and_value(&self) -> Result<(u64, u64), ()> {
+        // cleared first, so that a write racing with this is flushed next time
+        let since = self.pending_since.swap(0, Ordering::AcqRel);
         let start = self.initial_value.load(Ordering::SeqCst);
-        let value = self.value.value_at(SystemTime::now());
+        let value = self.value.value_at(self.clock.now());
         let offset = if start == 0 {
             value
         } else {
@@ -85,6 +186,12 @@ impl CachedCounterValue {
         {
             Ok(_) => Ok((offset, value)),
             Err(newer) => {
+                let _ = self.pending_since.compare_exchange(
+                    0,
+                    since,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
                 if newer == 0 {
                     // We got reset because of expiry, this fresh value can wait the next iteration
                     Ok((0, 0))
@@ -99,11 +206,23 @@ impl CachedCounterValue {
 
     fn no_pending_writes(&self) -> bool {
         let start = self.initial_value.load(Ordering::SeqCst);
-        let value = self.value.value_at(SystemTime::now());
+        let value = self.value.value_at(self.clock.now());
         value - start == 0
     }
 
+    fn pending_writes_and_headroom(&self, counter: &Counter) -> (u64, u64) {
+        let start = self.initial_value.load(Ordering::SeqCst);
+        let value = self.value.value_at(self.clock.now());
+        (
+            value.saturating_sub(start),
+            counter.max_value().saturating_sub(value),
+        )
+    }
+
     fn revert_writes(&self, writes: u64) -> Result<(), ()> {
+        if writes > 0 {
+            self.mark_pending_since(self.clock.now());
+        }
         let newer = self.initial_value.load(Ordering::SeqCst);
         if newer > writes {
             return match self.initial_value.compare_exchange(
@@ -120,23 +239,23 @@ impl CachedCounterValue {
     }
 
     pub fn hits(&self, _: &Counter) -> u64 {
-        self.value.value_at(SystemTime::now())
+        self.value.value_at(self.clock.now())
     }
 
     pub fn remaining(&self, counter: &Counter) -> u64 {
         counter.max_value() - self.hits(counter)
     }
 
-    pub fn is_limited(&self, counter: &Counter, delta: u64) -> bool {
-        self.hits(counter) as i128 + delta as i128 > counter.max_value() as i128
+    pub fn is_limited_within(&self, counter: &Counter, max_value: u64, delta: u64) -> bool {
+        self.hits(counter) as i128 + delta as i128 > max_value as i128
     }
 
     pub fn ttl(&self) -> Duration {
-        self.value.ttl()
+        self.value.ttl_at(self.clock.now())
     }
 
     pub fn requires_fast_flush(&self, within: &Duration) -> bool {
-        self.from_authority.load(Ordering::Acquire).not() || &self.value.ttl() <= within
+        self.from_authority.load(Ordering::Acquire).not() || &self.ttl() <= within
     }
 }
 
@@ -146,6 +265,7 @@ pub struct Batcher {
     interval: Duration,
     priority_flush: AtomicBool,
     limiter: Semaphore,
+    policy: FlushPolicy,
 }
 
 impl Batcher {
@@ -156,11 +276,12 @@ impl Batcher {
             interval: period,
             priority_flush: AtomicBool::new(false),
             limiter: Semaphore::new(max_cached_counters),
+            policy: FlushPolicy::default(),
         }
     }
 
     pub async fn add(&self, counter: Counter, value: Arc<CachedCounterValue>) {
-        let priority = value.requires_fast_flush(&self.interval);
+        let mut priority = value.requires_fast_flush(&self.interval);
         match self.updates.entry(counter.clone()) {
             Entry::Occupied(needs_merge) => {
                 let arc = needs_merge.get();
@@ -174,6 +295,10 @@ impl Batcher {
                 miss.insert_entry(value);
             }
         };
+        priority |= match self.updates.get(&counter) {
+            Some(entry) => self.policy.is_hot(&counter, entry.value()),
+            None => false,
+        };
         if priority {
             self.priority_flush.store(true, Ordering::Release);
         }
@@ -192,21 +317,49 @@ impl Batcher {
                 batch.extend(
                     self.updates
                         .iter()
-                        .filter(|entry| entry.value().requires_fast_flush(&self.interval))
+                        .filter(|entry| {
+                            entry.value().requires_fast_flush(&self.interval)
+                                || self.policy.is_hot(entry.key(), entry.value())
+                        })
                         .take(max)
                         .map(|e| e.key().clone()),
                 );
+                let mut held_back = false;
                 if let Some(remaining) = max.checked_sub(batch.len()) {
-                    batch.extend(self.updates.iter().take(remaining).map(|e| e.key().clone()));
+                    batch.extend(
+                        self.updates
+                            .iter()
+                            .filter(|entry| {
+                                let hold = self.policy.holds_back(
+                                    entry.key(),
+                                    entry.value(),
+                                    self.interval,
+                                );
+                                held_back |= hold;
+                                !hold
+                            })
+                            .take(remaining)
+                            .map(|e| e.key().clone()),
+                    );
+                }
+                if batch.is_empty() && held_back {
+                    // only cold counters, wait for them to warm up or age
+                    ready = self.wait_for_priority_flush().await;
+                    continue;
                 }
                 let mut result = HashMap::new();
+                let mut staleness = Vec::with_capacity(batch.len());
                 for counter in &batch {
                     let value = self.updates.get(counter).unwrap().clone();
+                    staleness.push(value.staleness());
                     result.insert(counter.clone(), value);
                 }
                 histogram!("batcher_flush_size").record(result.len() as f64);
                 let result = consumer(result).await;
                 if result.is_ok() {
+                    for stale in staleness.into_iter().filter(|s| !s.is_zero()) {
+                        histogram!("counter_staleness").record(stale.as_secs_f64());
+                    }
                     batch.iter().for_each(|counter| {
                         let prev = self
                             .updates
@@ -237,6 +390,24 @@ impl Batcher {
         }
     }
 
+    async fn wait_for_priority_flush(&self) -> bool {
+        select! {
+            _ = async {
+                loop {
+                    self.notifier.notified().await;
+                    if self
+                        .priority_flush
+                        .compare_exchange(true, false, Ordering::Release, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+            } => true,
+            _ = tokio::time::sleep(self.interval) => true,
+        }
+    }
+
     fn batch_ready(&self, size: usize) -> bool {
         self.updates.len() >= size
             || self
@@ -255,6 +426,7 @@ impl Default for Batcher {
 pub struct CountersCache {
     cache: Cache<Counter, Arc<CachedCounterValue>>,
     batcher: Batcher,
+    clock: Arc<dyn Clock>,
 }
 
 impl CountersCache {
@@ -275,6 +447,26 @@ impl CountersCache {
         &self.batcher
     }
 
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// The number of qualified counters of `limit` pending to be flushed that
+    /// haven't expired. Those flushed already are counted by Redis, and the
+    /// pending ones are few, as they get flushed every period.
+    pub fn cardinality(&self, limit: &Limit) -> u64 {
+        self.batcher
+            .updates
+            .iter()
+            .filter(|entry| {
+                let counter = entry.key();
+                counter.is_qualified()
+                    && counter.limit() == limit
+                    && entry.value().ttl() > Duration::ZERO
+            })
+            .count() as u64
+    }
+
     pub fn return_pending_writes(
         &self,
         counter: &Counter,
@@ -288,7 +480,11 @@ impl CountersCache {
                     entry.value().clone()
                 } else {
                     miss = true;
-                    let value = Arc::new(CachedCounterValue::from_authority(counter, value));
+                    let value = Arc::new(CachedCounterValue::from_authority(
+                        counter,
+                        value,
+                        Arc::clone(&self.clock),
+                    ));
                     value.delta(counter, writes);
                     value
                 }
@@ -307,7 +503,7 @@ impl CountersCache {
         remote_deltas: u64,
         expiry: SystemTime,
     ) -> Arc<CachedCounterValue> {
-        if expiry > SystemTime::now() {
+        if expiry > self.clock.now() {
             let mut from_cache = true;
             let cached = self.cache.get_with(counter.clone(), || {
                 from_cache = false;
@@ -317,7 +513,11 @@ impl CountersCache {
                     cached_value.add_from_authority(remote_deltas, expiry, counter.max_value());
                     cached_value.clone()
                 } else {
-                    Arc::new(CachedCounterValue::from_authority(&counter, redis_val))
+                    Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        redis_val,
+                        Arc::clone(&self.clock),
+                    ))
                 }
             });
             if from_cache {
@@ -326,17 +526,37 @@ impl CountersCache {
             return cached;
         }
         Arc::new(CachedCounterValue::load_from_authority_asap(
-            &counter, redis_val,
+            &counter,
+            redis_val,
+            Arc::clone(&self.clock),
         ))
     }
 
+    /// Applies the value another instance flushed to Redis of `counter`, if
+    /// it's cached here. Only what's past the last value known from Redis is
+    /// added, so that the hits this instance also learns of when flushing
+    /// aren't counted twice.
+    pub fn apply_remote_value(&self, counter: Counter, redis_val: u64, expiry: SystemTime) {
+        if let Some(cached) = self.cache.get(&counter) {
+            let remote_deltas =
+                redis_val.saturating_sub(cached.initial_value.load(Ordering::SeqCst));
+            if remote_deltas > 0 {
+                self.apply_remote_delta(counter, redis_val, remote_deltas, expiry);
+            }
+        }
+    }
+
     pub async fn increase_by(&self, counter: &Counter, delta: u64) {
         let val = self.cache.get_with_by_ref(counter, || {
             gauge!("cache_size").increment(1);
             if let Some(entry) = self.batcher.updates.get(counter) {
                 entry.value().clone()
             } else {
-                Arc::new(CachedCounterValue::load_from_authority_asap(counter, 0))
+                Arc::new(CachedCounterValue::load_from_authority_asap(
+                    counter,
+                    0,
+                    Arc::clone(&self.clock),
+                ))
             }
         });
         val.delta(counter, delta);
@@ -346,12 +566,16 @@ impl CountersCache {
 
 pub struct CountersCacheBuilder {
     max_cached_counters: usize,
+    clock: Arc<dyn Clock>,
+    flush_policy: FlushPolicy,
 }
 
 impl CountersCacheBuilder {
     pub fn new() -> Self {
         Self {
             max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
+            clock: Arc::new(SystemClock),
+            flush_policy: FlushPolicy::default(),
         }
     }
 
@@ -360,6 +584,16 @@ impl CountersCacheBuilder {
         self
     }
 
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
     fn eviction_listener(
         _key: Arc<Counter>,
         value: Arc<CachedCounterValue>,
@@ -377,7 +611,11 @@ impl CountersCacheBuilder {
                 .max_capacity(self.max_cached_counters as u64)
                 .eviction_listener(Self::eviction_listener)
                 .build(),
-            batcher: Batcher::new(period, self.max_cached_counters),
+            batcher: Batcher {
+                policy: self.flush_policy,
+                ..Batcher::new(period, self.max_cached_counters)
+            },
+            clock: Arc::clone(&self.clock),
         }
     }
 }
@@ -393,15 +631,17 @@ mod tests {
 
     mod cached_counter_value {
         use std::ops::{Add, Not};
+        use std::sync::Arc;
         use std::time::{Duration, SystemTime};
 
+        use crate::clock::{ManualClock, SystemClock};
         use crate::storage::redis::counters_cache::tests::test_counter;
         use crate::storage::redis::counters_cache::CachedCounterValue;
 
         #[test]
         fn records_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             assert_eq!(value.pending_writes(), Ok(0));
             value.delta(&counter, 5);
             assert_eq!(value.pending_writes(), Ok(5));
@@ -410,7 +650,7 @@ mod tests {
         #[test]
         fn consumes_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, 5);
             assert_eq!(value.pending_writes(), Ok(5));
             assert_eq!(value.pending_writes(), Ok(0));
@@ -419,17 +659,32 @@ mod tests {
         #[test]
         fn no_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, 5);
             assert!(value.no_pending_writes().not());
             assert!(value.pending_writes().is_ok());
             assert!(value.no_pending_writes());
         }
 
+        #[test]
+        fn tracks_staleness_of_pending_writes() {
+            let clock = Arc::new(ManualClock::default());
+            let counter = test_counter(10, None);
+            let value = CachedCounterValue::from_authority(&counter, 0, clock.clone());
+            assert_eq!(value.staleness(), Duration::ZERO);
+            value.delta(&counter, 1);
+            clock.advance(Duration::from_secs(1));
+            value.delta(&counter, 1);
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(value.staleness(), Duration::from_secs(2));
+            assert_eq!(value.pending_writes(), Ok(2));
+            assert_eq!(value.staleness(), Duration::ZERO);
+        }
+
         #[test]
         fn adding_from_auth_not_affecting_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, 5);
             assert!(value.no_pending_writes().not());
             value.add_from_authority(
@@ -444,21 +699,22 @@ mod tests {
         #[test]
         fn from_authority_no_need_to_flush() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             assert!(value.requires_fast_flush(&Duration::from_secs(30)).not());
         }
 
         #[test]
         fn from_authority_needs_to_flush_within_ttl() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             assert!(value.requires_fast_flush(&Duration::from_secs(90)));
         }
 
         #[test]
         fn fake_needs_to_flush_within_ttl() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::load_from_authority_asap(&counter, 0);
+            let value =
+                CachedCounterValue::load_from_authority_asap(&counter, 0, Arc::new(SystemClock));
             assert!(value.requires_fast_flush(&Duration::from_secs(30)));
         }
 
@@ -467,15 +723,19 @@ mod tests {
             let hits = 4;
 
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, hits);
             assert!(value.ttl() > Duration::from_millis(59999));
             assert_eq!(value.hits(&counter), hits);
             let remaining = counter.max_value() - hits;
             assert_eq!(value.remaining(&counter), remaining);
-            assert!(value.is_limited(&counter, 1).not());
-            assert!(value.is_limited(&counter, remaining).not());
-            assert!(value.is_limited(&counter, remaining + 1));
+            assert!(value
+                .is_limited_within(&counter, counter.max_value(), 1)
+                .not());
+            assert!(value
+                .is_limited_within(&counter, counter.max_value(), remaining)
+                .not());
+            assert!(value.is_limited_within(&counter, counter.max_value(), remaining + 1));
         }
     }
 
@@ -483,10 +743,33 @@ mod tests {
         use std::sync::Arc;
         use std::time::{Duration, SystemTime};
 
+        use crate::clock::{Clock, SystemClock};
         use crate::storage::redis::counters_cache::tests::test_counter;
-        use crate::storage::redis::counters_cache::{Batcher, CachedCounterValue};
+        use crate::storage::redis::counters_cache::{Batcher, CachedCounterValue, FlushPolicy};
         use crate::storage::redis::DEFAULT_MAX_CACHED_COUNTERS;
 
+        // Tells the time as tokio's clock, once paused, has it move on
+        #[derive(Debug)]
+        struct TokioClock {
+            started: tokio::time::Instant,
+            start: SystemTime,
+        }
+
+        impl TokioClock {
+            fn new() -> Self {
+                Self {
+                    started: tokio::time::Instant::now(),
+                    start: SystemTime::now(),
+                }
+            }
+        }
+
+        impl Clock for TokioClock {
+            fn now(&self) -> SystemTime {
+                self.start + self.started.elapsed()
+            }
+        }
+
         #[tokio::test]
         async fn consume_waits_when_empty() {
             let duration = Duration::from_millis(100);
@@ -512,7 +795,11 @@ mod tests {
                 tokio::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(40)).await;
                     let counter = test_counter(6, None);
-                    let arc = Arc::new(CachedCounterValue::from_authority(&counter, 0));
+                    let arc = Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
                     batcher.add(counter, arc).await;
                 });
             }
@@ -539,7 +826,11 @@ mod tests {
                 tokio::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(40)).await;
                     let counter = test_counter(6, None);
-                    let arc = Arc::new(CachedCounterValue::from_authority(&counter, 0));
+                    let arc = Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
                     batcher.add(counter, arc).await;
                 });
             }
@@ -562,7 +853,11 @@ mod tests {
             let start = SystemTime::now();
             {
                 let counter = test_counter(6, None);
-                let arc = Arc::new(CachedCounterValue::from_authority(&counter, 0));
+                let arc = Arc::new(CachedCounterValue::from_authority(
+                    &counter,
+                    0,
+                    Arc::new(SystemClock),
+                ));
                 batcher.add(counter, arc).await;
             }
             batcher
@@ -578,31 +873,123 @@ mod tests {
                 .expect("Always Ok!");
         }
 
-        #[tokio::test]
+        // on tokio's paused clock, that only moves on when all the tasks wait,
+        // so that the flush happens as the counter is added, however loaded the
+        // machine running the test is
+        #[tokio::test(start_paused = true)]
         async fn consume_triggers_on_fast_flush() {
             let duration = Duration::from_millis(100);
             let batcher = Arc::new(Batcher::new(duration, DEFAULT_MAX_CACHED_COUNTERS));
-            let start = SystemTime::now();
+            let start = tokio::time::Instant::now();
             {
                 let batcher = Arc::clone(&batcher);
                 tokio::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(40)).await;
                     let counter = test_counter(6, None);
-                    let arc = Arc::new(CachedCounterValue::load_from_authority_asap(&counter, 0));
+                    let arc = Arc::new(CachedCounterValue::load_from_authority_asap(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
                     batcher.add(counter, arc).await;
                 });
             }
             batcher
                 .consume(2, |items| {
                     assert_eq!(items.len(), 1);
-                    let wait_period = SystemTime::now().duration_since(start).unwrap();
-                    assert!(wait_period >= Duration::from_millis(40));
-                    assert!(wait_period < Duration::from_millis(50));
+                    // when the counter got added, rather than after the period
+                    assert_eq!(start.elapsed(), Duration::from_millis(40));
+                    async { Ok::<(), ()>(()) }
+                })
+                .await
+                .expect("Always Ok!");
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn consume_triggers_on_hot_counter() {
+            let duration = Duration::from_millis(100);
+            let batcher = Arc::new(Batcher {
+                policy: FlushPolicy::adaptive(),
+                ..Batcher::new(duration, DEFAULT_MAX_CACHED_COUNTERS)
+            });
+            let start = tokio::time::Instant::now();
+            {
+                let batcher = Arc::clone(&batcher);
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(40)).await;
+                    let counter = test_counter(10, None);
+                    let arc = Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
+                    arc.delta(&counter, 6);
+                    batcher.add(counter, arc).await;
+                });
+            }
+            batcher
+                .consume(2, |items| {
+                    assert_eq!(items.len(), 1);
+                    // when the counter got added, rather than after the period
+                    assert_eq!(start.elapsed(), Duration::from_millis(40));
                     async { Ok::<(), ()>(()) }
                 })
                 .await
                 .expect("Always Ok!");
         }
+
+        // the staleness of the counter is told by the clock it's given, that
+        // moves on along with the paused one the batcher waits on
+        #[tokio::test(start_paused = true)]
+        async fn consume_holds_back_cold_counters() {
+            let duration = Duration::from_millis(50);
+            let batcher = Arc::new(Batcher {
+                policy: FlushPolicy::Adaptive {
+                    hot_ratio: 0.5,
+                    cold_ratio: 0.05,
+                    cold_periods: 2,
+                },
+                ..Batcher::new(duration, DEFAULT_MAX_CACHED_COUNTERS)
+            });
+            let start = tokio::time::Instant::now();
+            {
+                let counter = test_counter(100, None);
+                let arc = Arc::new(CachedCounterValue::from_authority(
+                    &counter,
+                    0,
+                    Arc::new(TokioClock::new()),
+                ));
+                arc.delta(&counter, 1);
+                batcher.add(counter, arc).await;
+            }
+            batcher
+                .consume(1, |items| {
+                    assert_eq!(items.len(), 1);
+                    // held back for `cold_periods`
+                    assert_eq!(start.elapsed(), Duration::from_millis(100));
+                    async { Ok::<(), ()>(()) }
+                })
+                .await
+                .expect("Always Ok!");
+        }
+    }
+
+    #[test]
+    fn applies_the_remote_values_of_cached_counters() {
+        let counter = test_counter(10, None);
+        let expiry = SystemTime::now().add(Duration::from_secs(1));
+        let cache = CountersCacheBuilder::new().build(Duration::default());
+
+        cache.apply_remote_value(counter.clone(), 3, expiry);
+        assert!(cache.get(&counter).is_none());
+
+        cache.apply_remote_delta(counter.clone(), 2, 0, expiry);
+        cache.apply_remote_value(counter.clone(), 5, expiry);
+        assert_eq!(cache.get(&counter).unwrap().hits(&counter), 5);
+
+        // already known
+        cache.apply_remote_value(counter.clone(), 4, expiry);
+        assert_eq!(cache.get(&counter).unwrap().hits(&counter), 5);
     }
 
     #[test]
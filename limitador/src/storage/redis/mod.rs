@@ -1,9 +1,12 @@
 use ::redis::RedisError;
 use std::time::Duration;
 
+mod connection;
+mod counter_updates;
 mod counters_cache;
 mod redis_async;
 mod redis_cached;
+mod redis_sharded;
 mod redis_sync;
 mod scripts;
 
@@ -13,11 +16,17 @@ pub const DEFAULT_MAX_CACHED_COUNTERS: usize = 10000;
 pub const DEFAULT_RESPONSE_TIMEOUT_MS: u64 = 350;
 
 use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::keys;
 use crate::storage::{Authorization, StorageErr};
+pub use counters_cache::FlushPolicy;
 pub use redis_async::AsyncRedisStorage;
 pub use redis_cached::CachedRedisStorage;
 pub use redis_cached::CachedRedisStorageBuilder;
+pub use redis_cached::PartitionPolicy;
+pub use redis_sharded::ShardedRedisStorage;
 pub use redis_sync::RedisStorage;
+use std::sync::Arc;
 
 impl From<RedisError> for StorageErr {
     fn from(e: RedisError) -> Self {
@@ -33,6 +42,144 @@ impl From<RedisError> for StorageErr {
     }
 }
 
+/// What all the keys of a storage start with, so that several deployments, or
+/// other applications, can share a Redis. The keys in the sets of the counters
+/// of each limit carry it too.
+#[derive(Clone, Debug, Default)]
+struct KeyPrefix(Arc<[u8]>);
+
+impl KeyPrefix {
+    fn new(prefix: &str) -> Result<Self, String> {
+        // the hash tags of the keys must remain the ones of their namespace
+        if prefix.contains(['{', '}']) {
+            return Err(format!(
+                "The prefix of the Redis keys can't contain '{{' or '}}': {prefix}"
+            ));
+        }
+        Ok(Self(prefix.as_bytes().into()))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn counter(&self, counter: &Counter) -> Vec<u8> {
+        self.prefixed(keys::key_for_counter(counter))
+    }
+
+    fn counters_of_limit(&self, limit: &Limit) -> Vec<u8> {
+        self.prefixed(keys::key_for_counters_of_limit(limit))
+    }
+
+    fn live_counters_of_limit(&self, limit: &Limit) -> Vec<u8> {
+        self.prefixed(keys::key_for_live_counters_of_limit(limit))
+    }
+
+    fn hash_tag(&self, limit: &Limit) -> Vec<u8> {
+        self.prefixed(keys::hash_tag(limit.namespace().as_ref()))
+    }
+
+    fn counter_from_key(&self, key: &[u8], limit: Arc<Limit>) -> Counter {
+        keys::counter_from_counter_key(key.strip_prefix(self.as_bytes()).unwrap_or(key), limit)
+    }
+
+    fn prefixed(&self, key: Vec<u8>) -> Vec<u8> {
+        if self.is_empty() {
+            return key;
+        }
+        let mut prefixed = Vec::with_capacity(self.0.len() + key.len());
+        prefixed.extend_from_slice(&self.0);
+        prefixed.extend(key);
+        prefixed
+    }
+}
+
+/// Prepares an invocation of [`scripts::CHECK_AND_UPDATE`] for `counters`.
+fn check_and_update_invocation<'a>(
+    script: &'a ::redis::Script,
+    key_prefix: &KeyPrefix,
+    counters: &[Counter],
+    delta: u64,
+) -> ::redis::ScriptInvocation<'a> {
+    let mut invocation = script.prepare_invoke();
+    invocation.arg(delta);
+    for counter in counters {
+        invocation
+            .key(key_prefix.counter(counter))
+            .key(key_prefix.counters_of_limit(counter.limit()))
+            .key(key_prefix.live_counters_of_limit(counter.limit()))
+            .arg(counter.max_value())
+            .arg(counter.window().as_secs());
+    }
+    invocation
+}
+
+/// Prepares an invocation of [`scripts::SCRIPT_ADMIT_COUNTER`] for `counter`,
+/// taking the room for it if `reserve`.
+fn admit_counter_invocation<'a>(
+    script: &'a ::redis::Script,
+    key_prefix: &KeyPrefix,
+    counter: &Counter,
+    max_counters: u64,
+    reserve: bool,
+) -> ::redis::ScriptInvocation<'a> {
+    let mut invocation = script.prepare_invoke();
+    invocation
+        .key(key_prefix.live_counters_of_limit(counter.limit()))
+        .arg(key_prefix.counter(counter))
+        .arg(max_counters)
+        .arg(counter.window().as_secs())
+        .arg(u8::from(reserve));
+    invocation
+}
+
+/// The key that held the counters of `limit` before the keys of the limits with
+/// an id got a hash tag, if it has one, along with an invocation of
+/// [`scripts::SCRIPT_MIGRATE_LEGACY_COUNTERS`] to move them under their current
+/// keys. Only worth invoking when that key exists.
+fn migrate_legacy_counters_invocation<'a>(
+    script: &'a ::redis::Script,
+    key_prefix: &KeyPrefix,
+    limit: &Limit,
+) -> Option<(Vec<u8>, ::redis::ScriptInvocation<'a>)> {
+    let legacy = keys::key_for_legacy_counters_of_limit(limit)?;
+    let mut invocation = script.prepare_invoke();
+    invocation
+        .key(legacy.clone())
+        .key(key_prefix.counters_of_limit(limit))
+        .key(key_prefix.live_counters_of_limit(limit))
+        .arg(key_prefix.hash_tag(limit));
+    Some((legacy, invocation))
+}
+
+/// Sets the remaining hits and expiration of the `counters` from what
+/// [`scripts::CHECK_AND_UPDATE`] returned.
+fn checked_and_updated(
+    counters: &mut [Counter],
+    delta: u64,
+    mut script_res: Vec<Option<i64>>,
+) -> Authorization {
+    let first_limited = script_res.remove(0).unwrap_or(0);
+    if first_limited > 0 {
+        is_limited(counters, delta, script_res).unwrap_or_else(|| {
+            Authorization::Limited(
+                counters[first_limited as usize - 1]
+                    .limit()
+                    .name()
+                    .map(|n| n.to_owned()),
+            )
+        })
+    } else {
+        // the values are the ones after the increment already
+        is_limited(counters, 0, script_res);
+        Authorization::Ok
+    }
+}
+
 pub fn is_limited(
     counters: &mut [Counter],
     delta: u64,
@@ -72,3 +219,86 @@ pub fn is_limited(
     }
     first_limited
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{checked_and_updated, KeyPrefix};
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::keys::key_for_counter;
+    use crate::storage::Authorization;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn counters() -> Vec<Counter> {
+        let ctx = Context::default();
+        let mut limit = Limit::with_id("id", "ns", 5, 1, vec![], vec![]);
+        limit.set_name("limited".to_owned());
+        vec![
+            Counter::new(Limit::new("ns", 10, 60, vec![], vec![]), &ctx)
+                .unwrap()
+                .unwrap(),
+            Counter::new(limit, &ctx).unwrap().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn prefixes_the_keys() {
+        let counter = counters().remove(0);
+        let prefix = KeyPrefix::new("tenant:").unwrap();
+
+        let key = prefix.counter(&counter);
+        assert!(key.starts_with(b"tenant:namespace:{ns}"));
+        assert!(prefix
+            .counters_of_limit(counter.limit())
+            .starts_with(b"tenant:namespace:{ns}"));
+        assert!(prefix
+            .live_counters_of_limit(counter.limit())
+            .starts_with(b"tenant:namespace:{ns}"));
+        assert_eq!(
+            prefix.counter_from_key(&key, Arc::new(counter.limit().clone())),
+            counter
+        );
+        assert_eq!(
+            KeyPrefix::default().counter(&counter),
+            key_for_counter(&counter)
+        );
+    }
+
+    #[test]
+    fn rejects_prefixes_with_hash_tags() {
+        assert!(KeyPrefix::new("{tenant}:").is_err());
+        assert!(KeyPrefix::new("tenant}:").is_err());
+    }
+
+    #[test]
+    fn reads_incremented_counters_of_the_exact_check() {
+        let mut counters = counters();
+        let auth = checked_and_updated(
+            &mut counters,
+            2,
+            vec![Some(0), Some(2), Some(60_000), Some(4), Some(500)],
+        );
+
+        assert!(matches!(auth, Authorization::Ok));
+        assert_eq!(counters[0].remaining(), Some(8));
+        assert_eq!(counters[0].expires_in(), Some(Duration::from_secs(60)));
+        assert_eq!(counters[1].remaining(), Some(1));
+        assert_eq!(counters[1].expires_in(), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn reads_untouched_counters_of_the_exact_check() {
+        let mut counters = counters();
+        let auth = checked_and_updated(
+            &mut counters,
+            2,
+            vec![Some(2), Some(0), Some(-2), Some(4), Some(500)],
+        );
+
+        assert!(matches!(auth, Authorization::Limited(Some(name)) if name == "limited"));
+        assert_eq!(counters[0].remaining(), Some(8));
+        assert_eq!(counters[0].expires_in(), Some(Duration::from_secs(60)));
+        assert_eq!(counters[1].remaining(), Some(0));
+    }
+}
@@ -4,22 +4,31 @@ use self::redis::aio::ConnectionManager;
 use self::redis::ConnectionInfo;
 use crate::counter::Counter;
 use crate::limit::Limit;
-use crate::storage::keys::*;
-use crate::storage::redis::is_limited;
-use crate::storage::redis::scripts::{SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS};
+use crate::storage::redis::connection::{
+    cluster_client, is_sentinel_url, sentinel_client, AsyncConnection, SentinelConnection,
+};
+use crate::storage::redis::scripts::{
+    CHECK_AND_UPDATE, SCRIPT_ADMIT_COUNTER, SCRIPT_CARDINALITY, SCRIPT_IMPORT_COUNTER,
+    SCRIPT_MIGRATE_LEGACY_COUNTERS, SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS,
+};
+use crate::storage::redis::{
+    admit_counter_invocation, check_and_update_invocation, checked_and_updated, is_limited,
+    migrate_legacy_counters_invocation, KeyPrefix,
+};
 use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
 use async_trait::async_trait;
-use redis::{AsyncCommands, ErrorKind, RedisError};
+use redis::{AsyncCommands, AsyncConnectionConfig, ErrorKind, RedisError};
 use std::collections::HashSet;
 use std::ops::Deref;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{info_span, Instrument};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, info_span, warn, Instrument};
 
-// Note: this implementation does not guarantee exact limits. Ensuring that we
-// never go over the limits would hurt performance. This implementation
-// sacrifices a bit of accuracy to be more performant.
+// Note: this implementation does not guarantee exact limits, unless built with
+// `exact_limits`. Ensuring that we never go over the limits would hurt
+// performance. By default, this implementation sacrifices a bit of accuracy to
+// be more performant.
 
 // TODO: the code of this implementation is almost identical to the blocking
 // one. The only exception is that the functions defined are "async" and all the
@@ -28,17 +37,33 @@ use tracing::{info_span, Instrument};
 
 #[derive(Clone)]
 pub struct AsyncRedisStorage {
-    conn_manager: ConnectionManager,
+    conn_manager: AsyncConnection,
+    exact_limits: bool,
+    key_prefix: KeyPrefix,
 }
 
 #[async_trait]
 impl AsyncCounterStorage for AsyncRedisStorage {
+    // In the background, the counters staying where they are until it's done
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let storage = self.clone();
+            let limit = limit.clone();
+            runtime.spawn(async move {
+                if let Err(err) = storage.migrate_legacy_counters(&limit).await {
+                    warn!("Couldn't move the counters of a limit to their current keys: {err}");
+                }
+            });
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
         let mut con = self.conn_manager.clone();
 
         match con
-            .get::<Vec<u8>, Option<i64>>(key_for_counter(counter))
+            .get::<Vec<u8>, Option<i64>>(self.key_prefix.counter(counter))
             .instrument(info_span!("datastore"))
             .await?
         {
@@ -52,8 +77,9 @@ impl AsyncCounterStorage for AsyncRedisStorage {
         let mut con = self.conn_manager.clone();
 
         redis::Script::new(SCRIPT_UPDATE_COUNTER)
-            .key(key_for_counter(counter))
-            .key(key_for_counters_of_limit(counter.limit()))
+            .key(self.key_prefix.counter(counter))
+            .key(self.key_prefix.counters_of_limit(counter.limit()))
+            .key(self.key_prefix.live_counters_of_limit(counter.limit()))
             .arg(counter.window().as_secs())
             .arg(delta)
             .invoke_async::<()>(&mut con)
@@ -71,78 +97,27 @@ impl AsyncCounterStorage for AsyncRedisStorage {
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
         let mut con = self.conn_manager.clone();
-        let counter_keys: Vec<Vec<u8>> = counters.iter().map(key_for_counter).collect();
-
-        if load_counters {
-            let script = redis::Script::new(VALUES_AND_TTLS);
-            let mut script_invocation = script.prepare_invoke();
 
-            for counter_key in &counter_keys {
-                script_invocation.key(counter_key);
-            }
-
-            let script_res: Vec<Option<i64>> = {
-                script_invocation
+        if self.exact_limits {
+            let script = redis::Script::new(CHECK_AND_UPDATE);
+            let script_res: Vec<Option<i64>> =
+                check_and_update_invocation(&script, &self.key_prefix, counters, delta)
                     .invoke_async(&mut con)
                     .instrument(info_span!("datastore"))
-                    .await?
-            };
-            if let Some(res) = is_limited(counters, delta, script_res) {
-                return Ok(res);
-            }
-        } else {
-            let counter_vals: Vec<Option<i64>> = {
-                redis::cmd("MGET")
-                    .arg(counter_keys.clone())
-                    .query_async(&mut con)
-                    .instrument(info_span!("datastore"))
-                    .await?
-            };
-
-            for (i, counter) in counters.iter().enumerate() {
-                // remaining  = max - (curr_val + delta)
-                let remaining = counter
-                    .max_value()
-                    .checked_sub(u64::try_from(counter_vals[i].unwrap_or(0)).unwrap_or(0) + delta);
-                if remaining.is_none() {
-                    return Ok(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
-                }
-            }
+                    .await?;
+            return Ok(checked_and_updated(counters, delta, script_res));
         }
 
-        let script = redis::Script::new(SCRIPT_UPDATE_COUNTER);
-        let mut pipeline = redis::pipe();
-        let mut pipeline = &mut pipeline;
-        for (counter_idx, key) in counter_keys.iter().enumerate() {
-            let counter = &counters[counter_idx];
-            pipeline = pipeline
-                .invoke_script(
-                    script
-                        .key(key)
-                        .key(key_for_counters_of_limit(counter.limit()))
-                        .arg(counter.window().as_secs())
-                        .arg(delta),
-                )
-                .ignore()
-        }
-        if let Err(err) = pipeline
-            .query_async::<()>(&mut con)
-            .instrument(info_span!("datastore"))
-            .await
-        {
-            if err.kind() == ErrorKind::NoScriptError {
-                script.prepare_invoke().load_async(&mut con).await?;
-                pipeline
-                    .query_async::<()>(&mut con)
-                    .instrument(info_span!("datastore"))
-                    .await?;
-            } else {
-                Err(err)?;
-            }
+        let limited = if load_counters {
+            self.load_counters(counters, delta).await?
+        } else {
+            self.check_counters(counters, delta).await?
+        };
+        if let Some(res) = limited {
+            return Ok(res);
         }
 
+        self.increment_counters(counters, delta).await?;
         Ok(Authorization::Ok)
     }
 
@@ -157,14 +132,15 @@ impl AsyncCounterStorage for AsyncRedisStorage {
 
         for limit in limits {
             let counter_keys = {
-                con.smembers::<Vec<u8>, HashSet<Vec<u8>>>(key_for_counters_of_limit(limit))
+                con.smembers::<Vec<u8>, HashSet<Vec<u8>>>(self.key_prefix.counters_of_limit(limit))
                     .instrument(info_span!("datastore"))
                     .await?
             };
 
             for counter_key in counter_keys {
-                let mut counter: Counter =
-                    counter_from_counter_key(&counter_key, Arc::clone(limit));
+                let mut counter: Counter = self
+                    .key_prefix
+                    .counter_from_key(&counter_key, Arc::clone(limit));
 
                 // If the key does not exist, it means that the counter expired,
                 // so we don't have to return it.
@@ -208,16 +184,122 @@ impl AsyncCounterStorage for AsyncRedisStorage {
     #[tracing::instrument(skip_all)]
     async fn clear(&self) -> Result<(), StorageErr> {
         let mut con = self.conn_manager.clone();
-        redis::cmd("FLUSHDB")
-            .query_async::<()>(&mut con)
+        if self.key_prefix.is_empty() {
+            redis::cmd("FLUSHDB")
+                .query_async::<()>(&mut con)
+                .instrument(info_span!("datastore"))
+                .await?;
+        } else {
+            con.delete_keys_with_prefix(self.key_prefix.as_bytes())
+                .instrument(info_span!("datastore"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        let mut con = self.conn_manager.clone();
+        let script = redis::Script::new(SCRIPT_ADMIT_COUNTER);
+        Ok(
+            admit_counter_invocation(&script, &self.key_prefix, counter, max_counters, true)
+                .invoke_async(&mut con)
+                .instrument(info_span!("datastore"))
+                .await?,
+        )
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn can_admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        let mut con = self.conn_manager.clone();
+        let script = redis::Script::new(SCRIPT_ADMIT_COUNTER);
+        Ok(
+            admit_counter_invocation(&script, &self.key_prefix, counter, max_counters, false)
+                .invoke_async(&mut con)
+                .instrument(info_span!("datastore"))
+                .await?,
+        )
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let mut con = self.conn_manager.clone();
+        Ok(redis::Script::new(SCRIPT_CARDINALITY)
+            .key(self.key_prefix.live_counters_of_limit(limit))
+            .invoke_async(&mut con)
+            .instrument(info_span!("datastore"))
+            .await?)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        let mut con = self.conn_manager.clone();
+
+        redis::Script::new(SCRIPT_IMPORT_COUNTER)
+            .key(self.key_prefix.counter(counter))
+            .key(self.key_prefix.counters_of_limit(counter.limit()))
+            .key(self.key_prefix.live_counters_of_limit(counter.limit()))
+            .arg(
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            )
+            .arg(value)
+            .invoke_async::<()>(&mut con)
             .instrument(info_span!("datastore"))
             .await?;
+
         Ok(())
     }
+
+    fn backend(&self) -> &'static str {
+        "redis"
+    }
 }
 
 impl AsyncRedisStorage {
+    /// Moves the counters of `limit` that a version of Limitador from before
+    /// the keys of the limits with an id got a hash tag left in Redis.
+    async fn migrate_legacy_counters(&self, limit: &Limit) -> Result<(), StorageErr> {
+        let script = redis::Script::new(SCRIPT_MIGRATE_LEGACY_COUNTERS);
+        let Some((legacy, invocation)) =
+            migrate_legacy_counters_invocation(&script, &self.key_prefix, limit)
+        else {
+            return Ok(());
+        };
+        let mut con = self.conn_manager.clone();
+        if !con.exists::<_, bool>(legacy).await? {
+            return Ok(());
+        }
+        let moved: u64 = invocation.invoke_async(&mut con).await?;
+        info!("Moved {moved} counters of a limit to their current keys");
+        Ok(())
+    }
+
+    /// Uses the Redis server at `redis_url`, or the master the sentinels of a
+    /// `redis+sentinel://` URL point to.
     pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
+        if is_sentinel_url(redis_url) {
+            return Self::new_with_connection(AsyncConnection::Sentinel(
+                SentinelConnection::new(sentinel_client(redis_url)?, AsyncConnectionConfig::new())
+                    .await?,
+            ))
+            .await;
+        }
         let info = ConnectionInfo::from_str(redis_url)?;
         Self::new_with_conn_manager(
             ConnectionManager::new(
@@ -229,20 +311,163 @@ impl AsyncRedisStorage {
         .await
     }
 
+    /// Uses the Redis Cluster some of whose nodes are listed, comma separated,
+    /// in `redis_urls`.
+    pub async fn new_cluster(redis_urls: &str) -> Result<Self, RedisError> {
+        let client = cluster_client(redis_urls)?.build()?;
+        Self::new_with_connection(AsyncConnection::Cluster(
+            client.get_async_connection().await?,
+        ))
+        .await
+    }
+
     pub async fn new_with_conn_manager(
         conn_manager: ConnectionManager,
     ) -> Result<Self, RedisError> {
-        let store = Self { conn_manager };
+        Self::new_with_connection(AsyncConnection::Single(Box::new(conn_manager))).await
+    }
+
+    pub(super) async fn new_with_connection(
+        conn_manager: AsyncConnection,
+    ) -> Result<Self, RedisError> {
+        let store = Self {
+            conn_manager,
+            exact_limits: false,
+            key_prefix: KeyPrefix::default(),
+        };
         store.load_script(SCRIPT_UPDATE_COUNTER).await?;
+        store.load_script(CHECK_AND_UPDATE).await?;
         store.load_script(VALUES_AND_TTLS).await?;
+        store.load_script(SCRIPT_CARDINALITY).await?;
         Ok(store)
     }
 
+    /// Checks and updates all the counters of a request in a single script, so
+    /// that concurrent requests can't go over the limits, at the cost of some
+    /// more work for Redis on every request.
+    pub fn exact_limits(mut self, exact_limits: bool) -> Self {
+        self.exact_limits = exact_limits;
+        self
+    }
+
+    /// Starts all the keys with `prefix`, which can't contain `{` or `}`, and
+    /// only clears those.
+    pub fn key_prefix(mut self, prefix: &str) -> Result<Self, String> {
+        self.key_prefix = KeyPrefix::new(prefix)?;
+        Ok(self)
+    }
+
+    pub(super) fn with_prefix(mut self, key_prefix: KeyPrefix) -> Self {
+        self.key_prefix = key_prefix;
+        self
+    }
+
+    pub(super) fn prefix(&self) -> &KeyPrefix {
+        &self.key_prefix
+    }
+
+    /// Whether `delta` more would go over the limit of any of the `counters`.
+    pub(super) async fn check_counters(
+        &self,
+        counters: &[Counter],
+        delta: u64,
+    ) -> Result<Option<Authorization>, StorageErr> {
+        let mut con = self.conn_manager.clone();
+        let counter_keys: Vec<Vec<u8>> = counters
+            .iter()
+            .map(|c| self.key_prefix.counter(c))
+            .collect();
+        let counter_vals: Vec<Option<i64>> = {
+            redis::cmd("MGET")
+                .arg(counter_keys)
+                .query_async(&mut con)
+                .instrument(info_span!("datastore"))
+                .await?
+        };
+
+        for (i, counter) in counters.iter().enumerate() {
+            // remaining  = max - (curr_val + delta)
+            let remaining = counter
+                .max_value()
+                .checked_sub(u64::try_from(counter_vals[i].unwrap_or(0)).unwrap_or(0) + delta);
+            if remaining.is_none() {
+                return Ok(Some(Authorization::Limited(
+                    counter.limit().name().map(|n| n.to_owned()),
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sets the remaining hits and expiration of the `counters`, returning
+    /// whether `delta` more would go over the limit of any of them.
+    pub(super) async fn load_counters(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Option<Authorization>, StorageErr> {
+        let mut con = self.conn_manager.clone();
+        let script = redis::Script::new(VALUES_AND_TTLS);
+        let mut script_invocation = script.prepare_invoke();
+
+        for counter in counters.iter() {
+            script_invocation.key(self.key_prefix.counter(counter));
+        }
+
+        let script_res: Vec<Option<i64>> = {
+            script_invocation
+                .invoke_async(&mut con)
+                .instrument(info_span!("datastore"))
+                .await?
+        };
+        Ok(is_limited(counters, delta, script_res))
+    }
+
+    /// Increments the `counters` by `delta`, in a single pipeline.
+    pub(super) async fn increment_counters(
+        &self,
+        counters: &[Counter],
+        delta: u64,
+    ) -> Result<(), StorageErr> {
+        let mut con = self.conn_manager.clone();
+        let script = redis::Script::new(SCRIPT_UPDATE_COUNTER);
+        let mut pipeline = redis::pipe();
+        let mut pipeline = &mut pipeline;
+        for counter in counters {
+            pipeline = pipeline
+                .invoke_script(
+                    script
+                        .key(self.key_prefix.counter(counter))
+                        .key(self.key_prefix.counters_of_limit(counter.limit()))
+                        .key(self.key_prefix.live_counters_of_limit(counter.limit()))
+                        .arg(counter.window().as_secs())
+                        .arg(delta),
+                )
+                .ignore()
+        }
+        if let Err(err) = pipeline
+            .query_async::<()>(&mut con)
+            .instrument(info_span!("datastore"))
+            .await
+        {
+            if err.kind() == ErrorKind::NoScriptError {
+                script.prepare_invoke().load_async(&mut con).await?;
+                pipeline
+                    .query_async::<()>(&mut con)
+                    .instrument(info_span!("datastore"))
+                    .await?;
+            } else {
+                Err(err)?;
+            }
+        }
+        Ok(())
+    }
+
     async fn delete_counters_associated_with_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
         let mut con = self.conn_manager.clone();
 
         let counter_keys = {
-            con.smembers::<Vec<u8>, HashSet<Vec<u8>>>(key_for_counters_of_limit(limit))
+            con.smembers::<Vec<u8>, HashSet<Vec<u8>>>(self.key_prefix.counters_of_limit(limit))
                 .instrument(info_span!("datastore"))
                 .await?
         };
@@ -253,7 +478,10 @@ impl AsyncRedisStorage {
                 .await?;
         }
 
-        con.del::<_, ()>(key_for_counters_of_limit(limit)).await?;
+        con.del::<_, ()>(self.key_prefix.counters_of_limit(limit))
+            .await?;
+        con.del::<_, ()>(self.key_prefix.live_counters_of_limit(limit))
+            .await?;
 
         Ok(())
     }
@@ -268,8 +496,15 @@ impl AsyncRedisStorage {
 
 #[cfg(test)]
 mod tests {
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use crate::storage::keys::bin::key_for_counter_v2;
+    use crate::storage::keys::key_for_legacy_counters_of_limit;
     use crate::storage::redis::AsyncRedisStorage;
-    use redis::ErrorKind;
+    use crate::storage::AsyncCounterStorage;
+    use redis::{AsyncCommands, ErrorKind};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn errs_on_bad_url() {
@@ -286,4 +521,44 @@ mod tests {
         assert_eq!(error.kind(), ErrorKind::IoError);
         assert!(error.is_connection_refusal())
     }
+
+    #[tokio::test]
+    async fn migrates_the_legacy_counters_with_async_redis() {
+        let storage = AsyncRedisStorage::new("redis://127.0.0.1:6379")
+            .await
+            .expect("We need a Redis running locally");
+        let limit = Limit::with_id(
+            "legacy_id",
+            "legacy_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit.clone(), &ctx).unwrap().unwrap();
+        let legacy_counter = key_for_counter_v2(&counter);
+        let legacy_counters = key_for_legacy_counters_of_limit(&limit).unwrap();
+
+        let mut con = storage.conn_manager.clone();
+        redis::pipe()
+            .set_ex(&legacy_counter, 3, 60)
+            .sadd(&legacy_counters, &legacy_counter)
+            .exec_async(&mut con)
+            .await
+            .unwrap();
+        // hit since under its current key
+        storage.update_counter(&counter, 1).await.unwrap();
+
+        storage.migrate_legacy_counters(&limit).await.unwrap();
+
+        let limits = HashSet::from([Arc::new(limit)]);
+        let counters = storage.get_counters(&limits).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(6));
+        assert!(!con.exists::<_, bool>(&legacy_counter).await.unwrap());
+        assert!(!con.exists::<_, bool>(&legacy_counters).await.unwrap());
+
+        storage.delete_counters(&limits).await.unwrap();
+    }
 }
@@ -1,24 +1,29 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
 use crate::limit::Limit;
-use crate::storage::keys::*;
+use crate::storage::redis::connection::{
+    cluster_client, is_sentinel_url, sentinel_client, AsyncConnection, SentinelConnection,
+};
+use crate::storage::redis::counter_updates::CounterUpdates;
 use crate::storage::redis::counters_cache::{
-    CachedCounterValue, CountersCache, CountersCacheBuilder,
+    CachedCounterValue, CountersCache, CountersCacheBuilder, FlushPolicy,
 };
 use crate::storage::redis::redis_async::AsyncRedisStorage;
 use crate::storage::redis::scripts::BATCH_UPDATE_COUNTERS;
 use crate::storage::redis::{
-    DEFAULT_BATCH_SIZE, DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS,
+    KeyPrefix, DEFAULT_BATCH_SIZE, DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS,
     DEFAULT_RESPONSE_TIMEOUT_MS,
 };
 use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
 use async_trait::async_trait;
-use metrics::gauge;
+use metrics::{counter, gauge};
 use redis::aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig};
-use redis::{ConnectionInfo, RedisError};
-use std::collections::{HashMap, HashSet};
+use redis::cluster_routing::get_slot;
+use redis::{AsyncConnectionConfig, ConnectionInfo, RedisError};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{error, info, info_span, warn, Instrument};
 
@@ -43,10 +48,36 @@ use tracing::{error, info, info_span, warn, Instrument};
 pub struct CachedRedisStorage {
     cached_counters: Arc<CountersCache>,
     async_redis_storage: AsyncRedisStorage,
+    partition: Arc<Partition>,
+    partition_policy: PartitionPolicy,
+}
+
+/// What [`CachedRedisStorage`] does while it can't flush its counters to
+/// Redis. Whatever the policy, the writes it couldn't flush are kept and
+/// flushed once Redis is reachable again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PartitionPolicy {
+    /// Keeps admitting requests against the local cache, for as long as the
+    /// partition lasts.
+    #[default]
+    ServeFromCache,
+    /// Keeps admitting requests against the local cache for up to that long,
+    /// then rejects all of them.
+    ServeFromCacheFor(Duration),
+    /// Admits up to the `max_value` of the limits divided by the number of
+    /// Limitador replicas expected to share the Redis, as each of them admits
+    /// on its own.
+    DivideAmong(u64),
+    /// Rejects all the requests.
+    FailClosed,
 }
 
 #[async_trait]
 impl AsyncCounterStorage for CachedRedisStorage {
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.async_redis_storage.add_counter(limit)
+    }
+
     #[tracing::instrument(skip_all)]
     async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
         self.async_redis_storage
@@ -72,63 +103,15 @@ impl AsyncCounterStorage for CachedRedisStorage {
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        let mut not_cached: Vec<&mut Counter> = vec![];
-        let mut first_limited = None;
-
-        // Check cached counters
-        for counter in counters.iter_mut() {
-            match self.cached_counters.get(counter) {
-                Some(val) => {
-                    if first_limited.is_none() && val.is_limited(counter, delta) {
-                        let a =
-                            Authorization::Limited(counter.limit().name().map(|n| n.to_owned()));
-                        if !load_counters {
-                            return Ok(a);
-                        }
-                        first_limited = Some(a);
-                    }
-                    if load_counters {
-                        counter.set_remaining(
-                            val.remaining(counter)
-                                .checked_sub(delta)
-                                .unwrap_or_default(),
-                        );
-                        counter.set_expires_in(val.ttl());
-                    }
-                }
-                _ => {
-                    not_cached.push(counter);
-                }
-            }
-        }
-
-        // Fetch non-cached counters, cache them, and check them
-        if !not_cached.is_empty() {
-            for counter in not_cached.iter_mut() {
-                let fake = CachedCounterValue::load_from_authority_asap(counter, 0);
-                let remaining = fake.remaining(counter);
-                if first_limited.is_none() && remaining == 0 {
-                    first_limited = Some(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
-                }
-                if load_counters {
-                    counter.set_remaining(remaining - delta);
-                    counter.set_expires_in(fake.ttl()); // todo: this is a plain lie!
-                }
-            }
-        }
-
-        if let Some(l) = first_limited {
-            return Ok(l);
-        }
-
-        // Update cached values
-        for counter in counters.iter() {
-            self.cached_counters.increase_by(counter, delta).await;
-        }
-
-        Ok(Authorization::Ok)
+        check_and_update_cached(
+            &self.cached_counters,
+            &self.partition,
+            self.partition_policy,
+            counters,
+            delta,
+            load_counters,
+        )
+        .await
     }
 
     #[tracing::instrument(skip_all)]
@@ -148,6 +131,69 @@ impl AsyncCounterStorage for CachedRedisStorage {
     async fn clear(&self) -> Result<(), StorageErr> {
         self.async_redis_storage.clear().await
     }
+
+    #[tracing::instrument(skip_all)]
+    async fn admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        match self.cached_counters.get(counter) {
+            Some(value) if value.ttl() > Duration::ZERO => Ok(true),
+            _ => {
+                self.async_redis_storage
+                    .admit_counter(counter, max_counters)
+                    .await
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn can_admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        match self.cached_counters.get(counter) {
+            Some(value) if value.ttl() > Duration::ZERO => Ok(true),
+            _ => {
+                self.async_redis_storage
+                    .can_admit_counter(counter, max_counters)
+                    .await
+            }
+        }
+    }
+
+    // Counters created by other instances only count once flushed to Redis, and
+    // the ones pending here may be there already, hence the greatest of both
+    // rather than their sum.
+    #[tracing::instrument(skip_all)]
+    async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let local = self.cached_counters.cardinality(limit);
+        let remote = self.async_redis_storage.cardinality(limit).await?;
+        Ok(local.max(remote))
+    }
+
+    // Straight to Redis, as the counters cached are only ever flushed as deltas
+    #[tracing::instrument(skip_all)]
+    async fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        self.async_redis_storage
+            .import_counter(counter, value, expires_at)
+            .await
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(self.cached_counters.clock())
+    }
+
+    fn backend(&self) -> &'static str {
+        "redis_cached"
+    }
 }
 
 impl CachedRedisStorage {
@@ -158,41 +204,91 @@ impl CachedRedisStorage {
             Duration::from_secs(DEFAULT_FLUSHING_PERIOD_SEC),
             DEFAULT_MAX_CACHED_COUNTERS,
             Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS),
+            Arc::new(SystemClock),
+            false,
+            KeyPrefix::default(),
+            PartitionPolicy::default(),
+            FlushPolicy::default(),
+            false,
         )
         .await
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn new_with_options(
         redis_url: &str,
         batch_size: usize,
         flushing_period: Duration,
         max_cached_counters: usize,
         response_timeout: Duration,
+        clock: Arc<dyn Clock>,
+        cluster: bool,
+        key_prefix: KeyPrefix,
+        partition_policy: PartitionPolicy,
+        flush_policy: FlushPolicy,
+        publish_updates: bool,
     ) -> Result<Self, RedisError> {
-        let info = ConnectionInfo::from_str(redis_url)?;
-        let redis_conn_manager = ConnectionManager::new_with_config(
-            redis::Client::open(info)
-                .expect("This couldn't fail in the past, yet now it did somehow!"),
-            ConnectionManagerConfig::default()
-                .set_connection_timeout((response_timeout * 3) + Duration::from_millis(50))
-                .set_response_timeout(response_timeout)
-                .set_number_of_retries(1),
-        )
-        .await?;
+        let connection_timeout = (response_timeout * 3) + Duration::from_millis(50);
+        let redis_conn_manager = if cluster {
+            let client = cluster_client(redis_url)?
+                .connection_timeout(connection_timeout)
+                .response_timeout(response_timeout)
+                .retries(1)
+                .build()?;
+            AsyncConnection::Cluster(client.get_async_connection().await?)
+        } else if is_sentinel_url(redis_url) {
+            AsyncConnection::Sentinel(
+                SentinelConnection::new(
+                    sentinel_client(redis_url)?,
+                    AsyncConnectionConfig::new()
+                        .set_connection_timeout(connection_timeout)
+                        .set_response_timeout(response_timeout),
+                )
+                .await?,
+            )
+        } else {
+            let info = ConnectionInfo::from_str(redis_url)?;
+            AsyncConnection::Single(Box::new(
+                ConnectionManager::new_with_config(
+                    redis::Client::open(info)
+                        .expect("This couldn't fail in the past, yet now it did somehow!"),
+                    ConnectionManagerConfig::default()
+                        .set_connection_timeout(connection_timeout)
+                        .set_response_timeout(response_timeout)
+                        .set_number_of_retries(1),
+                )
+                .await?,
+            ))
+        };
 
+        let partition = Arc::new(Partition::new(Arc::clone(&clock)));
         let cached_counters = CountersCacheBuilder::new()
             .max_cached_counters(max_cached_counters)
+            .clock(clock)
+            .flush_policy(flush_policy)
             .build(flushing_period);
 
         let counters_cache = Arc::new(cached_counters);
-        let partitioned = Arc::new(AtomicBool::new(false));
         let async_redis_storage =
-            AsyncRedisStorage::new_with_conn_manager(redis_conn_manager.clone()).await?;
+            AsyncRedisStorage::new_with_connection(redis_conn_manager.clone())
+                .await?
+                .with_prefix(key_prefix);
+
+        let counter_updates = publish_updates.then(|| {
+            let updates = Arc::new(CounterUpdates::new(async_redis_storage.prefix()));
+            tokio::spawn(Arc::clone(&updates).subscribe(
+                redis_url.to_owned(),
+                cluster,
+                Arc::clone(&counters_cache),
+            ));
+            updates
+        });
 
         {
             let counters_cache_clone = counters_cache.clone();
             let conn = redis_conn_manager.clone();
-            let p = Arc::clone(&partitioned);
+            let p = Arc::clone(&partition);
+            let key_prefix = async_redis_storage.prefix().clone();
             tokio::spawn(async move {
                 loop {
                     flush_batcher_and_update_counters(
@@ -200,6 +296,9 @@ impl CachedRedisStorage {
                         counters_cache_clone.clone(),
                         p.clone(),
                         batch_size,
+                        cluster,
+                        &key_prefix,
+                        counter_updates.as_deref(),
                     )
                     .await;
                 }
@@ -213,24 +312,61 @@ impl CachedRedisStorage {
         Ok(Self {
             cached_counters: counters_cache,
             async_redis_storage,
+            partition,
+            partition_policy,
         })
     }
 }
 
-fn flip_partitioned(storage: &AtomicBool, partition: bool) -> bool {
-    let we_flipped = storage
-        .compare_exchange(!partition, partition, Ordering::Release, Ordering::Acquire)
-        .is_ok();
-    if we_flipped {
-        if partition {
-            gauge!("datastore_partitioned").set(1);
-            error!("Partition to Redis detected!")
-        } else {
-            gauge!("datastore_partitioned").set(0);
-            warn!("Partition to Redis resolved!");
+/// Whether the counters can't be flushed to Redis, and since when.
+struct Partition {
+    partitioned: AtomicBool,
+    since: Mutex<SystemTime>,
+    // from the end of a partition until the writes that piled up during it
+    // are flushed
+    reconciling: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Partition {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            partitioned: AtomicBool::new(false),
+            since: Mutex::new(UNIX_EPOCH),
+            reconciling: AtomicBool::new(false),
+            clock,
         }
     }
-    we_flipped
+
+    fn is_partitioned(&self) -> bool {
+        self.partitioned.load(Ordering::Acquire)
+    }
+
+    fn duration(&self) -> Duration {
+        self.clock
+            .now()
+            .duration_since(*self.since.lock().unwrap())
+            .unwrap_or_default()
+    }
+
+    fn flip(&self, partition: bool) -> bool {
+        let we_flipped = self
+            .partitioned
+            .compare_exchange(!partition, partition, Ordering::Release, Ordering::Acquire)
+            .is_ok();
+        if we_flipped {
+            if partition {
+                *self.since.lock().unwrap() = self.clock.now();
+                gauge!("datastore_partitioned").set(1);
+                error!("Partition to Redis detected!")
+            } else {
+                self.reconciling.store(true, Ordering::Release);
+                gauge!("datastore_partitioned").set(0);
+                warn!("Partition to Redis resolved after {:?}!", self.duration());
+            }
+        }
+        we_flipped
+    }
 }
 
 pub struct CachedRedisStorageBuilder {
@@ -239,6 +375,12 @@ pub struct CachedRedisStorageBuilder {
     flushing_period: Duration,
     max_cached_counters: usize,
     response_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    cluster: bool,
+    key_prefix: KeyPrefix,
+    partition_policy: PartitionPolicy,
+    flush_policy: FlushPolicy,
+    publish_updates: bool,
 }
 
 impl CachedRedisStorageBuilder {
@@ -249,6 +391,12 @@ impl CachedRedisStorageBuilder {
             flushing_period: Duration::from_secs(DEFAULT_FLUSHING_PERIOD_SEC),
             max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
             response_timeout: Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS),
+            clock: Arc::new(SystemClock),
+            cluster: false,
+            key_prefix: KeyPrefix::default(),
+            partition_policy: PartitionPolicy::default(),
+            flush_policy: FlushPolicy::default(),
+            publish_updates: false,
         }
     }
 
@@ -272,6 +420,48 @@ impl CachedRedisStorageBuilder {
         self
     }
 
+    /// The clock the cached counters tell the time with. The windows of the
+    /// counters in Redis are still subject to Redis' own clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Uses the Redis Cluster some of whose nodes are listed, comma separated,
+    /// in the URL the builder was created with. The batches of counter
+    /// updates are then flushed with one call per slot they touch.
+    pub fn cluster(mut self, cluster: bool) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    /// Starts all the keys with `prefix`, which can't contain `{` or `}`, and
+    /// only clears those.
+    pub fn key_prefix(mut self, prefix: &str) -> Result<Self, String> {
+        self.key_prefix = KeyPrefix::new(prefix)?;
+        Ok(self)
+    }
+
+    /// What to do while the counters can't be flushed to Redis.
+    pub fn partition_policy(mut self, partition_policy: PartitionPolicy) -> Self {
+        self.partition_policy = partition_policy;
+        self
+    }
+
+    /// When to flush the pending writes of each counter to Redis.
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Publishes the counter values flushed to Redis, and applies the ones the
+    /// other instances sharing it publish as they come, rather than on the
+    /// next flush.
+    pub fn publish_updates(mut self, publish_updates: bool) -> Self {
+        self.publish_updates = publish_updates;
+        self
+    }
+
     pub async fn build(self) -> Result<CachedRedisStorage, RedisError> {
         CachedRedisStorage::new_with_options(
             &self.redis_url,
@@ -279,67 +469,207 @@ impl CachedRedisStorageBuilder {
             self.flushing_period,
             self.max_cached_counters,
             self.response_timeout,
+            self.clock,
+            self.cluster,
+            self.key_prefix,
+            self.partition_policy,
+            self.flush_policy,
+            self.publish_updates,
         )
         .await
     }
 }
 
+// Checks the `counters` against the values cached, and counts `delta` on them
+// when they're all within their limits, as `partition_policy` says when the
+// counters can't be flushed to Redis.
+async fn check_and_update_cached(
+    cached_counters: &CountersCache,
+    partition: &Partition,
+    partition_policy: PartitionPolicy,
+    counters: &mut [Counter],
+    delta: u64,
+    load_counters: bool,
+) -> Result<Authorization, StorageErr> {
+    let mut not_cached: Vec<&mut Counter> = vec![];
+    let mut first_limited = None;
+
+    let mut replicas = 1;
+    if partition.is_partitioned() {
+        let reject = match partition_policy {
+            PartitionPolicy::ServeFromCache => false,
+            PartitionPolicy::ServeFromCacheFor(max) => partition.duration() > max,
+            PartitionPolicy::DivideAmong(n) => {
+                replicas = n.max(1);
+                false
+            }
+            PartitionPolicy::FailClosed => true,
+        };
+        if reject {
+            return Ok(match counters.first() {
+                Some(counter) => {
+                    Authorization::Limited(counter.limit().name().map(|n| n.to_owned()))
+                }
+                None => Authorization::Ok,
+            });
+        }
+    }
+
+    // Check cached counters
+    for counter in counters.iter_mut() {
+        match cached_counters.get(counter) {
+            Some(val) => {
+                let max_value = counter.max_value() / replicas;
+                if first_limited.is_none() && val.is_limited_within(counter, max_value, delta) {
+                    let a = Authorization::Limited(counter.limit().name().map(|n| n.to_owned()));
+                    if !load_counters {
+                        return Ok(a);
+                    }
+                    first_limited = Some(a);
+                }
+                if load_counters {
+                    counter.set_remaining(
+                        max_value
+                            .saturating_sub(val.hits(counter))
+                            .saturating_sub(delta),
+                    );
+                    counter.set_expires_in(val.ttl());
+                }
+            }
+            _ => {
+                not_cached.push(counter);
+            }
+        }
+    }
+
+    // Fetch non-cached counters, cache them, and check them
+    if !not_cached.is_empty() {
+        for counter in not_cached.iter_mut() {
+            let fake = CachedCounterValue::load_from_authority_asap(
+                counter,
+                0,
+                Arc::clone(cached_counters.clock()),
+            );
+            let remaining = fake.remaining(counter) / replicas;
+            if first_limited.is_none() && remaining == 0 {
+                first_limited = Some(Authorization::Limited(
+                    counter.limit().name().map(|n| n.to_owned()),
+                ));
+            }
+            if load_counters {
+                counter.set_remaining(remaining.saturating_sub(delta));
+                counter.set_expires_in(fake.ttl()); // todo: this is a plain lie!
+            }
+        }
+    }
+
+    if let Some(l) = first_limited {
+        return Ok(l);
+    }
+
+    // Update cached values
+    for counter in counters.iter() {
+        cached_counters.increase_by(counter, delta).await;
+    }
+
+    Ok(Authorization::Ok)
+}
+
+pub(super) type PendingUpdate = (Counter, u64, u64, SystemTime);
+
+// The writes pending on each of the counters to flush, along with their value
+fn pending_updates(
+    counters_and_deltas: HashMap<Counter, Arc<CachedCounterValue>>,
+) -> Vec<PendingUpdate> {
+    let mut pending: Vec<PendingUpdate> = Vec::with_capacity(counters_and_deltas.len());
+    for (counter, value) in counters_and_deltas {
+        let (delta, last_value_from_redis) = value
+            .pending_writes_and_value()
+            .expect("State machine is wrong!");
+        if delta > 0 {
+            pending.push((counter, last_value_from_redis, delta, UNIX_EPOCH));
+        }
+    }
+    pending
+}
+
 async fn update_counters<C: ConnectionLike>(
     redis_conn: &mut C,
-    counters_and_deltas: HashMap<Counter, Arc<CachedCounterValue>>,
-) -> Result<Vec<(Counter, u64, u64, SystemTime)>, (Vec<(Counter, u64, u64, SystemTime)>, StorageErr)>
-{
-    let redis_script = redis::Script::new(BATCH_UPDATE_COUNTERS);
-    let mut script_invocation = redis_script.prepare_invoke();
+    pending: Vec<PendingUpdate>,
+    per_slot: bool,
+    key_prefix: &KeyPrefix,
+) -> Result<Vec<PendingUpdate>, (Vec<PendingUpdate>, StorageErr)> {
+    if !per_slot {
+        return update_counters_in_slot(redis_conn, pending, key_prefix).await;
+    }
 
-    let res = if counters_and_deltas.is_empty() {
-        Default::default()
-    } else {
-        let mut res: Vec<(Counter, u64, u64, SystemTime)> =
-            Vec::with_capacity(counters_and_deltas.len());
-
-        for (counter, value) in counters_and_deltas {
-            let (delta, last_value_from_redis) = value
-                .pending_writes_and_value()
-                .expect("State machine is wrong!");
-            if delta > 0 {
-                script_invocation.key(key_for_counter(&counter));
-                script_invocation.key(key_for_counters_of_limit(counter.limit()));
-                script_invocation.arg(counter.window().as_secs());
-                script_invocation.arg(delta);
-                // We need to store the counter in the actual order we are sending it to the script
-                res.push((counter, last_value_from_redis, delta, UNIX_EPOCH));
+    // A script can only touch the keys of a single slot of a Redis Cluster
+    let mut slots: BTreeMap<u16, Vec<PendingUpdate>> = BTreeMap::new();
+    for update in pending {
+        let slot = get_slot(&key_prefix.counter(&update.0));
+        slots.entry(slot).or_default().push(update);
+    }
+    let mut res = Vec::new();
+    let mut slots = slots.into_values();
+    while let Some(updates) = slots.next() {
+        match update_counters_in_slot(redis_conn, updates, key_prefix).await {
+            Ok(updated) => res.extend(updated),
+            Err((mut failed, err)) => {
+                // the slots that weren't flushed yet are to be reverted too
+                failed.extend(slots.flatten());
+                return Err((failed, err));
             }
         }
+    }
+    Ok(res)
+}
 
-        // The redis crate is not working with tables, thus the response will be a Vec of counter values
-        let script_res: Vec<i64> = match script_invocation
-            .invoke_async(redis_conn)
-            .instrument(info_span!("datastore"))
-            .await
-        {
-            Ok(res) => res,
-            Err(err) => {
-                return Err((res, err.into()));
-            }
-        };
+async fn update_counters_in_slot<C: ConnectionLike>(
+    redis_conn: &mut C,
+    mut res: Vec<PendingUpdate>,
+    key_prefix: &KeyPrefix,
+) -> Result<Vec<PendingUpdate>, (Vec<PendingUpdate>, StorageErr)> {
+    if res.is_empty() {
+        return Ok(res);
+    }
 
-        // We need to update the values and ttls returned by redis
-        let counters_range = 0..res.len();
-        let script_res_range = (0..script_res.len()).step_by(2);
-
-        for (i, j) in counters_range.zip(script_res_range) {
-            let (_, val, delta, expires_at) = &mut res[i];
-            *delta = u64::try_from(script_res[j])
-                .unwrap_or(0)
-                .saturating_sub(*val); // new value - previous one = remote writes
-            *val = u64::try_from(script_res[j]).unwrap_or(0); // update to value to newest
-            *expires_at =
-                UNIX_EPOCH + Duration::from_millis(u64::try_from(script_res[j + 1]).unwrap_or(0));
+    let redis_script = redis::Script::new(BATCH_UPDATE_COUNTERS);
+    let mut script_invocation = redis_script.prepare_invoke();
+    // The counters are sent to the script in the order of `res`
+    for (counter, _, delta, _) in &res {
+        script_invocation.key(key_prefix.counter(counter));
+        script_invocation.key(key_prefix.counters_of_limit(counter.limit()));
+        script_invocation.key(key_prefix.live_counters_of_limit(counter.limit()));
+        script_invocation.arg(counter.window().as_secs());
+        script_invocation.arg(delta);
+    }
+
+    // The redis crate is not working with tables, thus the response will be a Vec of counter values
+    let script_res: Vec<i64> = match script_invocation
+        .invoke_async(redis_conn)
+        .instrument(info_span!("datastore"))
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => {
+            return Err((res, err.into()));
         }
-        res
     };
 
+    // We need to update the values and ttls returned by redis
+    let counters_range = 0..res.len();
+    let script_res_range = (0..script_res.len()).step_by(2);
+
+    for (i, j) in counters_range.zip(script_res_range) {
+        let (_, val, delta, expires_at) = &mut res[i];
+        *delta = u64::try_from(script_res[j])
+            .unwrap_or(0)
+            .saturating_sub(*val); // new value - previous one = remote writes
+        *val = u64::try_from(script_res[j]).unwrap_or(0); // update to value to newest
+        *expires_at =
+            UNIX_EPOCH + Duration::from_millis(u64::try_from(script_res[j + 1]).unwrap_or(0));
+    }
+
     Ok(res)
 }
 
@@ -348,25 +678,56 @@ async fn update_counters<C: ConnectionLike>(
 async fn flush_batcher_and_update_counters<C: ConnectionLike>(
     mut redis_conn: C,
     cached_counters: Arc<CountersCache>,
-    partitioned: Arc<AtomicBool>,
+    partition: Arc<Partition>,
     batch_size: usize,
+    per_slot: bool,
+    key_prefix: &KeyPrefix,
+    counter_updates: Option<&CounterUpdates>,
 ) {
+    let mut consumed = 0;
+    // the writes admitted here while partitioned, until they're flushed
+    let mut local_writes: HashMap<Counter, u64> = HashMap::new();
     let updated_counters = cached_counters
         .batcher()
         .consume(batch_size, |counters| {
-            if !counters.is_empty() && !partitioned.load(Ordering::Acquire) {
+            consumed = counters.len();
+            if !counters.is_empty() && !partition.is_partitioned() {
                 info!("Flushing {} counter updates", counters.len());
             }
-            update_counters(&mut redis_conn, counters)
+            let pending = pending_updates(counters);
+            if partition.is_partitioned() || partition.reconciling.load(Ordering::Acquire) {
+                local_writes = pending
+                    .iter()
+                    .map(|(counter, _, writes, _)| (counter.clone(), *writes))
+                    .collect();
+            }
+            update_counters(&mut redis_conn, pending, per_slot, key_prefix)
         })
         .await
         .map(|result| {
-            flip_partitioned(&partitioned, false);
+            partition.flip(false);
+            if partition.reconciling.load(Ordering::Acquire) {
+                // only those of the hits admitted here that landed past the
+                // limit, as the other instances count theirs
+                let over_admitted: u64 = result
+                    .iter()
+                    .filter_map(|(counter, new_value, _, _)| {
+                        let writes = local_writes.get(counter)?;
+                        Some((*writes).min(new_value.saturating_sub(counter.max_value())))
+                    })
+                    .sum();
+                if over_admitted > 0 {
+                    counter!("datastore_partition_over_admitted").increment(over_admitted);
+                }
+                if consumed < batch_size {
+                    partition.reconciling.store(false, Ordering::Release);
+                }
+            }
             result
         })
         .or_else(|(data, err)| {
             if err.is_transient() {
-                let new_partition = flip_partitioned(&partitioned, true);
+                let new_partition = partition.flip(true);
                 if new_partition {
                     warn!("Error flushing {}", err);
                 }
@@ -392,6 +753,18 @@ async fn flush_batcher_and_update_counters<C: ConnectionLike>(
         })
         .expect("Unrecoverable Redis error!");
 
+    if let Some(counter_updates) = counter_updates {
+        if !updated_counters.is_empty() {
+            if let Err(err) = counter_updates
+                .publish_cmd(&updated_counters)
+                .exec_async(&mut redis_conn)
+                .await
+            {
+                warn!("Couldn't publish the counter updates: {}", err);
+            }
+        }
+    }
+
     for (counter, new_value, remote_deltas, ttl) in updated_counters {
         cached_counters.apply_remote_delta(counter, new_value, remote_deltas, ttl);
     }
@@ -399,20 +772,27 @@ async fn flush_batcher_and_update_counters<C: ConnectionLike>(
 
 #[cfg(test)]
 mod tests {
+    use crate::clock::{ManualClock, SystemClock};
     use crate::counter::Counter;
     use crate::limit::Limit;
-    use crate::storage::keys::{key_for_counter, key_for_counters_of_limit};
+    use crate::storage::keys::{
+        key_for_counter, key_for_counters_of_limit, key_for_live_counters_of_limit,
+    };
     use crate::storage::redis::counters_cache::{
         CachedCounterValue, CountersCache, CountersCacheBuilder,
     };
-    use crate::storage::redis::redis_cached::{flush_batcher_and_update_counters, update_counters};
-    use crate::storage::redis::CachedRedisStorage;
+    use crate::storage::redis::redis_cached::{
+        check_and_update_cached, flush_batcher_and_update_counters, pending_updates,
+        update_counters, Partition,
+    };
+    use crate::storage::redis::{CachedRedisStorage, KeyPrefix, PartitionPolicy};
+    use crate::storage::Authorization;
     use redis::{Cmd, ErrorKind, RedisError, Value};
     use redis_test::{MockCmd, MockRedisConnection};
     use std::collections::HashMap;
     use std::io;
     use std::ops::Add;
-    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
     use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -457,6 +837,7 @@ mod tests {
         let arc = Arc::new(CachedCounterValue::from_authority(
             &counter,
             INITIAL_VALUE_FROM_REDIS,
+            Arc::new(SystemClock),
         ));
         arc.delta(&counter, LOCAL_INCREMENTS);
         counters_and_deltas.insert(counter.clone(), arc);
@@ -474,18 +855,24 @@ mod tests {
 
         let mut mock_client = MockRedisConnection::new(vec![MockCmd::new(
             redis::cmd("EVALSHA")
-                .arg("95a717e821d8fbdd667b5e4c6fede4c9cad16006")
-                .arg("2")
+                .arg("370c03076122051c25f589e4de918dc773f75b90")
+                .arg("3")
                 .arg(key_for_counter(&counter))
                 .arg(key_for_counters_of_limit(counter.limit()))
+                .arg(key_for_live_counters_of_limit(counter.limit()))
                 .arg(60)
                 .arg(LOCAL_INCREMENTS),
             Ok(mock_response),
         )]);
 
-        let mut result = update_counters(&mut mock_client, counters_and_deltas)
-            .await
-            .unwrap();
+        let mut result = update_counters(
+            &mut mock_client,
+            pending_updates(counters_and_deltas),
+            false,
+            &KeyPrefix::default(),
+        )
+        .await
+        .unwrap();
 
         let (c, new_value, remote_increments, expire_at) = result.remove(0);
         assert_eq!(key_for_counter(&counter), key_for_counter(&c));
@@ -533,10 +920,11 @@ mod tests {
 
         let mock_client = MockRedisConnection::new(vec![MockCmd::new(
             redis::cmd("EVALSHA")
-                .arg("95a717e821d8fbdd667b5e4c6fede4c9cad16006")
-                .arg("2")
+                .arg("370c03076122051c25f589e4de918dc773f75b90")
+                .arg("3")
                 .arg(key_for_counter(&counter))
                 .arg(key_for_counters_of_limit(counter.limit()))
+                .arg(key_for_live_counters_of_limit(counter.limit()))
                 .arg(60)
                 .arg(2),
             Ok(mock_response),
@@ -547,19 +935,31 @@ mod tests {
             .batcher()
             .add(
                 counter.clone(),
-                Arc::new(CachedCounterValue::load_from_authority_asap(&counter, 2)),
+                Arc::new(CachedCounterValue::load_from_authority_asap(
+                    &counter,
+                    2,
+                    Arc::new(SystemClock),
+                )),
             )
             .await;
 
         let cached_counters: Arc<CountersCache> = Arc::new(cache);
-        let partitioned = Arc::new(AtomicBool::new(false));
+        let partition = Arc::new(Partition::new(Arc::new(SystemClock)));
 
         if let Some(c) = cached_counters.get(&counter) {
             assert_eq!(c.hits(&counter), 2);
         }
 
-        flush_batcher_and_update_counters(mock_client, cached_counters.clone(), partitioned, 100)
-            .await;
+        flush_batcher_and_update_counters(
+            mock_client,
+            cached_counters.clone(),
+            partition,
+            100,
+            false,
+            &KeyPrefix::default(),
+            None,
+        )
+        .await;
 
         let c = cached_counters.get(&counter).unwrap();
         assert_eq!(c.hits(&counter), 8);
@@ -587,32 +987,153 @@ mod tests {
         assert!(error.is_timeout());
         let mock_client = MockRedisConnection::new(vec![MockCmd::new::<&mut Cmd, Value>(
             redis::cmd("EVALSHA")
-                .arg("95a717e821d8fbdd667b5e4c6fede4c9cad16006")
-                .arg("2")
+                .arg("370c03076122051c25f589e4de918dc773f75b90")
+                .arg("3")
                 .arg(key_for_counter(&counter))
                 .arg(key_for_counters_of_limit(counter.limit()))
+                .arg(key_for_live_counters_of_limit(counter.limit()))
                 .arg(60)
                 .arg(3),
             Err(error),
         )]);
 
         let cache = CountersCacheBuilder::new().build(Duration::from_millis(10));
-        let value = Arc::new(CachedCounterValue::from_authority(&counter, 2));
+        let value = Arc::new(CachedCounterValue::from_authority(
+            &counter,
+            2,
+            Arc::new(SystemClock),
+        ));
         value.delta(&counter, 3);
         cache.batcher().add(counter.clone(), value).await;
 
         let cached_counters: Arc<CountersCache> = Arc::new(cache);
-        let partitioned = Arc::new(AtomicBool::new(false));
+        let partition = Arc::new(Partition::new(Arc::new(SystemClock)));
 
         if let Some(c) = cached_counters.get(&counter) {
             assert_eq!(c.hits(&counter), 5);
         }
 
-        flush_batcher_and_update_counters(mock_client, cached_counters.clone(), partitioned, 100)
-            .await;
+        flush_batcher_and_update_counters(
+            mock_client,
+            cached_counters.clone(),
+            partition,
+            100,
+            false,
+            &KeyPrefix::default(),
+            None,
+        )
+        .await;
 
         let c = cached_counters.get(&counter).unwrap();
         assert_eq!(c.hits(&counter), 5);
         assert_eq!(c.pending_writes(), Ok(3));
     }
+
+    #[test]
+    fn partition_tracks_how_long_it_lasts() {
+        let clock = Arc::new(ManualClock::default());
+        let partition = Partition::new(clock.clone());
+        assert!(!partition.is_partitioned());
+
+        assert!(partition.flip(true));
+        assert!(!partition.flip(true));
+        assert!(partition.is_partitioned());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(partition.duration(), Duration::from_secs(5));
+
+        assert!(partition.flip(false));
+        assert!(!partition.is_partitioned());
+        assert!(partition.reconciling.load(Ordering::Acquire));
+    }
+
+    // A counter of a limit of 10 hits a minute, with `hits` known from Redis
+    fn cached_counter(cache: &CountersCache, hits: u64) -> Counter {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([("app_id".to_string(), "foo".to_string())]).into();
+        let counter = Counter::new(limit, &ctx)
+            .expect("counter creation failed!")
+            .expect("must have a counter");
+        let expiry = cache.clock().now() + counter.window();
+        cache.apply_remote_delta(counter.clone(), hits, 0, expiry);
+        counter
+    }
+
+    async fn is_admitted(
+        cache: &CountersCache,
+        partition: &Partition,
+        policy: PartitionPolicy,
+        counter: &Counter,
+    ) -> bool {
+        let authorization =
+            check_and_update_cached(cache, partition, policy, &mut [counter.clone()], 1, false)
+                .await
+                .unwrap();
+        matches!(authorization, Authorization::Ok)
+    }
+
+    #[tokio::test]
+    async fn fail_closed_rejects_while_partitioned() {
+        let clock = Arc::new(ManualClock::default());
+        let cache = CountersCacheBuilder::new()
+            .clock(clock.clone())
+            .build(Duration::from_secs(1));
+        let partition = Partition::new(clock);
+        let counter = cached_counter(&cache, 0);
+        let policy = PartitionPolicy::FailClosed;
+
+        assert!(is_admitted(&cache, &partition, policy, &counter).await);
+        partition.flip(true);
+        assert!(!is_admitted(&cache, &partition, policy, &counter).await);
+        partition.flip(false);
+        assert!(is_admitted(&cache, &partition, policy, &counter).await);
+    }
+
+    #[tokio::test]
+    async fn serve_from_cache_for_rejects_once_the_partition_lasted_too_long() {
+        let clock = Arc::new(ManualClock::default());
+        let cache = CountersCacheBuilder::new()
+            .clock(clock.clone())
+            .build(Duration::from_secs(1));
+        let partition = Partition::new(clock.clone());
+        let counter = cached_counter(&cache, 0);
+        let policy = PartitionPolicy::ServeFromCacheFor(Duration::from_secs(10));
+
+        partition.flip(true);
+        assert!(is_admitted(&cache, &partition, policy, &counter).await);
+        clock.advance(Duration::from_secs(10));
+        assert!(is_admitted(&cache, &partition, policy, &counter).await);
+        clock.advance(Duration::from_secs(1));
+        assert!(!is_admitted(&cache, &partition, policy, &counter).await);
+    }
+
+    #[tokio::test]
+    async fn divide_among_admits_a_share_of_the_limits_while_partitioned() {
+        let clock = Arc::new(ManualClock::default());
+        let cache = CountersCacheBuilder::new()
+            .clock(clock.clone())
+            .build(Duration::from_secs(1));
+        let partition = Partition::new(clock);
+        let counter = cached_counter(&cache, 4);
+        let policy = PartitionPolicy::DivideAmong(2);
+
+        partition.flip(true);
+        assert!(is_admitted(&cache, &partition, policy, &counter).await);
+        // 5 hits, the share of each of the 2 replicas
+        assert!(!is_admitted(&cache, &partition, policy, &counter).await);
+
+        let mut counters = [counter.clone()];
+        check_and_update_cached(&cache, &partition, policy, &mut counters, 0, true)
+            .await
+            .unwrap();
+        assert_eq!(counters[0].remaining(), Some(0));
+
+        partition.flip(false);
+        assert!(is_admitted(&cache, &partition, policy, &counter).await);
+    }
 }
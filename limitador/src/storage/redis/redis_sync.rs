@@ -1,27 +1,43 @@
 extern crate redis;
 
-use self::redis::{Commands, ConnectionInfo, ConnectionLike, IntoConnectionInfo, RedisError};
+use self::redis::cluster::ClusterClient;
+use self::redis::sentinel::SentinelClient;
+use self::redis::{
+    Commands, ConnectionInfo, ConnectionLike, ErrorKind, IntoConnectionInfo, RedisError, Value,
+};
 use crate::counter::Counter;
 use crate::limit::Limit;
-use crate::storage::keys::*;
-use crate::storage::redis::is_limited;
-use crate::storage::redis::scripts::{SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS};
+use crate::storage::redis::connection::{
+    cluster_client, is_sentinel_url, sentinel_client, Connection,
+};
+use crate::storage::redis::scripts::{
+    CHECK_AND_UPDATE, SCRIPT_ADMIT_COUNTER, SCRIPT_CARDINALITY, SCRIPT_IMPORT_COUNTER,
+    SCRIPT_MIGRATE_LEGACY_COUNTERS, SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS,
+};
+use crate::storage::redis::{
+    admit_counter_invocation, check_and_update_invocation, checked_and_updated, is_limited,
+    migrate_legacy_counters_invocation, KeyPrefix,
+};
 use crate::storage::{Authorization, CounterStorage, StorageErr};
 use r2d2::{ManageConnection, Pool};
 use std::collections::HashSet;
 use std::ops::Deref;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
 const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
 const MAX_REDIS_CONNS: u32 = 20; // TODO: make it configurable
 
-// Note: this implementation does no guarantee exact limits. Ensuring that we
-// never go over the limits would hurt performance. This implementation
-// sacrifices a bit of accuracy to be more performant.
+// Note: this implementation does no guarantee exact limits, unless built with
+// `exact_limits`. Ensuring that we never go over the limits would hurt
+// performance. By default, this implementation sacrifices a bit of accuracy to
+// be more performant.
 
 pub struct RedisStorage {
     conn_pool: Pool<RedisConnectionManager>,
+    exact_limits: bool,
+    key_prefix: KeyPrefix,
 }
 
 impl CounterStorage for RedisStorage {
@@ -29,14 +45,18 @@ impl CounterStorage for RedisStorage {
     fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
         let mut con = self.conn_pool.get()?;
 
-        match con.get::<Vec<u8>, Option<i64>>(key_for_counter(counter))? {
+        match con.get::<Vec<u8>, Option<i64>>(self.key_prefix.counter(counter))? {
             Some(val) => Ok(u64::try_from(val).unwrap_or(0) + delta <= counter.max_value()),
             None => Ok(counter.max_value().checked_sub(delta).is_some()),
         }
     }
 
     #[tracing::instrument(skip_all)]
-    fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        // the counters stay where they are until the limit is added again
+        if let Err(err) = self.migrate_legacy_counters(limit) {
+            warn!("Couldn't move the counters of a limit to their current keys: {err}");
+        }
         Ok(())
     }
 
@@ -45,8 +65,9 @@ impl CounterStorage for RedisStorage {
         let mut con = self.conn_pool.get()?;
 
         redis::Script::new(SCRIPT_UPDATE_COUNTER)
-            .key(key_for_counter(counter))
-            .key(key_for_counters_of_limit(counter.limit()))
+            .key(self.key_prefix.counter(counter))
+            .key(self.key_prefix.counters_of_limit(counter.limit()))
+            .key(self.key_prefix.live_counters_of_limit(counter.limit()))
             .arg(counter.window().as_secs())
             .arg(delta)
             .invoke::<()>(&mut *con)?;
@@ -62,7 +83,19 @@ impl CounterStorage for RedisStorage {
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
         let mut con = self.conn_pool.get()?;
-        let counter_keys: Vec<Vec<u8>> = counters.iter().map(key_for_counter).collect();
+
+        if self.exact_limits {
+            let script = redis::Script::new(CHECK_AND_UPDATE);
+            let script_res: Vec<Option<i64>> =
+                check_and_update_invocation(&script, &self.key_prefix, counters, delta)
+                    .invoke(&mut *con)?;
+            return Ok(checked_and_updated(counters, delta, script_res));
+        }
+
+        let counter_keys: Vec<Vec<u8>> = counters
+            .iter()
+            .map(|c| self.key_prefix.counter(c))
+            .collect();
 
         if load_counters {
             let script = redis::Script::new(VALUES_AND_TTLS);
@@ -98,7 +131,8 @@ impl CounterStorage for RedisStorage {
             let counter = &counters[counter_idx];
             redis::Script::new(SCRIPT_UPDATE_COUNTER)
                 .key(key)
-                .key(key_for_counters_of_limit(counter.limit()))
+                .key(self.key_prefix.counters_of_limit(counter.limit()))
+                .key(self.key_prefix.live_counters_of_limit(counter.limit()))
                 .arg(counter.window().as_secs())
                 .arg(delta)
                 .invoke::<()>(&mut *con)?;
@@ -114,12 +148,13 @@ impl CounterStorage for RedisStorage {
         let mut con = self.conn_pool.get()?;
 
         for limit in limits {
-            let counter_keys =
-                con.smembers::<Vec<u8>, HashSet<Vec<u8>>>(key_for_counters_of_limit(limit))?;
+            let counter_keys = con
+                .smembers::<Vec<u8>, HashSet<Vec<u8>>>(self.key_prefix.counters_of_limit(limit))?;
 
             for counter_key in counter_keys {
-                let mut counter: Counter =
-                    counter_from_counter_key(&counter_key, Arc::clone(limit));
+                let mut counter: Counter = self
+                    .key_prefix
+                    .counter_from_key(&counter_key, Arc::clone(limit));
 
                 // If the key does not exist, it means that the counter expired,
                 // so we don't have to return it.
@@ -150,13 +185,15 @@ impl CounterStorage for RedisStorage {
         let mut con = self.conn_pool.get()?;
 
         for limit in limits {
-            let counter_keys = con
-                .smembers::<Vec<u8>, HashSet<Vec<u8>>>(key_for_counters_of_limit(limit.deref()))?;
+            let counter_keys = con.smembers::<Vec<u8>, HashSet<Vec<u8>>>(
+                self.key_prefix.counters_of_limit(limit.deref()),
+            )?;
 
             for counter_key in counter_keys {
                 con.del::<_, ()>(counter_key)?;
             }
-            con.del::<_, ()>(key_for_counters_of_limit(limit))?;
+            con.del::<_, ()>(self.key_prefix.counters_of_limit(limit))?;
+            con.del::<_, ()>(self.key_prefix.live_counters_of_limit(limit))?;
         }
 
         Ok(())
@@ -165,25 +202,146 @@ impl CounterStorage for RedisStorage {
     #[tracing::instrument(skip_all)]
     fn clear(&self) -> Result<(), StorageErr> {
         let mut con = self.conn_pool.get()?;
-        redis::cmd("FLUSHDB").exec(&mut *con)?;
+        if self.key_prefix.is_empty() {
+            redis::cmd("FLUSHDB").exec(&mut *con)?;
+        } else {
+            con.delete_keys_with_prefix(self.key_prefix.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        let script = redis::Script::new(SCRIPT_ADMIT_COUNTER);
+        Ok(
+            admit_counter_invocation(&script, &self.key_prefix, counter, max_counters, true)
+                .invoke(&mut *con)?,
+        )
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn can_admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        let script = redis::Script::new(SCRIPT_ADMIT_COUNTER);
+        Ok(
+            admit_counter_invocation(&script, &self.key_prefix, counter, max_counters, false)
+                .invoke(&mut *con)?,
+        )
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        Ok(redis::Script::new(SCRIPT_CARDINALITY)
+            .key(self.key_prefix.live_counters_of_limit(limit))
+            .invoke(&mut *con)?)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        let mut con = self.conn_pool.get()?;
+
+        redis::Script::new(SCRIPT_IMPORT_COUNTER)
+            .key(self.key_prefix.counter(counter))
+            .key(self.key_prefix.counters_of_limit(counter.limit()))
+            .key(self.key_prefix.live_counters_of_limit(counter.limit()))
+            .arg(
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            )
+            .arg(value)
+            .invoke::<()>(&mut *con)?;
+
         Ok(())
     }
+
+    fn backend(&self) -> &'static str {
+        "redis"
+    }
 }
 
 impl RedisStorage {
+    /// Moves the counters of `limit` that a version of Limitador from before
+    /// the keys of the limits with an id got a hash tag left in Redis.
+    fn migrate_legacy_counters(&self, limit: &Limit) -> Result<(), StorageErr> {
+        let script = redis::Script::new(SCRIPT_MIGRATE_LEGACY_COUNTERS);
+        let Some((legacy, invocation)) =
+            migrate_legacy_counters_invocation(&script, &self.key_prefix, limit)
+        else {
+            return Ok(());
+        };
+        let mut con = self.conn_pool.get()?;
+        if !con.exists::<_, bool>(legacy)? {
+            return Ok(());
+        }
+        let moved: u64 = invocation.invoke(&mut *con)?;
+        info!("Moved {moved} counters of a limit to their current keys");
+        Ok(())
+    }
+
+    /// Uses the Redis server at `redis_url`, or the master the sentinels of a
+    /// `redis+sentinel://` URL point to.
     pub fn new(redis_url: &str) -> Result<Self, String> {
-        let conn_manager = match RedisConnectionManager::new(redis_url) {
+        let conn_manager = if is_sentinel_url(redis_url) {
+            RedisConnectionManager::sentinel(redis_url)
+        } else {
+            RedisConnectionManager::new(redis_url)
+        };
+        let conn_manager = match conn_manager {
+            Ok(conn_manager) => conn_manager,
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+        Self::with_conn_manager(conn_manager)
+    }
+
+    /// Uses the Redis Cluster some of whose nodes are listed, comma separated,
+    /// in `redis_urls`.
+    pub fn new_cluster(redis_urls: &str) -> Result<Self, String> {
+        let conn_manager = match RedisConnectionManager::cluster(redis_urls) {
             Ok(conn_manager) => conn_manager,
             Err(err) => {
                 return Err(err.to_string());
             }
         };
+        Self::with_conn_manager(conn_manager)
+    }
+
+    /// Checks and updates all the counters of a request in a single script, so
+    /// that concurrent requests can't go over the limits, at the cost of some
+    /// more work for Redis on every request.
+    pub fn exact_limits(mut self, exact_limits: bool) -> Self {
+        self.exact_limits = exact_limits;
+        self
+    }
+
+    /// Starts all the keys with `prefix`, which can't contain `{` or `}`, and
+    /// only clears those.
+    pub fn key_prefix(mut self, prefix: &str) -> Result<Self, String> {
+        self.key_prefix = KeyPrefix::new(prefix)?;
+        Ok(self)
+    }
+
+    fn with_conn_manager(conn_manager: RedisConnectionManager) -> Result<Self, String> {
         match Pool::builder()
             .connection_timeout(Duration::from_secs(3))
             .max_size(MAX_REDIS_CONNS)
             .build(conn_manager)
         {
-            Ok(conn_pool) => Ok(Self { conn_pool }),
+            Ok(conn_pool) => Ok(Self {
+                conn_pool,
+                exact_limits: false,
+                key_prefix: KeyPrefix::default(),
+            }),
             Err(err) => Err(err.to_string()),
         }
     }
@@ -193,32 +351,61 @@ impl RedisStorage {
 // crate. That crate has not been updated in a long time and depends on an old
 // version of the Redis crate. That's why I decided not to import it.
 
-#[derive(Debug)]
-pub struct RedisConnectionManager {
-    connection_info: ConnectionInfo,
+pub enum RedisConnectionManager {
+    Single(ConnectionInfo),
+    Sentinel(Mutex<SentinelClient>),
+    Cluster(ClusterClient),
 }
 
 impl RedisConnectionManager {
     pub fn new<T: IntoConnectionInfo>(params: T) -> Result<Self, RedisError> {
-        Ok(Self {
-            connection_info: params.into_connection_info()?,
-        })
+        Ok(Self::Single(params.into_connection_info()?))
+    }
+
+    pub fn sentinel(redis_url: &str) -> Result<Self, RedisError> {
+        Ok(Self::Sentinel(Mutex::new(sentinel_client(redis_url)?)))
+    }
+
+    pub fn cluster(redis_urls: &str) -> Result<Self, RedisError> {
+        Ok(Self::Cluster(cluster_client(redis_urls)?.build()?))
     }
 }
 
 impl ManageConnection for RedisConnectionManager {
-    type Connection = redis::Connection;
+    type Connection = Connection;
     type Error = RedisError;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        match redis::Client::open(self.connection_info.clone()) {
-            Ok(client) => client.get_connection(),
-            Err(err) => Err(err),
+        match self {
+            Self::Single(connection_info) => match redis::Client::open(connection_info.clone()) {
+                Ok(client) => client.get_connection().map(Connection::Single),
+                Err(err) => Err(err),
+            },
+            Self::Sentinel(client) => client
+                .lock()
+                .unwrap()
+                .get_connection()
+                .map(Connection::Single),
+            Self::Cluster(client) => client.get_connection().map(Connection::Cluster),
         }
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        redis::cmd("PING").query(conn)
+        match self {
+            // after a failover, the former master is still up as a replica
+            Self::Sentinel(_) => {
+                let role: Vec<Value> = redis::cmd("ROLE").query(conn)?;
+                match role
+                    .into_iter()
+                    .next()
+                    .map(redis::from_owned_redis_value::<String>)
+                {
+                    Some(Ok(role)) if role == "master" => Ok(()),
+                    _ => Err((ErrorKind::ReadOnly, "Not connected to the master").into()),
+                }
+            }
+            _ => redis::cmd("PING").query(conn),
+        }
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
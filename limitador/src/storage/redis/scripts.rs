@@ -6,9 +6,14 @@
 // means that the update counter script would not work when run as a MULTI/EXEC
 // because the counter key could expire between the "set" and the "incrby"
 // calls.
+//
+// The live counters of a limit are also kept in a sorted set, scored by when
+// they expire in ms, which is pruned of the expired ones as counters get added,
+// so that counting them doesn't go through all of them.
 
 // KEYS[1]: counter key
 // KEYS[2]: key that contains the counters that belong to the limit
+// KEYS[3]: key that contains the live counters of the limit
 // ARGV[1]: counter TTL
 // ARGV[2]: delta
 pub const SCRIPT_UPDATE_COUNTER: &str = "
@@ -16,34 +21,132 @@ pub const SCRIPT_UPDATE_COUNTER: &str = "
     if c == tonumber(ARGV[2]) then
       redis.call('expire', KEYS[1], ARGV[1])
       redis.call('sadd', KEYS[2], KEYS[1])
+      local t = redis.call('time')
+      local now = t[1] * 1000 + math.floor(t[2] / 1000)
+      redis.call('zremrangebyscore', KEYS[3], '-inf', now)
+      redis.call('zadd', KEYS[3], now + tonumber(ARGV[1]) * 1000, KEYS[1])
     end
     return c";
 
-// KEY[i]: Counter key
-// KEY[i+1]: Limit key
-// ARGV[i]: TTLs
-// ARGV[i+1]: Deltas
+// KEYS[1]: counter key
+// KEYS[2]: key that contains the counters that belong to the limit
+// KEYS[3]: key that contains the live counters of the limit
+// ARGV[1]: counter expiry, as a Unix time in ms
+// ARGV[2]: value
+// Replaces the value and the expiry of the counter, live or not.
+pub const SCRIPT_IMPORT_COUNTER: &str = "
+    redis.call('set', KEYS[1], ARGV[2])
+    redis.call('pexpireat', KEYS[1], ARGV[1])
+    redis.call('sadd', KEYS[2], KEYS[1])
+    redis.call('zadd', KEYS[3], ARGV[1], KEYS[1])
+    return tonumber(ARGV[2])";
+
+// KEYS[3n-2]: key of the n-th counter
+// KEYS[3n-1]: key that contains the counters that belong to its limit
+// KEYS[3n]: key that contains the live counters of its limit
+// ARGV[1]: delta
+// ARGV[2n]: max value of the n-th counter
+// ARGV[2n+1]: TTL of the n-th counter
+// Checks all the counters before incrementing any of them, so that concurrent
+// requests can't go over the limits. The first position of the list returned
+// is the 1-based position of the first counter that would go over its limit,
+// or 0 when none does and all of them got incremented. Then come the value and
+// TTL (in ms) of each counter, as in VALUES_AND_TTLS, after the increment if
+// there was one.
+pub const CHECK_AND_UPDATE: &str = "
+    local delta = tonumber(ARGV[1])
+    local res = {0}
+    for n = 1, #KEYS / 3 do
+        local value = tonumber(redis.call('get', KEYS[3*n-2]) or '0')
+        if res[1] == 0 and value + delta > tonumber(ARGV[2*n]) then
+            res[1] = n
+        end
+        table.insert(res, value)
+        table.insert(res, redis.call('pttl', KEYS[3*n-2]))
+    end
+    if res[1] == 0 then
+        for n = 1, #KEYS / 3 do
+            local key = KEYS[3*n-2]
+            local c = redis.call('incrby', key, delta)
+            res[2*n] = c
+            if c == delta then
+                local ttl = tonumber(ARGV[2*n+1])
+                redis.call('expire', key, ttl)
+                redis.call('sadd', KEYS[3*n-1], key)
+                local t = redis.call('time')
+                local now = t[1] * 1000 + math.floor(t[2] / 1000)
+                redis.call('zremrangebyscore', KEYS[3*n], '-inf', now)
+                redis.call('zadd', KEYS[3*n], now + ttl * 1000, key)
+                res[2*n+1] = ttl * 1000
+            end
+        end
+    end
+    return res
+";
+
+// KEYS[3n-2]: key of the n-th counter
+// KEYS[3n-1]: key that contains the counters that belong to its limit
+// KEYS[3n]: key that contains the live counters of its limit
+// ARGV[2n-1]: TTL of the n-th counter
+// ARGV[2n]: delta of the n-th counter
 // This function returns a list with the values and TTLs for the updated counter_keys,
 // the first position the counter value and the second the TTL
 pub const BATCH_UPDATE_COUNTERS: &str = "
     local res = {}
-    for i = 1, #KEYS, 2 do
-        local counter_key = KEYS[i]
-        local limit_key = KEYS[i+1]
-        local ttl = ARGV[i]
-        local delta = ARGV[i+1]
+    for n = 1, #KEYS / 3 do
+        local counter_key = KEYS[3*n-2]
+        local limit_key = KEYS[3*n-1]
+        local live_key = KEYS[3*n]
+        local ttl = ARGV[2*n-1]
+        local delta = ARGV[2*n]
 
         local c = redis.call('incrby', counter_key, delta)
         table.insert(res, c)
         if c == tonumber(delta) then
             redis.call('expire', counter_key, ttl)
             redis.call('sadd', limit_key, counter_key)
+            local t = redis.call('time')
+            local now = t[1] * 1000 + math.floor(t[2] / 1000)
+            redis.call('zremrangebyscore', live_key, '-inf', now)
+            redis.call('zadd', live_key, now + tonumber(ttl) * 1000, counter_key)
         end
         table.insert(res, redis.call('pexpiretime', counter_key))
     end
     return res
 ";
 
+// KEYS[1]: key that contains the live counters of the limit
+// Returns the number of those counters that haven't expired, after removing
+// the others.
+pub const SCRIPT_CARDINALITY: &str = "
+    local t = redis.call('time')
+    redis.call('zremrangebyscore', KEYS[1], '-inf', t[1] * 1000 + math.floor(t[2] / 1000))
+    return redis.call('zcard', KEYS[1])";
+
+// KEYS[1]: key that contains the live counters of the limit
+// ARGV[1]: counter key
+// ARGV[2]: max number of live counters of the limit
+// ARGV[3]: counter TTL
+// ARGV[4]: whether to take the room for the counter, 1 or 0
+// Returns 1 when the counter is one of the live counters of the limit, or
+// there's room left for it among them, that it then takes until it expires if
+// asked to, and 0 otherwise. Only the key of the live counters is passed, as the counters of
+// a sharded storage may be on other servers than the limit's.
+pub const SCRIPT_ADMIT_COUNTER: &str = "
+    local t = redis.call('time')
+    local now = t[1] * 1000 + math.floor(t[2] / 1000)
+    redis.call('zremrangebyscore', KEYS[1], '-inf', now)
+    if redis.call('zscore', KEYS[1], ARGV[1]) then
+        return 1
+    end
+    if redis.call('zcard', KEYS[1]) >= tonumber(ARGV[2]) then
+        return 0
+    end
+    if ARGV[4] == '1' then
+        redis.call('zadd', KEYS[1], now + tonumber(ARGV[3]) * 1000, ARGV[1])
+    end
+    return 1";
+
 // KEYS: the function returns the value and TTL (in ms) for these keys
 // The first position of the list returned contains the value of KEYS[1], the
 // second position contains its TTL. The third position contains the value of
@@ -56,3 +159,37 @@ pub const VALUES_AND_TTLS: &str = "
     end
     return res
 ";
+
+// KEYS[1]: key that contained the counters of a limit with an id, before their
+//          keys got a hash tag
+// KEYS[2]: key that contains the counters that belong to the limit
+// KEYS[3]: key that contains the live counters of the limit
+// ARGV[1]: what the key of a counter got in front of it: the prefix of the
+//          keys and the hash tag
+// Moves the live counters to their current keys, adding their values to the
+// ones hit there since, and returns how many got moved. Only ever run where the
+// legacy keys are, a single Redis, as they span several slots of a cluster.
+pub const SCRIPT_MIGRATE_LEGACY_COUNTERS: &str = "
+    local t = redis.call('time')
+    local now = t[1] * 1000 + math.floor(t[2] / 1000)
+    local moved = 0
+    for _, legacy in ipairs(redis.call('smembers', KEYS[1])) do
+        local ttl = redis.call('pttl', legacy)
+        if ttl > 0 then
+            local key = ARGV[1] .. legacy
+            if redis.call('exists', key) == 0 then
+                redis.call('rename', legacy, key)
+            else
+                redis.call('incrby', key, redis.call('get', legacy))
+                redis.call('del', legacy)
+                ttl = redis.call('pttl', key)
+            end
+            redis.call('sadd', KEYS[2], key)
+            redis.call('zadd', KEYS[3], now + ttl, key)
+            moved = moved + 1
+        else
+            redis.call('del', legacy)
+        end
+    end
+    redis.call('del', KEYS[1])
+    return moved";
@@ -0,0 +1,692 @@
+use crate::clock::Clock;
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+use async_trait::async_trait;
+use metrics::{counter, gauge};
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+const HALF_OPEN: u8 = 2;
+
+/// Wraps a storage, either a [`CounterStorage`] or an
+/// [`AsyncCounterStorage`], to make it weather failures of its backend:
+///
+/// - the calls taking longer than the [timeout](Self::timeout) fail with a
+///   transient error. Only the async storages can be timed out.
+/// - the reads failing with a [transient](StorageErr::is_transient) error are
+///   [retried](Self::retries), after an exponential backoff with jitter, and
+///   so are deletions. Hits aren't: one that failed, or timed out, after
+///   reaching the backend would then be counted twice.
+/// - after a number of transient failures in a row, the
+///   [circuit breaker](Self::circuit_breaker) opens: the calls fail right
+///   away with a transient error, without reaching the backend, until it
+///   cools down. The first call after that probes the backend, closing the
+///   breaker if it succeeds and opening it again otherwise.
+///
+/// The state of the breaker is published as the `storage_circuit_breaker_state`
+/// gauge, labeled by the backend of the storage: 0 when closed, 1 when open and
+/// 2 while probing.
+pub struct ResilientStorage<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl<S> ResilientStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            timeout: None,
+            retries: 0,
+            backoff: Duration::from_millis(10),
+            breaker: None,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retries a call up to `retries` times, the n-th retry waiting up to
+    /// `backoff` × 2ⁿ⁻¹.
+    pub fn retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Opens the breaker after `failures` transient failures in a row, for
+    /// `cool_down`.
+    pub fn circuit_breaker(mut self, failures: u32, cool_down: Duration) -> Self {
+        self.breaker = Some(CircuitBreaker::new(failures.max(1), cool_down));
+        self
+    }
+
+    /// Whether a call to the `backend` may go through, and if so whether it
+    /// probes it, see [`CircuitBreaker::admit`].
+    fn admit(&self, backend: &'static str) -> Result<bool, StorageErr> {
+        match &self.breaker {
+            Some(breaker) => breaker.admit(backend),
+            None => Ok(false),
+        }
+    }
+
+    fn record<T>(&self, result: &Result<T, StorageErr>, probe: bool) {
+        if let Some(breaker) = &self.breaker {
+            breaker.record(result, probe);
+        }
+    }
+
+    /// Records the outcome of an attempt, returning how long to wait before
+    /// retrying it, if it is to be.
+    fn settle<T>(
+        &self,
+        attempt: u32,
+        result: &Result<T, StorageErr>,
+        probe: bool,
+    ) -> Option<Duration> {
+        self.record(result, probe);
+        match result {
+            Err(err) if err.is_transient() && attempt < self.retries => {
+                counter!("storage_retries").increment(1);
+                Some(jittered(self.backoff.saturating_mul(1 << attempt.min(16))))
+            }
+            _ => None,
+        }
+    }
+
+    async fn timed<T>(
+        &self,
+        call: impl Future<Output = Result<T, StorageErr>>,
+    ) -> Result<T, StorageErr> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .unwrap_or_else(|elapsed| {
+                    Err(StorageErr {
+                        msg: format!("timed out after {timeout:?}"),
+                        source: Some(Box::new(elapsed)),
+                        transient: true,
+                    })
+                }),
+            None => call.await,
+        }
+    }
+}
+
+impl<S: CounterStorage> ResilientStorage<S> {
+    fn call<T>(&self, mut op: impl FnMut() -> Result<T, StorageErr>) -> Result<T, StorageErr> {
+        let mut attempt = 0;
+        loop {
+            let probe = self.admit(self.inner.backend())?;
+            let result = op();
+            match self.settle(attempt, &result, probe) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    fn call_once<T>(&self, op: impl FnOnce() -> Result<T, StorageErr>) -> Result<T, StorageErr> {
+        let probe = self.admit(self.inner.backend())?;
+        let result = op();
+        self.record(&result, probe);
+        result
+    }
+}
+
+impl<S: AsyncCounterStorage> ResilientStorage<S> {
+    async fn call_async_once<T>(
+        &self,
+        call: impl Future<Output = Result<T, StorageErr>>,
+    ) -> Result<T, StorageErr> {
+        let probe = self.admit(self.inner.backend())?;
+        let result = self.timed(call).await;
+        self.record(&result, probe);
+        result
+    }
+}
+
+// The calls borrow their arguments, which a closure returning their future
+// couldn't hand over to each attempt
+macro_rules! call_async {
+    ($self:ident, $call:expr) => {{
+        let mut attempt = 0;
+        loop {
+            let probe = $self.admit($self.inner.backend())?;
+            let result = $self.timed($call).await;
+            match $self.settle(attempt, &result, probe) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => break result,
+            }
+            attempt += 1;
+        }
+    }};
+}
+
+#[async_trait]
+impl<S: AsyncCounterStorage> AsyncCounterStorage for ResilientStorage<S> {
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.inner.add_counter(limit)
+    }
+
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        call_async!(self, self.inner.is_within_limits(counter, delta))
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.call_async_once(self.inner.update_counter(counter, delta))
+            .await
+    }
+
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.call_async_once(self.inner.check_and_update(counters, delta, load_counters))
+            .await
+    }
+
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        call_async!(self, self.inner.get_counters(limits))
+    }
+
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        call_async!(self, self.inner.delete_counters(limits))
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        call_async!(self, self.inner.clear())
+    }
+
+    async fn admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        call_async!(self, self.inner.admit_counter(counter, max_counters))
+    }
+
+    async fn can_admit_counter(
+        &self,
+        counter: &Counter,
+        max_counters: u64,
+    ) -> Result<bool, StorageErr> {
+        call_async!(self, self.inner.can_admit_counter(counter, max_counters))
+    }
+
+    async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        call_async!(self, self.inner.cardinality(limit))
+    }
+
+    async fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        self.call_async_once(self.inner.import_counter(counter, value, expires_at))
+            .await
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.clock()
+    }
+
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+}
+
+impl<S: CounterStorage> CounterStorage for ResilientStorage<S> {
+    fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        self.call(|| self.inner.is_within_limits(counter, delta))
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.call(|| self.inner.add_counter(limit))
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.call_once(|| self.inner.update_counter(counter, delta))
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.call_once(|| self.inner.check_and_update(counters, delta, load_counters))
+    }
+
+    fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
+        self.call(|| self.inner.get_counters(limits))
+    }
+
+    fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        self.call(|| self.inner.delete_counters(limits))
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        self.call(|| self.inner.clear())
+    }
+
+    fn admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        self.call(|| self.inner.admit_counter(counter, max_counters))
+    }
+
+    fn can_admit_counter(&self, counter: &Counter, max_counters: u64) -> Result<bool, StorageErr> {
+        self.call(|| self.inner.can_admit_counter(counter, max_counters))
+    }
+
+    fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+        self.call(|| self.inner.cardinality(limit))
+    }
+
+    fn import_counter(
+        &self,
+        counter: &Counter,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), StorageErr> {
+        self.call_once(|| self.inner.import_counter(counter, value, expires_at))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.clock()
+    }
+
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+}
+
+/// A random duration between half and all of `delay`.
+fn jittered(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let half = delay / 2;
+    half + Duration::from_nanos(random % (half.as_nanos() as u64).max(1))
+}
+
+struct CircuitBreaker {
+    threshold: u32,
+    cool_down: Duration,
+    state: AtomicU8,
+    failures: AtomicU32,
+    opened_at: Mutex<Instant>,
+    probing: AtomicBool,
+    // labeling its state, as first admitting a call to it
+    backend: OnceLock<&'static str>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            threshold,
+            cool_down,
+            state: AtomicU8::new(CLOSED),
+            failures: AtomicU32::new(0),
+            opened_at: Mutex::new(Instant::now()),
+            probing: AtomicBool::new(false),
+            backend: OnceLock::new(),
+        }
+    }
+
+    /// Lets a call through while closed, and a single one once cooled down
+    /// after opening, that probes the backend, which it returns whether it is.
+    fn admit(&self, backend: &'static str) -> Result<bool, StorageErr> {
+        self.backend.get_or_init(|| {
+            gauge!("storage_circuit_breaker_state", "backend" => backend).set(CLOSED);
+            backend
+        });
+        if self.state.load(Ordering::Acquire) == CLOSED {
+            return Ok(false);
+        }
+        let cooled_down = self.opened_at.lock().unwrap().elapsed() >= self.cool_down;
+        if cooled_down
+            && self
+                .probing
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.transition(HALF_OPEN);
+            return Ok(true);
+        }
+        counter!("storage_circuit_breaker_rejections").increment(1);
+        Err(StorageErr {
+            msg: "circuit breaker open".to_owned(),
+            source: None,
+            transient: true,
+        })
+    }
+
+    /// Records the outcome of a call. Once open, only the one of the `probe`
+    /// counts, the calls let through before it opened being stale by then.
+    fn record<T>(&self, result: &Result<T, StorageErr>, probe: bool) {
+        let closed = self.state.load(Ordering::Acquire) == CLOSED;
+        match result {
+            Err(err) if err.is_transient() => {
+                let failures = self.failures.fetch_add(1, Ordering::AcqRel) + 1;
+                if probe || (closed && failures >= self.threshold) {
+                    *self.opened_at.lock().unwrap() = Instant::now();
+                    if self.transition(OPEN) {
+                        warn!("Opened the circuit breaker of the storage: {}", err);
+                    }
+                }
+            }
+            // the backend answered, even if with an error
+            _ => {
+                if probe || closed {
+                    self.failures.store(0, Ordering::Release);
+                    if self.transition(CLOSED) {
+                        warn!("Closed the circuit breaker of the storage");
+                    }
+                }
+            }
+        }
+        if probe {
+            self.probing.store(false, Ordering::Release);
+        }
+    }
+
+    fn transition(&self, state: u8) -> bool {
+        let changed = self.state.swap(state, Ordering::AcqRel) != state;
+        if changed {
+            let backend = self.backend.get().copied().unwrap_or_default();
+            gauge!("storage_circuit_breaker_state", "backend" => backend).set(state);
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResilientStorage;
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+    use async_trait::async_trait;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Fails its first `failures` calls with a transient error, then delegates
+    /// to an in memory storage.
+    struct Flaky {
+        failures: AtomicU32,
+        calls: AtomicU32,
+        delay: Duration,
+        inner: InMemoryStorage,
+    }
+
+    impl Flaky {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures: AtomicU32::new(failures),
+                calls: AtomicU32::new(0),
+                delay: Duration::ZERO,
+                inner: InMemoryStorage::default(),
+            }
+        }
+
+        fn attempt(&self) -> Result<(), StorageErr> {
+            self.calls.fetch_add(1, Ordering::AcqRel);
+            let left = self.failures.load(Ordering::Acquire);
+            if left > 0 {
+                self.failures.store(left - 1, Ordering::Release);
+                return Err(StorageErr {
+                    msg: "flaky".to_owned(),
+                    source: None,
+                    transient: true,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AsyncCounterStorage for Flaky {
+        fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+            CounterStorage::add_counter(&self.inner, limit)
+        }
+
+        async fn is_within_limits(
+            &self,
+            counter: &Counter,
+            delta: u64,
+        ) -> Result<bool, StorageErr> {
+            tokio::time::sleep(self.delay).await;
+            self.attempt()?;
+            CounterStorage::is_within_limits(&self.inner, counter, delta)
+        }
+
+        async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+            self.attempt()?;
+            CounterStorage::update_counter(&self.inner, counter, delta)
+        }
+
+        async fn check_and_update<'a>(
+            &self,
+            counters: &mut Vec<Counter>,
+            delta: u64,
+            load_counters: bool,
+        ) -> Result<Authorization, StorageErr> {
+            self.attempt()?;
+            CounterStorage::check_and_update(&self.inner, counters, delta, load_counters)
+        }
+
+        async fn get_counters(
+            &self,
+            limits: &HashSet<Arc<Limit>>,
+        ) -> Result<HashSet<Counter>, StorageErr> {
+            CounterStorage::get_counters(&self.inner, limits)
+        }
+
+        async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+            CounterStorage::delete_counters(&self.inner, limits)
+        }
+
+        async fn clear(&self) -> Result<(), StorageErr> {
+            CounterStorage::clear(&self.inner)
+        }
+
+        async fn admit_counter(
+            &self,
+            counter: &Counter,
+            max_counters: u64,
+        ) -> Result<bool, StorageErr> {
+            CounterStorage::admit_counter(&self.inner, counter, max_counters)
+        }
+
+        async fn can_admit_counter(
+            &self,
+            counter: &Counter,
+            max_counters: u64,
+        ) -> Result<bool, StorageErr> {
+            CounterStorage::can_admit_counter(&self.inner, counter, max_counters)
+        }
+
+        async fn cardinality(&self, limit: &Limit) -> Result<u64, StorageErr> {
+            CounterStorage::cardinality(&self.inner, limit)
+        }
+    }
+
+    /// A [`Flaky`] blocking storage.
+    struct BlockingFlaky(Flaky);
+
+    impl CounterStorage for BlockingFlaky {
+        fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+            self.0.attempt()?;
+            self.0.inner.is_within_limits(counter, delta)
+        }
+
+        fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+            self.0.inner.add_counter(limit)
+        }
+
+        fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+            self.0.attempt()?;
+            self.0.inner.update_counter(counter, delta)
+        }
+
+        fn check_and_update(
+            &self,
+            counters: &mut Vec<Counter>,
+            delta: u64,
+            load_counters: bool,
+        ) -> Result<Authorization, StorageErr> {
+            self.0.attempt()?;
+            self.0
+                .inner
+                .check_and_update(counters, delta, load_counters)
+        }
+
+        fn get_counters(
+            &self,
+            limits: &HashSet<Arc<Limit>>,
+        ) -> Result<HashSet<Counter>, StorageErr> {
+            self.0.inner.get_counters(limits)
+        }
+
+        fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+            self.0.inner.delete_counters(limits)
+        }
+
+        fn clear(&self) -> Result<(), StorageErr> {
+            self.0.inner.clear()
+        }
+    }
+
+    fn test_counter() -> Counter {
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        Counter::new(limit, &Default::default()).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let storage = ResilientStorage::new(Flaky::new(2)).retries(2, Duration::from_millis(1));
+        let counter = test_counter();
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+        let result = storage.is_within_limits(&counter, 1).await;
+        assert!(matches!(result, Ok(true)));
+        assert_eq!(storage.inner.calls.load(Ordering::Acquire), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_retries() {
+        let storage = ResilientStorage::new(Flaky::new(3)).retries(2, Duration::from_millis(1));
+        let result = storage.is_within_limits(&test_counter(), 1).await;
+        assert!(result.is_err_and(|err| err.is_transient()));
+        assert_eq!(storage.inner.calls.load(Ordering::Acquire), 3);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_hits() {
+        let storage = ResilientStorage::new(Flaky::new(1)).retries(2, Duration::from_millis(1));
+        let mut counters = vec![test_counter()];
+        AsyncCounterStorage::add_counter(&storage, counters[0].limit()).unwrap();
+        let result = storage.check_and_update(&mut counters, 1, true).await;
+        assert!(result.is_err_and(|err| err.is_transient()));
+        assert!(storage.update_counter(&counters[0], 1).await.is_ok());
+        assert_eq!(storage.inner.calls.load(Ordering::Acquire), 2);
+        let result = storage.check_and_update(&mut counters, 1, true).await;
+        assert!(matches!(result, Ok(Authorization::Ok)));
+        assert_eq!(counters[0].remaining(), Some(8));
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_calls() {
+        let mut flaky = Flaky::new(0);
+        flaky.delay = Duration::from_millis(100);
+        let storage = ResilientStorage::new(flaky).timeout(Duration::from_millis(10));
+        let result = storage.is_within_limits(&test_counter(), 1).await;
+        assert!(result.is_err_and(|err| err.is_transient() && err.msg().contains("timed out")));
+    }
+
+    #[tokio::test]
+    async fn short_circuits_once_open() {
+        let storage =
+            ResilientStorage::new(Flaky::new(3)).circuit_breaker(2, Duration::from_millis(50));
+        let counter = test_counter();
+        for _ in 0..2 {
+            assert!(storage.update_counter(&counter, 1).await.is_err());
+        }
+        let result = storage.update_counter(&counter, 1).await;
+        assert!(result.is_err_and(|err| err.msg() == "circuit breaker open"));
+        assert_eq!(storage.inner.calls.load(Ordering::Acquire), 2);
+
+        // the probe fails, opening it again
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(storage
+            .update_counter(&counter, 1)
+            .await
+            .is_err_and(|err| err.msg() == "flaky"));
+        assert!(storage
+            .update_counter(&counter, 1)
+            .await
+            .is_err_and(|err| err.msg() == "circuit breaker open"));
+
+        // the probe succeeds, closing it
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(storage.update_counter(&counter, 1).await.is_ok());
+        assert!(storage.update_counter(&counter, 1).await.is_ok());
+        assert_eq!(storage.inner.calls.load(Ordering::Acquire), 5);
+    }
+
+    #[tokio::test]
+    async fn stays_open_on_stale_successes() {
+        let mut flaky = Flaky::new(2);
+        flaky.delay = Duration::from_millis(50);
+        let storage = ResilientStorage::new(flaky).circuit_breaker(2, Duration::from_secs(60));
+        let counter = test_counter();
+        AsyncCounterStorage::add_counter(&storage, counter.limit()).unwrap();
+
+        // let through before the breaker opens, and answering after
+        let (stale, _) = tokio::join!(storage.is_within_limits(&counter, 1), async {
+            for _ in 0..2 {
+                assert!(storage.update_counter(&counter, 1).await.is_err());
+            }
+        });
+        assert!(matches!(stale, Ok(true)));
+        assert!(storage
+            .update_counter(&counter, 1)
+            .await
+            .is_err_and(|err| err.msg() == "circuit breaker open"));
+    }
+
+    #[test]
+    fn retries_blocking_storages() {
+        let storage = ResilientStorage::new(BlockingFlaky(Flaky::new(2)))
+            .retries(2, Duration::from_millis(1))
+            .circuit_breaker(3, Duration::from_secs(1));
+        let mut counters = vec![test_counter()];
+        CounterStorage::add_counter(&storage, counters[0].limit()).unwrap();
+        let result = CounterStorage::is_within_limits(&storage, &counters[0], 1);
+        assert!(matches!(result, Ok(true)));
+        assert_eq!(storage.inner.0.calls.load(Ordering::Acquire), 3);
+        let result = CounterStorage::check_and_update(&storage, &mut counters, 1, true);
+        assert!(matches!(result, Ok(Authorization::Ok)));
+        assert_eq!(counters[0].remaining(), Some(9));
+    }
+}
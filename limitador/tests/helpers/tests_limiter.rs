@@ -1,70 +1,52 @@
 use limitador::counter::Counter;
 use limitador::errors::LimitadorError;
 use limitador::limit::{Context, Limit, Namespace};
-use limitador::{AsyncRateLimiter, CheckResult, RateLimiter};
+use limitador::storage::dump::CounterDump;
+#[cfg(any(feature = "disk_storage", feature = "redis_storage"))]
+use limitador::AsyncRateLimiter;
+use limitador::{CheckResult, Limiter, RateLimiter};
 use std::collections::HashSet;
 
 // This exposes a struct that wraps both implementations of the rate limiter,
-// the blocking and the async one. This allows us to avoid duplications in the
-// tests.
-
-enum LimiterImpl {
-    Blocking(RateLimiter),
-    #[cfg(feature = "redis_storage")]
-    Async(AsyncRateLimiter),
-}
+// the blocking and the async one, behind the `Limiter` trait. This allows us
+// to avoid duplications in the tests.
 
 pub struct TestsLimiter {
-    limiter_impl: LimiterImpl,
+    limiter_impl: Box<dyn Limiter>,
 }
 
 impl TestsLimiter {
     pub fn new_from_blocking_impl(limiter: RateLimiter) -> Self {
         Self {
-            limiter_impl: LimiterImpl::Blocking(limiter),
+            limiter_impl: Box::new(limiter),
         }
     }
 
-    #[cfg(feature = "redis_storage")]
+    #[cfg(any(feature = "disk_storage", feature = "redis_storage"))]
     pub fn new_from_async_impl(limiter: AsyncRateLimiter) -> Self {
         Self {
-            limiter_impl: LimiterImpl::Async(limiter),
+            limiter_impl: Box::new(limiter),
         }
     }
 
     pub async fn get_namespaces(&self) -> HashSet<Namespace> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.get_namespaces(),
-            LimiterImpl::Async(limiter) => limiter.get_namespaces(),
-        }
+        self.limiter_impl.get_namespaces()
     }
 
     pub async fn add_limit(&self, limit: &Limit) -> bool {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.add_limit(limit.clone()),
-            LimiterImpl::Async(limiter) => limiter.add_limit(limit.clone()),
-        }
+        self.limiter_impl.add_limit(limit.clone())
     }
 
     pub async fn delete_limit(&self, limit: &Limit) -> Result<(), LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.delete_limit(limit),
-            LimiterImpl::Async(limiter) => limiter.delete_limit(limit).await,
-        }
+        self.limiter_impl.delete_limit(limit).await
     }
 
     pub async fn get_limits(&self, namespace: &str) -> HashSet<Limit> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.get_limits(&namespace.into()),
-            LimiterImpl::Async(limiter) => limiter.get_limits(&namespace.into()),
-        }
+        self.limiter_impl.get_limits(&namespace.into())
     }
 
     pub async fn delete_limits(&self, namespace: &str) -> Result<(), LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.delete_limits(&namespace.into()),
-            LimiterImpl::Async(limiter) => limiter.delete_limits(&namespace.into()).await,
-        }
+        self.limiter_impl.delete_limits(&namespace.into()).await
     }
 
     pub async fn is_rate_limited(
@@ -73,14 +55,9 @@ impl TestsLimiter {
         ctx: &Context<'_>,
         delta: u64,
     ) -> Result<CheckResult, LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => {
-                limiter.is_rate_limited(&namespace.into(), ctx, delta)
-            }
-            LimiterImpl::Async(limiter) => {
-                limiter.is_rate_limited(&namespace.into(), ctx, delta).await
-            }
-        }
+        self.limiter_impl
+            .is_rate_limited(&namespace.into(), ctx, delta)
+            .await
     }
 
     pub async fn update_counters(
@@ -89,14 +66,9 @@ impl TestsLimiter {
         ctx: &Context<'_>,
         delta: u64,
     ) -> Result<(), LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => {
-                limiter.update_counters(&namespace.into(), ctx, delta)
-            }
-            LimiterImpl::Async(limiter) => {
-                limiter.update_counters(&namespace.into(), ctx, delta).await
-            }
-        }
+        self.limiter_impl
+            .update_counters(&namespace.into(), ctx, delta)
+            .await
     }
 
     pub async fn check_rate_limited_and_update(
@@ -106,32 +78,43 @@ impl TestsLimiter {
         delta: u64,
         load_counters: bool,
     ) -> Result<CheckResult, LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => {
-                limiter.check_rate_limited_and_update(&namespace.into(), ctx, delta, load_counters)
-            }
-            LimiterImpl::Async(limiter) => {
-                limiter
-                    .check_rate_limited_and_update(&namespace.into(), ctx, delta, load_counters)
-                    .await
-            }
-        }
+        self.limiter_impl
+            .check_rate_limited_and_update(&namespace.into(), ctx, delta, load_counters)
+            .await
     }
 
     pub async fn get_counters(&self, namespace: &str) -> Result<HashSet<Counter>, LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.get_counters(&namespace.into()),
-            LimiterImpl::Async(limiter) => limiter.get_counters(&namespace.into()).await,
-        }
+        self.limiter_impl.get_counters(&namespace.into()).await
+    }
+
+    pub async fn cardinality(&self, limit: &Limit) -> Result<u64, LimitadorError> {
+        self.limiter_impl.cardinality(limit).await
+    }
+
+    pub async fn export_counters(
+        &self,
+        namespace: &str,
+    ) -> Result<Vec<CounterDump>, LimitadorError> {
+        self.limiter_impl.export_counters(&namespace.into()).await
+    }
+
+    pub async fn export_limit_counters(
+        &self,
+        limit: &Limit,
+    ) -> Result<Vec<CounterDump>, LimitadorError> {
+        self.limiter_impl.export_limit_counters(limit).await
+    }
+
+    pub async fn import_counters(&self, dumps: Vec<CounterDump>) -> Result<u64, LimitadorError> {
+        self.limiter_impl.import_counters(dumps).await
     }
 
     pub async fn configure_with(
         &self,
         limits: impl IntoIterator<Item = Limit>,
     ) -> Result<(), LimitadorError> {
-        match &self.limiter_impl {
-            LimiterImpl::Blocking(limiter) => limiter.configure_with(limits),
-            LimiterImpl::Async(limiter) => limiter.configure_with(limits).await,
-        }
+        self.limiter_impl
+            .configure_with(limits.into_iter().collect())
+            .await
     }
 }
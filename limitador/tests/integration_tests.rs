@@ -30,6 +30,16 @@ macro_rules! test_with_all_storage_impls {
                 $function(&mut TestsLimiter::new_from_blocking_impl(rate_limiter)).await;
             }
 
+            #[cfg(feature = "disk_storage")]
+            #[tokio::test]
+            async fn [<$function _disk_storage_on_blocking_pool>]() {
+                let dir = TempDir::new().expect("We should have a dir!");
+                let storage = DiskStorage::open(dir.path(), OptimizeFor::Throughput).expect("Couldn't open temp dir");
+                let rate_limiter =
+                    AsyncRateLimiter::new_with_storage(Box::new(BlockingStorageAdapter::new(storage)));
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
             #[cfg(feature = "redis_storage")]
             #[tokio::test]
             #[serial]
@@ -55,6 +65,19 @@ macro_rules! test_with_all_storage_impls {
                 $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
             }
 
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            async fn [<$function _with_sharded_redis>]() {
+                // the databases of a single server stand for the shards
+                let storage = ShardedRedisStorage::new("redis://127.0.0.1:6379/1,redis://127.0.0.1:6379/2,redis://127.0.0.1:6379/3").await.expect("We need a Redis running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
             #[cfg(feature = "redis_storage")]
             #[tokio::test]
             #[serial]
@@ -69,10 +92,115 @@ macro_rules! test_with_all_storage_impls {
                 );
                 $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
             }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            #[ignore = "needs Redis Sentinel, see REDIS_SENTINEL_URL"]
+            async fn [<$function _with_sync_redis_sentinel>]() {
+                let storage = RedisStorage::new(&crate::redis_sentinel_url()).expect("We need Redis Sentinel running locally");
+                storage.clear().unwrap();
+                let rate_limiter = RateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_blocking_impl(rate_limiter)).await;
+            }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            #[ignore = "needs Redis Sentinel, see REDIS_SENTINEL_URL"]
+            async fn [<$function _with_async_redis_sentinel>]() {
+                let storage = AsyncRedisStorage::new(&crate::redis_sentinel_url()).await.expect("We need Redis Sentinel running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            #[ignore = "needs Redis Sentinel, see REDIS_SENTINEL_URL"]
+            async fn [<$function _with_async_redis_sentinel_and_local_cache>]() {
+                let storage_builder = CachedRedisStorageBuilder::new(&crate::redis_sentinel_url()).
+                    flushing_period(Duration::from_millis(2)).
+                    max_cached_counters(10000);
+                let storage = storage_builder.build().await.expect("We need Redis Sentinel running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            #[ignore = "needs a Redis Cluster, see REDIS_CLUSTER_URLS"]
+            async fn [<$function _with_sync_redis_cluster>]() {
+                let storage = RedisStorage::new_cluster(&crate::redis_cluster_urls()).expect("We need a Redis Cluster running locally");
+                storage.clear().unwrap();
+                let rate_limiter = RateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_blocking_impl(rate_limiter)).await;
+            }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            #[ignore = "needs a Redis Cluster, see REDIS_CLUSTER_URLS"]
+            async fn [<$function _with_async_redis_cluster>]() {
+                let storage = AsyncRedisStorage::new_cluster(&crate::redis_cluster_urls()).await.expect("We need a Redis Cluster running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            #[ignore = "needs a Redis Cluster, see REDIS_CLUSTER_URLS"]
+            async fn [<$function _with_async_redis_cluster_and_local_cache>]() {
+                let storage_builder = CachedRedisStorageBuilder::new(&crate::redis_cluster_urls()).
+                    cluster(true).
+                    flushing_period(Duration::from_millis(2)).
+                    max_cached_counters(10000);
+                let storage = storage_builder.build().await.expect("We need a Redis Cluster running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
         }
     };
 }
 
+/// The `redis+sentinel://` URL of the master the ignored `_redis_sentinel`
+/// tests run against.
+#[cfg(feature = "redis_storage")]
+fn redis_sentinel_url() -> String {
+    std::env::var("REDIS_SENTINEL_URL")
+        .unwrap_or_else(|_| "redis+sentinel://127.0.0.1:26379/mymaster".to_owned())
+}
+
+/// The comma separated URLs of some of the nodes of the Redis Cluster the
+/// ignored `_redis_cluster` tests run against.
+#[cfg(feature = "redis_storage")]
+fn redis_cluster_urls() -> String {
+    std::env::var("REDIS_CLUSTER_URLS").unwrap_or_else(|_| {
+        (7000..7006)
+            .map(|port| format!("redis://127.0.0.1:{port}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    })
+}
+
 #[cfg(feature = "distributed_storage")]
 async fn distributed_storage_factory(
     count: usize,
@@ -148,25 +276,35 @@ mod test {
             use limitador::storage::redis::AsyncRedisStorage;
             use limitador::storage::redis::CachedRedisStorageBuilder;
             use limitador::storage::redis::RedisStorage;
+            use limitador::storage::redis::ShardedRedisStorage;
 
-            use limitador::AsyncRateLimiter;
             use serial_test::serial;
             use crate::test::limitador::storage::CounterStorage;
             use crate::test::limitador::storage::AsyncCounterStorage;
         }
     }
 
-    use self::limitador::counter::Counter;
+    use self::limitador::counter::{Counter, VariableHasher};
     use self::limitador::RateLimiter;
+    use self::limitador::RateLimiterBuilder;
     use crate::helpers::tests_limiter::*;
-    use limitador::limit::Limit;
+    use limitador::clock::ManualClock;
+    use limitador::errors::LimitadorError;
+    use limitador::limit::{Context, Expression, Limit, OnEvaluationError, OnTooManyCounters};
+    #[cfg(feature = "disk_storage")]
+    use limitador::storage::blocking::BlockingStorageAdapter;
     #[cfg(feature = "disk_storage")]
     use limitador::storage::disk::{DiskStorage, OptimizeFor};
     #[cfg(feature = "distributed_storage")]
     use limitador::storage::distributed::CrInMemoryStorage;
     use limitador::storage::in_memory::InMemoryStorage;
+    #[cfg(feature = "disk_storage")]
+    use limitador::storage::Storage;
+    #[cfg(any(feature = "disk_storage", feature = "redis_storage"))]
+    use limitador::AsyncRateLimiter;
     use std::collections::{HashMap, HashSet};
     use std::future::Future;
+    use std::sync::Arc;
     use std::thread::sleep;
     use std::time::Duration;
     #[cfg(feature = "disk_storage")]
@@ -208,6 +346,10 @@ mod test {
     test_with_all_storage_impls!(configure_with_deletes_all_except_the_limits_given);
     test_with_all_storage_impls!(configure_with_updates_the_limits);
     test_with_all_storage_impls!(add_limit_only_adds_if_not_present);
+    test_with_all_storage_impls!(evaluation_errors_follow_the_policy_of_the_limit);
+    test_with_all_storage_impls!(caps_the_number_of_qualified_counters);
+    test_with_all_storage_impls!(denied_and_read_only_requests_take_no_room_for_counters);
+    test_with_all_storage_impls!(import_the_counters_exported_from_another_storage);
 
     test_with_distributed_storage_impls!(distributed_rate_limited);
 
@@ -1282,6 +1424,424 @@ mod test {
         assert_eq!(known_limit.name(), None);
     }
 
+    async fn evaluation_errors_follow_the_policy_of_the_limit(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let mut skipped = Limit::new(
+            namespace,
+            0,
+            60,
+            vec!["int(x) + 1 == 2".try_into().expect("failed parsing!")],
+            vec![],
+        );
+        skipped.set_on_error(OnEvaluationError::Skip);
+        rate_limiter.add_limit(&skipped).await;
+
+        let ctx = HashMap::from([("x".to_string(), "one".to_string())]).into();
+        assert!(
+            !rate_limiter
+                .is_rate_limited(namespace, &ctx, 1)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        let mut applied = Limit::new(
+            "applied_namespace",
+            0,
+            60,
+            vec!["int(x) + 1 == 2".try_into().expect("failed parsing!")],
+            vec![],
+        );
+        applied.set_on_error(OnEvaluationError::Apply);
+        rate_limiter.add_limit(&applied).await;
+        assert!(
+            rate_limiter
+                .is_rate_limited("applied_namespace", &ctx, 1)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        let mut failing = Limit::new(
+            namespace,
+            10,
+            60,
+            vec!["x + 1 == 2".try_into().expect("failed parsing!")],
+            vec![],
+        );
+        failing.set_name("failing".to_string());
+        rate_limiter.add_limit(&failing).await;
+
+        match rate_limiter.is_rate_limited(namespace, &ctx, 1).await {
+            Err(LimitadorError::LimitEvaluationError { limit, .. }) => {
+                assert_eq!(limit.name(), Some("failing"))
+            }
+            other => panic!("unexpected result: {:?}", other.map(|r| r.limited)),
+        }
+    }
+
+    async fn caps_the_number_of_qualified_counters(rate_limiter: &mut TestsLimiter) {
+        let policies = [
+            ("deny", OnTooManyCounters::Deny),
+            ("untracked", OnTooManyCounters::AllowUntracked),
+            ("overflow", OnTooManyCounters::Overflow),
+        ];
+        for (namespace, policy) in policies {
+            let mut limit = Limit::new(
+                namespace,
+                2,
+                60,
+                vec![],
+                vec!["user".try_into().expect("failed parsing!")],
+            );
+            limit.set_max_counters(2);
+            limit.set_on_too_many_counters(policy);
+            rate_limiter.add_limit(&limit).await;
+        }
+
+        let rate_limiter: &TestsLimiter = rate_limiter;
+        let ctx = |user: &str| -> Context {
+            HashMap::from([("user".to_string(), user.to_string())]).into()
+        };
+        let limited = |namespace: &'static str, user: &'static str| async move {
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx(user), 1, false)
+                .await
+                .unwrap()
+                .limited
+        };
+
+        for (namespace, _) in policies {
+            for user in ["a", "b"] {
+                assert!(!limited(namespace, user).await);
+            }
+        }
+
+        assert!(limited("deny", "c").await);
+        assert!(!limited("deny", "a").await);
+        assert_eq!(rate_limiter.get_counters("deny").await.unwrap().len(), 2);
+
+        for _ in 0..3 {
+            assert!(!limited("untracked", "c").await);
+        }
+        assert_eq!(
+            rate_limiter.get_counters("untracked").await.unwrap().len(),
+            2
+        );
+
+        assert!(!limited("overflow", "c").await);
+        assert!(!limited("overflow", "d").await);
+        assert!(limited("overflow", "c").await);
+        assert!(!limited("overflow", "a").await);
+        assert_eq!(
+            rate_limiter.get_counters("overflow").await.unwrap().len(),
+            3
+        );
+
+        let limit = rate_limiter
+            .get_limits("overflow")
+            .await
+            .into_iter()
+            .next()
+            .unwrap();
+        assert_eq!(rate_limiter.cardinality(&limit).await.unwrap(), 3);
+    }
+
+    async fn denied_and_read_only_requests_take_no_room_for_counters(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut per_user = Limit::new(
+            namespace,
+            10,
+            60,
+            vec![],
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        per_user.set_name("per_user".to_string());
+        per_user.set_max_counters(2);
+        let mut per_app = Limit::new(
+            namespace,
+            10,
+            60,
+            vec![],
+            vec!["app".try_into().expect("failed parsing!")],
+        );
+        per_app.set_name("per_app".to_string());
+        per_app.set_max_counters(1);
+        rate_limiter.add_limit(&per_user).await;
+        rate_limiter.add_limit(&per_app).await;
+
+        let ctx = |user: &str, app: &str| -> Context {
+            HashMap::from([
+                ("user".to_string(), user.to_string()),
+                ("app".to_string(), app.to_string()),
+            ])
+            .into()
+        };
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &ctx("a", "x"), 1, false)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &ctx("b", "y"), 1, true)
+            .await
+            .unwrap();
+        assert!(result.limited);
+        assert_eq!(result.limit_name, Some("per_app".to_string()));
+        assert_eq!(result.counters.len(), 2);
+        let denied = result
+            .counters
+            .iter()
+            .find(|counter| counter.limit().name() == Some("per_app"))
+            .unwrap();
+        assert_eq!(denied.remaining(), Some(0));
+        assert_eq!(rate_limiter.cardinality(&per_user).await.unwrap(), 1);
+
+        let result = rate_limiter
+            .is_rate_limited(namespace, &ctx("c", "x"), 1)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+        assert_eq!(rate_limiter.cardinality(&per_user).await.unwrap(), 1);
+        assert_eq!(rate_limiter.cardinality(&per_app).await.unwrap(), 1);
+    }
+
+    async fn import_the_counters_exported_from_another_storage(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let per_app = Limit::new(
+            namespace,
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let overall = Limit::new(namespace, 10, 60, vec![], Vec::<Expression>::new());
+
+        let source = TestsLimiter::new_from_blocking_impl(RateLimiter::new_with_storage(Box::<
+            InMemoryStorage,
+        >::default(
+        )));
+        for limit in [&per_app, &overall] {
+            source.add_limit(limit).await;
+        }
+        for (app_id, hits) in [("1", 3), ("2", 2)] {
+            let ctx = HashMap::from([
+                ("req_method".to_string(), "GET".to_string()),
+                ("app_id".to_string(), app_id.to_string()),
+            ])
+            .into();
+            source.update_counters(namespace, &ctx, hits).await.unwrap();
+        }
+
+        let mut dumps = source.export_counters(namespace).await.unwrap();
+        assert_eq!(dumps.len(), 3);
+        for (limit, counters) in [(&per_app, 2), (&overall, 1)] {
+            let dumps = source.export_limit_counters(limit).await.unwrap();
+            assert_eq!(dumps.len(), counters);
+        }
+        let mut expired = dumps[0].clone();
+        expired.expires_at = 0;
+        let mut unknown = dumps[0].clone();
+        unknown.seconds = 30;
+        dumps.extend([expired, unknown]);
+
+        for limit in [&per_app, &overall] {
+            rate_limiter.add_limit(limit).await;
+        }
+        // importing again replaces the counters, rather than adding to them
+        for _ in 0..2 {
+            assert_eq!(
+                rate_limiter.import_counters(dumps.clone()).await.unwrap(),
+                3
+            );
+        }
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 3);
+        for counter in counters {
+            let remaining = match counter.set_variables().get("app_id") {
+                Some(app_id) if app_id == "1" => 7,
+                Some(_) => 8,
+                None => 5,
+            };
+            assert_eq!(counter.remaining(), Some(remaining));
+            assert!(counter.expires_in().unwrap() <= Duration::from_secs(60));
+        }
+    }
+
+    #[tokio::test]
+    async fn windows_expire_as_the_clock_says_in_memory_storage() {
+        let clock = Arc::new(ManualClock::default());
+        let rate_limiter = RateLimiterBuilder::with_clock(10_000, clock.clone()).build();
+        windows_expire_as_the_clock_says(
+            &mut TestsLimiter::new_from_blocking_impl(rate_limiter),
+            &clock,
+        )
+        .await;
+    }
+
+    #[cfg(feature = "disk_storage")]
+    #[tokio::test]
+    async fn windows_expire_as_the_clock_says_disk_storage() {
+        let clock = Arc::new(ManualClock::default());
+        let dir = TempDir::new().expect("We should have a dir!");
+        let storage =
+            DiskStorage::open_with_clock(dir.path(), OptimizeFor::Throughput, clock.clone())
+                .expect("Couldn't open temp dir");
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(storage));
+        windows_expire_as_the_clock_says(
+            &mut TestsLimiter::new_from_blocking_impl(rate_limiter),
+            &clock,
+        )
+        .await;
+    }
+
+    async fn windows_expire_as_the_clock_says(
+        rate_limiter: &mut TestsLimiter,
+        clock: &ManualClock,
+    ) {
+        let namespace = "test_namespace";
+        let max_hits = 3;
+        let limit = Limit::new(
+            namespace,
+            max_hits,
+            3600,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("req_method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+        let ctx = values.into();
+
+        for _ in 0..max_hits {
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &ctx, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        clock.advance(Duration::from_secs(3599));
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert!(rate_limiter
+            .get_counters(namespace)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes_the_variables_of_the_counters_in_memory_storage() {
+        let rate_limiter = RateLimiterBuilder::new(10_000)
+            .hash_variables(VariableHasher::new("secret"))
+            .build();
+        hashes_the_variables_of_the_counters(&mut TestsLimiter::new_from_blocking_impl(
+            rate_limiter,
+        ))
+        .await;
+    }
+
+    #[cfg(feature = "disk_storage")]
+    #[tokio::test]
+    async fn hashes_the_variables_of_the_counters_disk_storage() {
+        let dir = TempDir::new().expect("We should have a dir!");
+        let storage =
+            DiskStorage::open(dir.path(), OptimizeFor::Throughput).expect("Couldn't open temp dir");
+        let rate_limiter =
+            RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)))
+                .hash_variables(VariableHasher::new("secret"))
+                .build();
+        hashes_the_variables_of_the_counters(&mut TestsLimiter::new_from_blocking_impl(
+            rate_limiter,
+        ))
+        .await;
+    }
+
+    async fn hashes_the_variables_of_the_counters(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let limit = Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(&limit).await;
+
+        let ctx_for = |user: &str| -> HashMap<String, String> {
+            HashMap::from([
+                ("req_method".to_string(), "GET".to_string()),
+                ("user".to_string(), user.to_string()),
+            ])
+        };
+        let alice = ctx_for("alice@example.com");
+        let bob = ctx_for("bob@example.com");
+
+        for values in [&alice, &bob] {
+            let ctx = values.clone().into();
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &ctx, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &alice.into(), 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 2);
+        let hasher = VariableHasher::new("secret");
+        let hashed: HashSet<String> = counters
+            .iter()
+            .map(|counter| counter.set_variables().get("user").unwrap().clone())
+            .collect();
+        assert_eq!(
+            hashed,
+            HashSet::from([
+                hasher.hash("alice@example.com"),
+                hasher.hash("bob@example.com")
+            ])
+        );
+    }
+
     #[allow(dead_code)]
     async fn distributed_rate_limited<Fut>(create_distributed_limiters: fn(count: usize) -> Fut)
     where
@@ -1340,4 +1900,321 @@ mod test {
         .await
         .unwrap());
     }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test]
+    #[serial]
+    #[ignore = "needs Redis Sentinel, see REDIS_SENTINEL_URL"]
+    async fn follows_the_master_after_a_sentinel_failover() {
+        let storage = AsyncRedisStorage::new(&crate::redis_sentinel_url())
+            .await
+            .expect("We need Redis Sentinel running locally");
+        storage.clear().await.unwrap();
+        let rate_limiter = AsyncRateLimiter::new_with_storage(Box::new(storage));
+
+        let namespace = "test_namespace".into();
+        let limit = Limit::new(
+            "test_namespace",
+            100,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(limit);
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("req_method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+        let ctx = values.into();
+
+        rate_limiter
+            .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+            .await
+            .unwrap();
+
+        let sentinel_url = crate::redis_sentinel_url();
+        let (sentinels, path) = sentinel_url
+            .trim_start_matches("redis+sentinel://")
+            .split_once('/')
+            .unwrap();
+        let sentinel = sentinels.split(',').next().unwrap();
+        let master_name = path.split(['/', '?', '#']).next().unwrap();
+        let mut sentinel = ::redis::Client::open(format!("redis://{sentinel}"))
+            .unwrap()
+            .get_multiplexed_async_connection()
+            .await
+            .unwrap();
+        ::redis::cmd("SENTINEL")
+            .arg("FAILOVER")
+            .arg(master_name)
+            .query_async::<()>(&mut sentinel)
+            .await
+            .unwrap();
+
+        // the first requests after the failover may fail, then the storage
+        // must have found the new master
+        assert!(eventually(
+            Duration::from_secs(30),
+            Duration::from_millis(500),
+            || async {
+                rate_limiter
+                    .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                    .await
+                    .is_ok()
+            }
+        )
+        .await
+        .unwrap());
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn exact_limits_hold_under_concurrency_with_async_redis() {
+        let storage = AsyncRedisStorage::new("redis://127.0.0.1:6379")
+            .await
+            .expect("We need a Redis running locally")
+            .exact_limits(true);
+        storage.clear().await.unwrap();
+        let rate_limiter = Arc::new(AsyncRateLimiter::new_with_storage(Box::new(storage)));
+
+        let max_hits = 5;
+        let limit = Limit::new(
+            "test_namespace",
+            max_hits,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(limit);
+
+        let requests = (0..50).map(|_| {
+            let rate_limiter = Arc::clone(&rate_limiter);
+            tokio::spawn(async move {
+                let mut values: HashMap<String, String> = HashMap::new();
+                values.insert("req_method".to_string(), "GET".to_string());
+                values.insert("app_id".to_string(), "test_app_id".to_string());
+                let ctx = values.into();
+                rate_limiter
+                    .check_rate_limited_and_update(&"test_namespace".into(), &ctx, 1, true)
+                    .await
+                    .unwrap()
+            })
+        });
+
+        let mut authorized = 0;
+        for request in requests.collect::<Vec<_>>() {
+            let result = request.await.unwrap();
+            if !result.limited {
+                authorized += 1;
+                assert!(result.counters.iter().all(|c| c.remaining().is_some()));
+            }
+        }
+        assert_eq!(authorized, max_hits);
+    }
+
+    #[test]
+    fn caps_hold_under_concurrency_in_memory_storage() {
+        let rate_limiter = RateLimiter::new(10_000);
+        let max_counters = 5;
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        limit.set_max_counters(max_counters);
+        rate_limiter.add_limit(limit.clone());
+
+        let admitted = std::thread::scope(|scope| {
+            let requests: Vec<_> = (0..50)
+                .map(|user| {
+                    let rate_limiter = &rate_limiter;
+                    scope.spawn(move || {
+                        let ctx = HashMap::from([("user".to_string(), user.to_string())]).into();
+                        !rate_limiter
+                            .check_rate_limited_and_update(&"test_namespace".into(), &ctx, 1, false)
+                            .unwrap()
+                            .limited
+                    })
+                })
+                .collect();
+            requests
+                .into_iter()
+                .map(|request| request.join().unwrap())
+                .filter(|admitted| *admitted)
+                .count() as u64
+        });
+        assert_eq!(admitted, max_counters);
+        assert_eq!(rate_limiter.cardinality(&limit).unwrap(), max_counters);
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn caps_hold_under_concurrency_with_async_redis() {
+        let storage = AsyncRedisStorage::new("redis://127.0.0.1:6379")
+            .await
+            .expect("We need a Redis running locally");
+        storage.clear().await.unwrap();
+        let rate_limiter = Arc::new(AsyncRateLimiter::new_with_storage(Box::new(storage)));
+
+        let max_counters = 5;
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        limit.set_max_counters(max_counters);
+        rate_limiter.add_limit(limit.clone());
+
+        let requests = (0..50).map(|user| {
+            let rate_limiter = Arc::clone(&rate_limiter);
+            tokio::spawn(async move {
+                let ctx = HashMap::from([("user".to_string(), user.to_string())]).into();
+                rate_limiter
+                    .check_rate_limited_and_update(&"test_namespace".into(), &ctx, 1, false)
+                    .await
+                    .unwrap()
+            })
+        });
+
+        let mut admitted = 0;
+        for request in requests.collect::<Vec<_>>() {
+            if !request.await.unwrap().limited {
+                admitted += 1;
+            }
+        }
+        assert_eq!(admitted, max_counters);
+        assert_eq!(
+            rate_limiter.cardinality(&limit).await.unwrap(),
+            max_counters
+        );
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test]
+    #[serial]
+    async fn clear_only_touches_the_prefixed_keys_with_async_redis() {
+        let mut con = ::redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_multiplexed_async_connection()
+            .await
+            .expect("We need a Redis running locally");
+        ::redis::cmd("SET")
+            .arg("other_app:key")
+            .arg("value")
+            .exec_async(&mut con)
+            .await
+            .unwrap();
+
+        let storage = AsyncRedisStorage::new("redis://127.0.0.1:6379")
+            .await
+            .expect("We need a Redis running locally")
+            .key_prefix("limitador:")
+            .unwrap();
+        let rate_limiter = AsyncRateLimiter::new_with_storage(Box::new(storage.clone()));
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        rate_limiter.add_limit(limit.clone());
+        rate_limiter
+            .check_rate_limited_and_update(&"test_namespace".into(), &Context::default(), 1, false)
+            .await
+            .unwrap();
+
+        let keys: Vec<String> = ::redis::cmd("KEYS")
+            .arg("limitador:*")
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        // the counter, along with the set of the counters of its limit and the
+        // one of its live counters
+        assert_eq!(keys.len(), 3);
+        assert_eq!(
+            rate_limiter
+                .get_counters(&"test_namespace".into())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        storage.clear().await.unwrap();
+
+        let keys: Vec<String> = ::redis::cmd("KEYS")
+            .arg("limitador:*")
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!(keys.is_empty());
+        let other: Option<String> = ::redis::cmd("GET")
+            .arg("other_app:key")
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert_eq!(other.as_deref(), Some("value"));
+        ::redis::cmd("DEL")
+            .arg("other_app:key")
+            .exec_async(&mut con)
+            .await
+            .unwrap();
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn cached_redis_instances_share_their_updates() {
+        let build = |flushing_period| {
+            CachedRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                .flushing_period(flushing_period)
+                .publish_updates(true)
+                .build()
+        };
+        // only learns of the other's hits from what it publishes
+        let slow = build(Duration::from_secs(60))
+            .await
+            .expect("We need a Redis running locally");
+        slow.clear().await.unwrap();
+        let fast = build(Duration::from_millis(10))
+            .await
+            .expect("We need a Redis running locally");
+
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        let slow = AsyncRateLimiter::new_with_storage(Box::new(slow));
+        let fast = AsyncRateLimiter::new_with_storage(Box::new(fast));
+        slow.add_limit(limit.clone());
+        fast.add_limit(limit);
+        let namespace = "test_namespace".into();
+        let ctx = Context::default();
+
+        // the first hit is flushed right away, caching the counter
+        assert!(
+            !slow
+                .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for _ in 0..9 {
+            assert!(
+                !fast
+                    .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(
+            slow.check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
 }